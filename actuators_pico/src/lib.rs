@@ -0,0 +1,914 @@
+#![no_std]
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use bincode::config::{Configuration, Fixint, LittleEndian, NoLimit};
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{decode_from_slice, encode_into_slice};
+use common_pico::{
+    AUTH_TOKEN, CODEC, FrameError, HEARTBEAT_PERIOD_MS, REQUEST_MAX_SIZE, firmware_version,
+    read_frame, send_error, send_heartbeat, send_nack,
+};
+use embassy_futures::select::{Either, Either4, select, select4};
+use embassy_net::tcp::{TcpReader, TcpSocket, TcpWriter};
+use embassy_rp::adc::{Adc, Blocking, Channel as AdcChannel};
+use embassy_rp::gpio::{Input, Level, Output};
+use embassy_rp::pwm::{Config as PwmConfig, Pwm, PwmError, SetDutyCycle};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer, with_timeout};
+use embedded_io_async::Write as _;
+use heapless::Vec;
+use loco_protocol::{
+    AUTH_TOKEN_EXT_ID, ActuatorId, ActuatorType, ActuatorsTelemetryPayload,
+    BACKEND_PROTOCOL_VERSION, CommandAckPayload, DRIVE_ACTUATOR_SIZE, DriveActuatorPayload,
+    DriveActuatorsBatchArray, Error as LocoProtocolError, ErrorCode, Extensions, HEADER_SIZE,
+    InputId, InputState, InputStatus, InputsStatusArray, Operation, PRESENT_ACTUATOR_SIZE,
+    PRESENT_ACTUATORS_EXT_ID, RegisterPayload, SERVO_ANGLE_MAX, SequenceCheck, SequenceCounter,
+    SequenceTracker, ServoAngle, SignalState, SwitchRailsState, TrackPowerState, check_array_len,
+    decode_command_id, decode_correlation_id, decode_payload, encode_extension_field,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    ConvertLocoProtocolType(LocoProtocolError),
+    DecodeFromSlice(DecodeError),
+    EncodeIntoSlice(EncodeError),
+    ReadFrame(FrameError),
+    SetPwmDutyCycle(PwmError),
+    TcpWrite(embassy_net::tcp::Error),
+    UnsupportedOperation(Operation),
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+impl Error {
+    // What's reported to the controller when a request fails, which isn't
+    // possible when the connection itself is broken
+    fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::ConvertLocoProtocolType(e) => Some(ErrorCode::from(e)),
+            Error::DecodeFromSlice(_)
+            | Error::ReadFrame(FrameError::Codec(LocoProtocolError::PayloadTooLarge(_))) => {
+                Some(ErrorCode::InvalidPayload)
+            }
+            Error::UnsupportedOperation(_) => Some(ErrorCode::UnsupportedOperation),
+            Error::SetPwmDutyCycle(_) => Some(ErrorCode::PwmError),
+            Error::EncodeIntoSlice(_) | Error::ReadFrame(_) | Error::TcpWrite(_) => None,
+        }
+    }
+
+    // Requests which only fail on their own, without anything going wrong
+    // with the connection or the actuators
+    fn is_malformed_request(&self) -> bool {
+        matches!(
+            self,
+            Error::ConvertLocoProtocolType(_)
+                | Error::DecodeFromSlice(_)
+                | Error::UnsupportedOperation(_)
+        )
+    }
+}
+
+/**
+ * Constants related to the optional current monitor. The current drawn from
+ * the power rail is measured through a shunt resistor connected to the ADC.
+ * Setting the threshold to None disables the monitor.
+ */
+pub const OVERCURRENT_ADC_THRESHOLD: Option<u16> = Some(3072);
+const CURRENT_SAMPLING_PERIOD_MS: u64 = 10;
+const TELEMETRY_PERIOD_MS: u64 = 1000;
+
+static CURRENT: AtomicU16 = AtomicU16::new(0);
+static OVERCURRENT: Signal<CriticalSectionRawMutex, u16> = Signal::new();
+
+/**
+ * Constants related to the digital inputs (pushbuttons, track contacts).
+ */
+const INPUT_SAMPLING_PERIOD_MS: u64 = 10;
+const INPUT_DEBOUNCE_SAMPLES: u8 = 3;
+
+static INPUT_EVENTS: Channel<CriticalSectionRawMutex, (InputId, InputState), 8> = Channel::new();
+
+/**
+ * ID of the last command applied, to be acknowledged to the controller. Only
+ * the last one matters, since acknowledging it acknowledges every command
+ * before it.
+ */
+static COMMAND_ACK: Signal<CriticalSectionRawMutex, u64> = Signal::new();
+
+/**
+ * Request rejected by the board, which doesn't tear down the connection. Only
+ * the last rejection is reported if several pile up.
+ */
+static NACK: Signal<CriticalSectionRawMutex, ErrorCode> = Signal::new();
+
+/**
+ * Maximum number of signal lights a board can drive, each one taking three
+ * GPIOs.
+ */
+pub const SIGNALS_MAX: usize = 4;
+
+/**
+ * Constants related to the servos throwing switch rails. Servos expect a
+ * pulse every 20ms, whose width sets the angle they turn to.
+ */
+pub const SERVOS_MAX: usize = 8;
+const SERVO_PWM_FREQ_HZ: u32 = 50;
+const SERVO_PERIOD_US: u16 = 20_000;
+const SERVO_MIN_PULSE_US: u16 = 1000;
+const SERVO_MAX_PULSE_US: u16 = 2000;
+
+// Every switch rails and servo, the track power and the signal lights
+const ACTUATORS_MAX: usize = 8 + SERVOS_MAX + 1 + SIGNALS_MAX;
+
+#[embassy_executor::task]
+pub async fn current_monitor_task(mut adc: Adc<'static, Blocking>, mut shunt: AdcChannel<'static>) {
+    // Safe to unwrap since the task is only spawned with a threshold set
+    let threshold = OVERCURRENT_ADC_THRESHOLD.unwrap();
+
+    loop {
+        match adc.blocking_read(&mut shunt) {
+            Ok(value) => {
+                CURRENT.store(value, Ordering::Release);
+                if value > threshold {
+                    log::error!("current_monitor_task(): Overcurrent detected ({})", value);
+                    OVERCURRENT.signal(value);
+                    // Leave some time for the controller to cut the power
+                    // before reporting the same event again.
+                    Timer::after_millis(TELEMETRY_PERIOD_MS).await;
+                }
+            }
+            Err(e) => log::error!("current_monitor_task(): Error reading ADC: {:?}", e),
+        }
+
+        Timer::after_millis(CURRENT_SAMPLING_PERIOD_MS).await;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn input_monitor_task(mut inputs: [DigitalInput; 4]) {
+    loop {
+        for input in inputs.iter_mut() {
+            if let Some(state) = input.poll()
+                && INPUT_EVENTS.try_send((input.id, state)).is_err()
+            {
+                log::warn!(
+                    "input_monitor_task(): Events queue full, dropping {}",
+                    input.id
+                );
+            }
+        }
+
+        Timer::after_millis(INPUT_SAMPLING_PERIOD_MS).await;
+    }
+}
+
+async fn send_message(
+    writer: &mut TcpWriter<'_>,
+    sequence: &mut SequenceCounter,
+    message: &mut [u8],
+    operation: Operation,
+    payload_len: usize,
+) -> Result<()> {
+    let frame_len = CODEC
+        .encode_message(message, operation, payload_len, sequence.next_sequence())
+        .map_err(Error::ConvertLocoProtocolType)?;
+
+    writer
+        .write_all(&message[..frame_len])
+        .await
+        .map_err(Error::TcpWrite)?;
+
+    Ok(())
+}
+
+async fn send_telemetry(
+    bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    writer: &mut TcpWriter<'_>,
+    sequence: &mut SequenceCounter,
+    current: u16,
+    overcurrent: bool,
+) -> Result<()> {
+    log::debug!("send_telemetry()");
+
+    let mut message = [0u8; REQUEST_MAX_SIZE];
+    let payload_len = encode_into_slice(
+        ActuatorsTelemetryPayload {
+            current,
+            overcurrent: overcurrent.into(),
+        },
+        &mut message[HEADER_SIZE..],
+        bincode_cfg,
+    )
+    .map_err(Error::EncodeIntoSlice)?;
+
+    send_message(
+        writer,
+        sequence,
+        &mut message,
+        Operation::ActuatorsTelemetry,
+        payload_len,
+    )
+    .await
+}
+
+async fn send_inputs_status(
+    bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    writer: &mut TcpWriter<'_>,
+    sequence: &mut SequenceCounter,
+    first_event: (InputId, InputState),
+) -> Result<()> {
+    log::debug!("send_inputs_status()");
+
+    let mut message = [0u8; REQUEST_MAX_SIZE];
+    let mut payload_offset = HEADER_SIZE + size_of::<InputsStatusArray>();
+    let mut updated_inputs: u8 = 0;
+
+    // Gather every pending event so they can be reported at once
+    let mut event = Some(first_event);
+    while let Some((input_id, state)) = event {
+        log::info!("{} is now {}", input_id, state);
+        payload_offset += encode_into_slice(
+            InputStatus {
+                input_id: input_id.into(),
+                state: state.into(),
+            },
+            &mut message[payload_offset..],
+            bincode_cfg,
+        )
+        .map_err(Error::EncodeIntoSlice)?;
+        updated_inputs += 1;
+        event = INPUT_EVENTS.try_receive().ok();
+    }
+
+    encode_into_slice(
+        InputsStatusArray {
+            len: updated_inputs,
+        },
+        &mut message[HEADER_SIZE..],
+        bincode_cfg,
+    )
+    .map_err(Error::EncodeIntoSlice)?;
+
+    send_message(
+        writer,
+        sequence,
+        &mut message,
+        Operation::InputsStatus,
+        payload_offset - HEADER_SIZE,
+    )
+    .await
+}
+
+async fn send_command_ack(
+    bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    writer: &mut TcpWriter<'_>,
+    sequence: &mut SequenceCounter,
+    command_id: u64,
+) -> Result<()> {
+    log::debug!("send_command_ack()");
+
+    let mut message = [0u8; REQUEST_MAX_SIZE];
+    let payload_len = encode_into_slice(
+        CommandAckPayload { command_id },
+        &mut message[HEADER_SIZE..],
+        bincode_cfg,
+    )
+    .map_err(Error::EncodeIntoSlice)?;
+
+    send_message(
+        writer,
+        sequence,
+        &mut message,
+        Operation::CommandAck,
+        payload_len,
+    )
+    .await
+}
+
+// Tells what the command was sent on behalf of, so that the logs of the board
+// can be matched with the ones of the controller
+fn log_correlation_id(extensions: Extensions) {
+    if let Ok(Some(correlation_id)) = decode_correlation_id(extensions) {
+        log::info!("Correlation ID {:016x}", correlation_id);
+    }
+}
+
+async fn send_register(
+    bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    writer: &mut TcpWriter<'_>,
+    sequence: &mut SequenceCounter,
+    present_actuators: &[u8],
+) -> Result<()> {
+    log::debug!("send_register()");
+
+    let mut message = [0u8; REQUEST_MAX_SIZE];
+    let mut payload_len = encode_into_slice(
+        RegisterPayload {
+            protocol_version: BACKEND_PROTOCOL_VERSION,
+            firmware_version: firmware_version!(),
+        },
+        &mut message[HEADER_SIZE..],
+        bincode_cfg,
+    )
+    .map_err(Error::EncodeIntoSlice)?;
+    payload_len += encode_extension_field(
+        &mut message[HEADER_SIZE + payload_len..],
+        PRESENT_ACTUATORS_EXT_ID,
+        present_actuators,
+    )
+    .map_err(Error::ConvertLocoProtocolType)?;
+    if let Some(auth_token) = AUTH_TOKEN {
+        payload_len += encode_extension_field(
+            &mut message[HEADER_SIZE + payload_len..],
+            AUTH_TOKEN_EXT_ID,
+            auth_token.as_bytes(),
+        )
+        .map_err(Error::ConvertLocoProtocolType)?;
+    }
+
+    send_message(
+        writer,
+        sequence,
+        &mut message,
+        Operation::Register,
+        payload_len,
+    )
+    .await
+}
+
+async fn send_reports(
+    bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    writer: &mut TcpWriter<'_>,
+    sequence: &mut SequenceCounter,
+) -> Result<()> {
+    log::debug!("send_reports()");
+
+    loop {
+        // Report telemetry periodically, or as soon as an overcurrent is
+        // detected. Inputs are reported as soon as they change, commands
+        // acknowledged as soon as they're applied, and rejected requests as
+        // soon as they're rejected. Without any current monitor, a heartbeat maintains the
+        // connection alive instead.
+        match with_timeout(
+            Duration::from_millis(TELEMETRY_PERIOD_MS.min(HEARTBEAT_PERIOD_MS)),
+            select4(
+                OVERCURRENT.wait(),
+                INPUT_EVENTS.receive(),
+                COMMAND_ACK.wait(),
+                NACK.wait(),
+            ),
+        )
+        .await
+        {
+            Ok(Either4::First(current)) => {
+                send_telemetry(bincode_cfg, writer, sequence, current, true).await?
+            }
+            Ok(Either4::Second(event)) => {
+                send_inputs_status(bincode_cfg, writer, sequence, event).await?
+            }
+            Ok(Either4::Third(command_id)) => {
+                send_command_ack(bincode_cfg, writer, sequence, command_id).await?
+            }
+            Ok(Either4::Fourth(code)) => send_nack(writer, sequence.next_sequence(), code)
+                .await
+                .map_err(Error::TcpWrite)?,
+            Err(_) if OVERCURRENT_ADC_THRESHOLD.is_some() => {
+                let current = CURRENT.load(Ordering::Acquire);
+                send_telemetry(bincode_cfg, writer, sequence, current, false).await?
+            }
+            Err(_) => send_heartbeat(writer, sequence.next_sequence())
+                .await
+                .map_err(Error::TcpWrite)?,
+        }
+    }
+}
+
+pub struct DigitalInput {
+    gpio: Input<'static>,
+    id: InputId,
+    active_level: Level,
+    state: InputState,
+    pending_samples: u8,
+}
+
+impl DigitalInput {
+    pub fn new(gpio: Input<'static>, id: InputId, active_level: Level) -> Self {
+        DigitalInput {
+            gpio,
+            id,
+            active_level,
+            state: InputState::default(),
+            pending_samples: 0,
+        }
+    }
+
+    // Returns the new state once it has been stable for enough samples, which
+    // filters out bouncing from mechanical contacts.
+    fn poll(&mut self) -> Option<InputState> {
+        let state = if self.gpio.get_level() == self.active_level {
+            InputState::Active
+        } else {
+            InputState::Inactive
+        };
+
+        if state == self.state {
+            self.pending_samples = 0;
+            return None;
+        }
+
+        self.pending_samples += 1;
+        if self.pending_samples < INPUT_DEBOUNCE_SAMPLES {
+            return None;
+        }
+
+        self.pending_samples = 0;
+        self.state = state;
+        Some(state)
+    }
+}
+
+pub struct SwitchRails {
+    pub gpio: Output<'static>,
+    pub id: ActuatorId,
+}
+
+impl SwitchRails {
+    fn switch(&mut self, state: SwitchRailsState) -> Result<()> {
+        log::debug!("SwitchRails::switch()");
+        let level = match state {
+            SwitchRailsState::Direct => Level::Low,
+            SwitchRailsState::Diverted => Level::High,
+        };
+        log::info!(
+            "SwitchRails::switch(): Setting {} to {} ({:?})",
+            self.id,
+            state,
+            level
+        );
+        self.gpio.set_level(level);
+        Ok(())
+    }
+}
+
+pub struct TrackPower {
+    pub gpio: Output<'static>,
+}
+
+impl TrackPower {
+    fn set(&mut self, state: TrackPowerState) -> Result<()> {
+        log::debug!("TrackPower::set()");
+        let level = match state {
+            TrackPowerState::On => Level::High,
+            TrackPowerState::Off => Level::Low,
+        };
+        log::info!("TrackPower::set(): Setting to {} ({:?})", state, level);
+        self.gpio.set_level(level);
+        Ok(())
+    }
+}
+
+pub struct SignalLight {
+    pub red: Output<'static>,
+    pub yellow: Output<'static>,
+    pub green: Output<'static>,
+    pub id: ActuatorId,
+}
+
+impl SignalLight {
+    fn set(&mut self, state: SignalState) -> Result<()> {
+        log::debug!("SignalLight::set()");
+        log::info!("SignalLight::set(): Setting {} to {}", self.id, state);
+        // Lamps are switched off first, so that two aspects are never shown
+        // at once
+        self.red.set_low();
+        self.yellow.set_low();
+        self.green.set_low();
+        match state {
+            SignalState::Red => self.red.set_high(),
+            SignalState::Yellow => self.yellow.set_high(),
+            SignalState::Green => self.green.set_high(),
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Servo throwing a switch rails, driven by a PWM output configured through
+ * servo_pwm_config(). No pulse is sent until the controller drives it, so
+ * that the servo doesn't move on power up.
+ */
+pub struct Servo {
+    pub pwm: Pwm<'static>,
+    pub id: ActuatorId,
+}
+
+impl Servo {
+    fn set(&mut self, angle: ServoAngle) -> Result<()> {
+        log::debug!("Servo::set()");
+        let range_us = u32::from(SERVO_MAX_PULSE_US - SERVO_MIN_PULSE_US);
+        let pulse_us = SERVO_MIN_PULSE_US
+            + (range_us * u32::from(u8::from(angle)) / u32::from(SERVO_ANGLE_MAX)) as u16;
+        log::info!(
+            "Servo::set(): Setting {} to {} ({}us)",
+            self.id,
+            angle,
+            pulse_us
+        );
+        self.pwm
+            .set_duty_cycle_fraction(pulse_us, SERVO_PERIOD_US)
+            .map_err(Error::SetPwmDutyCycle)
+    }
+}
+
+// The divider keeps the period of 50Hz within the 16 bits of the counter
+pub fn servo_pwm_config() -> PwmConfig {
+    let clock_freq_hz = embassy_rp::clocks::clk_sys_freq();
+    let divider = 64u8;
+
+    let mut cfg = PwmConfig::default();
+    cfg.top = (clock_freq_hz / (SERVO_PWM_FREQ_HZ * divider as u32)) as u16 - 1;
+    cfg.divider = divider.into();
+    cfg
+}
+
+#[derive(Copy, Clone)]
+enum ActuatorCommand {
+    SwitchRails(ActuatorId, SwitchRailsState),
+    TrackPower(TrackPowerState),
+    Signal(ActuatorId, SignalState),
+    Servo(ActuatorId, ServoAngle),
+}
+
+pub struct Actuators {
+    bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    switch_rails: Vec<SwitchRails, 8>,
+    track_power: TrackPower,
+    signals: Vec<SignalLight, SIGNALS_MAX>,
+    servos: Vec<Servo, SERVOS_MAX>,
+    // Kept across connections, so that a command sent again after a
+    // reconnect isn't applied twice, which matters for pulsed switch rails
+    last_command_id: Option<u64>,
+}
+
+impl Actuators {
+    pub fn new(
+        switch_rails: Vec<SwitchRails, 8>,
+        track_power: TrackPower,
+        signals: Vec<SignalLight, SIGNALS_MAX>,
+        servos: Vec<Servo, SERVOS_MAX>,
+    ) -> Self {
+        log::debug!("Actuators::new()");
+
+        Actuators {
+            bincode_cfg: bincode::config::legacy(),
+            switch_rails,
+            track_power,
+            signals,
+            servos,
+            last_command_id: None,
+        }
+    }
+
+    // Advertised to the loco_controller, so that it never drives an actuator
+    // which isn't wired
+    fn present_actuators(&self) -> Vec<u8, { ACTUATORS_MAX * PRESENT_ACTUATOR_SIZE }> {
+        let switch_rails = self
+            .switch_rails
+            .iter()
+            .map(|s| (s.id, ActuatorType::SwitchRails));
+        let track_power = [(ActuatorId::TrackPower, ActuatorType::TrackPower)];
+        let signals = self.signals.iter().map(|s| (s.id, ActuatorType::Signal));
+        let servos = self.servos.iter().map(|s| (s.id, ActuatorType::Servo));
+
+        switch_rails
+            .chain(track_power)
+            .chain(signals)
+            .chain(servos)
+            .flat_map(|(id, actuator_type)| [u8::from(id), u8::from(actuator_type)])
+            .collect()
+    }
+
+    // Switch rails which aren't wired on this board are reported, the
+    // controller only being supposed to drive the ones it was told about
+    fn update_switch_rails(&mut self, id: ActuatorId, state: SwitchRailsState) -> Result<()> {
+        log::debug!("Actuators::update_actuator()");
+        match self
+            .switch_rails
+            .iter_mut()
+            .find(|switch_rail| switch_rail.id == id)
+        {
+            Some(switch_rail) => switch_rail.switch(state)?,
+            None => {
+                log::warn!("Actuators::update_switch_rails(): Unknown {}", id);
+                NACK.signal(ErrorCode::UnknownActuator);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Same as update_switch_rails(), for the servos
+    fn update_servo(&mut self, id: ActuatorId, angle: ServoAngle) -> Result<()> {
+        log::debug!("Actuators::update_servo()");
+        match self.servos.iter_mut().find(|servo| servo.id == id) {
+            Some(servo) => servo.set(angle)?,
+            None => {
+                log::warn!("Actuators::update_servo(): Unknown {}", id);
+                NACK.signal(ErrorCode::UnknownActuator);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Signals which aren't wired on this board are ignored, as the controller
+    // drives the same signals whatever the board
+    fn update_signal(&mut self, id: ActuatorId, state: SignalState) -> Result<()> {
+        log::debug!("Actuators::update_signal()");
+        match self.signals.iter_mut().find(|signal| signal.id == id) {
+            Some(signal) => signal.set(state)?,
+            None => {
+                log::warn!("Actuators::update_signal(): Unknown {}", id);
+                NACK.signal(ErrorCode::UnknownActuator);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn decode_actuator_command(payload: DriveActuatorPayload) -> Result<ActuatorCommand> {
+        let actuator_id: ActuatorId = payload
+            .actuator_id
+            .try_into()
+            .map_err(Error::ConvertLocoProtocolType)?;
+        let actuator_type: ActuatorType = payload
+            .actuator_type
+            .try_into()
+            .map_err(Error::ConvertLocoProtocolType)?;
+
+        let command = match actuator_type {
+            ActuatorType::SwitchRails => ActuatorCommand::SwitchRails(
+                actuator_id,
+                payload
+                    .actuator_state
+                    .try_into()
+                    .map_err(Error::ConvertLocoProtocolType)?,
+            ),
+            ActuatorType::TrackPower => ActuatorCommand::TrackPower(
+                payload
+                    .actuator_state
+                    .try_into()
+                    .map_err(Error::ConvertLocoProtocolType)?,
+            ),
+            ActuatorType::Signal => ActuatorCommand::Signal(
+                actuator_id,
+                payload
+                    .actuator_state
+                    .try_into()
+                    .map_err(Error::ConvertLocoProtocolType)?,
+            ),
+            ActuatorType::Servo => ActuatorCommand::Servo(
+                actuator_id,
+                payload
+                    .actuator_state
+                    .try_into()
+                    .map_err(Error::ConvertLocoProtocolType)?,
+            ),
+        };
+
+        Ok(command)
+    }
+
+    fn apply_actuator_command(&mut self, command: ActuatorCommand) -> Result<()> {
+        match command {
+            ActuatorCommand::SwitchRails(actuator_id, state) => {
+                self.update_switch_rails(actuator_id, state)
+            }
+            ActuatorCommand::TrackPower(state) => self.track_power.set(state),
+            ActuatorCommand::Signal(actuator_id, state) => self.update_signal(actuator_id, state),
+            ActuatorCommand::Servo(actuator_id, angle) => self.update_servo(actuator_id, angle),
+        }
+    }
+
+    // Commands the controller sends again after a reconnect are acknowledged
+    // without being applied, if they already were before the connection broke
+    fn is_duplicate_command(&self, command_id: Option<u64>) -> bool {
+        match (command_id, self.last_command_id) {
+            (Some(id), Some(last)) if id <= last => {
+                log::warn!("Actuators::is_duplicate_command(): Skipping command {}", id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn command_applied(&mut self, command_id: Option<u64>) {
+        if let Some(id) = command_id {
+            self.last_command_id = Some(self.last_command_id.map_or(id, |last| last.max(id)));
+            COMMAND_ACK.signal(id);
+        }
+    }
+
+    fn handle_op_drive_actuator(&mut self, payload: &[u8]) -> Result<()> {
+        log::debug!("Actuators::handle_op_drive_actuator()");
+
+        let (drive_actuator_payload, extensions): (DriveActuatorPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        let command_id = decode_command_id(extensions).map_err(Error::ConvertLocoProtocolType)?;
+        log_correlation_id(extensions);
+        let command = Self::decode_actuator_command(drive_actuator_payload)?;
+
+        if !self.is_duplicate_command(command_id) {
+            self.apply_actuator_command(command)?;
+        }
+        self.command_applied(command_id);
+
+        Ok(())
+    }
+
+    fn handle_op_drive_actuators_batch(&mut self, payload: &[u8]) -> Result<()> {
+        log::debug!("Actuators::handle_op_drive_actuators_batch()");
+
+        let (batch, entries_offset): (DriveActuatorsBatchArray, usize) =
+            decode_from_slice(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        check_array_len(batch.len, DRIVE_ACTUATOR_SIZE, &payload[entries_offset..])
+            .map_err(Error::ConvertLocoProtocolType)?;
+
+        // The batch is applied atomically: every entry is decoded and checked
+        // first, so that a single invalid entry leaves all actuators untouched.
+        let mut offset = entries_offset;
+        for _ in 0..batch.len {
+            let (drive_actuator_payload, len): (DriveActuatorPayload, usize) =
+                decode_from_slice(&payload[offset..], self.bincode_cfg)
+                    .map_err(Error::DecodeFromSlice)?;
+            Self::decode_actuator_command(drive_actuator_payload)?;
+            offset += len;
+        }
+        let command_id = decode_command_id(Extensions::new(&payload[offset..]))
+            .map_err(Error::ConvertLocoProtocolType)?;
+        log_correlation_id(Extensions::new(&payload[offset..]));
+        if self.is_duplicate_command(command_id) {
+            self.command_applied(command_id);
+            return Ok(());
+        }
+
+        // Entries are applied in order, as the controller relies on it for
+        // throwing the switch rails of a route one after the other
+        let mut offset = entries_offset;
+        for _ in 0..batch.len {
+            let (drive_actuator_payload, len): (DriveActuatorPayload, usize) =
+                decode_from_slice(&payload[offset..], self.bincode_cfg)
+                    .map_err(Error::DecodeFromSlice)?;
+            let command = Self::decode_actuator_command(drive_actuator_payload)?;
+            self.apply_actuator_command(command)?;
+            offset += len;
+        }
+
+        log::info!(
+            "Actuators::handle_op_drive_actuators_batch(): {} actuators driven",
+            batch.len
+        );
+        self.command_applied(command_id);
+
+        Ok(())
+    }
+
+    async fn handle_messages(&mut self, socket: &mut TcpReader<'_>) -> Result<()> {
+        log::debug!("Actuators::handle_messages()");
+        let mut rx_sequence = SequenceTracker::default();
+        loop {
+            log::info!("Actuators::handle_messages(): Waiting for incoming bytes...");
+
+            // Corrupted frames are dropped before anything gets decoded from
+            // them, and so are frames coming again or out of order
+            let mut buf = [0u8; REQUEST_MAX_SIZE];
+            let Some(frame) = read_frame(socket, &mut buf, 0)
+                .await
+                .map_err(Error::ReadFrame)?
+            else {
+                continue;
+            };
+            let header = frame.header;
+
+            match rx_sequence.check(header.sequence) {
+                SequenceCheck::InOrder => {}
+                SequenceCheck::Gap(lost) => log::warn!(
+                    "Actuators::handle_messages(): {} frames lost before {}",
+                    lost,
+                    header.sequence
+                ),
+                check @ (SequenceCheck::Duplicate | SequenceCheck::Reordered) => {
+                    log::warn!(
+                        "Actuators::handle_messages(): Discarding frame {} ({:?})",
+                        header.sequence,
+                        check
+                    );
+                    continue;
+                }
+            }
+
+            let op = match frame.operation() {
+                Ok(op) => op,
+                Err(e) => {
+                    log::warn!(
+                        "Actuators::handle_messages(): Rejecting operation {}",
+                        header.operation
+                    );
+                    NACK.signal(ErrorCode::from(&e));
+                    continue;
+                }
+            };
+            log::info!("Actuators::handle_messages(): Operation {:?}", op);
+
+            let payload = frame.payload;
+
+            let result = match op {
+                Operation::DriveActuator => self.handle_op_drive_actuator(payload),
+                Operation::DriveActuatorsBatch => self.handle_op_drive_actuators_batch(payload),
+                Operation::Connect
+                | Operation::SensorsStatus
+                | Operation::ControlLoco
+                | Operation::LocoStatus
+                | Operation::ActuatorsTelemetry
+                | Operation::InputsStatus
+                | Operation::Register
+                | Operation::TimeSync
+                | Operation::HoldOnDisconnect
+                | Operation::Error
+                | Operation::Disconnect
+                | Operation::Heartbeat
+                | Operation::ControlLocoFunctions
+                | Operation::LocoTelemetry
+                | Operation::CommandAck
+                | Operation::EmergencyStop
+                | Operation::SelectWireFormat
+                | Operation::Nack
+                | Operation::SafeCrawlReport => Err(Error::UnsupportedOperation(op)),
+            };
+
+            // A malformed request is rejected on its own, leaving the
+            // actuators as they were
+            match result {
+                Ok(()) => {}
+                Err(e) if e.is_malformed_request() => {
+                    log::warn!("Actuators::handle_messages(): Rejecting {:?}: {:?}", op, e);
+                    // Safe to unwrap since every malformed request has a code
+                    NACK.signal(e.code().unwrap());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+
+            log::info!("Actuators::handle_messages(): Operation {:?} completed", op);
+        }
+    }
+
+    pub async fn handle_connection(&mut self, socket: &mut TcpSocket<'_>) -> Result<()> {
+        log::debug!("Actuators::handle_connection()");
+
+        let bincode_cfg = self.bincode_cfg;
+        let present_actuators = self.present_actuators();
+        let (mut reader, mut writer) = socket.split();
+        // Sequence numbers of the frames sent through this connection
+        let mut tx_sequence = SequenceCounter::default();
+
+        // Register to the controller so it can check our versions, and learn
+        // which actuators are there
+        send_register(
+            bincode_cfg,
+            &mut writer,
+            &mut tx_sequence,
+            &present_actuators,
+        )
+        .await?;
+
+        // Whichever side fails first tears down the whole connection
+        let result = match select(
+            self.handle_messages(&mut reader),
+            send_reports(bincode_cfg, &mut writer, &mut tx_sequence),
+        )
+        .await
+        {
+            Either::First(res) => res,
+            Either::Second(res) => res,
+        };
+
+        // The controller is told why, as long as the connection still works
+        if let Err(e) = &result
+            && let Some(code) = e.code()
+            && let Err(report_error) =
+                send_error(&mut writer, tx_sequence.next_sequence(), code).await
+        {
+            log::warn!(
+                "Actuators::handle_connection(): Error reporting {}: {:?}",
+                code,
+                report_error
+            );
+        }
+
+        result
+    }
+}
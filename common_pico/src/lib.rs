@@ -1,40 +1,110 @@
 #![no_std]
 
+mod provisioning;
+
+use bincode::config::{Configuration, Fixint, LittleEndian, NoLimit};
 use cyw43::{Control, JoinOptions};
 use cyw43_pio::{PioSpi, RM2_CLOCK_DIVIDER};
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_net::tcp::{ConnectError, TcpSocket};
-use embassy_net::{Config, IpAddress, IpEndpoint, Stack, StackResources};
+use embassy_net::{Config, IpAddress, IpEndpoint, Ipv4Address, Stack, StackResources};
 use embassy_rp::clocks::RoscRng;
 use embassy_rp::gpio::{Level, Output, Pin};
-use embassy_rp::peripherals::{DMA_CH0, PIO0, USB};
+use embassy_rp::peripherals::{DMA_CH0, FLASH, PIO0, USB};
 use embassy_rp::pio::{InterruptHandler as PioInterruptHandler, Pio, PioPin};
 use embassy_rp::usb::{Driver as UsbDriver, InterruptHandler as UsbInterruptHandler};
 use embassy_rp::{Peri, bind_interrupts};
-use embassy_time::Timer;
+use embassy_time::{Duration, TimeoutError, Timer, with_timeout};
+use embedded_io_async::{Read, ReadExactError, Write};
+use heapless::Vec;
+use loco_protocol::{
+    Codec, DISCONNECT_PAYLOAD_SIZE, DisconnectPayload, DisconnectReason, ERROR_PAYLOAD_SIZE,
+    Error as LocoProtocolError, ErrorCode, ErrorPayload, FRAME_CRC_SIZE, Frame, HEADER_SIZE,
+    Operation, PAYLOAD_MAX_LEN,
+};
+use provisioning::{NetworkSettings, PROVISIONING_JOIN_ATTEMPTS, SettingsFlash, run_provisioning};
 use rand::RngCore;
 use static_cell::StaticCell;
 
 /**
  * Constants related to the WiFi connection between the Pi Pico boards
- * and the main controller.
+ * and the main controller. They're only used until other settings have been
+ * stored to flash through the provisioning access point.
  */
 pub const WIFI_NETWORK: &str = "loco-controller";
 pub const WIFI_PASSWORD: &str = "locoloco";
-pub const SERVER_IP_ADDRESS: IpAddress = IpAddress::v4(10, 42, 0, 1);
+pub const SERVER_IP_ADDRESS: Ipv4Address = Ipv4Address::new(10, 42, 0, 1);
 pub const SERVER_TCP_PORT_LOCOS: u16 = 8004;
 pub const SERVER_TCP_PORT_SENSORS: u16 = 8005;
 pub const SERVER_TCP_PORT_ACTUATORS: u16 = 8006;
 
+/**
+ * Token the loco_controller expects from the boards, if it's been given one.
+ * It's set through the LOCOLOCO_AUTH_TOKEN environment variable when
+ * building, so that it never ends up in the sources.
+ */
+pub const AUTH_TOKEN: Option<&str> = option_env!("LOCOLOCO_AUTH_TOKEN");
+
+/**
+ * Constants related to the standby loco_controllers. A board tries the
+ * loco_controllers in the order they've been provisioned, the primary one
+ * first, and moves on to the next one when it can't connect in time.
+ */
+pub const SERVERS_MAX: usize = 3;
+pub const SERVER_CONNECT_TIMEOUT_MS: u64 = 3000;
+
+pub type ServerAddresses = Vec<IpAddress, SERVERS_MAX>;
+
 /**
  * Constants related to the protocol, but specific to the Pi Pico constraints.
  */
-pub const PAYLOAD_MAX_SIZE: usize = 256;
-pub const HEADER_SIZE: usize = 0x3;
-pub const REQUEST_MAX_SIZE: usize = HEADER_SIZE + PAYLOAD_MAX_SIZE;
+pub const PAYLOAD_MAX_SIZE: usize = PAYLOAD_MAX_LEN;
+pub const REQUEST_MAX_SIZE: usize = HEADER_SIZE + PAYLOAD_MAX_SIZE + FRAME_CRC_SIZE;
 pub const RESPONSE_MAX_SIZE: usize = 1024;
 
+/**
+ * Frames every message exchanged with the loco_controller, bounded to what
+ * the Pi Pico can hold.
+ */
+pub const CODEC: Codec<Configuration<LittleEndian, Fixint, NoLimit>> =
+    Codec::new(bincode::config::legacy(), PAYLOAD_MAX_SIZE);
+
+/**
+ * Every board sends a Heartbeat whenever it hasn't sent anything else for
+ * this long, so that the loco_controller can tell it's still there.
+ */
+pub const HEARTBEAT_PERIOD_MS: u64 = 1000;
+
+/**
+ * Parses a version number known at build time, so that firmwares can report
+ * their own crate version to the main controller.
+ */
+pub const fn parse_version_number(number: &str) -> u8 {
+    let bytes = number.as_bytes();
+    let mut value: u8 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    value
+}
+
+/**
+ * Expands into the FirmwareVersion of the crate calling this macro.
+ */
+#[macro_export]
+macro_rules! firmware_version {
+    () => {
+        loco_protocol::FirmwareVersion {
+            major: $crate::parse_version_number(env!("CARGO_PKG_VERSION_MAJOR")),
+            minor: $crate::parse_version_number(env!("CARGO_PKG_VERSION_MINOR")),
+            patch: $crate::parse_version_number(env!("CARGO_PKG_VERSION_PATCH")),
+        }
+    };
+}
+
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
     USBCTRL_IRQ => UsbInterruptHandler<USB>;
@@ -72,15 +142,22 @@ pub async fn initialize_program(program_name: &str) {
     log::info!("Hello {}!", program_name);
 }
 
+/**
+ * Joins the WiFi network stored to flash, or the built-in one, returning the
+ * addresses of the loco_controllers along with the network stack. When the
+ * network can't be joined, the board falls back to the provisioning access
+ * point.
+ */
 pub async fn initialize_wifi<'a, 'b>(
     spawner: &Spawner,
+    flash: Peri<'static, FLASH>,
     pwr_pin: Peri<'static, impl Pin>,
     cs_pin: Peri<'static, impl Pin>,
     pio_pin: Peri<'static, PIO0>,
     dio: Peri<'static, impl PioPin>,
     clk: Peri<'static, impl PioPin>,
     dma: Peri<'static, DMA_CH0>,
-) -> (Control<'a>, Stack<'b>) {
+) -> (Control<'a>, Stack<'b>, ServerAddresses) {
     let fw = include_bytes!("../../cyw43-firmware/43439A0.bin");
     let clm = include_bytes!("../../cyw43-firmware/43439A0_clm.bin");
 
@@ -125,14 +202,26 @@ pub async fn initialize_wifi<'a, 'b>(
 
     unwrap!(spawner.spawn(net_task(runner)));
 
+    let mut flash = SettingsFlash::new_blocking(flash);
+    let settings = NetworkSettings::load(&mut flash).unwrap_or_default();
+    let join_options = || {
+        if settings.password.is_empty() {
+            JoinOptions::new_open()
+        } else {
+            JoinOptions::new(settings.password.as_bytes())
+        }
+    };
+
+    let mut attempts = 0;
     loop {
-        match control
-            .join(WIFI_NETWORK, JoinOptions::new(WIFI_PASSWORD.as_bytes()))
-            .await
-        {
+        match control.join(&settings.ssid, join_options()).await {
             Ok(_) => break,
             Err(err) => {
-                log::error!("join failed with status={}", err.status);
+                log::error!("join {} failed with status={}", settings.ssid, err.status);
+                attempts += 1;
+                if PROVISIONING_JOIN_ATTEMPTS.is_some_and(|max| attempts >= max) {
+                    run_provisioning(spawner, &mut control, stack, &mut flash, &settings).await;
+                }
             }
         }
     }
@@ -144,23 +233,232 @@ pub async fn initialize_wifi<'a, 'b>(
     }
     log::info!("DHCP is now up!");
 
-    (control, stack)
+    let servers = settings
+        .servers
+        .iter()
+        .map(|server_ip| IpAddress::Ipv4(*server_ip))
+        .collect();
+
+    (control, stack, servers)
 }
 
+/**
+ * Connects to the first loco_controller accepting the connection, trying the
+ * standby ones only once those before them failed. The error of the last
+ * attempt is returned when none of them can be reached.
+ */
 pub async fn connect_loco_controller<'a>(
     stack: Stack<'a>,
     rx_buffer: &'a mut [u8],
     tx_buffer: &'a mut [u8],
-    addr: IpAddress,
+    servers: &[IpAddress],
     port: u16,
 ) -> Result<TcpSocket<'a>, ConnectError> {
     let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
 
-    let remote_endpoint = IpEndpoint { addr, port };
+    let mut error = ConnectError::NoRoute;
+    for addr in servers {
+        let remote_endpoint = IpEndpoint { addr: *addr, port };
+
+        log::info!("Connecting to {:?}...", remote_endpoint);
+        match with_timeout(
+            Duration::from_millis(SERVER_CONNECT_TIMEOUT_MS),
+            socket.connect(remote_endpoint),
+        )
+        .await
+        {
+            Ok(Ok(())) => {
+                log::info!("Connected to {:?}", socket.remote_endpoint());
+                return Ok(socket);
+            }
+            Ok(Err(e)) => error = e,
+            Err(TimeoutError) => error = ConnectError::TimedOut,
+        }
+        log::warn!("Connecting to {:?} failed: {:?}", remote_endpoint, error);
+
+        // Back to the closed state, ready for the next attempt
+        socket.abort();
+    }
+
+    Err(error)
+}
+
+/**
+ * Informs the loco_controller that this device is going away on purpose, for
+ * instance before an update or before going to sleep, and closes the socket.
+ * The loco_controller immediately marks the device offline and stops sending
+ * it anything, rather than waiting for the connection to fail, and keeps the
+ * reason around for the operator.
+ */
+pub async fn disconnect_loco_controller(
+    socket: &mut TcpSocket<'_>,
+    sequence: u16,
+    reason: DisconnectReason,
+) -> Result<(), embassy_net::tcp::Error> {
+    socket
+        .write_all(&disconnect_message(sequence, reason))
+        .await?;
+    socket.flush().await?;
+    socket.close();
+
+    Ok(())
+}
+
+/**
+ * Tells the loco_controller that this device is alive. Works with a whole
+ * socket as well as with its writing half.
+ */
+pub async fn send_heartbeat<W>(writer: &mut W, sequence: u16) -> Result<(), embassy_net::tcp::Error>
+where
+    W: Write<Error = embassy_net::tcp::Error>,
+{
+    log::debug!("send_heartbeat()");
+
+    writer
+        .write_all(&empty_message(Operation::Heartbeat, sequence))
+        .await
+}
+
+/**
+ * Tells the loco_controller why a request couldn't be handled, before the
+ * connection gets closed. Works with a whole socket as well as with its
+ * writing half.
+ */
+pub async fn send_error<W>(
+    writer: &mut W,
+    sequence: u16,
+    code: ErrorCode,
+) -> Result<(), embassy_net::tcp::Error>
+where
+    W: Write<Error = embassy_net::tcp::Error>,
+{
+    log::debug!("send_error(): {}", code);
+
+    writer
+        .write_all(&error_message(Operation::Error, sequence, code))
+        .await
+}
 
-    log::info!("Connecting to {:?}...", remote_endpoint);
-    socket.connect(remote_endpoint).await?;
-    log::info!("Connected to {:?}", socket.remote_endpoint());
+/**
+ * Rejects a request the Pico can't make sense of, in place of its response.
+ * The connection stays up, as the controller only gives up on this request.
+ * Works with a whole socket as well as with its writing half.
+ */
+pub async fn send_nack<W>(
+    writer: &mut W,
+    sequence: u16,
+    code: ErrorCode,
+) -> Result<(), embassy_net::tcp::Error>
+where
+    W: Write<Error = embassy_net::tcp::Error>,
+{
+    log::debug!("send_nack(): {}", code);
+
+    writer
+        .write_all(&error_message(Operation::Nack, sequence, code))
+        .await
+}
 
-    Ok(socket)
+fn error_message(
+    operation: Operation,
+    sequence: u16,
+    code: ErrorCode,
+) -> [u8; HEADER_SIZE + ERROR_PAYLOAD_SIZE + FRAME_CRC_SIZE] {
+    let mut message = [0u8; HEADER_SIZE + ERROR_PAYLOAD_SIZE + FRAME_CRC_SIZE];
+    // Safe to unwrap since both the header and the payload always fit, followed
+    // by their CRC
+    CODEC
+        .encode(
+            &mut message,
+            operation,
+            ErrorPayload { code: code.into() },
+            sequence,
+        )
+        .unwrap();
+
+    message
+}
+
+fn disconnect_message(
+    sequence: u16,
+    reason: DisconnectReason,
+) -> [u8; HEADER_SIZE + DISCONNECT_PAYLOAD_SIZE + FRAME_CRC_SIZE] {
+    let mut message = [0u8; HEADER_SIZE + DISCONNECT_PAYLOAD_SIZE + FRAME_CRC_SIZE];
+    // Safe to unwrap since both the header and the payload always fit, followed
+    // by their CRC
+    CODEC
+        .encode(
+            &mut message,
+            Operation::Disconnect,
+            DisconnectPayload {
+                reason: reason.into(),
+            },
+            sequence,
+        )
+        .unwrap();
+
+    message
+}
+
+fn empty_message(operation: Operation, sequence: u16) -> [u8; HEADER_SIZE + FRAME_CRC_SIZE] {
+    let mut message = [0u8; HEADER_SIZE + FRAME_CRC_SIZE];
+    // Safe to unwrap since a header always fits into HEADER_SIZE, followed by
+    // its CRC
+    CODEC
+        .encode_message(&mut message, operation, 0, sequence)
+        .unwrap();
+
+    message
+}
+
+/**
+ * Failure to read a frame from the loco_controller, after which the stream
+ * can't be trusted anymore.
+ */
+#[derive(Debug)]
+pub enum FrameError {
+    Codec(LocoProtocolError),
+    TcpRead(ReadExactError<embassy_net::tcp::Error>),
+}
+
+/**
+ * Reads the frame whose first `received` bytes are already in buf, which is
+ * how a Pico waits for the first byte of a frame while doing something else.
+ * Corrupted frames are discarded as long as the stream remains in sync, in
+ * which case None is returned and the next frame is up to the caller. The
+ * sequence number of the frame is left for the caller to check.
+ */
+pub async fn read_frame<'a, R>(
+    reader: &mut R,
+    buf: &'a mut [u8; REQUEST_MAX_SIZE],
+    received: usize,
+) -> Result<Option<Frame<'a>>, FrameError>
+where
+    R: Read<Error = embassy_net::tcp::Error>,
+{
+    reader
+        .read_exact(&mut buf[received..HEADER_SIZE])
+        .await
+        .map_err(FrameError::TcpRead)?;
+    let header = CODEC
+        .decode_header(&buf[..HEADER_SIZE])
+        .map_err(FrameError::Codec)?;
+
+    // The Codec bounds the payload to PAYLOAD_MAX_SIZE, hence the whole frame
+    // fits into the buffer
+    let frame_len = header.frame_len();
+    reader
+        .read_exact(&mut buf[HEADER_SIZE..frame_len])
+        .await
+        .map_err(FrameError::TcpRead)?;
+
+    let frame: &'a [u8] = &buf[..frame_len];
+    match CODEC.decode_frame(frame) {
+        Ok(frame) => Ok(Some(frame)),
+        Err(e @ LocoProtocolError::FrameChecksumMismatch(..)) => {
+            log::warn!("read_frame(): Discarding corrupted frame: {:?}", e);
+            Ok(None)
+        }
+        Err(e) => Err(FrameError::Codec(e)),
+    }
 }
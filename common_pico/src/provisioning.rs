@@ -0,0 +1,518 @@
+use core::fmt::Write as _;
+
+use cyw43::Control;
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{ConfigV4, IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4};
+use embassy_rp::flash::{Blocking, ERASE_SIZE, Flash};
+use embassy_rp::peripherals::FLASH;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write as _;
+use heapless::{String, Vec};
+use loco_protocol::crc16;
+
+use crate::{SERVER_IP_ADDRESS, SERVERS_MAX, WIFI_NETWORK, WIFI_PASSWORD};
+
+/**
+ * Constants related to the provisioning access point, started when the
+ * configured WiFi can't be joined. Setting PROVISIONING_JOIN_ATTEMPTS to None
+ * makes the boards retry joining forever instead.
+ */
+pub const PROVISIONING_JOIN_ATTEMPTS: Option<u32> = Some(10);
+const PROVISIONING_SSID: &str = "locoloco-setup";
+const PROVISIONING_PASSWORD: &str = "locoloco";
+const PROVISIONING_CHANNEL: u8 = 6;
+const PROVISIONING_IP_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+// Only the device used for provisioning is expected to join
+const PROVISIONING_CLIENT_IP_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 2);
+const PROVISIONING_HTTP_PORT: u16 = 80;
+
+/**
+ * The settings are kept in the last sector of the flash. The firmwares are
+ * linked into the first 2 MiB, far from it, so that flashing a new firmware
+ * doesn't wipe them.
+ */
+pub const FLASH_SIZE: usize = 4 * 1024 * 1024;
+const SETTINGS_OFFSET: u32 = (FLASH_SIZE - ERASE_SIZE) as u32;
+const SETTINGS_MAGIC: [u8; 4] = *b"LCW2";
+const SSID_MAX_LEN: usize = 32;
+const PASSWORD_MAX_LEN: usize = 64;
+// Magic, SSID and password with their lengths, server IPs with their count
+// and CRC
+const SETTINGS_SIZE: usize = 4 + 1 + SSID_MAX_LEN + 1 + PASSWORD_MAX_LEN + 1 + 4 * SERVERS_MAX + 2;
+// Settings stored before the standby servers, holding a single server IP
+const LEGACY_SETTINGS_MAGIC: [u8; 4] = *b"LCWF";
+const LEGACY_SETTINGS_SIZE: usize = 4 + 1 + SSID_MAX_LEN + 1 + PASSWORD_MAX_LEN + 4 + 2;
+// Comma separated IPv4 addresses
+const SERVERS_FIELD_MAX_LEN: usize = 16 * SERVERS_MAX;
+
+pub type SettingsFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+/**
+ * WiFi network to join, and where to find the loco_controllers on it, the
+ * primary one first.
+ */
+pub struct NetworkSettings {
+    pub ssid: String<SSID_MAX_LEN>,
+    pub password: String<PASSWORD_MAX_LEN>,
+    pub servers: Vec<Ipv4Address, SERVERS_MAX>,
+}
+
+impl Default for NetworkSettings {
+    // Safe to unwrap since the built-in network fits into the settings
+    fn default() -> Self {
+        NetworkSettings {
+            ssid: String::try_from(WIFI_NETWORK).unwrap(),
+            password: String::try_from(WIFI_PASSWORD).unwrap(),
+            servers: Vec::from_array([SERVER_IP_ADDRESS]),
+        }
+    }
+}
+
+impl NetworkSettings {
+    fn to_bytes(&self) -> [u8; SETTINGS_SIZE] {
+        let mut bytes = [0u8; SETTINGS_SIZE];
+        let (magic, rest) = bytes.split_at_mut(4);
+        magic.copy_from_slice(&SETTINGS_MAGIC);
+        let (ssid, rest) = rest.split_at_mut(1 + SSID_MAX_LEN);
+        ssid[0] = self.ssid.len() as u8;
+        ssid[1..1 + self.ssid.len()].copy_from_slice(self.ssid.as_bytes());
+        let (password, rest) = rest.split_at_mut(1 + PASSWORD_MAX_LEN);
+        password[0] = self.password.len() as u8;
+        password[1..1 + self.password.len()].copy_from_slice(self.password.as_bytes());
+        rest[0] = self.servers.len() as u8;
+        for (octets, server_ip) in rest[1..1 + 4 * SERVERS_MAX]
+            .chunks_exact_mut(4)
+            .zip(&self.servers)
+        {
+            octets.copy_from_slice(&server_ip.octets());
+        }
+
+        let crc = crc16(&bytes[..SETTINGS_SIZE - 2]);
+        bytes[SETTINGS_SIZE - 2..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    // Anything else than settings written by to_bytes(), such as an erased
+    // sector, is rejected
+    fn from_bytes(bytes: &[u8; SETTINGS_SIZE]) -> Option<Self> {
+        let size = match <[u8; 4]>::try_from(&bytes[..4]) {
+            Ok(SETTINGS_MAGIC) => SETTINGS_SIZE,
+            Ok(LEGACY_SETTINGS_MAGIC) => LEGACY_SETTINGS_SIZE,
+            _ => return None,
+        };
+        let (content, crc) = bytes[..size].split_at(size - 2);
+        if crc16(content).to_le_bytes() != crc {
+            return None;
+        }
+
+        let string = |field: &[u8]| {
+            let len = usize::from(field[0]);
+            field
+                .get(1..1 + len)
+                .and_then(|s| core::str::from_utf8(s).ok())
+        };
+        let (ssid, rest) = content[4..].split_at(1 + SSID_MAX_LEN);
+        let (password, rest) = rest.split_at(1 + PASSWORD_MAX_LEN);
+        let (count, server_ips) = if size == LEGACY_SETTINGS_SIZE {
+            (1, rest)
+        } else {
+            (usize::from(rest[0]), &rest[1..])
+        };
+        let servers: Vec<Ipv4Address, SERVERS_MAX> = server_ips
+            .chunks_exact(4)
+            .take(count.min(SERVERS_MAX))
+            .map(|octets| Ipv4Address::new(octets[0], octets[1], octets[2], octets[3]))
+            .collect();
+        if servers.is_empty() {
+            return None;
+        }
+
+        Some(NetworkSettings {
+            ssid: String::try_from(string(ssid)?).ok()?,
+            password: String::try_from(string(password)?).ok()?,
+            servers,
+        })
+    }
+
+    pub fn load(flash: &mut SettingsFlash) -> Option<Self> {
+        let mut bytes = [0u8; SETTINGS_SIZE];
+        if let Err(e) = flash.blocking_read(SETTINGS_OFFSET, &mut bytes) {
+            log::error!("failed reading network settings: {:?}", e);
+            return None;
+        }
+
+        NetworkSettings::from_bytes(&bytes)
+    }
+
+    fn save(&self, flash: &mut SettingsFlash) -> Result<(), embassy_rp::flash::Error> {
+        flash.blocking_erase(SETTINGS_OFFSET, SETTINGS_OFFSET + ERASE_SIZE as u32)?;
+        flash.blocking_write(SETTINGS_OFFSET, &self.to_bytes())
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    ConnectionClosed,
+    RequestTooLarge,
+    Tcp(embassy_net::tcp::Error),
+}
+
+/**
+ * Turns the CYW43 into an access point serving a minimal page to enter the
+ * network settings, so that boards can be deployed at a new venue without
+ * being reflashed. The settings are stored to flash, and the board reboots
+ * to join the new network. Never returns.
+ */
+pub async fn run_provisioning(
+    spawner: &Spawner,
+    control: &mut Control<'_>,
+    stack: Stack<'static>,
+    flash: &mut SettingsFlash,
+    settings: &NetworkSettings,
+) -> ! {
+    log::warn!(
+        "starting access point {} for provisioning, browse http://{}",
+        PROVISIONING_SSID,
+        PROVISIONING_IP_ADDRESS
+    );
+    control
+        .start_ap_wpa2(
+            PROVISIONING_SSID,
+            PROVISIONING_PASSWORD,
+            PROVISIONING_CHANNEL,
+        )
+        .await;
+    stack.set_config_v4(ConfigV4::Static(StaticConfigV4 {
+        address: Ipv4Cidr::new(PROVISIONING_IP_ADDRESS, 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    }));
+    unwrap!(spawner.spawn(dhcp_server_task(stack)));
+
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 2048];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(PROVISIONING_HTTP_PORT).await {
+            log::warn!("provisioning accept error: {:?}", e);
+            continue;
+        }
+
+        match serve_request(&mut socket, settings).await {
+            Ok(Some(new_settings)) => match new_settings.save(flash) {
+                Ok(()) => {
+                    log::info!("network settings saved, rebooting");
+                    socket.close();
+                    let _ = socket.flush().await;
+                    Timer::after_secs(1).await;
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                Err(e) => log::error!("failed saving network settings: {:?}", e),
+            },
+            Ok(None) => {}
+            Err(e) => log::warn!("provisioning request error: {:?}", e),
+        }
+
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+// Answers a single request, returning the settings submitted through the
+// form if they're valid
+async fn serve_request(
+    socket: &mut TcpSocket<'_>,
+    settings: &NetworkSettings,
+) -> Result<Option<NetworkSettings>, Error> {
+    let mut request = [0u8; 1024];
+    let (head_len, body_len) = read_request(socket, &mut request).await?;
+    let body = &request[head_len + 4..head_len + 4 + body_len];
+
+    let (new_settings, message) = if request.starts_with(b"POST ") {
+        match parse_form(body) {
+            Ok(s) => (Some(s), "Saved, the board now reboots to join the network."),
+            Err(reason) => (None, reason),
+        }
+    } else {
+        (None, "")
+    };
+
+    let mut page: String<2048> = String::new();
+    // Safe to ignore, as the page is only cut short by a very long SSID
+    let _ = write_page(
+        &mut page,
+        new_settings.as_ref().unwrap_or(settings),
+        message,
+    );
+    let mut header: String<128> = String::new();
+    let _ = write!(
+        header,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        page.len()
+    );
+    socket
+        .write_all(header.as_bytes())
+        .await
+        .map_err(Error::Tcp)?;
+    socket
+        .write_all(page.as_bytes())
+        .await
+        .map_err(Error::Tcp)?;
+
+    Ok(new_settings)
+}
+
+// Reads until the whole body announced by the headers has been received,
+// returning the length of the headers and of the body
+async fn read_request(socket: &mut TcpSocket<'_>, buf: &mut [u8]) -> Result<(usize, usize), Error> {
+    let mut len = 0;
+    loop {
+        if len == buf.len() {
+            return Err(Error::RequestTooLarge);
+        }
+        let n = socket.read(&mut buf[len..]).await.map_err(Error::Tcp)?;
+        if n == 0 {
+            return Err(Error::ConnectionClosed);
+        }
+        len += n;
+
+        let Some(head_len) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") else {
+            continue;
+        };
+        let body_len = core::str::from_utf8(&buf[..head_len])
+            .ok()
+            .and_then(|head| {
+                head.split("\r\n").find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse().ok())?
+                })
+            })
+            .unwrap_or(0);
+        if head_len + 4 + body_len > buf.len() {
+            return Err(Error::RequestTooLarge);
+        }
+        if len >= head_len + 4 + body_len {
+            return Ok((head_len, body_len));
+        }
+    }
+}
+
+fn parse_form(body: &[u8]) -> Result<NetworkSettings, &'static str> {
+    let ssid: String<SSID_MAX_LEN> =
+        form_value(body, "ssid").ok_or("The SSID must be 1 to 32 characters long.")?;
+    let password: String<PASSWORD_MAX_LEN> =
+        form_value(body, "password").ok_or("The password is too long.")?;
+    let servers_error = "The servers must be 1 to 3 IPv4 addresses, separated by commas.";
+    let mut servers = Vec::new();
+    for server in form_value::<SERVERS_FIELD_MAX_LEN>(body, "servers")
+        .ok_or(servers_error)?
+        .split(',')
+    {
+        let server_ip = server.trim().parse().map_err(|_| servers_error)?;
+        servers.push(server_ip).map_err(|_| servers_error)?;
+    }
+
+    if ssid.is_empty() {
+        return Err("The SSID must be 1 to 32 characters long.");
+    }
+    // WPA2 passphrases, or an open network
+    if !password.is_empty() && password.len() < 8 {
+        return Err("The password must be at least 8 characters long.");
+    }
+
+    Ok(NetworkSettings {
+        ssid,
+        password,
+        servers,
+    })
+}
+
+// Decodes a field of an application/x-www-form-urlencoded body
+fn form_value<const N: usize>(body: &[u8], key: &str) -> Option<String<N>> {
+    let value = body.split(|b| *b == b'&').find_map(|field| {
+        let eq = field.iter().position(|b| *b == b'=')?;
+        (&field[..eq] == key.as_bytes()).then(|| &field[eq + 1..])
+    })?;
+
+    let mut decoded: Vec<u8, N> = Vec::new();
+    let mut bytes = value.iter();
+    while let Some(b) = bytes.next() {
+        let byte = match b {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [*bytes.next()?, *bytes.next()?];
+                u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b => *b,
+        };
+        decoded.push(byte).ok()?;
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn write_page(
+    page: &mut impl core::fmt::Write,
+    settings: &NetworkSettings,
+    message: &str,
+) -> core::fmt::Result {
+    write!(
+        page,
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+         <title>locoloco setup</title></head><body><h1>locoloco setup</h1><p>{}</p>\
+         <form method=\"post\"><p>WiFi SSID<br><input name=\"ssid\" value=\"",
+        message
+    )?;
+    write_escaped(page, &settings.ssid)?;
+    page.write_str(
+        "\"></p><p>WiFi password, empty for an open network<br><input name=\"password\" type=\"password\"></p>\
+         <p>loco_controller IP addresses, primary first, separated by commas<br><input name=\"servers\" value=\"",
+    )?;
+    for (i, server_ip) in settings.servers.iter().enumerate() {
+        if i > 0 {
+            page.write_char(',')?;
+        }
+        write!(page, "{}", server_ip)?;
+    }
+    page.write_str("\"></p><p><input type=\"submit\" value=\"Save\"></p></form></body></html>")
+}
+
+fn write_escaped(page: &mut impl core::fmt::Write, value: &str) -> core::fmt::Result {
+    value.chars().try_for_each(|c| match c {
+        '&' => page.write_str("&amp;"),
+        '<' => page.write_str("&lt;"),
+        '>' => page.write_str("&gt;"),
+        '"' => page.write_str("&quot;"),
+        c => page.write_char(c),
+    })
+}
+
+/**
+ * Constants related to the minimal DHCP server of the provisioning access
+ * point, which leases the same address to whoever asks.
+ */
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_OFFSET: usize = 240;
+// BOOTP replies are padded to their historical minimum size
+const DHCP_REPLY_SIZE: usize = 300;
+const DHCP_LEASE_SECS: u32 = 3600;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCP_OPTION_SUBNET_MASK: u8 = 1;
+const DHCP_OPTION_LEASE_TIME: u8 = 51;
+const DHCP_OPTION_MESSAGE_TYPE: u8 = 53;
+const DHCP_OPTION_SERVER_ID: u8 = 54;
+const DHCP_OPTION_PAD: u8 = 0;
+const DHCP_OPTION_END: u8 = 255;
+
+#[embassy_executor::task]
+async fn dhcp_server_task(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    unwrap!(socket.bind(DHCP_SERVER_PORT));
+
+    let mut request = [0u8; 576];
+    let mut reply = [0u8; DHCP_REPLY_SIZE];
+
+    loop {
+        let len = match socket.recv_from(&mut request).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                log::warn!("DHCP receive error: {:?}", e);
+                continue;
+            }
+        };
+        if !dhcp_reply(&request[..len], &mut reply) {
+            continue;
+        }
+
+        // The client has no address yet
+        let endpoint = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), DHCP_CLIENT_PORT);
+        if let Err(e) = socket.send_to(&reply, endpoint).await {
+            log::warn!("DHCP send error: {:?}", e);
+        }
+    }
+}
+
+fn dhcp_option(options: &[u8], code: u8) -> Option<&[u8]> {
+    let mut i = 0;
+    loop {
+        match *options.get(i)? {
+            DHCP_OPTION_PAD => i += 1,
+            DHCP_OPTION_END => return None,
+            c => {
+                let len = usize::from(*options.get(i + 1)?);
+                let value = options.get(i + 2..i + 2 + len)?;
+                if c == code {
+                    return Some(value);
+                }
+                i += 2 + len;
+            }
+        }
+    }
+}
+
+// Offers an address to a discovering client, and acknowledges its request,
+// telling whether there's something to send back
+fn dhcp_reply(request: &[u8], reply: &mut [u8; DHCP_REPLY_SIZE]) -> bool {
+    if request.len() < DHCP_OPTIONS_OFFSET
+        || request[0] != BOOTREQUEST
+        || request[236..240] != DHCP_MAGIC_COOKIE
+    {
+        return false;
+    }
+    let reply_type = match dhcp_option(&request[DHCP_OPTIONS_OFFSET..], DHCP_OPTION_MESSAGE_TYPE)
+        .and_then(|t| t.first())
+    {
+        Some(&DHCPDISCOVER) => DHCPOFFER,
+        Some(&DHCPREQUEST) => DHCPACK,
+        _ => return false,
+    };
+
+    reply.fill(0);
+    // Ethernet hardware addresses, then the transaction ID and flags of the
+    // request
+    reply[..3].copy_from_slice(&[BOOTREPLY, 1, 6]);
+    reply[4..8].copy_from_slice(&request[4..8]);
+    reply[10..12].copy_from_slice(&request[10..12]);
+    reply[16..20].copy_from_slice(&PROVISIONING_CLIENT_IP_ADDRESS.octets());
+    reply[20..24].copy_from_slice(&PROVISIONING_IP_ADDRESS.octets());
+    reply[28..44].copy_from_slice(&request[28..44]);
+    reply[236..240].copy_from_slice(&DHCP_MAGIC_COOKIE);
+
+    let mut offset = DHCP_OPTIONS_OFFSET;
+    let mut push_option = |code: u8, value: &[u8]| {
+        reply[offset] = code;
+        reply[offset + 1] = value.len() as u8;
+        reply[offset + 2..offset + 2 + value.len()].copy_from_slice(value);
+        offset += 2 + value.len();
+    };
+    push_option(DHCP_OPTION_MESSAGE_TYPE, &[reply_type]);
+    push_option(DHCP_OPTION_SERVER_ID, &PROVISIONING_IP_ADDRESS.octets());
+    push_option(DHCP_OPTION_LEASE_TIME, &DHCP_LEASE_SECS.to_be_bytes());
+    push_option(DHCP_OPTION_SUBNET_MASK, &[255, 255, 255, 0]);
+    reply[offset] = DHCP_OPTION_END;
+
+    true
+}
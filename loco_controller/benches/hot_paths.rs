@@ -0,0 +1,277 @@
+//! Hot paths of the 100 Hz control loop: route search, loco commands and a
+//! whole Oracle cycle. The locos and the actuators board are faked over
+//! loopback, hence the commands include a round-trip through the kernel,
+//! though not through the WiFi. Run with `cargo bench`.
+
+use std::{
+    collections::BTreeMap,
+    hint::black_box,
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{Arc, LazyLock},
+    thread,
+    time::Duration,
+};
+
+use bincode::{config::legacy, encode_to_vec};
+use criterion::{Criterion, criterion_group, criterion_main};
+use loco_controller::{
+    backend::{Backend, LocoIntent, OracleMode},
+    buffer_stops::BufferStops,
+    command_ids::CommandIds,
+    config::{Config, LocoConfig},
+    oracle::Oracle,
+    power::PowerDistricts,
+    rail_network::{CheckpointId, RailNetwork, TrackId},
+    signals::Signals,
+    switch_order::SwitchOrder,
+    tags::TagDatabase,
+};
+use loco_protocol::{
+    BACKEND_PROTOCOL_VERSION, Codec, ConnectPayload, ControlLocoResponse, Direction,
+    FRAME_CRC_SIZE, FirmwareVersion, HEADER_SIZE, LocoId, LocoStatusResponse, MotorStatus,
+    Operation, PAYLOAD_MAX_LEN, Speed,
+};
+use tokio::{net::TcpListener, runtime::Runtime};
+
+const CHECKPOINTS: [CheckpointId; 8] = [
+    CheckpointId::Checkpoint1,
+    CheckpointId::Checkpoint2,
+    CheckpointId::Checkpoint3,
+    CheckpointId::Checkpoint4,
+    CheckpointId::Checkpoint5,
+    CheckpointId::Checkpoint6,
+    CheckpointId::Station1,
+    CheckpointId::Station2,
+];
+const TRACKS: [TrackId; 3] = [TrackId::Track1, TrackId::Station1, TrackId::Station2];
+const DIRECTIONS: [Direction; 2] = [Direction::Forward, Direction::Backward];
+
+// Serves the connections of the boards, the way the loco_controller does
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| Runtime::new().unwrap());
+
+fn loco_id(n: u8) -> LocoId {
+    LocoId::try_from(n).unwrap()
+}
+
+// Answers the requests the way the loco firmware does, applying whatever it's
+// commanded
+fn fake_loco(port: u16, loco_id: LocoId) {
+    let codec = Codec::new(legacy(), PAYLOAD_MAX_LEN);
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut buf = [0; HEADER_SIZE + PAYLOAD_MAX_LEN + FRAME_CRC_SIZE];
+    let connect = ConnectPayload {
+        loco_id: loco_id.into(),
+        device_id: u8::from(loco_id).into(),
+        protocol_version: BACKEND_PROTOCOL_VERSION,
+        firmware_version: FirmwareVersion {
+            major: 0,
+            minor: 1,
+            patch: 0,
+        },
+    };
+    let len = codec
+        .encode(&mut buf, Operation::Connect, connect, 0)
+        .unwrap();
+    stream.write_all(&buf[..len]).unwrap();
+
+    thread::spawn(move || {
+        loop {
+            let mut header = [0; HEADER_SIZE];
+            if stream.read_exact(&mut header).is_err() {
+                return;
+            }
+            let header = codec.decode_header(&header).unwrap();
+            let mut rest = vec![0; usize::from(header.payload_len) + FRAME_CRC_SIZE];
+            stream.read_exact(&mut rest).unwrap();
+
+            let response = match Operation::try_from(header.operation) {
+                Ok(Operation::ControlLoco) => encode_to_vec(
+                    ControlLocoResponse {
+                        direction: rest[0],
+                        speed: rest[1],
+                    },
+                    legacy(),
+                ),
+                Ok(Operation::LocoStatus) => encode_to_vec(
+                    LocoStatusResponse {
+                        direction: Direction::Forward.into(),
+                        speed: Speed::Stop.into(),
+                        motor_status: MotorStatus::Running.into(),
+                    },
+                    legacy(),
+                ),
+                _ => continue,
+            };
+            stream.write_all(&response.unwrap()).unwrap();
+        }
+    });
+}
+
+// Drops whatever the actuators board is sent, acknowledging nothing
+fn fake_actuators(port: u16) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    thread::spawn(move || io::copy(&mut stream, &mut io::sink()));
+}
+
+// Backend with the given number of locos connected, along with the actuators
+// board
+fn backend(locos: u8) -> Arc<Backend> {
+    let mut config = Config::default();
+    // Every command gets sent, however close to the previous one
+    config.backend.loco_command_min_spacing_ms = 0;
+    // The fake boards never send any heartbeat
+    config.backend.heartbeat_timeout_ms = 3_600_000;
+    let roster: BTreeMap<LocoId, LocoConfig> = (1..=locos)
+        .map(|n| (loco_id(n), LocoConfig::default()))
+        .collect();
+    let backend = Arc::new(Backend::new(
+        &config.backend,
+        &config.history,
+        &roster,
+        &config.network,
+        Arc::new(TagDatabase::load(&config.tags, &roster)),
+        CommandIds::load(&config.backend).unwrap(),
+        RUNTIME.handle().clone(),
+    ));
+
+    let locos_listener = RUNTIME
+        .block_on(TcpListener::bind(("127.0.0.1", 0)))
+        .unwrap();
+    let locos_port = locos_listener.local_addr().unwrap().port();
+    let shared_backend = backend.clone();
+    RUNTIME.spawn(async move {
+        loop {
+            let (stream, _) = locos_listener.accept().await.unwrap();
+            let backend = shared_backend.clone();
+            tokio::spawn(async move { backend.handle_loco_connection(stream).await });
+        }
+    });
+    for n in 1..=locos {
+        fake_loco(locos_port, loco_id(n));
+    }
+
+    let actuators_listener = RUNTIME
+        .block_on(TcpListener::bind(("127.0.0.1", 0)))
+        .unwrap();
+    let actuators_port = actuators_listener.local_addr().unwrap().port();
+    let shared_backend = backend.clone();
+    RUNTIME.spawn(async move {
+        let (stream, _) = actuators_listener.accept().await.unwrap();
+        let _ = shared_backend.serve_actuators(stream).await;
+    });
+    fake_actuators(actuators_port);
+
+    while (1..=locos).any(|n| {
+        backend
+            .block_on(backend.cached_loco_status(loco_id(n)))
+            .is_err()
+    }) || !backend.actuators_connected()
+    {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    backend
+}
+
+fn route_search(c: &mut Criterion) {
+    let rail_network = RailNetwork::new();
+
+    c.bench_function("rail_network/distance_every_pair", |b| {
+        b.iter(|| {
+            let mut reachable = 0;
+            for from in CHECKPOINTS {
+                for direction in DIRECTIONS {
+                    for to in CHECKPOINTS {
+                        if rail_network
+                            .distance(from, direction, black_box(to))
+                            .is_some()
+                        {
+                            reachable += 1;
+                        }
+                    }
+                }
+            }
+            reachable
+        })
+    });
+
+    c.bench_function("rail_network/next_checkpoint_every_track", |b| {
+        b.iter(|| {
+            let mut found = 0;
+            for from in CHECKPOINTS {
+                for direction in DIRECTIONS {
+                    for track_id in TRACKS {
+                        if rail_network
+                            .next_checkpoint_id_for_track_id_target(
+                                0,
+                                from,
+                                direction,
+                                black_box(track_id),
+                            )
+                            .is_some()
+                        {
+                            found += 1;
+                        }
+                    }
+                }
+            }
+            found
+        })
+    });
+}
+
+fn control_loco(c: &mut Criterion) {
+    let backend = backend(1);
+    let mut speeds = [Speed::Slow, Speed::Normal].into_iter().cycle();
+
+    // The speed changes every time, as a command identical to the last one
+    // is never sent
+    c.bench_function("backend/control_loco", |b| {
+        b.iter(|| {
+            backend
+                .block_on(backend.control_loco(
+                    loco_id(1),
+                    Direction::Forward,
+                    speeds.next().unwrap(),
+                    None,
+                ))
+                .unwrap()
+        })
+    });
+}
+
+// Eight locos already running forward around the layout, one past every
+// checkpoint, so that the Oracle has to sort out which ones go on
+fn oracle_cycle(c: &mut Criterion) {
+    let backend = backend(8);
+    let config = Config::default();
+    for (n, checkpoint_id) in (1..=8).zip(CHECKPOINTS) {
+        backend.set_loco_location(loco_id(n), Some(checkpoint_id), "bench");
+        backend.set_loco_intent(
+            loco_id(n),
+            LocoIntent::Drive(Direction::Forward, TrackId::Track1),
+        );
+        backend
+            .block_on(backend.control_loco(loco_id(n), Direction::Forward, Speed::Normal, None))
+            .unwrap();
+    }
+    backend.set_oracle_mode(OracleMode::Auto);
+
+    let mut oracle = Oracle::new(
+        backend.clone(),
+        Arc::new(PowerDistricts::new(backend.clone(), &config.network)),
+        Arc::new(BufferStops::new(backend.clone(), &config.network)),
+        Arc::new(Signals::new(&config.network)),
+        Arc::new(SwitchOrder::new(&config.network)),
+        &config.oracle,
+        None,
+    );
+
+    c.bench_function("oracle/cycle_8_locos", |b| {
+        b.iter(|| oracle.process().unwrap())
+    });
+}
+
+criterion_group!(benches, route_search, control_loco, oracle_cycle);
+criterion_main!(benches);
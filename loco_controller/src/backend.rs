@@ -1,43 +1,124 @@
 use std::{
-    collections::HashMap,
-    io::{self, Write},
-    net::TcpStream,
+    any::type_name,
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
     sync::{
-        Mutex,
-        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        mpsc::{self, Receiver, Sender},
     },
+    time::{Duration, Instant},
 };
 
 use bincode::{
+    Decode, Encode,
     config::{Configuration, Fixint, LittleEndian, NoLimit},
-    decode_from_std_read, encode_to_vec,
+    decode_from_slice, encode_to_vec,
     error::{DecodeError, EncodeError},
 };
+use futures_util::future::join_all;
 use loco_protocol::{
-    ActuatorId, ActuatorType, BACKEND_PROTOCOL_MAGIC_NUMBER, ConnectPayload, ControlLocoPayload,
-    Direction, DriveActuatorPayload, Error as LocoProtocolError, Header, LocoId,
-    LocoStatusResponse, Operation, SensorId, SensorStatus, SensorsStatusArray, Speed,
+    ANONYMOUS_LOCO_ID, AUTH_TOKEN_EXT_ID, ActuatorId, ActuatorType, ActuatorsTelemetryPayload,
+    BACKEND_PROTOCOL_MAGIC_NUMBER, BACKEND_PROTOCOL_VERSION, COMMAND_EXT_ID, COMMAND_ID_SIZE,
+    CORRELATION_EXT_ID, CORRELATION_ID_SIZE, Codec, CommandAckPayload, ConnectPayload,
+    ControlLocoFunctionsPayload, ControlLocoFunctionsResponse, ControlLocoPayload,
+    ControlLocoResponse, Direction, DisconnectPayload, DisconnectReason, DriveActuatorPayload,
+    DriveActuatorsBatchArray, EXTENSION_FIELD_HEADER_SIZE, Error as LocoProtocolError, ErrorCode,
+    ErrorPayload, Extensions, FRAME_CRC_SIZE, FirmwareVersion, HEADER_SIZE,
+    HoldOnDisconnectPayload, INPUT_STATUS_SIZE, InputId, InputState, InputStatus,
+    InputsStatusArray, LOCO_TELEMETRY_EXT_BATTERY_MV, LOCO_TELEMETRY_EXT_POWER_LOST,
+    LOCO_TELEMETRY_EXT_RSSI, LOCO_TELEMETRY_EXT_SUPPLY, LOCO_TELEMETRY_EXT_TEMPERATURE,
+    LocoFunctions, LocoId, LocoStatusResponse, MotorStatus, Operation, PAYLOAD_MAX_LEN,
+    PRESENT_ACTUATOR_SIZE, PRESENT_ACTUATORS_EXT_ID, PRESENT_SENSORS_EXT_ID, RAMP_EXT_ID,
+    RAMP_SIZE, RegisterPayload, SENSORS_STATUS_EXT_EVENTS, SENSORS_STATUS_EXT_SENSOR_TYPES,
+    SENSORS_STATUS_EXT_UNKNOWN_TAGS, SafeCrawlReportPayload, SelectWireFormatPayload, SensorEvent,
+    SensorId, SensorType, SequenceCheck, SequenceCounter, SequenceTracker, Speed, SpeedCurve,
+    SpeedSteps, SwitchRailsState, TimeSyncPayload, TrackPowerState, UNKNOWN_TAG_SIZE,
+    WIRE_FORMATS_EXT_ID, WireFormat, check_array_len, decode_payload, decode_sensors_status_batch,
+    encode_extension_field, encode_payload,
 };
-use log::debug;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    runtime::Handle,
+    select,
+    sync::{Mutex as AsyncMutex, oneshot},
+    time::timeout,
+};
 
-use crate::rail_network::{CheckpointId, TrackId};
+use crate::{
+    command_ids::CommandIds,
+    config::{BackendConfig, DutyCurve, HistoryConfig, LocoConfig, NetworkConfig, ServoAngles},
+    consist::ConsistIssue,
+    correlation,
+    event_log::{EventLog, Replay, ReplayQuery},
+    frame_trace::{FrameDirection, FrameRecord, FrameTracer, FramesFilter},
+    history::{History, HistoryEntry, HistoryPage, HistoryQuery},
+    learned::{LearnedDriving, LearnedDrivingDescription},
+    maintenance::{MaintenanceBanner, TimeOfDay},
+    passing::Maneuver,
+    rail_network::{CheckpointId, RailNetwork, TrackId},
+    serial_bus::{Error as SerialBusError, SerialBus},
+    startup::StartupStep,
+    tags::{TagDatabase, TagOwner, TagUid},
+};
 
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("Actuator {0} ({1}) not present on the actuators board")]
+    ActuatorNotPresent(ActuatorId, ActuatorType),
     #[error("Actuators not connected")]
     ActuatorsNotConnected,
     #[error("Error converting into expected type")]
     ConvertLocoProtocolType(LocoProtocolError),
+    #[error("Error decoding payload: {0}")]
+    DecodeFromSlice(#[source] DecodeError),
     #[error("Error decoding from TCP stream: {0}")]
     DecodeFromStream(#[source] DecodeError),
+    #[error("{0:?} failed with {1}")]
+    DeviceFailed(Device, ErrorCode),
+    #[error("Loco {0} already connected, rejecting device {1:#x}")]
+    DuplicateLoco(LocoId, u64),
+    #[error("Error encoding payload: {0:?}")]
+    EncodePayload(LocoProtocolError),
     #[error("Error encoding to vec: {0}")]
     EncodeToVec(#[source] EncodeError),
-    #[error("Invalid backend protocol magic number {0}")]
-    InvalidBackendProtocolMagicNumber(u8),
+    #[error("Error framing message: {0:?}")]
+    Framing(LocoProtocolError),
+    #[error("Incompatible backend protocol version {0} from {1:?}")]
+    IncompatibleProtocolVersion(u8, Device),
+    #[error("Invalid trim {0}%, expecting {MIN_TRIM_PERCENT}% to {MAX_TRIM_PERCENT}%")]
+    InvalidTrim(u8),
+    #[error(
+        "Invalid speed scale {0}%, expecting {MIN_SPEED_SCALE_PERCENT}% to {MAX_SPEED_SCALE_PERCENT}%"
+    )]
+    InvalidSpeedScale(u8),
     #[error("Loco {0} not connected")]
     LocoNotConnected(LocoId),
+    #[error("Loco {0} didn't answer in time")]
+    LocoTimedOut(LocoId),
+    #[error("{0:?} missed its heartbeats")]
+    MissedHeartbeats(Device),
+    #[error("Loco {0} not in the roster")]
+    UnknownLoco(LocoId),
+    #[error("Payload of {0} bytes too large")]
+    PayloadTooLarge(usize),
+    #[error("Error reading from TCP stream {0}")]
+    ReadTcpStream(#[source] io::Error),
+    #[error("Request rejected by {0:?}: {1}")]
+    RequestRejected(Device, ErrorCode),
+    #[error("Error throwing turnout through the serial bus: {0}")]
+    SerialBus(#[source] SerialBusError),
+    #[error("{0:?} didn't give the expected token")]
+    Unauthorized(Device),
+    #[error("Switch rails {0} held by the Oracle")]
+    SwitchRailsHeld(ActuatorId),
     #[error("Unsupported operation {0}")]
     UnsupportedOperation(Operation),
     #[error("Error writing to TCP stream {0}")]
@@ -46,6 +127,25 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+const CONTROLLER_VERSION_MAJOR: &str = env!("CARGO_PKG_VERSION_MAJOR");
+const CLOCK_OFFSET_SAMPLES: usize = 16;
+const LATE_DETECTION_US: u64 = 1_000_000;
+// Room for the fixed part of any payload sent to a loco
+const LOCO_PAYLOAD_MAX_SIZE: usize = 32;
+// A loco which vanished without closing its connection must not hold up
+// whoever commands it, the Oracle first
+const LOCO_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bounds of the trim applied to the duty cycles sent to a loco. A loco which
+// needs more than that has to be looked at rather than compensated for.
+pub const MIN_TRIM_PERCENT: u8 = 50;
+pub const MAX_TRIM_PERCENT: u8 = 150;
+
+// Bounds of the speed scale applied to every loco on top of its trim, which
+// can only slow the whole layout down
+pub const MIN_SPEED_SCALE_PERCENT: u8 = 10;
+pub const MAX_SPEED_SCALE_PERCENT: u8 = 100;
+
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum OracleMode {
@@ -53,22 +153,241 @@ pub enum OracleMode {
     Auto,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    Loco(LocoId),
+    Sensors,
+    Actuators,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceInfo {
+    device: Device,
+    protocol_version: u8,
+    firmware_version: String,
+    online: bool,
+    // When anything was last received from the device
+    last_seen_us: u64,
+    hardware: Hardware,
+    // Last failure reported by the device, kept across its reconnections
+    last_error: Option<ReportedError>,
+    // Why the device last went away on purpose, kept across its reconnections
+    // as well
+    disconnect_reason: Option<DisconnectReason>,
+}
+
+#[derive(Serialize, Copy, Clone, Debug)]
+pub struct ReportedError {
+    code: ErrorCode,
+    reported_at_us: u64,
+}
+
+// What a board advertised having when registering, None when it didn't tell
+// in which case it's assumed to have everything
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Hardware {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sensors: Option<Vec<SensorId>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actuators: Option<Vec<(ActuatorId, ActuatorType)>>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Alarm {
+    Overcurrent,
+    DuplicateLoco,
+    SafetyViolation,
+    UnknownTag,
+    LostWagon,
+    ConsistMismatch,
+    EmergencyStop,
+    // Reported by a device, along with its code
+    DeviceError,
+    // Something tried to connect as a device without the expected token
+    Unauthorized,
+    // A loco stopped and went away before its battery runs flat
+    LowBattery,
+    // A loco which lost its connection gave up crawling to the next
+    // checkpoint, and stands somewhere in between
+    StrandedLoco,
+}
+
+/**
+ * Notable things happening on the rail network, delivered to every
+ * subscriber in the order they happened.
+ */
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    SensorHit {
+        loco_id: LocoId,
+        sensor_id: SensorId,
+        timestamp_us: u64,
+    },
+    // The loco left the sensor, which tells it passed through the checkpoint
+    // rather than stopped on it
+    SensorDeparture {
+        loco_id: LocoId,
+        sensor_id: SensorId,
+        timestamp_us: u64,
+    },
+    IntentCompleted {
+        loco_id: LocoId,
+        intent: LocoIntent,
+    },
+    OracleDecision {
+        actuators: Vec<(ActuatorId, ActuatorType, u8)>,
+        locos: Vec<(LocoId, Direction, Speed)>,
+        // Locos held at a station by the cap on moving locos, the first one
+        // leaving next
+        queue: Vec<LocoId>,
+        // Locos giving way to another one in a passing loop
+        maneuvers: Vec<Maneuver>,
+    },
+    LocoIntentSet {
+        loco_id: LocoId,
+        intent: LocoIntent,
+    },
+    LocoCommandApplied {
+        loco_id: LocoId,
+        direction: Direction,
+        speed: Speed,
+    },
+    ActuatorsDriven {
+        actuators: Vec<(ActuatorId, ActuatorType, u8)>,
+    },
+    StartupProgress {
+        step: StartupStep,
+    },
+    LocationCorrected {
+        loco_id: LocoId,
+        sensor_id: Option<SensorId>,
+    },
+    LocoConnected {
+        loco_id: LocoId,
+        device_id: u64,
+    },
+    LocoDisconnected {
+        loco_id: LocoId,
+        // The loco reported losing the track power before going away, as
+        // opposed to crashing or leaving the WiFi coverage
+        power_lost: bool,
+        // What the loco told when going away on purpose
+        reason: Option<DisconnectReason>,
+    },
+    ActuatorsConnected,
+    ActuatorsDisconnected,
+    EmergencyStop {
+        reason: String,
+    },
+    MaintenanceStarted {
+        banner: MaintenanceBanner,
+    },
+    MaintenanceEnded,
+    // Day program switched in, by the timetable or by hand, at the given time
+    // of the fast clock
+    DayProgramSwitched {
+        program: String,
+        clock: TimeOfDay,
+    },
+    // Tag missing from the database, seen for the first time at this sensor
+    UnknownTag {
+        uid: TagUid,
+        sensor_id: SensorId,
+    },
+    WagonHit {
+        wagon: String,
+        sensor_id: SensorId,
+        timestamp_us: u64,
+    },
+    ConsistIssue {
+        issue: ConsistIssue,
+    },
+    // Reported by a loco once connected again, after it crawled on its own
+    // towards the next checkpoint while disconnected
+    SafeCrawl {
+        loco_id: LocoId,
+        direction: Direction,
+        reached_checkpoint: bool,
+        crawl_ms: u32,
+    },
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LocationCorrection {
+    loco_id: LocoId,
+    from: Option<CheckpointId>,
+    to: Option<CheckpointId>,
+    // Client which asked for the correction
+    client: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AlarmRecord {
+    alarm: Alarm,
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<DeviceError>,
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+pub struct DeviceError {
+    device: Device,
+    code: ErrorCode,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AlarmsFilter {
+    r#type: Option<Alarm>,
+    active: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum LocoIntent {
     Drive(Direction, TrackId),
     Stop(Direction, CheckpointId),
+    Follow(Direction, LocoId),
+}
+
+impl LocoIntent {
+    pub fn direction(&self) -> Direction {
+        match *self {
+            LocoIntent::Drive(direction, _)
+            | LocoIntent::Stop(direction, _)
+            | LocoIntent::Follow(direction, _) => direction,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LocoStatus {
     direction: Direction,
     speed: Speed,
+    motor_status: MotorStatus,
     location: Option<SensorId>,
+    location_timestamp_us: Option<u64>,
+    // Whether the loco still stands over the checkpoint it's located at
+    on_checkpoint: bool,
     intent: Option<LocoIntent>,
+    functions: LocoFunctions,
+    command_rtt_us: Option<u64>,
+    telemetry: LocoTelemetry,
+    // How long the current connection of the loco has been up, and how many
+    // times it connected again since the controller started
+    connected_us: u64,
+    reconnects: u32,
+    // How long ago the loco reported its direction, speed and motor status
+    age_us: u64,
 }
 
 impl LocoStatus {
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
     pub fn speed(&self) -> Speed {
         self.speed
     }
@@ -77,306 +396,3296 @@ impl LocoStatus {
         self.location
     }
 
+    pub fn location_timestamp_us(&self) -> Option<u64> {
+        self.location_timestamp_us
+    }
+
+    pub fn on_checkpoint(&self) -> bool {
+        self.on_checkpoint
+    }
+
     pub fn intent(&self) -> Option<LocoIntent> {
         self.intent
     }
+
+    pub fn command_rtt(&self) -> Option<Duration> {
+        self.command_rtt_us.map(Duration::from_micros)
+    }
+}
+
+/**
+ * Paces the commands sent to a loco. Only the most recent command matters,
+ * hence a new command supersedes any command which couldn't be sent yet, and
+ * a command identical to the last one sent is not sent again. Commands are
+ * spaced by at least a configurable minimum so that the loco doesn't receive a
+ * burst of them after a WiFi hiccup. A command only differing by its ramp
+ * isn't a new command.
+ */
+#[derive(Default)]
+struct LocoCommandPacer {
+    pending: Option<(Direction, Speed, Option<Duration>)>,
+    last_sent: Option<(Direction, Speed, Instant)>,
+}
+
+impl LocoCommandPacer {
+    fn reset(&mut self) {
+        self.pending = None;
+        self.last_sent = None;
+    }
+
+    // Sends the last command again, which is needed whenever what's sent for
+    // the same command changes
+    fn resend(&mut self) {
+        if self.pending.is_none() {
+            self.pending = self.last_sent.map(|(d, s, _)| (d, s, None));
+        }
+    }
+
+    // Records a command sent regardless of the pacing, which supersedes
+    // anything pending
+    fn sent(&mut self, direction: Direction, speed: Speed) {
+        self.pending = None;
+        self.last_sent = Some((direction, speed, Instant::now()));
+    }
+
+    fn is_idle(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    fn push(&mut self, direction: Direction, speed: Speed, ramp: Option<Duration>) {
+        self.pending = match self.last_sent {
+            Some((d, s, _)) if d == direction && s == speed => None,
+            _ => Some((direction, speed, ramp)),
+        };
+    }
+
+    fn pop(&mut self, min_spacing: Duration) -> Option<(Direction, Speed, Option<Duration>)> {
+        if let Some((_, _, sent_at)) = self.last_sent
+            && sent_at.elapsed() < min_spacing
+        {
+            return None;
+        }
+
+        let (direction, speed, ramp) = self.pending.take()?;
+        self.last_sent = Some((direction, speed, Instant::now()));
+
+        Some((direction, speed, ramp))
+    }
+}
+
+/**
+ * Smoothed round-trip time of the commands sent to a loco, measured between
+ * sending a ControlLoco and receiving its acknowledgement. Samples are
+ * averaged the same way TCP does (RFC 6298), so that a single slow command
+ * doesn't throw the estimation off.
+ */
+#[derive(Default)]
+struct CommandRtt {
+    srtt: Option<Duration>,
+}
+
+impl CommandRtt {
+    fn reset(&mut self) {
+        self.srtt = None;
+    }
+
+    fn add_sample(&mut self, sample: Duration) {
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+}
+
+/**
+ * Battery, temperature, WiFi signal and track power last reported by a loco
+ * along with its status, or on its own when the track power changes. Each of
+ * them is only known if the loco could measure it, the battery being only
+ * measured on a loco which has one.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+pub struct LocoTelemetry {
+    battery_mv: Option<u16>,
+    temperature: Option<i8>,
+    rssi: Option<i8>,
+    // Raw ADC reading of the motor supply, taken from the track
+    supply: Option<u16>,
+    power_lost: Option<bool>,
+}
+
+impl LocoTelemetry {
+    fn power_lost(&self) -> bool {
+        self.power_lost == Some(true)
+    }
+}
+
+// Status last reported by a loco, along with when it was reported
+#[derive(Copy, Clone)]
+struct ReportedStatus {
+    direction: Direction,
+    speed: Speed,
+    motor_status: MotorStatus,
+    reported_at_us: u64,
+}
+
+/**
+ * Command payload along with its ID, kept until the device acknowledges it.
+ * Should the connection break meanwhile, it's sent again as is once the
+ * device is back, and the device skips it if it had already applied it.
+ */
+#[derive(Clone)]
+struct UnackedCommand {
+    id: u64,
+    operation: Operation,
+    payload: Vec<u8>,
+}
+
+// Write half of a connection, along with the sequence numbers of the frames
+// sent through it
+struct FrameWriter {
+    half: OwnedWriteHalf,
+    tx_sequence: SequenceCounter,
+}
+
+// Tells how long a response is once enough of it was received to decode it,
+// since responses aren't framed
+type ResponseLen = Box<dyn Fn(&[u8]) -> std::result::Result<usize, DecodeError> + Send + Sync>;
+
+// Request sent to a loco, waiting for the task reading the connection to
+// hand its response over
+struct AwaitedResponse {
+    kind: &'static str,
+    len: ResponseLen,
+    sender: oneshot::Sender<Result<Vec<u8>>>,
+}
+
+/**
+ * Current connection of a loco. The write half is locked for a whole
+ * request, so that the loco answers one request at a time, while the read
+ * half is served by the task which accepted the connection. Dropping the link
+ * stops that task.
+ */
+struct LocoLink {
+    // Tells the connection apart from the previous ones of the same loco
+    id: u32,
+    writer: Arc<AsyncMutex<FrameWriter>>,
+    awaited: Option<AwaitedResponse>,
+    _closed: oneshot::Sender<()>,
 }
 
 #[derive(Default)]
 struct LocoInfo {
-    stream: Option<TcpStream>,
+    link: Option<LocoLink>,
+    device_id: Option<u64>,
+    command_pacer: LocoCommandPacer,
+    // ControlLoco sent without getting its response
+    unacked: Option<(Direction, Speed, UnackedCommand)>,
+    command_rtt: CommandRtt,
     location: Option<SensorId>,
+    location_timestamp_us: Option<u64>,
+    // Arrived at its location and not departed since, which is never known
+    // for a location set by hand
+    on_checkpoint: bool,
     intent: Option<LocoIntent>,
+    // Functions last acknowledged by the loco through its current connection
+    functions: LocoFunctions,
+    reported_status: Option<ReportedStatus>,
+    telemetry: LocoTelemetry,
+    // Negotiated by the loco through its current connection
+    wire_format: WireFormat,
+    connected_at_us: u64,
+    connections: u32,
+}
+
+impl LocoInfo {
+    // The response to a command tells what the loco now runs at, sparing a
+    // round-trip to learn about it
+    fn command_answered(&mut self, resp: &ControlLocoResponse, now_us: u64) {
+        let (Some(reported), Ok(direction), Ok(speed)) = (
+            self.reported_status.as_mut(),
+            Direction::try_from(resp.direction),
+            Speed::try_from(resp.speed),
+        ) else {
+            return;
+        };
+
+        reported.direction = direction;
+        reported.speed = speed;
+        reported.reported_at_us = now_us;
+    }
+
+    fn status(&self, reported: ReportedStatus, now_us: u64) -> LocoStatus {
+        LocoStatus {
+            direction: reported.direction,
+            speed: reported.speed,
+            motor_status: reported.motor_status,
+            location: self.location,
+            location_timestamp_us: self.location_timestamp_us,
+            on_checkpoint: self.on_checkpoint,
+            intent: self.intent,
+            functions: self.functions,
+            command_rtt_us: self.command_rtt.rtt().map(|rtt| rtt.as_micros() as u64),
+            telemetry: self.telemetry,
+            connected_us: now_us.saturating_sub(self.connected_at_us),
+            reconnects: self.connections.saturating_sub(1),
+            age_us: now_us.saturating_sub(reported.reported_at_us),
+        }
+    }
+}
+
+// How a traced frame is labelled, from the operation its Header carries
+fn frame_kind(frame: &[u8]) -> String {
+    match frame.get(1).map(|op| Operation::try_from(*op)) {
+        Some(Ok(op)) => op.to_string(),
+        _ => "Unknown".to_string(),
+    }
+}
+
+/**
+ * Estimates the offset between a Pico clock and the controller clock. Every
+ * sample is the difference between the arrival time on the controller and
+ * the time at which the Pico sent the message, which is the real offset plus
+ * the network latency. Keeping the minimum over a sliding window filters out
+ * the WiFi jitter.
+ */
+#[derive(Default)]
+struct ClockOffset {
+    samples: VecDeque<i64>,
+}
+
+impl ClockOffset {
+    fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    fn add_sample(&mut self, sample: i64) {
+        if self.samples.len() >= CLOCK_OFFSET_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn offset(&self) -> Option<i64> {
+        self.samples.iter().min().copied()
+    }
+}
+
+// Actuator commands kept until the board acknowledges them, beyond which the
+// oldest ones are given up on
+const UNACKED_ACTUATOR_COMMANDS_MAX: usize = 32;
+
+// Past its expiry, a command is only the intention of a layout which moved on
+// since, hence not worth sending again
+struct QueuedCommand {
+    command: UnackedCommand,
+    expires_at: Instant,
 }
 
 #[derive(Default)]
 struct ActuatorInfo {
-    stream: Option<TcpStream>,
+    // The frames received are tracked by the task serving the board
+    writer: Option<Arc<AsyncMutex<FrameWriter>>>,
+    // Sent in this order, and acknowledged in the same order
+    unacked: VecDeque<QueuedCommand>,
+    // Position every switch rails was last driven to since the board
+    // connected, since the board doesn't report them
+    switch_rails: BTreeMap<ActuatorId, SwitchRailsState>,
+    // Same for the track power
+    track_power: BTreeMap<ActuatorId, TrackPowerState>,
 }
 
 pub struct Backend {
     bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    codec: Codec<Configuration<LittleEndian, Fixint, NoLimit>>,
     loco_info: HashMap<LocoId, Mutex<LocoInfo>>,
+    // Loco boards assigned to a loco by the roster, whatever they claim
+    loco_devices: HashMap<u64, LocoId>,
+    auth_token: Option<String>,
+    correlation_ids_on_wire: bool,
+    // Which loco gives way to which one, as set by the roster
+    loco_priorities: BTreeMap<LocoId, u8>,
     actuator_info: Mutex<ActuatorInfo>,
     oracle_enabled: AtomicBool,
+    alarms: Mutex<History<AlarmRecord>>,
+    location_corrections: Mutex<History<LocationCorrection>>,
+    inputs: Mutex<HashMap<InputId, InputState>>,
+    devices: Mutex<HashMap<Device, DeviceInfo>>,
+    epoch: Instant,
+    sensors_clock_offset: Mutex<ClockOffset>,
+    loco_command_min_spacing: Duration,
+    heartbeat_timeout: Duration,
+    actuator_command_expiry: Duration,
+    speed_curves: Mutex<BTreeMap<LocoId, SpeedCurve>>,
+    trims: Mutex<BTreeMap<LocoId, u8>>,
+    duty_curves: BTreeMap<LocoId, DutyCurve>,
+    speed_scale_percent: AtomicU8,
+    servo_angles: Mutex<BTreeMap<ActuatorId, ServoAngles>>,
+    serial_bus: Option<SerialBus>,
+    switch_defaults: Mutex<BTreeMap<ActuatorId, SwitchRailsState>>,
+    // Switch rails of the segments granted by the last Oracle decision
+    oracle_switch_rails: AsyncMutex<Vec<ActuatorId>>,
+    maintenance: Mutex<Option<MaintenanceBanner>>,
+    tags: Arc<TagDatabase>,
+    command_ids: CommandIds,
+    event_log: EventLog,
+    event_subscribers: Mutex<Vec<Sender<Event>>>,
+    frame_tracer: FrameTracer,
+    runtime: Handle,
+    learned_driving: LearnedDriving,
 }
 
 impl Backend {
-    pub fn new() -> Self {
+    pub fn new(
+        config: &BackendConfig,
+        history_config: &HistoryConfig,
+        roster: &BTreeMap<LocoId, LocoConfig>,
+        network: &NetworkConfig,
+        tags: Arc<TagDatabase>,
+        command_ids: CommandIds,
+        runtime: Handle,
+    ) -> Self {
         debug!("Backend::new()");
 
         let bincode_cfg = bincode::config::legacy();
-        let loco_info = HashMap::from([
-            (LocoId::Loco1, Mutex::new(LocoInfo::default())),
-            (LocoId::Loco2, Mutex::new(LocoInfo::default())),
-        ]);
+        // No board sends more than it can hold itself, hence anything larger
+        // comes from a broken or rogue peer
+        let codec = Codec::new(bincode_cfg, PAYLOAD_MAX_LEN);
+        let loco_info = roster
+            .keys()
+            .map(|loco_id| (*loco_id, Mutex::new(LocoInfo::default())))
+            .collect();
+        let loco_devices = roster
+            .iter()
+            .filter_map(|(loco_id, loco)| loco.device_id.map(|id| (id, *loco_id)))
+            .collect();
+        let loco_priorities = roster
+            .iter()
+            .map(|(loco_id, loco)| (*loco_id, loco.priority))
+            .collect();
         let actuator_info = Mutex::new(ActuatorInfo::default());
         let oracle_enabled = AtomicBool::new(false);
+        let alarms = Mutex::new(History::new(
+            history_config.max_entries,
+            history_config.max_age(),
+        ));
+        let location_corrections = Mutex::new(History::new(
+            history_config.max_entries,
+            history_config.max_age(),
+        ));
+        let inputs = Mutex::new(HashMap::new());
+        let devices = Mutex::new(HashMap::new());
+        let epoch = Instant::now();
+        let sensors_clock_offset = Mutex::new(ClockOffset::default());
 
         Backend {
             bincode_cfg,
+            codec,
             loco_info,
+            loco_devices,
+            auth_token: config.auth_token.clone(),
+            correlation_ids_on_wire: config.correlation_ids_on_wire,
+            loco_priorities,
             actuator_info,
             oracle_enabled,
+            alarms,
+            location_corrections,
+            inputs,
+            devices,
+            epoch,
+            sensors_clock_offset,
+            loco_command_min_spacing: config.loco_command_min_spacing(),
+            heartbeat_timeout: config.heartbeat_timeout(),
+            actuator_command_expiry: config.actuator_command_expiry(),
+            speed_curves: Mutex::new(config.speed_curves.clone()),
+            trims: Mutex::new(config.trims.clone()),
+            duty_curves: config.duty_curves.clone(),
+            speed_scale_percent: AtomicU8::new(config.speed_scale_percent),
+            servo_angles: Mutex::new(network.servo_angles.clone()),
+            serial_bus: (!config.serial_bus.turnouts.is_empty())
+                .then(|| SerialBus::new(&config.serial_bus)),
+            switch_defaults: Mutex::new(network.switch_defaults.clone()),
+            oracle_switch_rails: AsyncMutex::new(Vec::new()),
+            maintenance: Mutex::new(None),
+            tags,
+            command_ids,
+            event_log: EventLog::new(history_config.max_events, history_config.max_age()),
+            event_subscribers: Mutex::new(Vec::new()),
+            frame_tracer: FrameTracer::new(history_config.max_age()),
+            runtime,
+            learned_driving: LearnedDriving::default(),
         }
     }
 
-    pub fn loco_ids(&self) -> Vec<LocoId> {
-        self.loco_info.iter().map(|(id, _)| *id).collect()
+    /**
+     * Runs a request to the devices to completion on behalf of a synchronous
+     * worker. Anything already running asynchronously, such as an HTTP
+     * handler, awaits the request instead.
+     */
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
     }
 
-    fn loco_info(&self, loco_id: &LocoId) -> &Mutex<LocoInfo> {
-        // Safe to unwrap since loco_info has been filled with every LocoId
-        self.loco_info.get(loco_id).unwrap()
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.event_subscribers.lock().unwrap().push(sender);
+        receiver
     }
 
-    fn retrieve_header_op(&self, stream: &mut TcpStream) -> Result<Operation> {
-        debug!("Backend::retrieve_header_op()");
-
-        // Retrieve header
-        let header: Header =
-            decode_from_std_read(stream, self.bincode_cfg).map_err(Error::DecodeFromStream)?;
-
-        debug!("Backend::retrieve_header_op(): {:?}", header);
+    /**
+     * Every change goes through here: the event is logged first, then applied
+     * to the state, and finally sent to the subscribers. Subscribers which
+     * went away are forgotten along the way.
+     */
+    pub fn notify(&self, event: Event) {
+        let now_us = self.now_us();
+        self.event_log.append(&event, now_us);
+        self.apply(&event, now_us);
+
+        self.event_subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
 
-        if header.magic != BACKEND_PROTOCOL_MAGIC_NUMBER {
-            return Err(Error::InvalidBackendProtocolMagicNumber(header.magic));
+    // Part of the state derived from the events. Callers of notify() may hold
+    // the lock of the loco being commanded, hence LocoCommandApplied must
+    // never lock it.
+    fn apply(&self, event: &Event, now_us: u64) {
+        match event {
+            Event::SensorHit {
+                loco_id,
+                sensor_id,
+                timestamp_us,
+            } => {
+                let mut loco_info = self.loco_info(loco_id).lock().unwrap();
+                loco_info.location = Some(*sensor_id);
+                loco_info.location_timestamp_us = Some(*timestamp_us);
+                loco_info.on_checkpoint = true;
+                self.learned_driving.hit(
+                    *loco_id,
+                    (*sensor_id).into(),
+                    *timestamp_us,
+                    !self.oracle_enabled(),
+                );
+            }
+            // Leaving any other sensor doesn't tell anything about where the
+            // loco now is
+            Event::SensorDeparture {
+                loco_id, sensor_id, ..
+            } => {
+                let mut loco_info = self.loco_info(loco_id).lock().unwrap();
+                if loco_info.location == Some(*sensor_id) {
+                    loco_info.on_checkpoint = false;
+                }
+            }
+            Event::LocationCorrected { loco_id, sensor_id } => {
+                let mut loco_info = self.loco_info(loco_id).lock().unwrap();
+                loco_info.location = *sensor_id;
+                loco_info.location_timestamp_us = Some(now_us);
+                loco_info.on_checkpoint = false;
+                self.learned_driving.interrupt(*loco_id);
+            }
+            Event::LocoCommandApplied {
+                loco_id,
+                direction,
+                speed,
+            } => {
+                self.learned_driving.command(
+                    *loco_id,
+                    *direction,
+                    *speed,
+                    now_us,
+                    !self.oracle_enabled(),
+                );
+            }
+            Event::LocoDisconnected { loco_id, .. } => {
+                self.learned_driving.interrupt(*loco_id);
+            }
+            Event::LocoIntentSet { loco_id, intent } => {
+                self.loco_info(loco_id).lock().unwrap().intent = Some(*intent);
+            }
+            Event::ActuatorsDriven { actuators } => {
+                let mut actuator_info = self.actuator_info.lock().unwrap();
+                for (actuator_id, actuator_type, actuator_state) in actuators.iter() {
+                    match actuator_type {
+                        ActuatorType::SwitchRails => {
+                            if let Ok(state) = SwitchRailsState::try_from(*actuator_state) {
+                                actuator_info.switch_rails.insert(*actuator_id, state);
+                            }
+                        }
+                        ActuatorType::TrackPower => {
+                            if let Ok(state) = TrackPowerState::try_from(*actuator_state) {
+                                actuator_info.track_power.insert(*actuator_id, state);
+                            }
+                        }
+                        // Signals are driven again on every Oracle cycle, and
+                        // servos are reported as the switch rails they throw
+                        ActuatorType::Signal | ActuatorType::Servo => {}
+                    }
+                }
+            }
+            // A board which just (re)connected may have lost its positions,
+            // unlike the decoders of the serial bus
+            Event::ActuatorsConnected | Event::ActuatorsDisconnected => {
+                let mut actuator_info = self.actuator_info.lock().unwrap();
+                actuator_info.switch_rails.retain(|actuator_id, _| {
+                    self.is_serial_turnout(*actuator_id, ActuatorType::SwitchRails)
+                });
+                actuator_info.track_power.clear();
+            }
+            Event::MaintenanceStarted { banner } => {
+                *self.maintenance.lock().unwrap() = Some(banner.clone());
+            }
+            Event::MaintenanceEnded => {
+                *self.maintenance.lock().unwrap() = None;
+            }
+            // Only ever notified by the Oracle from its own thread, which can
+            // wait for the lock
+            Event::OracleDecision { actuators, .. } => {
+                *self.oracle_switch_rails.blocking_lock() = actuators
+                    .iter()
+                    .filter(|(_, actuator_type, _)| *actuator_type == ActuatorType::SwitchRails)
+                    .map(|(actuator_id, _, _)| *actuator_id)
+                    .collect();
+            }
+            Event::IntentCompleted { .. }
+            | Event::StartupProgress { .. }
+            | Event::LocoConnected { .. }
+            | Event::DayProgramSwitched { .. }
+            | Event::EmergencyStop { .. }
+            | Event::UnknownTag { .. }
+            | Event::WagonHit { .. }
+            | Event::ConsistIssue { .. }
+            | Event::SafeCrawl { .. } => {}
         }
+    }
 
-        let op = Operation::try_from(header.operation).map_err(Error::ConvertLocoProtocolType)?;
-        debug!("Backend::retrieve_header_op(): Operation {:?}", op);
-
-        Ok(op)
+    pub fn events(&self, query: &HistoryQuery) -> HistoryPage<Event> {
+        self.event_log.events(query)
     }
 
-    fn handle_op_connect(&self, mut stream: TcpStream) -> Result<()> {
-        debug!("Backend::handle_op_connect()");
+    pub fn last_event_seq(&self) -> u64 {
+        self.event_log.last_seq()
+    }
 
-        // Retrieve payload
-        let payload: ConnectPayload =
-            decode_from_std_read(&mut stream, self.bincode_cfg).map_err(Error::DecodeFromStream)?;
-        let loco_id = LocoId::try_from(payload.loco_id).map_err(Error::ConvertLocoProtocolType)?;
-        debug!("Backend::handle_op_connect(): LocoId {:?}", loco_id);
+    pub fn events_after(&self, seq: u64) -> Vec<HistoryEntry<Event>> {
+        self.event_log.events_after(seq)
+    }
 
-        self.loco_info(&loco_id).lock().unwrap().stream = Some(stream);
+    pub fn oracle_decisions(&self, query: &HistoryQuery) -> HistoryPage<Event> {
+        self.event_log.oracle_decisions(query)
+    }
 
-        Ok(())
+    pub fn replay_events(&self, query: &ReplayQuery) -> Replay {
+        self.event_log.replay(query)
     }
 
-    pub fn handle_loco_connection(&self, mut stream: TcpStream) -> Result<()> {
-        debug!("Backend::handle_connection()");
+    pub fn loco_ids(&self) -> Vec<LocoId> {
+        self.loco_info.keys().copied().collect()
+    }
 
-        let op = self.retrieve_header_op(&mut stream)?;
+    pub fn loco_priorities(&self) -> &BTreeMap<LocoId, u8> {
+        &self.loco_priorities
+    }
 
-        match op {
-            Operation::Connect => self.handle_op_connect(stream)?,
-            Operation::ControlLoco
-            | Operation::LocoStatus
-            | Operation::SensorsStatus
-            | Operation::DriveActuator => {
-                return Err(Error::UnsupportedOperation(op));
-            }
+    // LocoIds coming from the outside, such as HTTP requests, must be checked
+    // against the roster before being used
+    pub fn check_loco(&self, loco_id: LocoId) -> Result<()> {
+        match self.loco_info.contains_key(&loco_id) {
+            true => Ok(()),
+            false => Err(Error::UnknownLoco(loco_id)),
         }
+    }
 
-        Ok(())
+    fn loco_info(&self, loco_id: &LocoId) -> &Mutex<LocoInfo> {
+        // Safe to unwrap since loco_info has been filled with every LocoId of
+        // the roster, and other ones are checked on their way in
+        self.loco_info.get(loco_id).unwrap()
     }
 
-    pub fn control_loco(&self, loco_id: LocoId, direction: Direction, speed: Speed) -> Result<()> {
-        debug!(
-            "Backend::control_loco(): loco_id {:?}, direction {:?}, speed {:?}",
-            loco_id, direction, speed
-        );
+    // Responses aren't framed, hence they're read for as long as they lack
+    // bytes to be decoded as what the request expects. They're traced on
+    // their own.
+    async fn read_response(
+        &self,
+        half: &mut OwnedReadHalf,
+        awaited: &AwaitedResponse,
+    ) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let decoded = loop {
+            match (awaited.len)(&bytes) {
+                Err(DecodeError::UnexpectedEnd { additional }) => {
+                    let len = bytes.len();
+                    bytes.resize(len + additional, 0);
+                    half.read_exact(&mut bytes[len..])
+                        .await
+                        .map_err(Error::ReadTcpStream)?;
+                }
+                decoded => break decoded,
+            }
+        };
 
-        let mut payload = encode_to_vec(
-            ControlLocoPayload {
-                direction: direction.into(),
-                speed: speed.into(),
-            },
-            self.bincode_cfg,
-        )
-        .map_err(Error::EncodeToVec)?;
+        // Frames which fail to decode are the most interesting ones
+        if self.frame_tracer.enabled() {
+            self.frame_tracer.record(
+                half.peer_addr().ok(),
+                FrameDirection::Rx,
+                awaited.kind,
+                &bytes,
+                self.now_us(),
+            );
+        }
 
-        let mut message = encode_to_vec(
-            Header {
-                magic: BACKEND_PROTOCOL_MAGIC_NUMBER,
-                operation: Operation::ControlLoco.into(),
-                payload_len: payload.len() as u8,
-            },
-            self.bincode_cfg,
-        )
-        .map_err(Error::EncodeToVec)?;
+        decoded.map_err(Error::DecodeFromStream)?;
 
-        message.append(&mut payload);
+        Ok(bytes)
+    }
 
-        self.loco_info(&loco_id)
-            .lock()
-            .unwrap()
-            .stream
-            .as_mut()
-            .ok_or(Error::LocoNotConnected(loco_id))?
-            .write_all(message.as_slice())
+    // Every frame sent goes through here, so that it can be traced. The
+    // payload is preceded by the Header, which takes the next sequence number
+    // of the stream, and followed by the CRC.
+    async fn write_frame(
+        &self,
+        half: &mut OwnedWriteHalf,
+        sequence: &mut SequenceCounter,
+        operation: Operation,
+        payload: &[u8],
+    ) -> Result<()> {
+        let mut frame = vec![0; HEADER_SIZE + payload.len() + FRAME_CRC_SIZE];
+        frame[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+        self.codec
+            .encode_message(
+                &mut frame,
+                operation,
+                payload.len(),
+                sequence.next_sequence(),
+            )
+            .map_err(Error::Framing)?;
+        half.write_all(&frame)
+            .await
             .map_err(Error::WriteTcpStream)?;
 
+        if self.frame_tracer.enabled() {
+            self.frame_tracer.record(
+                half.peer_addr().ok(),
+                FrameDirection::Tx,
+                &operation.to_string(),
+                &frame,
+                self.now_us(),
+            );
+        }
+
         Ok(())
     }
 
-    pub fn loco_status(&self, loco_id: LocoId) -> Result<LocoStatus> {
-        debug!("Backend::loco_status(): loco_id {:?}", loco_id);
+    pub fn set_frame_tracing(&self, enabled: bool) {
+        info!("Backend::set_frame_tracing(): {}", enabled);
+        self.frame_tracer.set_enabled(enabled);
+    }
 
-        let message = encode_to_vec(
-            Header {
-                magic: BACKEND_PROTOCOL_MAGIC_NUMBER,
-                operation: Operation::LocoStatus.into(),
-                payload_len: 0,
-            },
-            self.bincode_cfg,
-        )
-        .map_err(Error::EncodeToVec)?;
+    pub fn traced_frames(
+        &self,
+        query: &HistoryQuery,
+        filter: &FramesFilter,
+    ) -> HistoryPage<FrameRecord> {
+        self.frame_tracer.frames(query, filter)
+    }
 
-        let status = {
-            let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+    // Frames failing their CRC are discarded, as long as the stream remains
+    // in sync, which the Codec tells from the next Header. So are the frames
+    // coming again or out of order, according to their sequence number.
+    async fn retrieve_message(
+        &self,
+        half: &mut OwnedReadHalf,
+        sequence: &mut SequenceTracker,
+    ) -> Result<(Operation, Vec<u8>)> {
+        debug!("Backend::retrieve_message()");
 
-            let stream = loco_info
-                .stream
-                .as_mut()
-                .ok_or(Error::LocoNotConnected(loco_id))?;
+        loop {
+            let mut bytes = vec![0; HEADER_SIZE];
+            half.read_exact(&mut bytes)
+                .await
+                .map_err(Error::ReadTcpStream)?;
+            let header = self.codec.decode_header(&bytes).map_err(Error::Framing)?;
+
+            debug!("Backend::retrieve_message(): {:?}", header);
+
+            // The whole payload is retrieved at once along with the CRC, so
+            // that the extensions following its fixed part never get mistaken
+            // for the next message
+            bytes.resize(header.frame_len(), 0);
+            half.read_exact(&mut bytes[HEADER_SIZE..])
+                .await
+                .map_err(Error::ReadTcpStream)?;
+
+            // Corrupted frames are traced as well, they're the most
+            // interesting ones
+            if self.frame_tracer.enabled() {
+                self.frame_tracer.record(
+                    half.peer_addr().ok(),
+                    FrameDirection::Rx,
+                    &frame_kind(&bytes),
+                    &bytes,
+                    self.now_us(),
+                );
+            }
+
+            let frame = match self.codec.decode_frame(&bytes) {
+                Ok(frame) => frame,
+                Err(e @ LocoProtocolError::FrameChecksumMismatch(..)) => {
+                    warn!(
+                        "Backend::retrieve_message(): discarding corrupted frame: {:?}",
+                        e
+                    );
+                    continue;
+                }
+                Err(e) => return Err(Error::Framing(e)),
+            };
+
+            match sequence.check(header.sequence) {
+                SequenceCheck::InOrder => {}
+                SequenceCheck::Gap(lost) => warn!(
+                    "Backend::retrieve_message(): {} frames lost before {}",
+                    lost, header.sequence
+                ),
+                check @ (SequenceCheck::Duplicate | SequenceCheck::Reordered) => {
+                    warn!(
+                        "Backend::retrieve_message(): discarding frame {} ({:?})",
+                        header.sequence, check
+                    );
+                    continue;
+                }
+            }
 
-            stream
-                .write_all(message.as_slice())
-                .map_err(Error::WriteTcpStream)?;
+            let op = frame.operation().map_err(Error::ConvertLocoProtocolType)?;
+            debug!("Backend::retrieve_message(): Operation {:?}", op);
 
-            let resp: LocoStatusResponse =
-                decode_from_std_read(stream, self.bincode_cfg).map_err(Error::DecodeFromStream)?;
+            return Ok((op, frame.payload.to_vec()));
+        }
+    }
 
-            LocoStatus {
-                direction: Direction::try_from(resp.direction)
-                    .map_err(Error::ConvertLocoProtocolType)?,
-                speed: Speed::try_from(resp.speed).map_err(Error::ConvertLocoProtocolType)?,
-                location: loco_info.location,
-                intent: loco_info.intent,
-            }
-        };
+    // No extension is known yet, hence every field comes from a newer
+    // firmware and is skipped
+    fn skip_extensions(&self, op: Operation, extensions: Extensions) -> Result<()> {
+        for field in extensions {
+            let field = field.map_err(Error::ConvertLocoProtocolType)?;
+            debug!(
+                "Backend::skip_extensions(): {} unknown tag {} ({} bytes)",
+                op,
+                field.tag,
+                field.value.len()
+            );
+        }
 
-        Ok(status)
+        Ok(())
     }
 
-    pub fn drive_actuator(
+    fn register_device(
         &self,
-        actuator_id: ActuatorId,
-        actuator_type: ActuatorType,
-        actuator_state: u8,
+        device: Device,
+        protocol_version: u8,
+        firmware_version: FirmwareVersion,
+        hardware: Hardware,
     ) -> Result<()> {
         debug!(
-            "Backend::drive_switch_rails(): actuator_id {:?}, actuator_type {:?}, state {}",
-            actuator_id, actuator_type, actuator_state
+            "Backend::register_device(): {:?}, protocol {}, firmware {}",
+            device, protocol_version, firmware_version
         );
 
-        let mut payload = encode_to_vec(
-            DriveActuatorPayload {
-                actuator_id: actuator_id.into(),
-                actuator_type: actuator_type.into(),
-                actuator_state,
-            },
-            self.bincode_cfg,
-        )
-        .map_err(Error::EncodeToVec)?;
-
-        let mut message = encode_to_vec(
-            Header {
-                magic: BACKEND_PROTOCOL_MAGIC_NUMBER,
-                operation: Operation::DriveActuator.into(),
-                payload_len: payload.len() as u8,
-            },
-            self.bincode_cfg,
-        )
-        .map_err(Error::EncodeToVec)?;
+        // Devices speaking a different protocol can't be trusted to decode
+        // anything we send, hence they are rejected.
+        if protocol_version != BACKEND_PROTOCOL_VERSION {
+            return Err(Error::IncompatibleProtocolVersion(protocol_version, device));
+        }
 
-        message.append(&mut payload);
+        if firmware_version.major.to_string() != CONTROLLER_VERSION_MAJOR {
+            warn!(
+                "Backend::register_device(): {:?} firmware {} doesn't match controller version {}",
+                device,
+                firmware_version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
 
-        self.actuator_info
-            .lock()
-            .unwrap()
-            .stream
-            .as_mut()
-            .ok_or(Error::ActuatorsNotConnected)?
-            .write_all(message.as_slice())
-            .map_err(Error::WriteTcpStream)?;
+        let mut devices = self.devices.lock().unwrap();
+        let last_error = devices.get(&device).and_then(|info| info.last_error);
+        let disconnect_reason = devices.get(&device).and_then(|info| info.disconnect_reason);
+        devices.insert(
+            device,
+            DeviceInfo {
+                device,
+                protocol_version,
+                firmware_version: firmware_version.to_string(),
+                online: true,
+                last_seen_us: self.now_us(),
+                hardware,
+                last_error,
+                disconnect_reason,
+            },
+        );
 
         Ok(())
     }
 
-    pub fn set_oracle_mode(&self, mode: OracleMode) {
-        let enable = match mode {
-            OracleMode::Off => false,
-            OracleMode::Auto => true,
-        };
-        self.oracle_enabled.store(enable, Ordering::Release);
+    // Anything received from a device tells it's alive
+    fn device_seen(&self, device: Device) {
+        if let Some(info) = self.devices.lock().unwrap().get_mut(&device) {
+            info.last_seen_us = self.now_us();
+        }
     }
 
-    pub fn oracle_enabled(&self) -> bool {
-        self.oracle_enabled.load(Ordering::Acquire)
-    }
+    fn mark_device_offline(&self, device: Device) {
+        debug!("Backend::mark_device_offline(): {:?}", device);
 
-    pub fn set_loco_intent(&self, loco_id: LocoId, intent: LocoIntent) {
-        self.loco_info(&loco_id)
-            .lock()
-            .unwrap()
-            .intent
-            .replace(intent);
+        if let Some(info) = self.devices.lock().unwrap().get_mut(&device) {
+            info.online = false;
+        }
     }
 
-    fn handle_op_sensors_status(&self, stream: &mut TcpStream) -> Result<()> {
-        debug!("Backend::handle_op_sensors_status()");
-
-        // Retrieve number of sensors being updated
-        let sensors_status_array: SensorsStatusArray =
-            decode_from_std_read(stream, self.bincode_cfg).map_err(Error::DecodeFromStream)?;
-
-        for _ in 0..sensors_status_array.len {
-            let sensor_status: SensorStatus =
-                decode_from_std_read(stream, self.bincode_cfg).map_err(Error::DecodeFromStream)?;
-            let loco_id =
-                LocoId::try_from(sensor_status.loco_id).map_err(Error::ConvertLocoProtocolType)?;
+    // Locos never talk unless being asked something, hence anything they send
+    // on their own is a heartbeat, telemetry or a notification. Apart from
+    // heartbeats and telemetry, notifications all mean the loco is going away,
+    // in which case commands stop being routed to it. So does a loco which
+    // stopped sending heartbeats. A loco whose connection closes after it
+    // reported losing the track power is reported as such rather than as
+    // gone. The connection is served until then, unless its link gets dropped
+    // meanwhile.
+    async fn serve_loco(
+        &self,
+        loco_id: LocoId,
+        link_id: u32,
+        mut half: OwnedReadHalf,
+        mut rx_sequence: SequenceTracker,
+        mut closed: oneshot::Receiver<()>,
+    ) {
+        let mut reason = None;
+        loop {
+            let mut first = [0u8; 1];
+            let peeked = select! {
+                _ = &mut closed => return,
+                peeked = timeout(self.heartbeat_timeout, half.peek(&mut first)) => peeked,
+            };
+            match peeked {
+                Ok(Ok(len)) if len > 0 => {}
+                Ok(_) => {
+                    if self
+                        .loco_info(&loco_id)
+                        .lock()
+                        .unwrap()
+                        .telemetry
+                        .power_lost()
+                    {
+                        warn!("Backend::serve_loco(): {} lost track power", loco_id);
+                    } else {
+                        warn!("Backend::serve_loco(): {} connection closed", loco_id);
+                    }
+                    break;
+                }
+                Err(_) => {
+                    warn!("Backend::serve_loco(): {} missed its heartbeats", loco_id);
+                    break;
+                }
+            }
+
+            // Responses are told apart from the messages by their magic
+            // number, which is never the first byte of a response
+            if first[0] != BACKEND_PROTOCOL_MAGIC_NUMBER {
+                if !self.hand_over_response(loco_id, link_id, &mut half).await {
+                    break;
+                }
+                continue;
+            }
+
+            match self.retrieve_message(&mut half, &mut rx_sequence).await {
+                Ok((Operation::Heartbeat, _)) => self.device_seen(Device::Loco(loco_id)),
+                Ok((Operation::LocoTelemetry, payload)) => {
+                    self.device_seen(Device::Loco(loco_id));
+                    let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+                    if let Err(e) =
+                        self.handle_op_loco_telemetry(loco_id, &mut loco_info.telemetry, &payload)
+                    {
+                        error!("Backend::serve_loco(): {} {}", loco_id, e);
+                    }
+                }
+                Ok((Operation::SafeCrawlReport, payload)) => {
+                    self.device_seen(Device::Loco(loco_id));
+                    if let Err(e) = self.handle_op_safe_crawl_report(loco_id, &payload) {
+                        error!("Backend::serve_loco(): {} {}", loco_id, e);
+                    }
+                }
+                Ok((Operation::Disconnect, payload)) => {
+                    match self.handle_op_disconnect(&payload, Device::Loco(loco_id)) {
+                        Ok(r) => reason = Some(r),
+                        Err(e) => error!("Backend::serve_loco(): {} {}", loco_id, e),
+                    }
+                    break;
+                }
+                // The loco rejects the request instead of answering it, while
+                // it keeps running. Without any request waiting, it's left
+                // over from a request whose response was given up on.
+                Ok((Operation::Nack, payload)) => {
+                    self.device_seen(Device::Loco(loco_id));
+                    match self.handle_op_nack(&payload, Device::Loco(loco_id)) {
+                        Ok(code) => self.fail_request(
+                            loco_id,
+                            link_id,
+                            Error::RequestRejected(Device::Loco(loco_id), code),
+                        ),
+                        Err(e) => error!("Backend::serve_loco(): {} {}", loco_id, e),
+                    }
+                }
+                // The loco gives up on the request, if any, and closes the
+                // connection right after
+                Ok((Operation::Error, payload)) => {
+                    match self.handle_op_error(&payload, Device::Loco(loco_id)) {
+                        Ok(code) => self.fail_request(
+                            loco_id,
+                            link_id,
+                            Error::DeviceFailed(Device::Loco(loco_id), code),
+                        ),
+                        Err(e) => error!("Backend::serve_loco(): {} {}", loco_id, e),
+                    }
+                    break;
+                }
+                Ok((op, _)) => {
+                    error!("Backend::serve_loco(): {} unexpected {}", loco_id, op);
+                    break;
+                }
+                Err(e) => {
+                    error!("Backend::serve_loco(): {} {}", loco_id, e);
+                    break;
+                }
+            }
+        }
+
+        self.drop_loco_link(loco_id, link_id, reason);
+    }
+
+    fn take_awaited_response(&self, loco_id: LocoId, link_id: u32) -> Option<AwaitedResponse> {
+        self.loco_info(&loco_id)
+            .lock()
+            .unwrap()
+            .link
+            .as_mut()
+            .filter(|link| link.id == link_id)
+            .and_then(|link| link.awaited.take())
+    }
+
+    // Returns whether the connection is still in sync, which it isn't after
+    // a response nobody asked for or one which can't be decoded
+    async fn hand_over_response(
+        &self,
+        loco_id: LocoId,
+        link_id: u32,
+        half: &mut OwnedReadHalf,
+    ) -> bool {
+        let Some(awaited) = self.take_awaited_response(loco_id, link_id) else {
+            warn!(
+                "Backend::hand_over_response(): {} answered without being asked",
+                loco_id
+            );
+            return false;
+        };
+
+        let response = self.read_response(half, &awaited).await;
+        let in_sync = response.is_ok();
+        if in_sync {
+            self.device_seen(Device::Loco(loco_id));
+        }
+        let _ = awaited.sender.send(response);
+
+        in_sync
+    }
+
+    // The request waiting for a response, if any, gets the error instead
+    fn fail_request(&self, loco_id: LocoId, link_id: u32, error: Error) {
+        if let Some(awaited) = self.take_awaited_response(loco_id, link_id) {
+            let _ = awaited.sender.send(Err(error));
+        }
+    }
+
+    // Unless it's been dropped or replaced already, by whoever then reported
+    // the loco disconnected
+    fn drop_loco_link(&self, loco_id: LocoId, link_id: u32, reason: Option<DisconnectReason>) {
+        let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+        if loco_info
+            .link
+            .as_ref()
+            .is_none_or(|link| link.id != link_id)
+        {
+            return;
+        }
+        loco_info.link = None;
+        loco_info.reported_status = None;
+        let power_lost = loco_info.telemetry.power_lost();
+        drop(loco_info);
+
+        self.loco_disconnected(loco_id, power_lost, reason);
+    }
+
+    // Must be called once the link of the loco is gone, without holding its
+    // lock
+    fn loco_disconnected(
+        &self,
+        loco_id: LocoId,
+        power_lost: bool,
+        reason: Option<DisconnectReason>,
+    ) {
+        self.mark_device_offline(Device::Loco(loco_id));
+        self.notify(Event::LocoDisconnected {
+            loco_id,
+            power_lost,
+            reason,
+        });
+    }
+
+    pub fn devices(&self) -> Vec<DeviceInfo> {
+        self.devices.lock().unwrap().values().cloned().collect()
+    }
+
+    // A device reports why it gave up on a request, right before closing its
+    // connection
+    fn handle_op_error(&self, payload: &[u8], device: Device) -> Result<ErrorCode> {
+        debug!("Backend::handle_op_error(): {:?}", device);
+
+        let code = self.decode_error_code(Operation::Error, payload)?;
+
+        error!("Backend::handle_op_error(): {:?} reported {}", device, code);
+        if let Some(info) = self.devices.lock().unwrap().get_mut(&device) {
+            info.last_error = Some(ReportedError {
+                code,
+                reported_at_us: self.now_us(),
+            });
+        }
+        self.raise_device_error(device, code);
+
+        Ok(code)
+    }
+
+    // A device going away on purpose tells why, which is kept for the
+    // operator. A loco stopping on a flat battery needs someone to recharge
+    // it, hence the alarm.
+    fn handle_op_disconnect(&self, payload: &[u8], device: Device) -> Result<DisconnectReason> {
+        debug!("Backend::handle_op_disconnect(): {:?}", device);
+
+        let (disconnect_payload, extensions): (DisconnectPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        self.skip_extensions(Operation::Disconnect, extensions)?;
+        let reason = DisconnectReason::try_from(disconnect_payload.reason)
+            .map_err(Error::ConvertLocoProtocolType)?;
+
+        info!(
+            "Backend::handle_op_disconnect(): {:?} disconnected ({})",
+            device, reason
+        );
+        if let Some(info) = self.devices.lock().unwrap().get_mut(&device) {
+            info.disconnect_reason = Some(reason);
+        }
+        if reason == DisconnectReason::LowBattery {
+            self.raise_alarm(Alarm::LowBattery);
+        }
+
+        Ok(reason)
+    }
+
+    // A rejected request means both sides disagree on the protocol, which is
+    // worth an alarm even though the device keeps going
+    fn handle_op_nack(&self, payload: &[u8], device: Device) -> Result<ErrorCode> {
+        debug!("Backend::handle_op_nack(): {:?}", device);
+
+        let code = self.decode_error_code(Operation::Nack, payload)?;
+        warn!(
+            "Backend::handle_op_nack(): {:?} rejected with {}",
+            device, code
+        );
+        self.raise_device_error(device, code);
+
+        Ok(code)
+    }
+
+    fn decode_error_code(&self, op: Operation, payload: &[u8]) -> Result<ErrorCode> {
+        let (error_payload, extensions): (ErrorPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        self.skip_extensions(op, extensions)?;
+
+        ErrorCode::try_from(error_payload.code).map_err(Error::ConvertLocoProtocolType)
+    }
+
+    // Anyone on the WiFi can reach the ports, hence devices must give the
+    // token shared with the controller, if any. Tokens are compared in
+    // constant time, so that timing them doesn't tell how much was right.
+    fn check_auth_token(&self, extensions: Extensions, device: Device) -> Result<()> {
+        let Some(expected) = &self.auth_token else {
+            return Ok(());
+        };
+
+        let token = extensions
+            .get(AUTH_TOKEN_EXT_ID)
+            .map_err(Error::ConvertLocoProtocolType)?
+            .unwrap_or_default();
+        let matching = token.len() == expected.len()
+            && token
+                .iter()
+                .zip(expected.as_bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        if !matching {
+            self.raise_alarm(Alarm::Unauthorized);
+            return Err(Error::Unauthorized(device));
+        }
+
+        Ok(())
+    }
+
+    // Boards send heartbeats whenever they've got nothing else to send, hence
+    // one staying silent for longer is gone
+    async fn next_message(
+        &self,
+        half: &mut OwnedReadHalf,
+        rx_sequence: &mut SequenceTracker,
+        device: Device,
+    ) -> Result<(Operation, Vec<u8>)> {
+        timeout(
+            self.heartbeat_timeout,
+            self.retrieve_message(half, rx_sequence),
+        )
+        .await
+        .map_err(|_| Error::MissedHeartbeats(device))?
+    }
+
+    // With a token configured, a board must register first, so that nothing
+    // it sends is trusted before it gave the token. The payload of the
+    // Register is returned to be handled once the board is served.
+    async fn expect_register(
+        &self,
+        half: &mut OwnedReadHalf,
+        rx_sequence: &mut SequenceTracker,
+        device: Device,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.auth_token.is_none() {
+            return Ok(None);
+        }
+
+        let (op, payload) = self.next_message(half, rx_sequence, device).await?;
+        if !matches!(op, Operation::Register) {
+            self.raise_alarm(Alarm::Unauthorized);
+            return Err(Error::Unauthorized(device));
+        }
+        let (_, extensions): (RegisterPayload, _) =
+            decode_payload(&payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        self.check_auth_token(extensions, device)?;
+
+        Ok(Some(payload))
+    }
+
+    fn handle_op_register(&self, payload: &[u8], device: Device) -> Result<()> {
+        debug!("Backend::handle_op_register()");
+
+        let (payload, extensions): (RegisterPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        self.check_auth_token(extensions, device)?;
+
+        let mut hardware = Hardware::default();
+        for field in extensions {
+            let field = field.map_err(Error::ConvertLocoProtocolType)?;
+            match field.tag {
+                AUTH_TOKEN_EXT_ID => {}
+                PRESENT_SENSORS_EXT_ID => {
+                    hardware.sensors = Some(
+                        field
+                            .value
+                            .iter()
+                            .map(|sensor_id| SensorId::try_from(*sensor_id))
+                            .collect::<std::result::Result<_, _>>()
+                            .map_err(Error::ConvertLocoProtocolType)?,
+                    );
+                }
+                PRESENT_ACTUATORS_EXT_ID => {
+                    let (entries, _) = field.value.as_chunks::<PRESENT_ACTUATOR_SIZE>();
+                    let mut actuators = Vec::with_capacity(entries.len());
+                    for [actuator_id, actuator_type] in entries {
+                        actuators.push((
+                            ActuatorId::try_from(*actuator_id)
+                                .map_err(Error::ConvertLocoProtocolType)?,
+                            ActuatorType::try_from(*actuator_type)
+                                .map_err(Error::ConvertLocoProtocolType)?,
+                        ));
+                    }
+                    hardware.actuators = Some(actuators);
+                }
+                _ => debug!(
+                    "Backend::handle_op_register(): unknown extension tag {} ({} bytes)",
+                    field.tag,
+                    field.value.len()
+                ),
+            }
+        }
+
+        self.register_device(
+            device,
+            payload.protocol_version,
+            payload.firmware_version,
+            hardware,
+        )
+    }
+
+    // Driving an actuator the board doesn't have would silently do nothing,
+    // or worse drive whatever is wired to the matching output
+    fn check_actuators_present(&self, actuators: &[(ActuatorId, ActuatorType, u8)]) -> Result<()> {
+        for (actuator_id, actuator_type, _) in actuators {
+            if !self.is_actuator_present(*actuator_id, *actuator_type) {
+                return Err(Error::ActuatorNotPresent(*actuator_id, *actuator_type));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_actuator_present(&self, actuator_id: ActuatorId, actuator_type: ActuatorType) -> bool {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(&Device::Actuators)
+            .and_then(|info| info.hardware.actuators.as_ref())
+            .is_none_or(|present| present.contains(&(actuator_id, actuator_type)))
+    }
+
+    fn is_serial_turnout(&self, actuator_id: ActuatorId, actuator_type: ActuatorType) -> bool {
+        actuator_type == ActuatorType::SwitchRails
+            && self
+                .serial_bus
+                .as_ref()
+                .is_some_and(|serial_bus| serial_bus.has_turnout(actuator_id))
+    }
+
+    // Every state is checked before anything gets thrown
+    fn throw_serial_turnouts(&self, actuators: &[(ActuatorId, ActuatorType, u8)]) -> Result<()> {
+        let Some(serial_bus) = &self.serial_bus else {
+            return Ok(());
+        };
+        let turnouts = actuators
+            .iter()
+            .map(|(actuator_id, _, actuator_state)| {
+                SwitchRailsState::try_from(*actuator_state)
+                    .map(|state| (*actuator_id, state))
+                    .map_err(Error::ConvertLocoProtocolType)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (actuator_id, state) in turnouts {
+            serial_bus
+                .throw(actuator_id, state)
+                .map_err(Error::SerialBus)?;
+        }
+
+        Ok(())
+    }
+
+    // Appends a new ID to the payload of a command, which lets the device
+    // skip the command if it gets it twice, and along with its correlation ID
+    // if told to
+    fn encode_command(&self, operation: Operation, mut payload: Vec<u8>) -> Result<UnackedCommand> {
+        let id = self.command_ids.next();
+        let mut extension = [0u8; EXTENSION_FIELD_HEADER_SIZE + COMMAND_ID_SIZE];
+        let extension_len =
+            encode_extension_field(&mut extension, COMMAND_EXT_ID, &id.to_le_bytes())
+                .map_err(Error::ConvertLocoProtocolType)?;
+        payload.extend_from_slice(&extension[..extension_len]);
+
+        if self.correlation_ids_on_wire
+            && let Some(correlation_id) = correlation::current()
+        {
+            let mut extension = [0u8; EXTENSION_FIELD_HEADER_SIZE + CORRELATION_ID_SIZE];
+            let extension_len = encode_extension_field(
+                &mut extension,
+                CORRELATION_EXT_ID,
+                &correlation_id.value().to_le_bytes(),
+            )
+            .map_err(Error::ConvertLocoProtocolType)?;
+            payload.extend_from_slice(&extension[..extension_len]);
+        }
+
+        Ok(UnackedCommand {
+            id,
+            operation,
+            payload,
+        })
+    }
+
+    // Fixed part of a payload sent to a loco, in the wire format it negotiated
+    fn encode_loco_payload<P: Encode + Serialize>(
+        &self,
+        payload: P,
+        format: WireFormat,
+    ) -> Result<Vec<u8>> {
+        let mut buf = vec![0; LOCO_PAYLOAD_MAX_SIZE];
+        let len = encode_payload(&mut buf, payload, self.bincode_cfg, format)
+            .map_err(Error::EncodePayload)?;
+        buf.truncate(len);
+
+        Ok(buf)
+    }
+
+    // Postcard is preferred whenever both sides support it. A loco listing no
+    // format isn't told about any.
+    fn negotiate_wire_format(&self, extensions: Extensions) -> Result<Option<WireFormat>> {
+        let mut wire_format = None;
+        for field in extensions {
+            let field = field.map_err(Error::ConvertLocoProtocolType)?;
+            match field.tag {
+                AUTH_TOKEN_EXT_ID => {}
+                WIRE_FORMATS_EXT_ID => {
+                    let supported = field
+                        .value
+                        .iter()
+                        .filter_map(|format| WireFormat::try_from(*format).ok())
+                        .filter(WireFormat::is_supported)
+                        .collect::<Vec<_>>();
+                    wire_format = Some(if supported.contains(&WireFormat::Postcard) {
+                        WireFormat::Postcard
+                    } else {
+                        WireFormat::Bincode
+                    });
+                }
+                _ => debug!(
+                    "Backend::negotiate_wire_format(): unknown tag {} ({} bytes)",
+                    field.tag,
+                    field.value.len()
+                ),
+            }
+        }
+
+        Ok(wire_format)
+    }
+
+    // The error is the only frame sent through a rejected connection
+    async fn send_error_op(&self, half: &mut OwnedWriteHalf, code: ErrorCode) -> Result<()> {
+        debug!("Backend::send_error_op(): {}", code);
+
+        let payload = encode_to_vec(ErrorPayload { code: code.into() }, self.bincode_cfg)
+            .map_err(Error::EncodeToVec)?;
+
+        self.write_frame(
+            half,
+            &mut SequenceCounter::default(),
+            Operation::Error,
+            &payload,
+        )
+        .await
+    }
+
+    // Returns the id of the link the loco connected through, and what tells
+    // once the link gets dropped
+    async fn handle_op_connect(
+        &self,
+        mut half: OwnedWriteHalf,
+        payload: &[u8],
+    ) -> Result<(LocoId, u32, oneshot::Receiver<()>)> {
+        debug!("Backend::handle_op_connect()");
+
+        // Retrieve payload
+        let (payload, extensions): (ConnectPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        let wire_format = self.negotiate_wire_format(extensions)?;
+        let claimed = LocoId::try_from(payload.loco_id).map_err(Error::ConvertLocoProtocolType)?;
+        let loco_id = self
+            .loco_devices
+            .get(&payload.device_id)
+            .copied()
+            .unwrap_or(claimed);
+        debug!(
+            "Backend::handle_op_connect(): LocoId {:?} (claimed {:?}), device {:#x}",
+            loco_id, claimed, payload.device_id
+        );
+
+        if let Err(e) = self.check_auth_token(extensions, Device::Loco(loco_id)) {
+            self.send_error_op(&mut half, ErrorCode::Unauthorized)
+                .await?;
+            return Err(e);
+        }
+
+        if let Err(e) = self.check_loco(loco_id) {
+            self.send_error_op(&mut half, ErrorCode::UnknownLocoId)
+                .await?;
+            return Err(e);
+        }
+
+        // A misconfigured board might claim the LocoId of a loco which is
+        // already connected. Reject it rather than stealing the connection of
+        // the real loco. The same board reconnecting is legit though.
+        let duplicate = {
+            let loco_info = self.loco_info(&loco_id).lock().unwrap();
+            loco_info
+                .device_id
+                .is_some_and(|id| id != payload.device_id)
+                && loco_info.link.is_some()
+        };
+        if duplicate {
+            self.send_error_op(&mut half, ErrorCode::DuplicateLocoId)
+                .await?;
+            self.raise_alarm(Alarm::DuplicateLoco);
+            return Err(Error::DuplicateLoco(loco_id, payload.device_id));
+        }
+
+        self.register_device(
+            Device::Loco(loco_id),
+            payload.protocol_version,
+            payload.firmware_version,
+            Hardware::default(),
+        )?;
+
+        // Sent before the link is shared, so that nothing can be sent to the
+        // loco in a format it isn't told about yet
+        let mut tx_sequence = SequenceCounter::default();
+        if let Some(format) = wire_format {
+            debug!("Backend::handle_op_connect(): {} uses {}", loco_id, format);
+            let payload = encode_to_vec(
+                SelectWireFormatPayload {
+                    format: format.into(),
+                },
+                self.bincode_cfg,
+            )
+            .map_err(Error::EncodeToVec)?;
+            self.write_frame(
+                &mut half,
+                &mut tx_sequence,
+                Operation::SelectWireFormat,
+                &payload,
+            )
+            .await?;
+        }
+
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+        loco_info.connections += 1;
+        let link_id = loco_info.connections;
+        // A rebooted loco connects again before its previous connection is
+        // known to be gone, which still has to be reported. Dropping the
+        // previous link stops the task serving it.
+        let replaced = loco_info
+            .link
+            .replace(LocoLink {
+                id: link_id,
+                writer: Arc::new(AsyncMutex::new(FrameWriter { half, tx_sequence })),
+                awaited: None,
+                _closed: closed_tx,
+            })
+            .is_some();
+        let power_lost = loco_info.telemetry.power_lost();
+        loco_info.connected_at_us = self.now_us();
+        loco_info.device_id = Some(payload.device_id);
+        loco_info.wire_format = wire_format.unwrap_or_default();
+        loco_info.command_pacer.reset();
+        loco_info.command_rtt.reset();
+        loco_info.functions = LocoFunctions::default();
+        loco_info.reported_status = None;
+        loco_info.telemetry = LocoTelemetry::default();
+        drop(loco_info);
+
+        if replaced {
+            info!(
+                "Backend::handle_op_connect(): {} replaces its previous connection",
+                loco_id
+            );
+            self.notify(Event::LocoDisconnected {
+                loco_id,
+                power_lost,
+                reason: None,
+            });
+        }
+        self.notify(Event::LocoConnected {
+            loco_id,
+            device_id: payload.device_id,
+        });
+
+        Ok((loco_id, link_id, closed_rx))
+    }
+
+    // Serves the connection of a loco until it goes away, hence meant to be
+    // run by a task of its own
+    pub async fn handle_loco_connection(&self, stream: TcpStream) -> Result<()> {
+        debug!("Backend::handle_connection()");
+
+        let (mut reader, writer) = stream.into_split();
+        let mut rx_sequence = SequenceTracker::default();
+        let (op, payload) = self.retrieve_message(&mut reader, &mut rx_sequence).await?;
+
+        match op {
+            Operation::Connect => {
+                let (loco_id, link_id, closed) = self.handle_op_connect(writer, &payload).await?;
+                self.serve_loco(loco_id, link_id, reader, rx_sequence, closed)
+                    .await;
+            }
+            Operation::ControlLoco
+            | Operation::LocoStatus
+            | Operation::SensorsStatus
+            | Operation::DriveActuator
+            | Operation::DriveActuatorsBatch
+            | Operation::ControlLocoFunctions
+            | Operation::CommandAck
+            | Operation::EmergencyStop
+            | Operation::ActuatorsTelemetry
+            | Operation::InputsStatus
+            | Operation::Register
+            | Operation::TimeSync
+            | Operation::HoldOnDisconnect
+            | Operation::Error
+            | Operation::Disconnect
+            | Operation::LocoTelemetry
+            | Operation::Heartbeat
+            | Operation::SelectWireFormat
+            | Operation::Nack
+            | Operation::SafeCrawlReport => {
+                return Err(Error::UnsupportedOperation(op));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the writer of the link of the loco along with its id, so that
+    // nothing is held across a request
+    fn loco_writer(&self, loco_id: LocoId) -> Result<(Arc<AsyncMutex<FrameWriter>>, u32)> {
+        let loco_info = self.loco_info(&loco_id).lock().unwrap();
+        let link = loco_info
+            .link
+            .as_ref()
+            .ok_or(Error::LocoNotConnected(loco_id))?;
+
+        Ok((link.writer.clone(), link.id))
+    }
+
+    // Sends a request through the connection of the loco and waits for the
+    // task serving it to hand the response over. Requests to the same loco
+    // are sent one at a time, while requests to different locos don't wait
+    // for each other.
+    async fn request_loco<D: Decode<()> + 'static>(
+        &self,
+        loco_id: LocoId,
+        operation: Operation,
+        payload: &[u8],
+    ) -> Result<D> {
+        let (writer, link_id) = self.loco_writer(loco_id)?;
+        let mut writer = writer.lock().await;
+
+        self.request_through(loco_id, link_id, &mut writer, operation, payload)
+            .await
+    }
+
+    // Same as request_loco(), through a link already held by the caller
+    async fn request_through<D: Decode<()> + 'static>(
+        &self,
+        loco_id: LocoId,
+        link_id: u32,
+        writer: &mut FrameWriter,
+        operation: Operation,
+        payload: &[u8],
+    ) -> Result<D> {
+        let (sender, receiver) = oneshot::channel();
+        let cfg = self.bincode_cfg;
+        let awaited = AwaitedResponse {
+            kind: type_name::<D>(),
+            len: Box::new(move |bytes| decode_from_slice::<D, _>(bytes, cfg).map(|(_, len)| len)),
+            sender,
+        };
+        {
+            let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+            match loco_info.link.as_mut() {
+                Some(link) if link.id == link_id => link.awaited = Some(awaited),
+                _ => return Err(Error::LocoNotConnected(loco_id)),
+            }
+        }
+
+        let FrameWriter { half, tx_sequence } = writer;
+        let result = timeout(LOCO_REQUEST_TIMEOUT, async {
+            self.write_frame(half, tx_sequence, operation, payload)
+                .await?;
+            receiver
+                .await
+                .map_err(|_| Error::LocoNotConnected(loco_id))?
+        })
+        .await
+        .map_err(|_| Error::LocoTimedOut(loco_id))
+        .and_then(|bytes| bytes)
+        .and_then(|bytes| {
+            decode_from_slice(&bytes, self.bincode_cfg)
+                .map(|(resp, _)| resp)
+                .map_err(Error::DecodeFromStream)
+        });
+
+        self.check_loco_connection(loco_id, link_id, result)
+    }
+
+    // Sends a frame the loco doesn't answer
+    async fn notify_loco(
+        &self,
+        loco_id: LocoId,
+        operation: Operation,
+        payload: &[u8],
+    ) -> Result<()> {
+        let (writer, link_id) = self.loco_writer(loco_id)?;
+        let mut writer = writer.lock().await;
+        let FrameWriter { half, tx_sequence } = &mut *writer;
+        let result = self
+            .write_frame(half, tx_sequence, operation, payload)
+            .await;
+        drop(writer);
+
+        self.check_loco_connection(loco_id, link_id, result)
+    }
+
+    // A connection failing a write or a read, or leaving a request
+    // unanswered, is either dead or out of sync, hence it's dropped right
+    // away rather than left for the next request to get stuck on
+    fn check_loco_connection<T>(
+        &self,
+        loco_id: LocoId,
+        link_id: u32,
+        result: Result<T>,
+    ) -> Result<T> {
+        if let Err(
+            e @ (Error::WriteTcpStream(_)
+            | Error::ReadTcpStream(_)
+            | Error::DecodeFromStream(_)
+            | Error::Framing(_)
+            | Error::LocoTimedOut(_)),
+        ) = &result
+        {
+            warn!(
+                "Backend::check_loco_connection(): {} dropping connection: {}",
+                loco_id, e
+            );
+            self.drop_loco_link(loco_id, link_id, None);
+        }
+
+        result
+    }
+
+    // A loco losing the track power soon goes away, which is then told apart
+    // from the loco crashing
+    fn handle_op_loco_telemetry(
+        &self,
+        loco_id: LocoId,
+        telemetry: &mut LocoTelemetry,
+        payload: &[u8],
+    ) -> Result<()> {
+        let reported = self.decode_loco_telemetry(payload)?;
+        if reported.power_lost() != telemetry.power_lost() {
+            if reported.power_lost() {
+                warn!(
+                    "Backend::handle_op_loco_telemetry(): {} lost track power",
+                    loco_id
+                );
+            } else {
+                info!(
+                    "Backend::handle_op_loco_telemetry(): {} track power is back",
+                    loco_id
+                );
+            }
+        }
+        *telemetry = reported;
+
+        Ok(())
+    }
+
+    fn decode_loco_telemetry(&self, payload: &[u8]) -> Result<LocoTelemetry> {
+        let mut telemetry = LocoTelemetry::default();
+
+        for field in Extensions::new(payload) {
+            let field = field.map_err(Error::ConvertLocoProtocolType)?;
+            let invalid = || {
+                Error::ConvertLocoProtocolType(LocoProtocolError::InvalidExtensionField(field.tag))
+            };
+            match field.tag {
+                LOCO_TELEMETRY_EXT_BATTERY_MV => {
+                    let value = field.value.try_into().map_err(|_| invalid())?;
+                    telemetry.battery_mv = Some(u16::from_le_bytes(value));
+                }
+                LOCO_TELEMETRY_EXT_TEMPERATURE => {
+                    let value = field.value.try_into().map_err(|_| invalid())?;
+                    telemetry.temperature = Some(i8::from_le_bytes(value));
+                }
+                LOCO_TELEMETRY_EXT_RSSI => {
+                    let value = field.value.try_into().map_err(|_| invalid())?;
+                    telemetry.rssi = Some(i8::from_le_bytes(value));
+                }
+                LOCO_TELEMETRY_EXT_SUPPLY => {
+                    let value = field.value.try_into().map_err(|_| invalid())?;
+                    telemetry.supply = Some(u16::from_le_bytes(value));
+                }
+                LOCO_TELEMETRY_EXT_POWER_LOST => {
+                    telemetry.power_lost = match field.value {
+                        [0] => Some(false),
+                        [1] => Some(true),
+                        _ => return Err(invalid()),
+                    };
+                }
+                tag => debug!(
+                    "Backend::decode_loco_telemetry(): unknown extension tag {} ({} bytes)",
+                    tag,
+                    field.value.len()
+                ),
+            }
+        }
+
+        debug!("Backend::decode_loco_telemetry(): {:?}", telemetry);
+
+        Ok(telemetry)
+    }
+
+    // The loco moved on its own while nobody was watching, which only the
+    // sensors can tell where it ended up. Stopping short of any checkpoint
+    // means someone has to go and fetch it.
+    fn handle_op_safe_crawl_report(&self, loco_id: LocoId, payload: &[u8]) -> Result<()> {
+        debug!("Backend::handle_op_safe_crawl_report()");
+
+        let (report, extensions): (SafeCrawlReportPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        self.skip_extensions(Operation::SafeCrawlReport, extensions)?;
+        let direction =
+            Direction::try_from(report.direction).map_err(Error::ConvertLocoProtocolType)?;
+        let reached_checkpoint = report.reached_checkpoint != 0;
+
+        if reached_checkpoint {
+            info!(
+                "Backend::handle_op_safe_crawl_report(): {} crawled {:?} to a checkpoint in {}ms",
+                loco_id, direction, report.crawl_ms
+            );
+        } else {
+            warn!(
+                "Backend::handle_op_safe_crawl_report(): {} gave up crawling {:?} after {}ms",
+                loco_id, direction, report.crawl_ms
+            );
+            self.raise_alarm(Alarm::StrandedLoco);
+        }
+        self.notify(Event::SafeCrawl {
+            loco_id,
+            direction,
+            reached_checkpoint,
+            crawl_ms: report.crawl_ms,
+        });
+
+        Ok(())
+    }
+
+    // Converts a DCC speed step into a speed the loco understands, based on
+    // the calibration curve of this loco.
+    pub fn speed_from_steps(&self, loco_id: LocoId, steps: SpeedSteps) -> Speed {
+        let curve = self
+            .speed_curves
+            .lock()
+            .unwrap()
+            .get(&loco_id)
+            .copied()
+            .unwrap_or_default();
+
+        steps.to_speed(&curve)
+    }
+
+    pub fn set_speed_curves(&self, speed_curves: BTreeMap<LocoId, SpeedCurve>) {
+        *self.speed_curves.lock().unwrap() = speed_curves;
+    }
+
+    // Called when switching to another layout
+    pub fn set_network(&self, network: &NetworkConfig) {
+        *self.servo_angles.lock().unwrap() = network.servo_angles.clone();
+        *self.switch_defaults.lock().unwrap() = network.switch_defaults.clone();
+    }
+
+    pub fn trim(&self, loco_id: LocoId) -> u8 {
+        self.trims
+            .lock()
+            .unwrap()
+            .get(&loco_id)
+            .copied()
+            .unwrap_or(100)
+    }
+
+    pub fn learned_driving(&self) -> LearnedDrivingDescription {
+        self.learned_driving.describe()
+    }
+
+    pub fn set_learned_driving_recording(&self, loco_id: LocoId, recording: bool) -> Result<()> {
+        self.check_loco(loco_id)?;
+        self.learned_driving.set_recording(loco_id, recording);
+        Ok(())
+    }
+
+    pub fn forget_learned_driving(&self, loco_id: LocoId) -> Result<()> {
+        self.check_loco(loco_id)?;
+        self.learned_driving.forget(loco_id);
+        Ok(())
+    }
+
+    // Speed the loco has been driven at by hand that long after it started
+    // through the segment, if it has been
+    pub fn learned_speed(
+        &self,
+        loco_id: LocoId,
+        from: CheckpointId,
+        to: CheckpointId,
+        elapsed_us: u64,
+    ) -> Option<Speed> {
+        self.learned_driving.speed(loco_id, from, to, elapsed_us)
+    }
+
+    // The new trim applies right away, even to a loco which is already
+    // running
+    pub fn set_trim(&self, loco_id: LocoId, trim_percent: u8) -> Result<()> {
+        debug!(
+            "Backend::set_trim(): loco_id {:?}, trim {}%",
+            loco_id, trim_percent
+        );
+
+        self.check_loco(loco_id)?;
+        if !(MIN_TRIM_PERCENT..=MAX_TRIM_PERCENT).contains(&trim_percent) {
+            return Err(Error::InvalidTrim(trim_percent));
+        }
+
+        self.trims.lock().unwrap().insert(loco_id, trim_percent);
+        self.loco_info(&loco_id)
+            .lock()
+            .unwrap()
+            .command_pacer
+            .resend();
+
+        Ok(())
+    }
+
+    pub fn speed_scale(&self) -> u8 {
+        self.speed_scale_percent.load(Ordering::Relaxed)
+    }
+
+    // Like the trims, the new scale applies right away to the locos which
+    // are already running
+    pub fn set_speed_scale(&self, scale_percent: u8) -> Result<()> {
+        info!("Backend::set_speed_scale(): {}%", scale_percent);
+
+        if !(MIN_SPEED_SCALE_PERCENT..=MAX_SPEED_SCALE_PERCENT).contains(&scale_percent) {
+            return Err(Error::InvalidSpeedScale(scale_percent));
+        }
+
+        self.speed_scale_percent
+            .store(scale_percent, Ordering::Relaxed);
+        for loco_id in self.loco_ids() {
+            self.loco_info(&loco_id)
+                .lock()
+                .unwrap()
+                .command_pacer
+                .resend();
+        }
+
+        Ok(())
+    }
+
+    // Applies the loco trim, the speed scale and then the duty curve onto a
+    // speed, which turns it into a duty cycle unless none of them changes
+    // anything
+    fn trimmed_speed(&self, loco_id: LocoId, speed: Speed) -> Speed {
+        if speed == Speed::Stop {
+            return speed;
+        }
+
+        let percent = u16::from(self.trim(loco_id)) * u16::from(self.speed_scale()) / 100;
+        let speed = if percent == 100 {
+            speed
+        } else {
+            let duty_cycle = u16::from(speed.duty_cycle()) * percent / 100;
+            Speed::PwmDutyCycle(duty_cycle.min(100) as u8)
+        };
+
+        match self.duty_curves.get(&loco_id) {
+            Some(curve) => Speed::PwmDutyCycle(curve.remap(speed.duty_cycle())),
+            None => speed,
+        }
+    }
+
+    // Connected locos which aren't stopped, as reported by the locos
+    // themselves
+    pub async fn moving_locos(&self) -> Result<Vec<LocoId>> {
+        let mut moving = Vec::new();
+        for (loco_id, status) in self.loco_statuses().await {
+            match status {
+                Ok(status) if status.speed() != Speed::Stop => moving.push(loco_id),
+                Ok(_) | Err(Error::LocoNotConnected(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(moving)
+    }
+
+    // Whether every connected loco is currently stopped
+    pub async fn locos_stopped(&self) -> Result<bool> {
+        Ok(self.moving_locos().await?.is_empty())
+    }
+
+    // The loco moves to the new speed progressively over the ramp, if any,
+    // rather than right away
+    pub async fn control_loco(
+        &self,
+        loco_id: LocoId,
+        direction: Direction,
+        speed: Speed,
+        ramp: Option<Duration>,
+    ) -> Result<()> {
+        debug!(
+            "Backend::control_loco(): loco_id {:?}, direction {:?}, speed {:?}, ramp {:?}",
+            loco_id, direction, speed, ramp
+        );
+
+        self.check_loco(loco_id)?;
+        {
+            let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+            if loco_info.link.is_none() {
+                return Err(Error::LocoNotConnected(loco_id));
+            }
+            loco_info.command_pacer.push(direction, speed, ramp);
+        }
+
+        self.send_pending_loco_command(loco_id).await
+    }
+
+    // Sends the commands which had to be delayed by the pacing, and must be
+    // called periodically.
+    pub async fn flush_loco_commands(&self) -> Result<()> {
+        for loco_id in self.loco_ids() {
+            match self.send_pending_loco_command(loco_id).await {
+                Ok(()) | Err(Error::LocoNotConnected(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // A command which the loco never answered, because the connection broke,
+    // is sent again once the loco is back, unless a newer one supersedes it
+    async fn send_pending_loco_command(&self, loco_id: LocoId) -> Result<()> {
+        // The link is taken before the command, so that commands are sent in
+        // the order they're popped
+        let (writer, link_id) = self.loco_writer(loco_id)?;
+        let mut writer = writer.lock().await;
+
+        let (direction, speed, trimmed_speed, command) = {
+            let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+            let (direction, speed, ramp, unacked) = match loco_info
+                .command_pacer
+                .pop(self.loco_command_min_spacing)
+            {
+                Some((direction, speed, ramp)) => (direction, speed, ramp, None),
+                None if loco_info.command_pacer.is_idle() => match loco_info.unacked.take() {
+                    Some((direction, speed, command)) => (direction, speed, None, Some(command)),
+                    None => return Ok(()),
+                },
+                None => return Ok(()),
+            };
+
+            debug!(
+                "Backend::send_pending_loco_command(): loco_id {:?}, direction {:?}, speed {:?}",
+                loco_id, direction, speed
+            );
+
+            let trimmed_speed = self.trimmed_speed(loco_id, speed);
+            let command = match unacked {
+                Some(command) => {
+                    info!(
+                        "Backend::send_pending_loco_command(): {} resending command {}",
+                        loco_id, command.id
+                    );
+                    command
+                }
+                None => {
+                    let mut payload = self.encode_loco_payload(
+                        ControlLocoPayload {
+                            direction: direction.into(),
+                            speed: trimmed_speed.into(),
+                        },
+                        loco_info.wire_format,
+                    )?;
+                    if let Some(ramp) = ramp {
+                        let ramp_ms = ramp.as_millis().min(u16::MAX.into()) as u16;
+                        let mut extension = [0u8; EXTENSION_FIELD_HEADER_SIZE + RAMP_SIZE];
+                        let extension_len = encode_extension_field(
+                            &mut extension,
+                            RAMP_EXT_ID,
+                            &ramp_ms.to_le_bytes(),
+                        )
+                        .map_err(Error::ConvertLocoProtocolType)?;
+                        payload.extend_from_slice(&extension[..extension_len]);
+                    }
+                    self.encode_command(Operation::ControlLoco, payload)?
+                }
+            };
+            loco_info.unacked = Some((direction, speed, command.clone()));
+
+            (direction, speed, trimmed_speed, command)
+        };
+
+        let sent_at = Instant::now();
+        let resp: ControlLocoResponse = self
+            .request_through(
+                loco_id,
+                link_id,
+                &mut writer,
+                command.operation,
+                &command.payload,
+            )
+            .await?;
+        drop(writer);
+        {
+            let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+            loco_info.command_rtt.add_sample(sent_at.elapsed());
+            loco_info.unacked = None;
+            loco_info.command_answered(&resp, self.now_us());
+        }
+
+        // The commanded speed is reported rather than the trimmed one, unless
+        // the loco applied something else
+        let (direction, speed) =
+            if resp.direction == u8::from(direction) && resp.speed == u8::from(trimmed_speed) {
+                (direction, speed)
+            } else {
+                warn!(
+                    "Backend::send_pending_loco_command(): {} ignored the command",
+                    loco_id
+                );
+                (
+                    Direction::try_from(resp.direction).map_err(Error::ConvertLocoProtocolType)?,
+                    Speed::try_from(resp.speed).map_err(Error::ConvertLocoProtocolType)?,
+                )
+            };
+
+        self.notify(Event::LocoCommandApplied {
+            loco_id,
+            direction,
+            speed,
+        });
+
+        Ok(())
+    }
+
+    async fn emergency_stop_loco(&self, loco_id: LocoId) -> Result<()> {
+        let resp: ControlLocoResponse = self
+            .request_loco(loco_id, Operation::EmergencyStop, &[])
+            .await?;
+        let direction =
+            Direction::try_from(resp.direction).map_err(Error::ConvertLocoProtocolType)?;
+        let speed = Speed::try_from(resp.speed).map_err(Error::ConvertLocoProtocolType)?;
+        let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+        loco_info.command_answered(&resp, self.now_us());
+        loco_info.command_pacer.sent(direction, speed);
+        drop(loco_info);
+
+        self.notify(Event::LocoCommandApplied {
+            loco_id,
+            direction,
+            speed,
+        });
+
+        Ok(())
+    }
+
+    // Functions aren't paced like the motor commands, since they never affect
+    // how the loco runs. Nor are they sent again after a reconnect, since the
+    // caller is told about the failure. The functions applied by the loco are
+    // returned.
+    pub async fn control_loco_functions(
+        &self,
+        loco_id: LocoId,
+        functions: LocoFunctions,
+    ) -> Result<LocoFunctions> {
+        debug!(
+            "Backend::control_loco_functions(): loco_id {:?}, functions {:?}",
+            loco_id, functions
+        );
+
+        self.check_loco(loco_id)?;
+        let wire_format = self.loco_info(&loco_id).lock().unwrap().wire_format;
+        let payload = self.encode_loco_payload(
+            ControlLocoFunctionsPayload {
+                functions: functions.into(),
+            },
+            wire_format,
+        )?;
+        let command = self.encode_command(Operation::ControlLocoFunctions, payload)?;
+        let resp: ControlLocoFunctionsResponse = self
+            .request_loco(loco_id, command.operation, &command.payload)
+            .await?;
+        let applied =
+            LocoFunctions::try_from(resp.functions).map_err(Error::ConvertLocoProtocolType)?;
+        if applied != functions {
+            warn!(
+                "Backend::control_loco_functions(): {} applied {:?}",
+                loco_id, applied
+            );
+        }
+        self.loco_info(&loco_id).lock().unwrap().functions = applied;
+
+        Ok(applied)
+    }
+
+    // Asks every connected loco to keep its current motion for hold_secs once
+    // disconnected, rather than stopping right away. This lets the controller
+    // be restarted without halting every train, as long as it comes back
+    // quickly enough.
+    pub async fn prepare_restart(&self, hold_secs: u8) -> Result<()> {
+        debug!("Backend::prepare_restart(): hold_secs {}", hold_secs);
+
+        for loco_id in self.loco_ids() {
+            let wire_format = self.loco_info(&loco_id).lock().unwrap().wire_format;
+            let payload =
+                self.encode_loco_payload(HoldOnDisconnectPayload { hold_secs }, wire_format)?;
+            match self
+                .notify_loco(loco_id, Operation::HoldOnDisconnect, &payload)
+                .await
+            {
+                Ok(()) | Err(Error::LocoNotConnected(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // The telemetry the loco sends right before its status is handled by the
+    // task serving the loco, before the status is handed over
+    pub async fn loco_status(&self, loco_id: LocoId) -> Result<LocoStatus> {
+        debug!("Backend::loco_status(): loco_id {:?}", loco_id);
+
+        self.check_loco(loco_id)?;
+        let resp: LocoStatusResponse = self
+            .request_loco(loco_id, Operation::LocoStatus, &[])
+            .await?;
+
+        let motor_status =
+            MotorStatus::try_from(resp.motor_status).map_err(Error::ConvertLocoProtocolType)?;
+        if motor_status == MotorStatus::Stalled {
+            warn!("Backend::loco_status(): {} motor stalled", loco_id);
+        }
+
+        let reported = ReportedStatus {
+            direction: Direction::try_from(resp.direction)
+                .map_err(Error::ConvertLocoProtocolType)?,
+            speed: Speed::try_from(resp.speed).map_err(Error::ConvertLocoProtocolType)?,
+            motor_status,
+            reported_at_us: self.now_us(),
+        };
+        let mut loco_info = self.loco_info(&loco_id).lock().unwrap();
+        loco_info.reported_status = Some(reported);
+
+        Ok(loco_info.status(reported, reported.reported_at_us))
+    }
+
+    // Asks every loco for its status at once, so that a slow loco only delays
+    // its own status. Locos which aren't connected are reported as such.
+    pub async fn loco_statuses(&self) -> Vec<(LocoId, Result<LocoStatus>)> {
+        let loco_ids = self.loco_ids();
+        let statuses = join_all(loco_ids.iter().map(|loco_id| self.loco_status(*loco_id))).await;
+
+        loco_ids.into_iter().zip(statuses).collect()
+    }
+
+    // Status last reported by the loco, which saves a round-trip to the loco
+    // as long as refresh_loco_statuses() keeps it up to date. The loco is
+    // only asked if it hasn't reported anything yet.
+    pub async fn cached_loco_status(&self, loco_id: LocoId) -> Result<LocoStatus> {
+        debug!("Backend::cached_loco_status(): loco_id {:?}", loco_id);
+
+        self.check_loco(loco_id)?;
+        {
+            let loco_info = self.loco_info(&loco_id).lock().unwrap();
+            if loco_info.link.is_none() {
+                return Err(Error::LocoNotConnected(loco_id));
+            }
+            if let Some(reported) = loco_info.reported_status {
+                return Ok(loco_info.status(reported, self.now_us()));
+            }
+        }
+
+        self.loco_status(loco_id).await
+    }
+
+    // Same as loco_statuses(), only the locos which haven't reported anything
+    // yet are asked
+    pub async fn cached_loco_statuses(&self) -> Vec<(LocoId, Result<LocoStatus>)> {
+        let loco_ids = self.loco_ids();
+        let statuses = join_all(
+            loco_ids
+                .iter()
+                .map(|loco_id| self.cached_loco_status(*loco_id)),
+        )
+        .await;
+
+        loco_ids.into_iter().zip(statuses).collect()
+    }
+
+    // Asks every connected loco for its status, keeping the cached statuses
+    // up to date. Must be called periodically.
+    pub async fn refresh_loco_statuses(&self) {
+        for (loco_id, status) in self.loco_statuses().await {
+            match status {
+                Ok(_) | Err(Error::LocoNotConnected(_)) => {}
+                Err(e) => error!("Backend::refresh_loco_statuses(): {} {}", loco_id, e),
+            }
+        }
+    }
+
+    pub async fn drive_actuator(
+        &self,
+        actuator_id: ActuatorId,
+        actuator_type: ActuatorType,
+        actuator_state: u8,
+    ) -> Result<()> {
+        debug!(
+            "Backend::drive_switch_rails(): actuator_id {:?}, actuator_type {:?}, state {}",
+            actuator_id, actuator_type, actuator_state
+        );
+
+        if self.is_serial_turnout(actuator_id, actuator_type) {
+            self.throw_serial_turnouts(&[(actuator_id, actuator_type, actuator_state)])?;
+        } else {
+            let on_wire = self.actuator_on_wire((actuator_id, actuator_type, actuator_state));
+            self.check_actuators_present(&[on_wire])?;
+
+            let payload = encode_to_vec(
+                DriveActuatorPayload {
+                    actuator_id: on_wire.0.into(),
+                    actuator_type: on_wire.1.into(),
+                    actuator_state: on_wire.2,
+                },
+                self.bincode_cfg,
+            )
+            .map_err(Error::EncodeToVec)?;
+
+            self.send_actuators_message(Operation::DriveActuator, payload)
+                .await?;
+        }
+
+        self.notify(Event::ActuatorsDriven {
+            actuators: vec![(actuator_id, actuator_type, actuator_state)],
+        });
+
+        Ok(())
+    }
+
+    /**
+     * Drive several actuators through a single message, which the actuators
+     * board applies atomically. This avoids paying the latency of one message
+     * per actuator when a whole route has to be set at once. Turnouts of the
+     * serial bus are thrown in order once the board got its message, since
+     * the bus can't be part of the same batch.
+     */
+    pub async fn drive_actuators(
+        &self,
+        actuators: &[(ActuatorId, ActuatorType, u8)],
+    ) -> Result<()> {
+        debug!("Backend::drive_actuators(): {:?}", actuators);
+
+        let (serial, board): (Vec<_>, Vec<_>) =
+            actuators
+                .iter()
+                .partition(|(actuator_id, actuator_type, _)| {
+                    self.is_serial_turnout(*actuator_id, *actuator_type)
+                });
+
+        if !board.is_empty() {
+            let on_wire: Vec<_> = board
+                .iter()
+                .map(|actuator| self.actuator_on_wire(*actuator))
+                .collect();
+            self.check_actuators_present(&on_wire)?;
+
+            let mut payload = encode_to_vec(
+                DriveActuatorsBatchArray {
+                    len: u8::try_from(on_wire.len())
+                        .map_err(|_| Error::PayloadTooLarge(on_wire.len()))?,
+                },
+                self.bincode_cfg,
+            )
+            .map_err(Error::EncodeToVec)?;
+
+            for (actuator_id, actuator_type, actuator_state) in on_wire.iter() {
+                payload.append(
+                    &mut encode_to_vec(
+                        DriveActuatorPayload {
+                            actuator_id: (*actuator_id).into(),
+                            actuator_type: (*actuator_type).into(),
+                            actuator_state: *actuator_state,
+                        },
+                        self.bincode_cfg,
+                    )
+                    .map_err(Error::EncodeToVec)?,
+                );
+            }
+
+            self.send_actuators_message(Operation::DriveActuatorsBatch, payload)
+                .await?;
+        }
+
+        self.throw_serial_turnouts(&serial)?;
+
+        self.notify(Event::ActuatorsDriven {
+            actuators: actuators.to_vec(),
+        });
+
+        Ok(())
+    }
+
+    // Switch rails thrown by a servo are sent as the angle matching their
+    // state, everything else in the controller only knowing about the state
+    fn actuator_on_wire(
+        &self,
+        (actuator_id, actuator_type, actuator_state): (ActuatorId, ActuatorType, u8),
+    ) -> (ActuatorId, ActuatorType, u8) {
+        let servo_angles = self.servo_angles.lock().unwrap();
+        let angles = match (actuator_type, servo_angles.get(&actuator_id)) {
+            (ActuatorType::SwitchRails, Some(angles)) => angles,
+            _ => return (actuator_id, actuator_type, actuator_state),
+        };
+
+        match SwitchRailsState::try_from(actuator_state) {
+            Ok(SwitchRailsState::Direct) => (actuator_id, ActuatorType::Servo, angles.direct),
+            Ok(SwitchRailsState::Diverted) => (actuator_id, ActuatorType::Servo, angles.diverted),
+            // Left for the board to reject
+            Err(_) => (actuator_id, actuator_type, actuator_state),
+        }
+    }
+
+    /**
+     * Throws switch rails on behalf of the automation, unless the Oracle
+     * relies on any of them to run a loco through its segment. The Oracle
+     * decisions are recorded under the same lock, so that none can sneak in
+     * between the check and the command.
+     */
+    pub async fn drive_free_switch_rails(
+        &self,
+        switch_rails: &BTreeMap<ActuatorId, SwitchRailsState>,
+    ) -> Result<()> {
+        let held = self.oracle_switch_rails.lock().await;
+        if let Some(actuator_id) = switch_rails.keys().find(|id| held.contains(id)) {
+            return Err(Error::SwitchRailsHeld(*actuator_id));
+        }
+
+        let actuators: Vec<(ActuatorId, ActuatorType, u8)> = switch_rails
+            .iter()
+            .map(|(actuator_id, state)| (*actuator_id, ActuatorType::SwitchRails, (*state).into()))
+            .collect();
+        self.drive_actuators(&actuators).await
+    }
+
+    // The command is kept until the board acknowledges it, as long as the
+    // board was connected when it was sent
+    async fn send_actuators_message(&self, operation: Operation, payload: Vec<u8>) -> Result<()> {
+        let command = self.encode_command(operation, payload)?;
+
+        // The writer is taken before the command is queued, so that commands
+        // are sent in the order they're queued
+        let writer = self
+            .actuator_info
+            .lock()
+            .unwrap()
+            .writer
+            .clone()
+            .ok_or(Error::ActuatorsNotConnected)?;
+        let mut writer = writer.lock().await;
+
+        {
+            let unacked = &mut self.actuator_info.lock().unwrap().unacked;
+            if unacked.len() >= UNACKED_ACTUATOR_COMMANDS_MAX
+                && let Some(dropped) = unacked.pop_front()
+            {
+                warn!(
+                    "Backend::send_actuators_message(): giving up on command {}",
+                    dropped.command.id
+                );
+            }
+            unacked.push_back(QueuedCommand {
+                command: command.clone(),
+                expires_at: Instant::now() + self.actuator_command_expiry,
+            });
+        }
+
+        let FrameWriter { half, tx_sequence } = &mut *writer;
+        self.write_frame(half, tx_sequence, command.operation, &command.payload)
+            .await
+    }
+
+    // Commands the board didn't acknowledge before its connection broke are
+    // sent again in order, the board skipping the ones it had already applied.
+    // Expired ones are dropped instead, so that the switches don't go through
+    // a whole backlog after an outage.
+    async fn resend_actuators_commands(&self) -> Result<()> {
+        let Some(writer) = self.actuator_info.lock().unwrap().writer.clone() else {
+            return Ok(());
+        };
+        let mut writer = writer.lock().await;
+
+        let commands: Vec<UnackedCommand> = {
+            let unacked = &mut self.actuator_info.lock().unwrap().unacked;
+            let now = Instant::now();
+            unacked.retain(|queued| {
+                let expired = queued.expires_at <= now;
+                if expired {
+                    warn!(
+                        "Backend::resend_actuators_commands(): dropping command {}, expired {:?} ago",
+                        queued.command.id,
+                        now - queued.expires_at
+                    );
+                }
+                !expired
+            });
+            unacked
+                .iter()
+                .map(|queued| queued.command.clone())
+                .collect()
+        };
+
+        let FrameWriter { half, tx_sequence } = &mut *writer;
+        for command in commands {
+            info!(
+                "Backend::resend_actuators_commands(): resending command {}",
+                command.id
+            );
+            self.write_frame(half, tx_sequence, command.operation, &command.payload)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // The Oracle sets its routes back on its next cycle, the defaults only
+    // make sure the layout never sits in whatever state the board powered up
+    // with
+    async fn drive_switch_defaults(&self) -> Result<()> {
+        let actuators: Vec<_> = self
+            .switch_defaults
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, state)| (*id, ActuatorType::SwitchRails, (*state).into()))
+            .collect();
+        // A default for a switch the board doesn't have must not prevent
+        // the other switches from being set
+        let (actuators, absent): (Vec<_>, Vec<_>) = actuators
+            .into_iter()
+            .partition(|(id, actuator_type, _)| self.is_actuator_present(*id, *actuator_type));
+        if !absent.is_empty() {
+            warn!(
+                "Backend::drive_switch_defaults(): skipping absent switches {:?}",
+                absent
+            );
+        }
+        if actuators.is_empty() {
+            return Ok(());
+        }
+
+        info!("Backend::drive_switch_defaults(): {:?}", actuators);
+        self.drive_actuators(&actuators).await
+    }
+
+    // Acknowledges every command up to the given one, since the board
+    // applies them in order
+    fn handle_op_command_ack(&self, payload: &[u8]) -> Result<()> {
+        debug!("Backend::handle_op_command_ack()");
+
+        let (payload, extensions): (CommandAckPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        self.skip_extensions(Operation::CommandAck, extensions)?;
+
+        self.actuator_info
+            .lock()
+            .unwrap()
+            .unacked
+            .retain(|queued| queued.command.id > payload.command_id);
+
+        Ok(())
+    }
+
+    pub fn actuators_connected(&self) -> bool {
+        self.actuator_info.lock().unwrap().writer.is_some()
+    }
+
+    pub fn switch_rails_positions(&self) -> BTreeMap<ActuatorId, SwitchRailsState> {
+        self.actuator_info.lock().unwrap().switch_rails.clone()
+    }
+
+    // None if not driven since the actuators board connected
+    pub fn track_power_state(&self, actuator_id: ActuatorId) -> Option<TrackPowerState> {
+        self.actuator_info
+            .lock()
+            .unwrap()
+            .track_power
+            .get(&actuator_id)
+            .copied()
+    }
+
+    /**
+     * Takes the control away from the Oracle and stops every loco, keeping
+     * their current direction. Every loco is attempted, even if some of them
+     * can't be reached.
+     */
+    // Every connected loco is sent the stop at once, so that a slow loco
+    // doesn't hold the others up. Neither the pacing nor the Oracle mode can
+    // delay it.
+    pub async fn emergency_stop(&self, reason: &str, alarm: Alarm) {
+        error!("Backend::emergency_stop(): {}", reason);
+
+        self.set_oracle_mode(OracleMode::Off);
+
+        let loco_ids = self.loco_ids();
+        for loco_id in loco_ids.iter() {
+            // Whatever was commanded before must never be sent again
+            self.loco_info(loco_id).lock().unwrap().unacked = None;
+        }
+
+        let stops = join_all(
+            loco_ids
+                .iter()
+                .map(|loco_id| self.emergency_stop_loco(*loco_id)),
+        )
+        .await;
+        for (loco_id, result) in loco_ids.iter().zip(stops) {
+            match result {
+                Ok(()) | Err(Error::LocoNotConnected(_)) => {}
+                Err(e) => error!("Backend::emergency_stop(): {} {}", loco_id, e),
+            }
+        }
+
+        self.raise_alarm(alarm);
+        self.notify(Event::EmergencyStop {
+            reason: reason.to_string(),
+        });
+    }
+
+    // Trains aren't stopped, since a wagon left behind may as well be a tag
+    // which couldn't be read
+    pub fn report_consist_issue(&self, issue: ConsistIssue) {
+        warn!("Backend::report_consist_issue(): {:?}", issue);
+
+        self.raise_alarm(match issue {
+            ConsistIssue::LostWagons { .. } => Alarm::LostWagon,
+            ConsistIssue::UnexpectedWagon { .. } => Alarm::ConsistMismatch,
+        });
+        self.notify(Event::ConsistIssue { issue });
+    }
+
+    /**
+     * Everything preventing the Oracle from safely driving the layout, empty
+     * if it can be enabled. The Oracle needs the actuators board to set the
+     * switch rails, the position of every switch rails it relies on, and the
+     * location of every connected loco. It also stays off during maintenance.
+     */
+    pub fn auto_mode_blockers(&self) -> Vec<String> {
+        let mut blockers = Vec::new();
+
+        if let Some(banner) = self.maintenance() {
+            blockers.push(banner.to_string());
+        }
+
+        let actuator_info = self.actuator_info.lock().unwrap();
+        if actuator_info.writer.is_none() {
+            blockers.push("actuators board not connected".to_string());
+        }
+        let unknown_switch_rails: Vec<ActuatorId> = RailNetwork::new()
+            .switch_rails_ids()
+            .into_iter()
+            .filter(|id| !actuator_info.switch_rails.contains_key(id))
+            .collect();
+        drop(actuator_info);
+        if !unknown_switch_rails.is_empty() {
+            blockers.push(format!(
+                "unknown position of switch rails {:?}",
+                unknown_switch_rails
+            ));
+        }
+
+        let unlocated_locos: Vec<LocoId> = self
+            .loco_ids()
+            .into_iter()
+            .filter(|id| {
+                let loco_info = self.loco_info(id).lock().unwrap();
+                loco_info.link.is_some() && loco_info.location.is_none()
+            })
+            .collect();
+        if !unlocated_locos.is_empty() {
+            blockers.push(format!("unknown location of locos {:?}", unlocated_locos));
+        }
+
+        blockers
+    }
+
+    // Maintenance window the layout is in, if any
+    pub fn maintenance(&self) -> Option<MaintenanceBanner> {
+        self.maintenance.lock().unwrap().clone()
+    }
+
+    pub fn set_oracle_mode(&self, mode: OracleMode) {
+        let enable = match mode {
+            OracleMode::Off => false,
+            OracleMode::Auto => true,
+        };
+        self.oracle_enabled.store(enable, Ordering::Release);
+    }
+
+    pub fn oracle_enabled(&self) -> bool {
+        self.oracle_enabled.load(Ordering::Acquire)
+    }
+
+    pub fn set_loco_intent(&self, loco_id: LocoId, intent: LocoIntent) {
+        self.notify(Event::LocoIntentSet { loco_id, intent });
+    }
+
+    fn handle_op_sensors_status(&self, payload: &[u8]) -> Result<()> {
+        debug!("Backend::handle_op_sensors_status()");
+
+        let (sensors_status, extensions) =
+            decode_sensors_status_batch(payload).map_err(Error::ConvertLocoProtocolType)?;
+
+        let mut updated_sensors = 0;
+        for sensor_status in sensors_status {
             let sensor_id = SensorId::try_from(sensor_status.sensor_id)
                 .map_err(Error::ConvertLocoProtocolType)?;
+            let sensor_type = SensorType::try_from(sensor_status.sensor_type)
+                .map_err(Error::ConvertLocoProtocolType)?;
+            let event = SensorEvent::try_from(sensor_status.event)
+                .map_err(Error::ConvertLocoProtocolType)?;
+            let loco_id = match sensor_status.loco_id {
+                ANONYMOUS_LOCO_ID => {
+                    let Some(loco_id) = self.attribute_detection(sensor_id, event) else {
+                        warn!(
+                            "Backend::handle_op_sensors_status(): can't tell who {} at {} ({})",
+                            event, sensor_id, sensor_type
+                        );
+                        continue;
+                    };
+                    loco_id
+                }
+                loco_id => LocoId::try_from(loco_id).map_err(Error::ConvertLocoProtocolType)?,
+            };
+            let timestamp_us = self.sensors_timestamp_us(sensor_status.timestamp_us);
+            match event {
+                SensorEvent::Arrived => self.record_detection(loco_id, sensor_id, timestamp_us),
+                SensorEvent::Departed => self.record_departure(loco_id, sensor_id, timestamp_us),
+            }
+            updated_sensors += 1;
+        }
+
+        debug!(
+            "Backend::handle_op_sensors_status(): {} sensors updated",
+            updated_sensors
+        );
+
+        for field in extensions {
+            let field = field.map_err(Error::ConvertLocoProtocolType)?;
+            // Already read along with the records
+            if matches!(
+                field.tag,
+                SENSORS_STATUS_EXT_SENSOR_TYPES | SENSORS_STATUS_EXT_EVENTS
+            ) {
+                continue;
+            }
+            if field.tag != SENSORS_STATUS_EXT_UNKNOWN_TAGS {
+                debug!(
+                    "Backend::handle_op_sensors_status(): unknown extension tag {} ({} bytes)",
+                    field.tag,
+                    field.value.len()
+                );
+                continue;
+            }
+
+            // Entries are made of the sensor_id followed by the 4 bytes of
+            // the UID
+            let (entries, _) = field.value.as_chunks::<UNKNOWN_TAG_SIZE>();
+            for [sensor_id, uid @ ..] in entries {
+                let sensor_id =
+                    SensorId::try_from(*sensor_id).map_err(Error::ConvertLocoProtocolType)?;
+                self.handle_unregistered_tag(TagUid::from(*uid), sensor_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Hall sensors, reed switches and IR beams only tell that something went
+    // by. A departure is the one of the loco standing on the sensor, and an
+    // arrival the one of a loco already standing on it, or else of the only
+    // moving loco for which the sensor is the next checkpoint.
+    fn attribute_detection(&self, sensor_id: SensorId, event: SensorEvent) -> Option<LocoId> {
+        let checkpoint_id = CheckpointId::from(sensor_id);
+        let rail_network = RailNetwork::new();
+
+        let mut standing = Vec::new();
+        let mut approaching = Vec::new();
+        for loco_id in self.loco_ids() {
+            let loco_info = self.loco_info(&loco_id).lock().unwrap();
+            let Some(location) = loco_info.location else {
+                continue;
+            };
+            if location == sensor_id {
+                standing.push(loco_id);
+            } else if let Some((direction, speed, _)) = loco_info.command_pacer.last_sent
+                && speed != Speed::Stop
+                && rail_network
+                    .next_checkpoint_ids(location.into(), direction)
+                    .contains(&checkpoint_id)
+            {
+                approaching.push(loco_id);
+            }
+        }
+
+        let candidates = match event {
+            SensorEvent::Arrived if standing.is_empty() => approaching,
+            SensorEvent::Arrived | SensorEvent::Departed => standing,
+        };
+        match candidates[..] {
+            [loco_id] => Some(loco_id),
+            _ => None,
+        }
+    }
+
+    // Tag the sensors board doesn't know about. The board doesn't tell when
+    // it detected it, which is assumed to be right now.
+    fn handle_unregistered_tag(&self, uid: TagUid, sensor_id: SensorId) {
+        match self.tags.owner(uid) {
+            Some(TagOwner::Loco(loco_id)) => {
+                self.record_detection(loco_id, sensor_id, self.now_us())
+            }
+            Some(TagOwner::Wagon(wagon)) => {
+                debug!(
+                    "Backend::handle_unregistered_tag(): wagon {} detected at {}",
+                    wagon, sensor_id
+                );
+                self.notify(Event::WagonHit {
+                    wagon,
+                    sensor_id,
+                    timestamp_us: self.now_us(),
+                });
+            }
+            // Only reported once per sensor, as the board keeps on sending
+            // the tags standing on its readers
+            None => {
+                if self.tags.sighted_unknown(uid, sensor_id) {
+                    warn!(
+                        "Backend::handle_unregistered_tag(): unknown tag {} detected at {}",
+                        uid, sensor_id
+                    );
+                    self.raise_alarm(Alarm::UnknownTag);
+                    self.notify(Event::UnknownTag { uid, sensor_id });
+                }
+            }
+        }
+    }
+
+    fn record_detection(&self, loco_id: LocoId, sensor_id: SensorId, timestamp_us: u64) {
+        debug!(
+            "Backend::record_detection(): {} detected at {} ({}us)",
+            loco_id, sensor_id, timestamp_us
+        );
+
+        // The sensors firmware and the tag database may know about locos
+        // which aren't part of the roster
+        if self.check_loco(loco_id).is_err() {
+            warn!(
+                "Backend::record_detection(): ignoring {} which isn't in the roster",
+                loco_id
+            );
+            return;
+        }
+
+        // Detections buffered by the sensors board while disconnected are
+        // replayed on reconnection. Only keep them if they're more recent
+        // than what's already known about the loco.
+        let location_timestamp_us = self
+            .loco_info(&loco_id)
+            .lock()
+            .unwrap()
+            .location_timestamp_us;
+        if location_timestamp_us.is_some_and(|t| t > timestamp_us) {
+            debug!(
+                "Backend::record_detection(): ignoring outdated detection of {} at {}",
+                loco_id, sensor_id
+            );
+            return;
+        }
+
+        let delay_us = self.now_us().saturating_sub(timestamp_us);
+        if delay_us > LATE_DETECTION_US {
+            info!(
+                "Backend::record_detection(): late detection of {} at {} ({}ms ago)",
+                loco_id,
+                sensor_id,
+                delay_us / 1000
+            );
+        }
+
+        self.notify(Event::SensorHit {
+            loco_id,
+            sensor_id,
+            timestamp_us,
+        });
+    }
+
+    fn record_departure(&self, loco_id: LocoId, sensor_id: SensorId, timestamp_us: u64) {
+        debug!(
+            "Backend::record_departure(): {} departed from {} ({}us)",
+            loco_id, sensor_id, timestamp_us
+        );
+
+        if self.check_loco(loco_id).is_err() {
+            warn!(
+                "Backend::record_departure(): ignoring {} which isn't in the roster",
+                loco_id
+            );
+            return;
+        }
+
+        // Same as for the detections, a departure replayed after a more
+        // recent detection is about a location the loco already left
+        let location_timestamp_us = self
+            .loco_info(&loco_id)
+            .lock()
+            .unwrap()
+            .location_timestamp_us;
+        if location_timestamp_us.is_some_and(|t| t > timestamp_us) {
             debug!(
-                "Backend::handle_op_sensors_status(): {} detected at {}",
+                "Backend::record_departure(): ignoring outdated departure of {} from {}",
                 loco_id, sensor_id
             );
-            self.loco_info(&loco_id).lock().unwrap().location = Some(sensor_id);
+            return;
+        }
+
+        self.notify(Event::SensorDeparture {
+            loco_id,
+            sensor_id,
+            timestamp_us,
+        });
+    }
+
+    /**
+     * Inject a detection as if it had just been reported by the sensors
+     * board, so that trains can be moved around without any firmware.
+     */
+    pub fn inject_sensor_event(&self, loco_id: LocoId, sensor_id: SensorId) {
+        info!(
+            "Backend::inject_sensor_event(): {} detected at {}",
+            loco_id, sensor_id
+        );
+        self.record_detection(loco_id, sensor_id, self.now_us());
+    }
+
+    /**
+     * Overrides the location of a loco, for the operator to correct the model
+     * after physically moving stock around. The correction is timestamped as
+     * a detection would be, so that older detections replayed by the sensors
+     * board can't undo it.
+     */
+    pub fn set_loco_location(&self, loco_id: LocoId, location: Option<CheckpointId>, client: &str) {
+        let now_us = self.now_us();
+        let sensor_id = location.map(SensorId::from);
+
+        let from = self
+            .loco_info(&loco_id)
+            .lock()
+            .unwrap()
+            .location
+            .map(CheckpointId::from);
+
+        warn!(
+            "Backend::set_loco_location(): {} moved from {:?} to {:?} by {}",
+            loco_id, from, location, client
+        );
+
+        self.location_corrections.lock().unwrap().push(
+            LocationCorrection {
+                loco_id,
+                from,
+                to: location,
+                client: client.to_string(),
+            },
+            now_us,
+        );
+        self.notify(Event::LocationCorrected { loco_id, sensor_id });
+    }
+
+    // Last checkpoint the loco has been seen at
+    pub fn loco_location(&self, loco_id: LocoId) -> Result<Option<CheckpointId>> {
+        self.check_loco(loco_id)?;
+        let location = self.loco_info(&loco_id).lock().unwrap().location;
+
+        Ok(location.map(CheckpointId::from))
+    }
+
+    pub fn loco_intent(&self, loco_id: LocoId) -> Result<Option<LocoIntent>> {
+        self.check_loco(loco_id)?;
+        Ok(self.loco_info(&loco_id).lock().unwrap().intent)
+    }
+
+    // Forgets where every loco is, until they're detected again
+    pub fn clear_occupancy(&self, client: &str) {
+        for loco_id in self.loco_ids() {
+            if self.loco_info(&loco_id).lock().unwrap().location.is_some() {
+                self.set_loco_location(loco_id, None, client);
+            }
+        }
+    }
+
+    pub fn location_corrections(&self, query: &HistoryQuery) -> HistoryPage<LocationCorrection> {
+        self.location_corrections
+            .lock()
+            .unwrap()
+            .query(query, |_| true)
+    }
+
+    // Microseconds elapsed since the controller started, which is the time
+    // reference of every timestamp it reports
+    pub fn now_us(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
+
+    // Converts a timestamp from the sensors board clock into the controller
+    // clock, falling back onto the arrival time if no estimation is known yet.
+    fn sensors_timestamp_us(&self, sensors_timestamp_us: u64) -> u64 {
+        match self.sensors_clock_offset.lock().unwrap().offset() {
+            Some(offset) => (sensors_timestamp_us as i64 + offset).max(0) as u64,
+            None => self.now_us(),
         }
+    }
+
+    fn handle_op_time_sync(&self, payload: &[u8]) -> Result<()> {
+        debug!("Backend::handle_op_time_sync()");
+
+        let (payload, extensions): (TimeSyncPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        self.skip_extensions(Operation::TimeSync, extensions)?;
+
+        let sample = self.now_us() as i64 - payload.time_us as i64;
+        let mut clock_offset = self.sensors_clock_offset.lock().unwrap();
+        clock_offset.add_sample(sample);
 
         debug!(
-            "Backend::handle_op_sensors_status(): {} sensors updated",
-            sensors_status_array.len
+            "Backend::handle_op_time_sync(): sample {}us, offset {:?}us",
+            sample,
+            clock_offset.offset()
         );
 
         Ok(())
     }
 
-    pub fn serve_sensors(&self, mut stream: TcpStream) -> Result<()> {
+    // Serves the connection of the sensors board until it goes away, hence
+    // meant to be run by a task of its own
+    pub async fn serve_sensors(&self, stream: TcpStream) -> Result<()> {
         debug!("Backend::serve_sensors()");
 
+        // The sensors board might have rebooted, invalidating any previous
+        // clock offset estimation.
+        self.sensors_clock_offset.lock().unwrap().reset();
+
+        // Nothing is ever sent to the board, though dropping the write half
+        // would close the connection
+        let (mut reader, _writer) = stream.into_split();
+
+        // Whether the board said goodbye or went silent, it's gone
+        let result = self.handle_sensors_messages(&mut reader).await;
+        self.mark_device_offline(Device::Sensors);
+
+        result
+    }
+
+    async fn handle_sensors_messages(&self, half: &mut OwnedReadHalf) -> Result<()> {
+        let mut rx_sequence = SequenceTracker::default();
+        if let Some(payload) = self
+            .expect_register(half, &mut rx_sequence, Device::Sensors)
+            .await?
+        {
+            self.handle_op_register(&payload, Device::Sensors)?;
+        }
+
         loop {
-            let op = self.retrieve_header_op(&mut stream)?;
+            let (op, payload) = self
+                .next_message(half, &mut rx_sequence, Device::Sensors)
+                .await?;
+            self.device_seen(Device::Sensors);
 
             match op {
-                Operation::SensorsStatus => self.handle_op_sensors_status(&mut stream)?,
+                Operation::SensorsStatus => self.handle_op_sensors_status(&payload)?,
+                Operation::Register => self.handle_op_register(&payload, Device::Sensors)?,
+                Operation::TimeSync => self.handle_op_time_sync(&payload)?,
+                Operation::Error => {
+                    self.handle_op_error(&payload, Device::Sensors)?;
+                }
+                Operation::Heartbeat => {}
+                Operation::Disconnect => {
+                    self.handle_op_disconnect(&payload, Device::Sensors)?;
+                    return Ok(());
+                }
                 Operation::Connect
                 | Operation::ControlLoco
                 | Operation::LocoStatus
-                | Operation::DriveActuator => {
+                | Operation::DriveActuator
+                | Operation::DriveActuatorsBatch
+                | Operation::ControlLocoFunctions
+                | Operation::CommandAck
+                | Operation::EmergencyStop
+                | Operation::ActuatorsTelemetry
+                | Operation::InputsStatus
+                | Operation::HoldOnDisconnect
+                | Operation::LocoTelemetry
+                | Operation::SelectWireFormat
+                | Operation::Nack
+                | Operation::SafeCrawlReport => {
                     return Err(Error::UnsupportedOperation(op));
                 }
             }
         }
     }
 
-    pub fn handle_actuators_connection(&self, stream: TcpStream) -> Result<()> {
-        debug!("Backend::handle_actuators_connection()");
+    pub fn alarms(&self) -> Vec<Alarm> {
+        self.alarms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.active)
+            .map(|r| r.alarm)
+            .collect()
+    }
+
+    // Same as alarms(), along with the errors reported by the devices
+    pub fn active_alarms(&self) -> Vec<AlarmRecord> {
+        self.alarms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.active)
+            .cloned()
+            .collect()
+    }
+
+    pub fn alarms_history(
+        &self,
+        query: &HistoryQuery,
+        filter: &AlarmsFilter,
+    ) -> HistoryPage<AlarmRecord> {
+        self.alarms.lock().unwrap().query(query, |r| {
+            filter.r#type.is_none_or(|a| a == r.alarm)
+                && filter.active.is_none_or(|a| a == r.active)
+        })
+    }
+
+    // Alarms are kept in the history once cleared, only they're not active
+    // anymore.
+    pub fn clear_alarms(&self) {
+        for record in self.alarms.lock().unwrap().iter_mut() {
+            record.active = false;
+        }
+    }
+
+    fn raise_alarm(&self, alarm: Alarm) {
+        error!("Backend::raise_alarm(): {:?}", alarm);
+        self.push_alarm(alarm, None);
+    }
+
+    // Each device and code is raised on its own, so that a new failure isn't
+    // hidden behind another one still active
+    fn raise_device_error(&self, device: Device, code: ErrorCode) {
+        error!("Backend::raise_device_error(): {:?} {}", device, code);
+        self.push_alarm(Alarm::DeviceError, Some(DeviceError { device, code }));
+    }
+
+    fn push_alarm(&self, alarm: Alarm, error: Option<DeviceError>) {
+        let now_us = self.now_us();
+        let mut alarms = self.alarms.lock().unwrap();
+        if !alarms
+            .iter()
+            .any(|r| r.active && r.alarm == alarm && r.error == error)
+        {
+            alarms.push(
+                AlarmRecord {
+                    alarm,
+                    active: true,
+                    error,
+                },
+                now_us,
+            );
+        }
+    }
+
+    async fn handle_op_actuators_telemetry(&self, payload: &[u8]) -> Result<()> {
+        debug!("Backend::handle_op_actuators_telemetry()");
+
+        let (telemetry, extensions): (ActuatorsTelemetryPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        self.skip_extensions(Operation::ActuatorsTelemetry, extensions)?;
 
-        self.actuator_info.lock().unwrap().stream = Some(stream);
+        debug!(
+            "Backend::handle_op_actuators_telemetry(): current {}, overcurrent {}",
+            telemetry.current, telemetry.overcurrent
+        );
+
+        if telemetry.overcurrent != 0 {
+            // The alarm is raised whether or not the track power can be cut,
+            // and failing to cut it mustn't end the session reporting the
+            // short circuit.
+            self.raise_alarm(Alarm::Overcurrent);
+            if let Err(e) = self
+                .drive_actuator(
+                    ActuatorId::TrackPower,
+                    ActuatorType::TrackPower,
+                    TrackPowerState::Off.into(),
+                )
+                .await
+            {
+                error!(
+                    "Backend::handle_op_actuators_telemetry(): Error cutting the track power: {}",
+                    e
+                );
+            }
+        }
 
         Ok(())
     }
+
+    pub fn inputs_status(&self) -> HashMap<InputId, InputState> {
+        self.inputs.lock().unwrap().clone()
+    }
+
+    fn handle_op_inputs_status(&self, payload: &[u8]) -> Result<()> {
+        debug!("Backend::handle_op_inputs_status()");
+
+        // Retrieve number of inputs being updated
+        let (inputs_status_array, mut offset): (InputsStatusArray, _) =
+            decode_from_slice(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        check_array_len(
+            inputs_status_array.len,
+            INPUT_STATUS_SIZE,
+            &payload[offset..],
+        )
+        .map_err(Error::ConvertLocoProtocolType)?;
+
+        for _ in 0..inputs_status_array.len {
+            let (input_status, len): (InputStatus, _) =
+                decode_from_slice(&payload[offset..], self.bincode_cfg)
+                    .map_err(Error::DecodeFromSlice)?;
+            offset += len;
+            let input_id =
+                InputId::try_from(input_status.input_id).map_err(Error::ConvertLocoProtocolType)?;
+            let state =
+                InputState::try_from(input_status.state).map_err(Error::ConvertLocoProtocolType)?;
+            debug!(
+                "Backend::handle_op_inputs_status(): {} is {}",
+                input_id, state
+            );
+            self.inputs.lock().unwrap().insert(input_id, state);
+        }
+
+        debug!(
+            "Backend::handle_op_inputs_status(): {} inputs updated",
+            inputs_status_array.len
+        );
+
+        self.skip_extensions(Operation::InputsStatus, Extensions::new(&payload[offset..]))
+    }
+
+    // Serves the connection of the actuators board until it goes away, hence
+    // meant to be run by a task of its own
+    pub async fn serve_actuators(&self, stream: TcpStream) -> Result<()> {
+        debug!("Backend::serve_actuators()");
+
+        // Registered before the board can be driven, or anyone on the WiFi
+        // could take its connection over
+        let (mut reader, writer) = stream.into_split();
+        let mut rx_sequence = SequenceTracker::default();
+        let register = self
+            .expect_register(&mut reader, &mut rx_sequence, Device::Actuators)
+            .await?;
+
+        // Logged before the board can be driven, so that the positions it
+        // forgets can't be mistaken for new ones
+        self.notify(Event::ActuatorsConnected);
+        self.actuator_info.lock().unwrap().writer = Some(Arc::new(AsyncMutex::new(FrameWriter {
+            half: writer,
+            tx_sequence: SequenceCounter::default(),
+        })));
+
+        // Whether the board said goodbye or went silent, it can't be driven
+        // anymore
+        let result = match register {
+            Some(payload) => self.handle_actuators_register(&payload).await,
+            None => Ok(()),
+        };
+        let result = match result {
+            Ok(()) => {
+                self.handle_actuators_messages(&mut reader, &mut rx_sequence)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        self.actuator_info.lock().unwrap().writer = None;
+        self.notify(Event::ActuatorsDisconnected);
+        self.mark_device_offline(Device::Actuators);
+
+        result
+    }
+
+    async fn handle_actuators_register(&self, payload: &[u8]) -> Result<()> {
+        self.handle_op_register(payload, Device::Actuators)?;
+        self.resend_actuators_commands().await?;
+        // After the resent commands, which carry older IDs and would be
+        // skipped by the board otherwise
+        self.drive_switch_defaults().await
+    }
+
+    async fn handle_actuators_messages(
+        &self,
+        half: &mut OwnedReadHalf,
+        rx_sequence: &mut SequenceTracker,
+    ) -> Result<()> {
+        loop {
+            let (op, payload) = self
+                .next_message(half, rx_sequence, Device::Actuators)
+                .await?;
+            self.device_seen(Device::Actuators);
+
+            match op {
+                Operation::ActuatorsTelemetry => {
+                    self.handle_op_actuators_telemetry(&payload).await?
+                }
+                Operation::InputsStatus => self.handle_op_inputs_status(&payload)?,
+                Operation::Register => self.handle_actuators_register(&payload).await?,
+                Operation::CommandAck => self.handle_op_command_ack(&payload)?,
+                // Either a failure the board recovered from, or the reason
+                // why it's about to close the connection
+                Operation::Error => {
+                    self.handle_op_error(&payload, Device::Actuators)?;
+                }
+                Operation::Nack => {
+                    self.handle_op_nack(&payload, Device::Actuators)?;
+                }
+                Operation::Heartbeat => {}
+                Operation::Disconnect => {
+                    self.handle_op_disconnect(&payload, Device::Actuators)?;
+                    return Ok(());
+                }
+                Operation::Connect
+                | Operation::ControlLoco
+                | Operation::LocoStatus
+                | Operation::SensorsStatus
+                | Operation::DriveActuator
+                | Operation::DriveActuatorsBatch
+                | Operation::ControlLocoFunctions
+                | Operation::EmergencyStop
+                | Operation::TimeSync
+                | Operation::HoldOnDisconnect
+                | Operation::LocoTelemetry
+                | Operation::SelectWireFormat
+                | Operation::SafeCrawlReport => {
+                    return Err(Error::UnsupportedOperation(op));
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,146 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use loco_protocol::{Direction, LocoId, Speed};
+use log::{error, warn};
+use thiserror::Error;
+
+use crate::{
+    backend::{Backend, Error as BackendError, Event},
+    config::NetworkConfig,
+    rail_network::{CheckpointId, RailNetwork},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error getting loco location: {0}")]
+    LocoLocation(#[source] BackendError),
+    #[error("Loco {0} is at {1:?}, the track ends right after it going {2:?}")]
+    BeyondBufferStop(LocoId, CheckpointId, Direction),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Whether a speed actually drives the loco
+fn is_moving(speed: Speed) -> bool {
+    speed.duty_cycle() > 0
+}
+
+/**
+ * Buffer stops of the active layout, each one right after the terminal
+ * checkpoint of a stub track. The segment leading to a terminal checkpoint is
+ * a protection zone, which locos go through at Slow. A loco reaching the
+ * terminal checkpoint gets stopped, and can't be driven any further.
+ */
+pub struct BufferStops {
+    backend: Arc<Backend>,
+    rail_network: RailNetwork,
+    dead_ends: RwLock<BTreeMap<CheckpointId, Direction>>,
+}
+
+impl BufferStops {
+    pub fn new(backend: Arc<Backend>, network: &NetworkConfig) -> Self {
+        BufferStops {
+            backend,
+            rail_network: RailNetwork::new(),
+            dead_ends: RwLock::new(network.dead_ends.clone()),
+        }
+    }
+
+    // Called when switching to another layout
+    pub fn set_network(&self, network: &NetworkConfig) {
+        *self.dead_ends.write().unwrap() = network.dead_ends.clone();
+    }
+
+    // Whether the track ends right after the checkpoint in this direction
+    pub fn is_terminal(&self, checkpoint_id: CheckpointId, direction: Direction) -> bool {
+        self.dead_ends.read().unwrap().get(&checkpoint_id) == Some(&direction)
+    }
+
+    // Caps the speed of a loco which has just gone by a checkpoint. Switch
+    // rails aren't taken into account, any way leading to a terminal
+    // checkpoint is a protection zone.
+    pub fn limit(&self, checkpoint_id: CheckpointId, direction: Direction, speed: Speed) -> Speed {
+        let approaching = self
+            .rail_network
+            .next_checkpoint_ids(checkpoint_id, direction)
+            .iter()
+            .any(|id| self.is_terminal(*id, direction));
+
+        if approaching && speed.duty_cycle() > Speed::Slow.duty_cycle() {
+            Speed::Slow
+        } else {
+            speed
+        }
+    }
+
+    // Checks a manual command, returning the speed the loco is allowed to
+    // run at. Nothing can be told about a loco which hasn't been located yet.
+    pub fn guard(&self, loco_id: LocoId, direction: Direction, speed: Speed) -> Result<Speed> {
+        let location = self
+            .backend
+            .loco_location(loco_id)
+            .map_err(Error::LocoLocation)?;
+        let Some(checkpoint_id) = location else {
+            return Ok(speed);
+        };
+        if !is_moving(speed) {
+            return Ok(speed);
+        }
+
+        if self.is_terminal(checkpoint_id, direction) {
+            return Err(Error::BeyondBufferStop(loco_id, checkpoint_id, direction));
+        }
+
+        Ok(self.limit(checkpoint_id, direction, speed))
+    }
+
+    // Slows down the locos entering a protection zone, and stops the ones
+    // reaching a terminal checkpoint, whoever is driving them
+    pub fn apply(&self, event: &Event) {
+        let Event::SensorHit {
+            loco_id, sensor_id, ..
+        } = event
+        else {
+            return;
+        };
+
+        let status = match self
+            .backend
+            .block_on(self.backend.cached_loco_status(*loco_id))
+        {
+            Ok(status) => status,
+            Err(e) => {
+                error!("BufferStops::apply(): {} {}", loco_id, e);
+                return;
+            }
+        };
+        let (direction, speed) = (status.direction(), status.speed());
+        if !is_moving(speed) {
+            return;
+        }
+
+        let checkpoint_id = CheckpointId::from(*sensor_id);
+        let limited = if self.is_terminal(checkpoint_id, direction) {
+            Speed::Stop
+        } else {
+            self.limit(checkpoint_id, direction, speed)
+        };
+        if limited == speed {
+            return;
+        }
+
+        warn!(
+            "BufferStops::apply(): {} going {:?} at {:?}, {:?} near the buffer stop",
+            loco_id, direction, checkpoint_id, limited
+        );
+        if let Err(e) = self.backend.block_on(
+            self.backend
+                .control_loco(*loco_id, direction, limited, None),
+        ) {
+            error!("BufferStops::apply(): {} {}", loco_id, e);
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex, mpsc::Receiver},
+    thread,
+    time::Instant,
+};
+
+use loco_protocol::{ActuatorType, Direction, LocoId, SensorId, Speed};
+use log::{error, info};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    backend::{Backend, Error as BackendError, Event, MAX_TRIM_PERCENT, MIN_TRIM_PERCENT},
+    config::CalibrationConfig,
+    rail_network::{RailNetwork, SegmentId},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error setting trim: {0}")]
+    SetTrim(#[source] BackendError),
+    #[error("Loco {0} has completed {1} laps, at least {MIN_LAPS} are needed")]
+    NotEnoughLaps(LocoId, usize),
+    #[error("Auto-trim needs two different locos")]
+    SameLoco,
+    #[error("Loco {0} would need a {1}% trim, which is out of range")]
+    TrimOutOfRange(LocoId, u64),
+    #[error("A calibration run is already in progress for loco {0}")]
+    RunInProgress(LocoId),
+    #[error("Oracle is running, can't drive the loco")]
+    OracleEnabled,
+    #[error("Locos {0:?} are moving, the loop isn't clear")]
+    LocosMoving(Vec<LocoId>),
+    #[error("Error checking the locos are stopped: {0}")]
+    MovingLocos(#[source] BackendError),
+    #[error("Error setting the switch rails of the loop: {0}")]
+    DriveSwitchRails(#[source] BackendError),
+    #[error("Error controlling the loco: {0}")]
+    ControlLoco(#[source] BackendError),
+    #[error("Only {0} duty cycles moved the loco, at least 2 are needed")]
+    NotEnoughPoints(usize),
+    #[error("Speed doesn't increase with the duty cycle")]
+    SpeedNotIncreasing,
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Laps kept per loco, the oldest ones being forgotten
+const MAX_LAPS: usize = 10;
+// Laps needed per loco for their average to be meaningful
+const MIN_LAPS: usize = 2;
+
+// Segments making the main loop, which doesn't go through any station
+const LOOP_SEGMENTS: [SegmentId; 6] = [
+    SegmentId::Segment1,
+    SegmentId::Segment2,
+    SegmentId::Segment3,
+    SegmentId::Segment4,
+    SegmentId::Segment5,
+    SegmentId::Segment6,
+];
+
+/**
+ * Measures the laps of a loco around the loop. A lap starts when the loco
+ * goes by a checkpoint, and completes when it gets back to it after going by
+ * any other checkpoint.
+ */
+#[derive(Default)]
+struct LapTimer {
+    start: Option<(SensorId, u64)>,
+    other_hits: usize,
+    laps_us: VecDeque<u64>,
+}
+
+impl LapTimer {
+    fn hit(&mut self, sensor_id: SensorId, timestamp_us: u64) {
+        match self.start {
+            Some((start, started_us)) if start == sensor_id => {
+                // Going by the same checkpoint twice in a row isn't a lap
+                if self.other_hits == 0 {
+                    return;
+                }
+                if self.laps_us.len() == MAX_LAPS {
+                    self.laps_us.pop_front();
+                }
+                self.laps_us
+                    .push_back(timestamp_us.saturating_sub(started_us));
+                self.start = Some((sensor_id, timestamp_us));
+                self.other_hits = 0;
+            }
+            Some(_) => self.other_hits += 1,
+            None => self.start = Some((sensor_id, timestamp_us)),
+        }
+    }
+
+    fn mean_lap_us(&self) -> Option<u64> {
+        if self.laps_us.len() < MIN_LAPS {
+            return None;
+        }
+
+        Some(self.laps_us.iter().sum::<u64>() / self.laps_us.len() as u64)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SpeedPoint {
+    duty_cycle: u8,
+    // Mean time spent going through a segment, None if the loco didn't reach
+    // the next checkpoint in time
+    segment_us: Option<u64>,
+}
+
+/**
+ * Linear fit of the speed of a loco against the commanded duty cycle, the
+ * speed being expressed in segments per second since the length of the
+ * segments isn't known. Trims are applied during the run, so the fit is the
+ * one of the trimmed loco.
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct SpeedFit {
+    points: Vec<SpeedPoint>,
+    slope: f64,
+    intercept: f64,
+    // Duty cycle below which the loco doesn't move, according to the fit
+    stall_duty_cycle: f64,
+}
+
+impl SpeedFit {
+    fn new(points: Vec<SpeedPoint>) -> Result<Self> {
+        let samples: Vec<(f64, f64)> = points
+            .iter()
+            .filter_map(|p| {
+                p.segment_us
+                    .filter(|us| *us > 0)
+                    .map(|us| (f64::from(p.duty_cycle), 1_000_000.0 / us as f64))
+            })
+            .collect();
+        if samples.len() < 2 {
+            return Err(Error::NotEnoughPoints(samples.len()));
+        }
+
+        // Ordinary least squares
+        let n = samples.len() as f64;
+        let mean_x = samples.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = samples.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let sxx: f64 = samples.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        let sxy: f64 = samples
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        if sxx <= 0.0 || sxy <= 0.0 {
+            return Err(Error::SpeedNotIncreasing);
+        }
+
+        let slope = sxy / sxx;
+        let intercept = mean_y - slope * mean_x;
+
+        Ok(SpeedFit {
+            points,
+            slope,
+            intercept,
+            stall_duty_cycle: (-intercept / slope).clamp(0.0, 100.0),
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum RunStatus {
+    Running { duty_cycle: u8 },
+    Completed,
+    Failed { reason: String },
+}
+
+#[derive(Serialize, Debug)]
+pub struct LocoCalibration {
+    trim_percent: u8,
+    laps_us: Vec<u64>,
+    speed_fit: Option<SpeedFit>,
+    run: Option<RunStatus>,
+}
+
+/**
+ * Per-loco trim, compensating for locos running at different speeds for the
+ * same commanded speed. Trims can either be set by hand, or computed out of
+ * the measured lap times so that two locos run matched.
+ */
+pub struct Calibration {
+    backend: Arc<Backend>,
+    config: CalibrationConfig,
+    lap_timers: Mutex<BTreeMap<LocoId, LapTimer>>,
+    speed_fits: Mutex<BTreeMap<LocoId, SpeedFit>>,
+    runs: Mutex<BTreeMap<LocoId, RunStatus>>,
+}
+
+impl Calibration {
+    pub fn new(backend: Arc<Backend>, config: &CalibrationConfig) -> Self {
+        Calibration {
+            backend,
+            config: config.clone(),
+            lap_timers: Mutex::new(BTreeMap::new()),
+            speed_fits: Mutex::new(BTreeMap::new()),
+            runs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn apply(&self, event: &Event) {
+        match event {
+            Event::SensorHit {
+                loco_id,
+                sensor_id,
+                timestamp_us,
+            } => self
+                .lap_timers
+                .lock()
+                .unwrap()
+                .entry(*loco_id)
+                .or_default()
+                .hit(*sensor_id, *timestamp_us),
+            // A loco moved by hand didn't run a lap
+            Event::LocationCorrected { loco_id, .. } => {
+                self.lap_timers.lock().unwrap().remove(loco_id);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn describe(&self) -> BTreeMap<LocoId, LocoCalibration> {
+        let lap_timers = self.lap_timers.lock().unwrap();
+        let speed_fits = self.speed_fits.lock().unwrap();
+        let runs = self.runs.lock().unwrap();
+        self.backend
+            .loco_ids()
+            .into_iter()
+            .map(|loco_id| {
+                (
+                    loco_id,
+                    LocoCalibration {
+                        trim_percent: self.backend.trim(loco_id),
+                        laps_us: lap_timers
+                            .get(&loco_id)
+                            .map(|t| t.laps_us.iter().copied().collect())
+                            .unwrap_or_default(),
+                        speed_fit: speed_fits.get(&loco_id).cloned(),
+                        run: runs.get(&loco_id).cloned(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    // Laps measured with the previous trim don't tell anything about the
+    // new one, hence they're forgotten
+    pub fn set_trim(&self, loco_id: LocoId, trim_percent: u8) -> Result<()> {
+        self.backend
+            .set_trim(loco_id, trim_percent)
+            .map_err(Error::SetTrim)?;
+        self.lap_timers.lock().unwrap().remove(&loco_id);
+
+        Ok(())
+    }
+
+    /**
+     * Trims both locos so that they both run at their average speed, the
+     * faster one being slowed down and the slower one sped up. Both locos
+     * are expected to have been running laps at the same commanded speed.
+     * This can be repeated until the lap times match closely enough.
+     */
+    pub fn auto_trim(&self, first: LocoId, second: LocoId) -> Result<BTreeMap<LocoId, u8>> {
+        if first == second {
+            return Err(Error::SameLoco);
+        }
+
+        let means = {
+            let lap_timers = self.lap_timers.lock().unwrap();
+            let mut means = Vec::new();
+            for loco_id in [first, second] {
+                let lap_timer = lap_timers.get(&loco_id);
+                match lap_timer.and_then(LapTimer::mean_lap_us) {
+                    Some(mean) => means.push((loco_id, mean)),
+                    None => {
+                        let laps = lap_timer.map_or(0, |t| t.laps_us.len());
+                        return Err(Error::NotEnoughLaps(loco_id, laps));
+                    }
+                }
+            }
+            means
+        };
+
+        let target_us = means.iter().map(|(_, mean)| mean).sum::<u64>() / means.len() as u64;
+        let mut trims = BTreeMap::new();
+        for (loco_id, mean_us) in means {
+            // The speed is inversely proportional to the lap time
+            let trim = u64::from(self.backend.trim(loco_id)) * mean_us / target_us.max(1);
+            info!(
+                "Calibration::auto_trim(): {} laps in {}us on average, needs {}%",
+                loco_id, mean_us, trim
+            );
+            match u8::try_from(trim) {
+                Ok(trim) if (MIN_TRIM_PERCENT..=MAX_TRIM_PERCENT).contains(&trim) => {
+                    trims.insert(loco_id, trim);
+                }
+                _ => return Err(Error::TrimOutOfRange(loco_id, trim)),
+            }
+        }
+
+        // Both trims are known to be valid, so that they're either both
+        // applied or none of them is
+        for (loco_id, trim) in trims.iter() {
+            self.set_trim(*loco_id, *trim)?;
+        }
+
+        Ok(trims)
+    }
+
+    /**
+     * Starts driving a loco around the main loop at every configured duty
+     * cycle, in the background. The loop has to be clear, hence the Oracle
+     * must be off and every other loco stopped. The run and the fitted curve
+     * are reported through describe().
+     */
+    pub fn start_run(self: &Arc<Self>, loco_id: LocoId) -> Result<()> {
+        self.backend
+            .check_loco(loco_id)
+            .map_err(Error::ControlLoco)?;
+        {
+            let mut runs = self.runs.lock().unwrap();
+            if let Some((running, _)) = runs
+                .iter()
+                .find(|(_, r)| matches!(r, RunStatus::Running { .. }))
+            {
+                return Err(Error::RunInProgress(*running));
+            }
+
+            if self.backend.oracle_enabled() {
+                return Err(Error::OracleEnabled);
+            }
+
+            let moving: Vec<LocoId> = self
+                .backend
+                .block_on(self.backend.moving_locos())
+                .map_err(Error::MovingLocos)?
+                .into_iter()
+                .filter(|id| *id != loco_id)
+                .collect();
+            if !moving.is_empty() {
+                return Err(Error::LocosMoving(moving));
+            }
+
+            // Without the actuators board, the switch rails are assumed to
+            // be set already
+            if self.backend.actuators_connected() {
+                let network = RailNetwork::new();
+                let actuators: Vec<_> = LOOP_SEGMENTS
+                    .iter()
+                    .flat_map(|id| network.segment(id).switch_rails().to_vec())
+                    .map(|s| (s.actuator_id(), ActuatorType::SwitchRails, s.state().into()))
+                    .collect();
+                self.backend
+                    .block_on(self.backend.drive_actuators(&actuators))
+                    .map_err(Error::DriveSwitchRails)?;
+            }
+
+            // Makes sure the loco is there before reporting the run started
+            self.backend
+                .block_on(
+                    self.backend
+                        .control_loco(loco_id, Direction::Forward, Speed::Stop, None),
+                )
+                .map_err(Error::ControlLoco)?;
+
+            runs.insert(
+                loco_id,
+                RunStatus::Running {
+                    duty_cycle: self.config.duty_cycles[0],
+                },
+            );
+        }
+
+        let calibration = self.clone();
+        let events = self.backend.subscribe();
+        thread::spawn(move || {
+            let result = calibration.run(loco_id, &events);
+
+            // Whatever happened, the loco mustn't be left running
+            if let Err(e) = calibration
+                .backend
+                .block_on(calibration.backend.control_loco(
+                    loco_id,
+                    Direction::Forward,
+                    Speed::Stop,
+                    None,
+                ))
+            {
+                error!("Calibration::start_run(): {}", e);
+            }
+
+            let status = match result {
+                Ok(speed_fit) => {
+                    info!("Calibration::start_run(): {} {:?}", loco_id, speed_fit);
+                    calibration
+                        .speed_fits
+                        .lock()
+                        .unwrap()
+                        .insert(loco_id, speed_fit);
+                    RunStatus::Completed
+                }
+                Err(e) => {
+                    error!("Calibration::start_run(): {}", e);
+                    RunStatus::Failed {
+                        reason: e.to_string(),
+                    }
+                }
+            };
+            calibration.runs.lock().unwrap().insert(loco_id, status);
+        });
+
+        Ok(())
+    }
+
+    fn run(&self, loco_id: LocoId, events: &Receiver<Event>) -> Result<SpeedFit> {
+        let mut points = Vec::new();
+        for duty_cycle in self.config.duty_cycles.iter() {
+            // The Oracle could have been enabled in the meantime
+            if self.backend.oracle_enabled() {
+                return Err(Error::OracleEnabled);
+            }
+
+            self.runs.lock().unwrap().insert(
+                loco_id,
+                RunStatus::Running {
+                    duty_cycle: *duty_cycle,
+                },
+            );
+
+            // Hits from the previous duty cycle don't tell anything anymore
+            events.try_iter().for_each(drop);
+            self.backend
+                .block_on(self.backend.control_loco(
+                    loco_id,
+                    Direction::Forward,
+                    Speed::PwmDutyCycle(*duty_cycle),
+                    None,
+                ))
+                .map_err(Error::ControlLoco)?;
+
+            let segment_us = self.measure_segment(loco_id, events);
+            info!(
+                "Calibration::run(): {} at {}%, {:?}us per segment",
+                loco_id, duty_cycle, segment_us
+            );
+            points.push(SpeedPoint {
+                duty_cycle: *duty_cycle,
+                segment_us,
+            });
+        }
+
+        SpeedFit::new(points)
+    }
+
+    // The speed changed somewhere along the current segment, so timing only
+    // starts at the next checkpoint
+    fn measure_segment(&self, loco_id: LocoId, events: &Receiver<Event>) -> Option<u64> {
+        let mut last_hit_us = None;
+        let mut total_us = 0;
+        for _ in 0..=self.config.segments_per_duty_cycle {
+            let hit_us = self.wait_for_hit(loco_id, events)?;
+            if let Some(last_hit_us) = last_hit_us {
+                total_us += hit_us.saturating_sub(last_hit_us);
+            }
+            last_hit_us = Some(hit_us);
+        }
+
+        Some(total_us / self.config.segments_per_duty_cycle as u64)
+    }
+
+    fn wait_for_hit(&self, loco_id: LocoId, events: &Receiver<Event>) -> Option<u64> {
+        let deadline = Instant::now() + self.config.segment_timeout();
+        loop {
+            let timeout = deadline.checked_duration_since(Instant::now())?;
+            match events.recv_timeout(timeout).ok()? {
+                Event::SensorHit {
+                    loco_id: hit_loco_id,
+                    timestamp_us,
+                    ..
+                } if hit_loco_id == loco_id => return Some(timestamp_us),
+                _ => continue,
+            }
+        }
+    }
+}
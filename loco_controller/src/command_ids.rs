@@ -0,0 +1,107 @@
+use std::{
+    io,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info};
+use thiserror::Error;
+
+use crate::{
+    config::BackendConfig,
+    journal::{Journal, Recovery},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error writing command IDs file {0}: {1}")]
+    Write(String, #[source] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// IDs reserved at once, so that the file isn't written for every command
+const RESERVED_IDS: u64 = 1024;
+
+struct Ids {
+    next: u64,
+    // First ID which hasn't been saved as used yet
+    reserved: u64,
+}
+
+/**
+ * Source of the IDs given to the commands sent to the devices. A device which
+ * stayed up while the controller restarted must never see an ID going back,
+ * or it would skip the commands as already applied. IDs are reserved by
+ * blocks in a file, and a restart goes on after the last reserved block,
+ * wasting what was left of it. Without a file, or if it got lost or corrupted,
+ * IDs start from the wall clock, which keeps them increasing as long as the
+ * clock doesn't go back.
+ */
+pub struct CommandIds {
+    journal: Option<Journal>,
+    recovery: Option<Recovery>,
+    ids: Mutex<Ids>,
+}
+
+impl CommandIds {
+    pub fn load(config: &BackendConfig) -> Result<Self> {
+        let now_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        let journal = config.command_ids_path.as_deref().map(Journal::new);
+        let mut next = now_us;
+        let mut recovery = None;
+        if let Some(journal) = &journal {
+            let (reserved, recovered) =
+                journal.recover(|content| content.trim().parse::<u64>().map_err(|e| e.to_string()));
+            next = next.max(reserved.unwrap_or(0));
+            recovery = Some(recovered);
+        }
+
+        let command_ids = CommandIds {
+            journal,
+            recovery,
+            ids: Mutex::new(Ids {
+                next,
+                reserved: next + RESERVED_IDS,
+            }),
+        };
+        command_ids.save(next + RESERVED_IDS)?;
+        info!("CommandIds::load(): starting from {}", next);
+
+        Ok(command_ids)
+    }
+
+    pub fn recovery(&self) -> Option<Recovery> {
+        self.recovery.clone()
+    }
+
+    fn save(&self, reserved: u64) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+
+        journal
+            .write(reserved.to_string().as_bytes())
+            .map_err(|e| Error::Write(journal.path().display().to_string(), e))
+    }
+
+    pub fn next(&self) -> u64 {
+        let mut ids = self.ids.lock().unwrap();
+        if ids.next >= ids.reserved {
+            ids.reserved = ids.next + RESERVED_IDS;
+            // The wall clock still keeps the IDs increasing on restart, as
+            // long as it doesn't go back
+            if let Err(e) = self.save(ids.reserved) {
+                error!("CommandIds::next(): {}", e);
+            }
+        }
+
+        let id = ids.next;
+        ids.next += 1;
+        id
+    }
+}
@@ -0,0 +1,1650 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::PathBuf,
+    time::Duration,
+};
+
+use loco_protocol::{
+    AUTH_TOKEN_MAX_LEN, ActuatorId, Direction, LOCO_UIDS, LocoFunctions, LocoId, SERVO_ANGLE_MAX,
+    SpeedCurve, SwitchRailsState,
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::{
+    backend::{
+        LocoIntent, MAX_SPEED_SCALE_PERCENT, MAX_TRIM_PERCENT, MIN_SPEED_SCALE_PERCENT,
+        MIN_TRIM_PERCENT,
+    },
+    maintenance::{TimeOfDay, Weekday},
+    plugin,
+    rail_network::{CheckpointId, Label, RailNetwork, SegmentId, TrackId},
+    tags::TagUid,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error reading configuration file {0}: {1}")]
+    ReadFile(String, #[source] io::Error),
+    #[error("Error parsing configuration file {0}: {1}")]
+    ParseFile(String, #[source] serde_json::Error),
+    #[error("Error parsing command line override {0}, expecting KEY=VALUE")]
+    ParseOverride(String),
+    #[error("Unknown configuration key {0} ({1})")]
+    UnknownKey(String, Origin),
+    #[error("Invalid value for configuration key {0} ({1}): {2}")]
+    InvalidValue(String, Origin, #[source] serde_json::Error),
+    #[error("Error deserializing configuration {0}")]
+    Deserialize(#[source] serde_json::Error),
+    #[error("Invalid configuration key {0} ({1}): {2}")]
+    Validation(String, Origin, String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+const ENV_PREFIX: &str = "LOCO_CONTROLLER_";
+
+// Maps whose keys are chosen freely, rather than being known from the
+// defaults. What's inside them is checked when deserializing.
+const OPEN_MAPS: [&str; 15] = [
+    "profiles",
+    "locos.roster",
+    "backend.speed_curves",
+    "backend.trims",
+    "backend.serial_bus.turnouts",
+    "backend.duty_curves",
+    "network.power_districts",
+    "network.dead_ends",
+    "network.signals",
+    "network.switch_order",
+    "network.servo_angles",
+    "network.switch_defaults",
+    "consists.wagons",
+    "day.programs",
+    "routes.programs",
+];
+
+fn in_open_map(path: &str) -> bool {
+    OPEN_MAPS
+        .iter()
+        .any(|m| path == *m || path.starts_with(&format!("{}.", m)))
+}
+
+/**
+ * Where the value of a configuration key comes from. Sources are layered by
+ * increasing priority: defaults, configuration file, environment variables
+ * and finally command line.
+ */
+#[derive(Clone, Debug)]
+pub enum Origin {
+    Default,
+    File(String, usize),
+    Env(String),
+    CommandLine,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default value"),
+            Origin::File(path, line) => write!(f, "{}:{}", path, line),
+            Origin::Env(var) => write!(f, "environment variable {}", var),
+            Origin::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortsConfig {
+    pub http: u16,
+    pub locos: u16,
+    pub sensors: u16,
+    pub actuators: u16,
+}
+
+impl Default for PortsConfig {
+    fn default() -> Self {
+        PortsConfig {
+            http: 8080,
+            locos: 8004,
+            sensors: 8005,
+            actuators: 8006,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests_per_sec: u32,
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_sec: 20,
+            burst: 40,
+        }
+    }
+}
+
+/**
+ * Read-only API for public viewers, showing where the locos are and what the
+ * signals show, but nothing about the devices and no way to control them. It
+ * listens on its own port, which is disabled unless given, so that it can be
+ * exposed through a reverse proxy without exposing the control API.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublicConfig {
+    pub port: Option<u16>,
+    // Clients are told apart by the address the reverse proxy forwards,
+    // rather than by the address of the connection
+    pub behind_proxy: bool,
+    pub rate_limit: RateLimitConfig,
+}
+
+impl Default for PublicConfig {
+    fn default() -> Self {
+        PublicConfig {
+            port: None,
+            behind_proxy: false,
+            rate_limit: RateLimitConfig {
+                requests_per_sec: 5,
+                burst: 10,
+            },
+        }
+    }
+}
+
+/**
+ * Loco running on the layout. Its tags locate it on top of the ones known to
+ * the sensors firmware. A loco board whose chip ID is given drives this loco,
+ * whatever LocoId it has been built with.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocoConfig {
+    pub tags: Vec<TagUid>,
+    pub device_id: Option<u64>,
+    // Locos with a lower priority give way to the ones with a higher priority
+    // heading towards them
+    pub priority: u8,
+}
+
+/**
+ * Every loco the controller knows about. Locos missing from the roster are
+ * rejected when connecting, and their detections are ignored.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocosConfig {
+    pub roster: BTreeMap<LocoId, LocoConfig>,
+}
+
+impl Default for LocosConfig {
+    fn default() -> Self {
+        LocosConfig {
+            roster: LOCO_UIDS
+                .iter()
+                .map(|(loco_id, _)| (*loco_id, LocoConfig::default()))
+                .collect(),
+        }
+    }
+}
+
+/**
+ * Address of a turnout decoder on the serial bus, along with what's sent to
+ * it for each state of its switch rails.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialTurnout {
+    pub address: u16,
+    pub direct: String,
+    pub diverted: String,
+}
+
+impl Default for SerialTurnout {
+    fn default() -> Self {
+        SerialTurnout {
+            address: 0,
+            direct: "0".to_string(),
+            diverted: "1".to_string(),
+        }
+    }
+}
+
+/**
+ * RS485 bus of legacy turnout decoders, reached through a serial port. Every
+ * command is written as the command template, {address} and {state} being
+ * replaced by the address of the decoder and the value of the state. When an
+ * acknowledgement template is set, the decoder must write it back as a line
+ * before the timeout.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialBusConfig {
+    pub port: String,
+    pub baud_rate: u32,
+    pub command: String,
+    pub ack: Option<String>,
+    pub ack_timeout_ms: u64,
+    // Switch rails thrown by a decoder of the bus rather than the actuators
+    // board
+    pub turnouts: BTreeMap<ActuatorId, SerialTurnout>,
+}
+
+impl Default for SerialBusConfig {
+    fn default() -> Self {
+        SerialBusConfig {
+            port: String::new(),
+            baud_rate: 115200,
+            command: "<T {address} {state}>\n".to_string(),
+            ack: None,
+            ack_timeout_ms: 500,
+            turnouts: BTreeMap::new(),
+        }
+    }
+}
+
+impl SerialBusConfig {
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.ack_timeout_ms)
+    }
+}
+
+/**
+ * Remaps the duty cycles applied to a loco, once trimmed and scaled. Small DC
+ * motors don't move below some duty cycle, which leaves the low end of the
+ * range useless. Any duty cycle but 0 is lifted to at least min_motion, 50%
+ * maps to mid and 100% stays 100%, with a linear interpolation in between.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DutyCurve {
+    pub min_motion: u8,
+    pub mid: u8,
+}
+
+impl Default for DutyCurve {
+    fn default() -> Self {
+        DutyCurve {
+            min_motion: 0,
+            mid: 50,
+        }
+    }
+}
+
+impl DutyCurve {
+    pub fn remap(&self, duty_cycle: u8) -> u8 {
+        let (min_motion, mid) = (u32::from(self.min_motion), u32::from(self.mid));
+        let duty_cycle = u32::from(duty_cycle.min(100));
+
+        let remapped = match duty_cycle {
+            0 => 0,
+            1..=50 => min_motion + (mid - min_motion) * duty_cycle / 50,
+            _ => mid + (100 - mid) * (duty_cycle - 50) / 50,
+        };
+
+        remapped as u8
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    pub loco_command_min_spacing_ms: u64,
+    pub loco_status_refresh_ms: u64,
+    // Devices silent for longer than this are considered gone
+    pub heartbeat_timeout_ms: u64,
+    // Actuator commands still unacknowledged after this long aren't sent
+    // again once the board is back, the layout having moved on since
+    pub actuator_command_expiry_ms: u64,
+    pub speed_curves: BTreeMap<LocoId, SpeedCurve>,
+    pub trims: BTreeMap<LocoId, u8>,
+    // Only for the locos whose motors need it
+    pub duty_curves: BTreeMap<LocoId, DutyCurve>,
+    // Applied to every loco on top of its trim, to slow the whole layout down
+    pub speed_scale_percent: u8,
+    // Where the command IDs are reserved, so that they keep increasing
+    // across restarts
+    pub command_ids_path: Option<PathBuf>,
+    pub serial_bus: SerialBusConfig,
+    // Shared with the boards, which are rejected unless they give it
+    pub auth_token: Option<String>,
+    // Commands carry the correlation ID of what they're sent on behalf of
+    pub correlation_ids_on_wire: bool,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            loco_command_min_spacing_ms: 100,
+            loco_status_refresh_ms: 500,
+            heartbeat_timeout_ms: 3000,
+            actuator_command_expiry_ms: 5000,
+            speed_curves: LOCO_UIDS
+                .iter()
+                .map(|(loco_id, _)| (*loco_id, SpeedCurve::default()))
+                .collect(),
+            trims: LOCO_UIDS
+                .iter()
+                .map(|(loco_id, _)| (*loco_id, 100))
+                .collect(),
+            duty_curves: BTreeMap::new(),
+            speed_scale_percent: 100,
+            command_ids_path: None,
+            serial_bus: SerialBusConfig::default(),
+            auth_token: None,
+            correlation_ids_on_wire: false,
+        }
+    }
+}
+
+impl BackendConfig {
+    pub fn loco_command_min_spacing(&self) -> Duration {
+        Duration::from_millis(self.loco_command_min_spacing_ms)
+    }
+
+    pub fn loco_status_refresh(&self) -> Duration {
+        Duration::from_millis(self.loco_status_refresh_ms)
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_timeout_ms)
+    }
+
+    pub fn actuator_command_expiry(&self) -> Duration {
+        Duration::from_millis(self.actuator_command_expiry_ms)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub max_entries: usize,
+    pub max_events: usize,
+    pub max_age_secs: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_entries: 1000,
+            max_events: 100_000,
+            max_age_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age_secs)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OracleConfig {
+    pub period_ms: u64,
+    pub approach_max_latency_ms: u64,
+    pub follow_min_gap: usize,
+    // Most locos moving at once, as a power district can only feed so much
+    // current. Unlimited if not set.
+    pub max_moving_locos: Option<usize>,
+    // Time over which a stopped loco reaches its speed when the Oracle starts
+    // it, to keep its wheels from slipping. Slowing down is always immediate.
+    pub start_ramp_ms: u16,
+    // Whether a loco heading towards another one pulls into a passing loop to
+    // let it through
+    pub passing_loops: bool,
+}
+
+impl Default for OracleConfig {
+    fn default() -> Self {
+        OracleConfig {
+            period_ms: 10,
+            approach_max_latency_ms: 50,
+            follow_min_gap: 1,
+            max_moving_locos: None,
+            start_ramp_ms: 0,
+            passing_loops: true,
+        }
+    }
+}
+
+impl OracleConfig {
+    pub fn period(&self) -> Duration {
+        Duration::from_millis(self.period_ms)
+    }
+
+    pub fn approach_max_latency(&self) -> Duration {
+        Duration::from_millis(self.approach_max_latency_ms)
+    }
+
+    pub fn start_ramp(&self) -> Option<Duration> {
+        (self.start_ramp_ms > 0).then(|| Duration::from_millis(self.start_ramp_ms.into()))
+    }
+}
+
+/**
+ * Safety sequence run when the controller boots, before the Oracle can be
+ * enabled. Switch rails are set to their startup position once the actuators
+ * board is there, and locos have to report they're stopped.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StartupConfig {
+    pub wait_for_actuators: bool,
+    pub switch_rails: BTreeMap<ActuatorId, SwitchRailsState>,
+    pub require_locos_stopped: bool,
+    pub poll_period_ms: u64,
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        StartupConfig {
+            wait_for_actuators: true,
+            switch_rails: BTreeMap::from([
+                (ActuatorId::SwitchRails1, SwitchRailsState::Direct),
+                (ActuatorId::SwitchRails2, SwitchRailsState::Direct),
+                (ActuatorId::SwitchRails3, SwitchRailsState::Direct),
+                (ActuatorId::SwitchRails4, SwitchRailsState::Direct),
+                (ActuatorId::SwitchRails5, SwitchRailsState::Direct),
+                (ActuatorId::SwitchRails6, SwitchRailsState::Direct),
+                (ActuatorId::SwitchRails7, SwitchRailsState::Direct),
+                (ActuatorId::SwitchRails8, SwitchRailsState::Direct),
+            ]),
+            require_locos_stopped: true,
+            poll_period_ms: 200,
+        }
+    }
+}
+
+impl StartupConfig {
+    pub fn poll_period(&self) -> Duration {
+        Duration::from_millis(self.poll_period_ms)
+    }
+}
+
+/**
+ * Safety monitor double checking the Oracle. A violation must last for the
+ * grace period before everything gets stopped.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafetyConfig {
+    pub enabled: bool,
+    pub period_ms: u64,
+    pub grace_ms: u64,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        SafetyConfig {
+            enabled: true,
+            period_ms: 100,
+            grace_ms: 1000,
+        }
+    }
+}
+
+impl SafetyConfig {
+    pub fn period(&self) -> Duration {
+        Duration::from_millis(self.period_ms)
+    }
+
+    pub fn grace(&self) -> Duration {
+        Duration::from_millis(self.grace_ms)
+    }
+}
+
+/**
+ * Automatic calibration run, driving a loco around the main loop at every duty
+ * cycle in turn and timing how long it takes to go through a few segments at
+ * each of them.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibrationConfig {
+    pub duty_cycles: Vec<u8>,
+    pub segments_per_duty_cycle: usize,
+    pub segment_timeout_secs: u64,
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        CalibrationConfig {
+            duty_cycles: Vec::from([30, 45, 60, 75, 90]),
+            segments_per_duty_cycle: 6,
+            segment_timeout_secs: 30,
+        }
+    }
+}
+
+impl CalibrationConfig {
+    pub fn segment_timeout(&self) -> Duration {
+        Duration::from_secs(self.segment_timeout_secs)
+    }
+}
+
+/**
+ * Time slot during which the crew services the layout, on the given days or
+ * every day if none is given.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceWindow {
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/**
+ * Maintenance windows, in the local time of the layout given by its offset
+ * from UTC.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    pub utc_offset_minutes: i32,
+    pub windows: Vec<MaintenanceWindow>,
+}
+
+/**
+ * Wagons every loco is expected to pull, from the one coupled to the loco to
+ * the tail of the train, named as in the tag database. The whole train has to
+ * go by a checkpoint within the tail timeout after the loco.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsistsConfig {
+    pub tail_timeout_ms: u64,
+    pub wagons: BTreeMap<LocoId, Vec<String>>,
+}
+
+impl Default for ConsistsConfig {
+    fn default() -> Self {
+        ConsistsConfig {
+            tail_timeout_ms: 5000,
+            wagons: BTreeMap::new(),
+        }
+    }
+}
+
+impl ConsistsConfig {
+    pub fn tail_timeout(&self) -> Duration {
+        Duration::from_millis(self.tail_timeout_ms)
+    }
+}
+
+/**
+ * Where the tags fitted under the rolling stock are registered. Without a
+ * database, only the tags known to the sensors firmware are recognized, and
+ * registered ones are lost on restart.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TagsConfig {
+    pub database_path: Option<PathBuf>,
+}
+
+/**
+ * Settings specific to a layout. Whatever a profile doesn't set is taken from
+ * the main configuration.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub network: Option<NetworkConfig>,
+    pub speed_curves: Option<BTreeMap<LocoId, SpeedCurve>>,
+    pub roster: BTreeMap<LocoId, Label>,
+}
+
+/**
+ * Program the layout runs during part of the day: how fast the locos go, and
+ * which functions they have on, such as their lights at night.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DayProgram {
+    // Speed scale left as it is if not set
+    pub speed_scale_percent: Option<u8>,
+    pub functions: BTreeMap<LocoId, LocoFunctions>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DayTimetableEntry {
+    pub at: TimeOfDay,
+    pub program: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DayConfig {
+    // Fast clock minutes going by for every real minute
+    pub fast_clock_ratio: u32,
+    // Time the fast clock shows when the controller starts
+    pub start: TimeOfDay,
+    pub programs: BTreeMap<String, DayProgram>,
+    // Programs switched in at the given fast clock times, every day
+    pub timetable: Vec<DayTimetableEntry>,
+}
+
+impl Default for DayConfig {
+    fn default() -> Self {
+        DayConfig {
+            fast_clock_ratio: 1,
+            start: TimeOfDay::from_minutes(6 * 60),
+            programs: BTreeMap::new(),
+            timetable: Vec::new(),
+        }
+    }
+}
+
+/**
+ * What starts a route program: a loco going by a checkpoint for the given
+ * number of times, or a fixed period.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum RouteTrigger {
+    Laps {
+        loco_id: LocoId,
+        checkpoint: CheckpointId,
+        every: u32,
+    },
+    Period {
+        every_secs: u64,
+    },
+}
+
+/**
+ * Step of a route program, which only starts once the previous one is done.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum RouteStep {
+    // Thrown all at once, as soon as the Oracle doesn't rely on any of them
+    SwitchRails(BTreeMap<ActuatorId, SwitchRailsState>),
+    Intent { loco_id: LocoId, intent: LocoIntent },
+    // Done once the loco completes its intent
+    WaitIntent { loco_id: LocoId },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RouteProgram {
+    pub trigger: RouteTrigger,
+    pub steps: Vec<RouteStep>,
+}
+
+/**
+ * Route programs run by the automation, by name.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutesConfig {
+    pub programs: BTreeMap<String, RouteProgram>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginsConfig {
+    pub enabled: Vec<String>,
+}
+
+/**
+ * Set of segments fed by the same supply, which can only deliver so much
+ * current. Its track power actuator, if any, isolates it from the supply.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PowerDistrictConfig {
+    pub segments: Vec<SegmentId>,
+    pub budget_ma: u32,
+    #[serde(default)]
+    pub track_power: Option<ActuatorId>,
+}
+
+/**
+ * Angles, in degrees, the servo throwing a switch rails is driven to for
+ * each of its states.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServoAngles {
+    pub direct: u8,
+    pub diverted: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptsConfig {
+    pub max_scripts: usize,
+    pub max_module_bytes: usize,
+    pub max_memory_bytes: usize,
+    pub fuel_per_hook: u64,
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        ScriptsConfig {
+            max_scripts: 8,
+            max_module_bytes: 1024 * 1024,
+            max_memory_bytes: 4 * 1024 * 1024,
+            fuel_per_hook: 1_000_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub checkpoints: BTreeMap<CheckpointId, Label>,
+    pub tracks: BTreeMap<TrackId, Label>,
+    pub power_districts: BTreeMap<String, PowerDistrictConfig>,
+    // Current drawn by a moving loco, stopped ones drawing next to nothing
+    pub loco_current_ma: u32,
+    // Terminal checkpoints of the stub tracks, along with the direction in
+    // which the track ends right after them
+    pub dead_ends: BTreeMap<CheckpointId, Direction>,
+    // Signal lights, along with the segment whose entrance each one guards
+    pub signals: BTreeMap<ActuatorId, SegmentId>,
+    // Order in which the switch rails of a segment must be thrown, for the
+    // segments which need one
+    pub switch_order: BTreeMap<SegmentId, Vec<ActuatorId>>,
+    // Switch rails thrown by a servo rather than a solenoid, which are driven
+    // to an angle matching their state
+    pub servo_angles: BTreeMap<ActuatorId, ServoAngles>,
+    // State the switch rails are set to whenever the actuators board
+    // connects, rather than whatever its GPIOs power up with
+    pub switch_defaults: BTreeMap<ActuatorId, SwitchRailsState>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            checkpoints: BTreeMap::from([
+                (CheckpointId::Checkpoint1, Label::new("Checkpoint 1")),
+                (CheckpointId::Checkpoint2, Label::new("Checkpoint 2")),
+                (CheckpointId::Checkpoint3, Label::new("Checkpoint 3")),
+                (CheckpointId::Checkpoint4, Label::new("Checkpoint 4")),
+                (CheckpointId::Checkpoint5, Label::new("Checkpoint 5")),
+                (CheckpointId::Checkpoint6, Label::new("Checkpoint 6")),
+                (CheckpointId::Station1, Label::new("Station 1")),
+                (CheckpointId::Station2, Label::new("Station 2")),
+            ]),
+            tracks: BTreeMap::from([
+                (TrackId::Track1, Label::new("Track 1")),
+                (TrackId::Station1, Label::new("Station 1")),
+                (TrackId::Station2, Label::new("Station 2")),
+            ]),
+            power_districts: BTreeMap::new(),
+            loco_current_ma: 300,
+            dead_ends: BTreeMap::new(),
+            signals: BTreeMap::new(),
+            switch_order: BTreeMap::new(),
+            servo_angles: BTreeMap::new(),
+            switch_defaults: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub ports: PortsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub public: PublicConfig,
+    pub locos: LocosConfig,
+    pub backend: BackendConfig,
+    pub history: HistoryConfig,
+    pub oracle: OracleConfig,
+    pub safety: SafetyConfig,
+    pub startup: StartupConfig,
+    pub calibration: CalibrationConfig,
+    pub maintenance: MaintenanceConfig,
+    pub tags: TagsConfig,
+    pub consists: ConsistsConfig,
+    pub network: NetworkConfig,
+    pub plugins: PluginsConfig,
+    pub scripts: ScriptsConfig,
+    pub day: DayConfig,
+    pub routes: RoutesConfig,
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    pub profile: Option<String>,
+}
+
+impl Config {
+    // Semantic checks which can't be expressed through the types, returning
+    // the offending key along with the reason.
+    fn validate(&self) -> std::result::Result<(), (String, String)> {
+        let mut ports = vec![
+            ("ports.http", self.ports.http),
+            ("ports.locos", self.ports.locos),
+            ("ports.sensors", self.ports.sensors),
+            ("ports.actuators", self.ports.actuators),
+        ];
+        if let Some(port) = self.public.port {
+            ports.push(("public.port", port));
+        }
+        for (i, (key, port)) in ports.iter().enumerate() {
+            if *port == 0 {
+                return Err((key.to_string(), "port can't be 0".to_string()));
+            }
+            if let Some((other_key, _)) = ports[..i].iter().find(|(_, p)| p == port) {
+                return Err((
+                    key.to_string(),
+                    format!("port {} already used by {}", port, other_key),
+                ));
+            }
+        }
+
+        let rate_limits = [
+            ("rate_limit", &self.rate_limit),
+            ("public.rate_limit", &self.public.rate_limit),
+        ];
+        for (key, rate_limit) in rate_limits {
+            if rate_limit.requests_per_sec == 0 {
+                return Err((
+                    format!("{}.requests_per_sec", key),
+                    "rate can't be 0".to_string(),
+                ));
+            }
+            if rate_limit.burst == 0 {
+                return Err((format!("{}.burst", key), "burst can't be 0".to_string()));
+            }
+        }
+
+        let mut tag_owners: BTreeMap<TagUid, LocoId> = LOCO_UIDS
+            .iter()
+            .map(|(loco_id, uid)| (TagUid::from(*uid), *loco_id))
+            .collect();
+        let mut device_owners: BTreeMap<u64, LocoId> = BTreeMap::new();
+        for (loco_id, loco) in self.locos.roster.iter() {
+            let key = format!("locos.roster.{}", serialized_key(loco_id));
+            for uid in loco.tags.iter() {
+                if let Some(owner) = tag_owners.insert(*uid, *loco_id)
+                    && owner != *loco_id
+                {
+                    return Err((
+                        format!("{}.tags", key),
+                        format!("tag {} already belongs to {}", uid, owner),
+                    ));
+                }
+            }
+            if let Some(device_id) = loco.device_id
+                && let Some(owner) = device_owners.insert(device_id, *loco_id)
+            {
+                return Err((
+                    format!("{}.device_id", key),
+                    format!("device {:#x} already drives {}", device_id, owner),
+                ));
+            }
+        }
+
+        if self.backend.loco_status_refresh_ms == 0 {
+            return Err((
+                "backend.loco_status_refresh_ms".to_string(),
+                "period can't be 0".to_string(),
+            ));
+        }
+
+        if self.backend.heartbeat_timeout_ms == 0 {
+            return Err((
+                "backend.heartbeat_timeout_ms".to_string(),
+                "timeout can't be 0".to_string(),
+            ));
+        }
+
+        validate_speed_curves("backend.speed_curves", &self.backend.speed_curves)?;
+        self.validate_locos("backend.speed_curves", self.backend.speed_curves.keys())?;
+        self.validate_locos("backend.trims", self.backend.trims.keys())?;
+        self.validate_locos("backend.duty_curves", self.backend.duty_curves.keys())?;
+
+        for (id, curve) in self.backend.duty_curves.iter() {
+            let key = format!("backend.duty_curves.{}", serialized_key(id));
+            if curve.mid > 100 {
+                return Err((
+                    format!("{}.mid", key),
+                    "duty cycle can't exceed 100".to_string(),
+                ));
+            }
+            if curve.min_motion > curve.mid {
+                return Err((
+                    format!("{}.min_motion", key),
+                    "can't exceed mid".to_string(),
+                ));
+            }
+        }
+
+        for (id, trim) in self.backend.trims.iter() {
+            if !(MIN_TRIM_PERCENT..=MAX_TRIM_PERCENT).contains(trim) {
+                return Err((
+                    format!("backend.trims.{}", serialized_key(id)),
+                    format!(
+                        "trim must be between {}% and {}%",
+                        MIN_TRIM_PERCENT, MAX_TRIM_PERCENT
+                    ),
+                ));
+            }
+        }
+
+        if let Some(auth_token) = &self.backend.auth_token
+            && !(1..=AUTH_TOKEN_MAX_LEN).contains(&auth_token.len())
+        {
+            return Err((
+                "backend.auth_token".to_string(),
+                format!("token must be between 1 and {} bytes", AUTH_TOKEN_MAX_LEN),
+            ));
+        }
+
+        self.validate_serial_bus()?;
+
+        if !(MIN_SPEED_SCALE_PERCENT..=MAX_SPEED_SCALE_PERCENT)
+            .contains(&self.backend.speed_scale_percent)
+        {
+            return Err((
+                "backend.speed_scale_percent".to_string(),
+                format!(
+                    "scale must be between {}% and {}%",
+                    MIN_SPEED_SCALE_PERCENT, MAX_SPEED_SCALE_PERCENT
+                ),
+            ));
+        }
+
+        if self.history.max_entries == 0 {
+            return Err((
+                "history.max_entries".to_string(),
+                "history can't be empty".to_string(),
+            ));
+        }
+        if self.history.max_events == 0 {
+            return Err((
+                "history.max_events".to_string(),
+                "event log can't be empty".to_string(),
+            ));
+        }
+
+        if self.oracle.period_ms == 0 {
+            return Err((
+                "oracle.period_ms".to_string(),
+                "period can't be 0".to_string(),
+            ));
+        }
+
+        if self.oracle.follow_min_gap == 0 {
+            return Err((
+                "oracle.follow_min_gap".to_string(),
+                "gap must be at least one segment".to_string(),
+            ));
+        }
+
+        if self.oracle.max_moving_locos == Some(0) {
+            return Err((
+                "oracle.max_moving_locos".to_string(),
+                "at least one loco must be allowed to move".to_string(),
+            ));
+        }
+
+        if self.safety.period_ms == 0 {
+            return Err((
+                "safety.period_ms".to_string(),
+                "period can't be 0".to_string(),
+            ));
+        }
+
+        if self
+            .startup
+            .switch_rails
+            .contains_key(&ActuatorId::TrackPower)
+        {
+            return Err((
+                "startup.switch_rails.trackpower".to_string(),
+                "not a switch rails".to_string(),
+            ));
+        }
+
+        if self.startup.poll_period_ms == 0 {
+            return Err((
+                "startup.poll_period_ms".to_string(),
+                "period can't be 0".to_string(),
+            ));
+        }
+
+        if self.calibration.duty_cycles.len() < 2 {
+            return Err((
+                "calibration.duty_cycles".to_string(),
+                "at least two duty cycles are needed to fit a curve".to_string(),
+            ));
+        }
+        if let Some(duty_cycle) = self.calibration.duty_cycles.iter().find(|d| **d > 100) {
+            return Err((
+                "calibration.duty_cycles".to_string(),
+                format!("duty cycle {}% is over 100%", duty_cycle),
+            ));
+        }
+
+        if self.calibration.segments_per_duty_cycle == 0 {
+            return Err((
+                "calibration.segments_per_duty_cycle".to_string(),
+                "at least one segment is needed".to_string(),
+            ));
+        }
+
+        if self.calibration.segment_timeout_secs == 0 {
+            return Err((
+                "calibration.segment_timeout_secs".to_string(),
+                "timeout can't be 0".to_string(),
+            ));
+        }
+
+        // UTC offsets range from -12:00 to +14:00
+        if !(-12 * 60..=14 * 60).contains(&self.maintenance.utc_offset_minutes) {
+            return Err((
+                "maintenance.utc_offset_minutes".to_string(),
+                "offset must be between -720 and 840 minutes".to_string(),
+            ));
+        }
+        for (i, window) in self.maintenance.windows.iter().enumerate() {
+            if window.start == window.end {
+                return Err((
+                    "maintenance.windows".to_string(),
+                    format!("window {} can't start and end at the same time", i),
+                ));
+            }
+        }
+
+        if self.consists.tail_timeout_ms == 0 {
+            return Err((
+                "consists.tail_timeout_ms".to_string(),
+                "timeout can't be 0".to_string(),
+            ));
+        }
+        self.validate_locos("consists.wagons", self.consists.wagons.keys())?;
+        let mut wagons: Vec<&String> = Vec::new();
+        for (loco_id, consist) in self.consists.wagons.iter() {
+            let key = format!("consists.wagons.{}", serialized_key(loco_id));
+            for wagon in consist.iter() {
+                if wagon.is_empty() {
+                    return Err((key, "wagon name can't be empty".to_string()));
+                }
+                if wagons.contains(&wagon) {
+                    return Err((key, format!("wagon {} is already part of a consist", wagon)));
+                }
+                wagons.push(wagon);
+            }
+        }
+
+        for name in self.plugins.enabled.iter() {
+            if plugin::builtin(name).is_none() {
+                return Err((
+                    "plugins.enabled".to_string(),
+                    format!("unknown plugin {}", name),
+                ));
+            }
+        }
+
+        // A script needs at least a page of memory, as soon as it exchanges
+        // anything with the host
+        if self.scripts.max_memory_bytes < 64 * 1024 {
+            return Err((
+                "scripts.max_memory_bytes".to_string(),
+                "memory can't be less than a 64KiB page".to_string(),
+            ));
+        }
+        if self.scripts.fuel_per_hook == 0 {
+            return Err((
+                "scripts.fuel_per_hook".to_string(),
+                "fuel can't be 0".to_string(),
+            ));
+        }
+        if self.day.fast_clock_ratio == 0 {
+            return Err((
+                "day.fast_clock_ratio".to_string(),
+                "ratio can't be 0".to_string(),
+            ));
+        }
+        for (name, program) in self.day.programs.iter() {
+            let key = format!("day.programs.{}", name);
+            if name.is_empty() {
+                return Err((key, "name can't be empty".to_string()));
+            }
+            if program.speed_scale_percent.is_some_and(|scale| {
+                !(MIN_SPEED_SCALE_PERCENT..=MAX_SPEED_SCALE_PERCENT).contains(&scale)
+            }) {
+                return Err((
+                    format!("{}.speed_scale_percent", key),
+                    format!(
+                        "scale must be between {}% and {}%",
+                        MIN_SPEED_SCALE_PERCENT, MAX_SPEED_SCALE_PERCENT
+                    ),
+                ));
+            }
+            self.validate_locos(&format!("{}.functions", key), program.functions.keys())?;
+        }
+        for (i, entry) in self.day.timetable.iter().enumerate() {
+            if !self.day.programs.contains_key(&entry.program) {
+                return Err((
+                    "day.timetable".to_string(),
+                    format!("entry {}: unknown program {}", i, entry.program),
+                ));
+            }
+            if self.day.timetable[..i].iter().any(|e| e.at == entry.at) {
+                return Err((
+                    "day.timetable".to_string(),
+                    format!(
+                        "entry {}: another program already starts at {}",
+                        i, entry.at
+                    ),
+                ));
+            }
+        }
+
+        for (name, program) in self.routes.programs.iter() {
+            let key = format!("routes.programs.{}", name);
+            if name.is_empty() {
+                return Err((key, "name can't be empty".to_string()));
+            }
+            match program.trigger {
+                RouteTrigger::Laps { loco_id, every, .. } => {
+                    self.validate_locos(&format!("{}.trigger", key), [loco_id].iter())?;
+                    if every == 0 {
+                        return Err((
+                            format!("{}.trigger.laps.every", key),
+                            "every can't be 0".to_string(),
+                        ));
+                    }
+                }
+                RouteTrigger::Period { every_secs } => {
+                    if every_secs == 0 {
+                        return Err((
+                            format!("{}.trigger.period.every_secs", key),
+                            "period can't be 0".to_string(),
+                        ));
+                    }
+                }
+            }
+            if program.steps.is_empty() {
+                return Err((
+                    format!("{}.steps", key),
+                    "at least one step is needed".to_string(),
+                ));
+            }
+            let step_key = format!("{}.steps", key);
+            for (i, step) in program.steps.iter().enumerate() {
+                match step {
+                    RouteStep::SwitchRails(switch_rails) => {
+                        if switch_rails.is_empty() {
+                            return Err((step_key, format!("step {} has no switch rails", i)));
+                        }
+                        if let Some(actuator_id) = switch_rails.keys().find(|id| {
+                            matches!(
+                                id,
+                                ActuatorId::TrackPower
+                                    | ActuatorId::Signal1
+                                    | ActuatorId::Signal2
+                                    | ActuatorId::Signal3
+                                    | ActuatorId::Signal4
+                            )
+                        }) {
+                            return Err((
+                                step_key,
+                                format!(
+                                    "step {}: {} is not a switch rails",
+                                    i,
+                                    serialized_key(actuator_id)
+                                ),
+                            ));
+                        }
+                    }
+                    RouteStep::Intent { loco_id, .. } | RouteStep::WaitIntent { loco_id } => {
+                        self.validate_locos(&step_key, [*loco_id].iter())?;
+                    }
+                }
+            }
+        }
+
+        validate_network("network", &self.network)?;
+
+        for (name, profile) in self.profiles.iter() {
+            let key = format!("profiles.{}", name);
+            if name.is_empty() {
+                return Err((key, "name can't be empty".to_string()));
+            }
+            if let Some(network) = &profile.network {
+                validate_network(&format!("{}.network", key), network)?;
+            }
+            if let Some(speed_curves) = &profile.speed_curves {
+                let key = format!("{}.speed_curves", key);
+                validate_speed_curves(&key, speed_curves)?;
+                self.validate_locos(&key, speed_curves.keys())?;
+            }
+            self.validate_locos(&format!("{}.roster", key), profile.roster.keys())?;
+            for (id, label) in profile.roster.iter() {
+                if label.name.is_empty() {
+                    let key = format!("{}.roster.{}.name", key, serialized_key(id));
+                    return Err((key, "name can't be empty".to_string()));
+                }
+            }
+        }
+
+        if let Some(name) = &self.profile
+            && !self.profiles.contains_key(name)
+        {
+            return Err(("profile".to_string(), format!("unknown profile {}", name)));
+        }
+
+        Ok(())
+    }
+
+    // Settings can only be given for the locos in the roster
+    fn validate_locos<'a>(
+        &self,
+        prefix: &str,
+        mut loco_ids: impl Iterator<Item = &'a LocoId>,
+    ) -> std::result::Result<(), (String, String)> {
+        match loco_ids.find(|id| !self.locos.roster.contains_key(id)) {
+            Some(id) => Err((
+                format!("{}.{}", prefix, serialized_key(id)),
+                "loco not in locos.roster".to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn validate_serial_bus(&self) -> std::result::Result<(), (String, String)> {
+        let serial_bus = &self.backend.serial_bus;
+        if serial_bus.turnouts.is_empty() {
+            return Ok(());
+        }
+
+        if serial_bus.port.is_empty() {
+            return Err((
+                "backend.serial_bus.port".to_string(),
+                "port needed to reach the turnouts".to_string(),
+            ));
+        }
+        if serial_bus.baud_rate == 0 {
+            return Err((
+                "backend.serial_bus.baud_rate".to_string(),
+                "baud rate can't be 0".to_string(),
+            ));
+        }
+        if !serial_bus.command.contains("{state}") {
+            return Err((
+                "backend.serial_bus.command".to_string(),
+                "command must contain {state}".to_string(),
+            ));
+        }
+        if serial_bus.ack.is_some() && serial_bus.ack_timeout_ms == 0 {
+            return Err((
+                "backend.serial_bus.ack_timeout_ms".to_string(),
+                "timeout can't be 0".to_string(),
+            ));
+        }
+
+        for actuator_id in serial_bus.turnouts.keys() {
+            let key = format!(
+                "backend.serial_bus.turnouts.{}",
+                serialized_key(actuator_id)
+            );
+            if matches!(
+                actuator_id,
+                ActuatorId::TrackPower
+                    | ActuatorId::Signal1
+                    | ActuatorId::Signal2
+                    | ActuatorId::Signal3
+                    | ActuatorId::Signal4
+            ) {
+                return Err((key, "not a switch rails".to_string()));
+            }
+            if self.network.servo_angles.contains_key(actuator_id) {
+                return Err((key, "already thrown by a servo".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_speed_curves(
+    prefix: &str,
+    speed_curves: &BTreeMap<LocoId, SpeedCurve>,
+) -> std::result::Result<(), (String, String)> {
+    for (id, curve) in speed_curves.iter() {
+        let key = format!("{}.{}", prefix, serialized_key(id));
+        for (name, v) in [
+            ("v_start", curve.v_start),
+            ("v_mid", curve.v_mid),
+            ("v_high", curve.v_high),
+        ] {
+            if v > 100 {
+                return Err((
+                    format!("{}.{}", key, name),
+                    "duty cycle can't exceed 100".to_string(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_network(
+    prefix: &str,
+    network: &NetworkConfig,
+) -> std::result::Result<(), (String, String)> {
+    for (id, label) in network.checkpoints.iter() {
+        if label.name.is_empty() {
+            let key = format!("{}.checkpoints.{}.name", prefix, serialized_key(id));
+            return Err((key, "name can't be empty".to_string()));
+        }
+    }
+    for (id, label) in network.tracks.iter() {
+        if label.name.is_empty() {
+            let key = format!("{}.tracks.{}.name", prefix, serialized_key(id));
+            return Err((key, "name can't be empty".to_string()));
+        }
+    }
+
+    if network.loco_current_ma == 0 {
+        let key = format!("{}.loco_current_ma", prefix);
+        return Err((key, "current can't be 0".to_string()));
+    }
+
+    let mut districts_by_segment: BTreeMap<SegmentId, &String> = BTreeMap::new();
+    for (name, district) in network.power_districts.iter() {
+        let key = format!("{}.power_districts.{}", prefix, name);
+        if district.budget_ma < network.loco_current_ma {
+            return Err((
+                format!("{}.budget_ma", key),
+                format!(
+                    "budget can't feed a single loco drawing {}mA",
+                    network.loco_current_ma
+                ),
+            ));
+        }
+        if district
+            .track_power
+            .is_some_and(|id| id != ActuatorId::TrackPower)
+        {
+            return Err((
+                format!("{}.track_power", key),
+                "not a track power actuator".to_string(),
+            ));
+        }
+        for segment_id in district.segments.iter() {
+            if let Some(other) = districts_by_segment.insert(*segment_id, name) {
+                return Err((
+                    format!("{}.segments", key),
+                    format!(
+                        "{} already belongs to district {}",
+                        serialized_key(segment_id),
+                        other
+                    ),
+                ));
+            }
+        }
+    }
+
+    for actuator_id in network.signals.keys() {
+        if !matches!(
+            actuator_id,
+            ActuatorId::Signal1 | ActuatorId::Signal2 | ActuatorId::Signal3 | ActuatorId::Signal4
+        ) {
+            let key = format!("{}.signals.{}", prefix, serialized_key(actuator_id));
+            return Err((key, "not a signal actuator".to_string()));
+        }
+    }
+
+    for actuator_id in network.switch_defaults.keys() {
+        if matches!(
+            actuator_id,
+            ActuatorId::TrackPower
+                | ActuatorId::Signal1
+                | ActuatorId::Signal2
+                | ActuatorId::Signal3
+                | ActuatorId::Signal4
+        ) {
+            let key = format!("{}.switch_defaults.{}", prefix, serialized_key(actuator_id));
+            return Err((key, "not a switch rails".to_string()));
+        }
+    }
+
+    for (actuator_id, angles) in network.servo_angles.iter() {
+        let key = format!("{}.servo_angles.{}", prefix, serialized_key(actuator_id));
+        if matches!(
+            actuator_id,
+            ActuatorId::TrackPower
+                | ActuatorId::Signal1
+                | ActuatorId::Signal2
+                | ActuatorId::Signal3
+                | ActuatorId::Signal4
+        ) {
+            return Err((key, "not a switch rails".to_string()));
+        }
+        for (name, angle) in [("direct", angles.direct), ("diverted", angles.diverted)] {
+            if angle > SERVO_ANGLE_MAX {
+                return Err((
+                    format!("{}.{}", key, name),
+                    format!("angle can't exceed {} degrees", SERVO_ANGLE_MAX),
+                ));
+            }
+        }
+    }
+
+    let rail_network = RailNetwork::new();
+    for (segment_id, order) in network.switch_order.iter() {
+        let mut expected: Vec<ActuatorId> = rail_network
+            .segment(segment_id)
+            .switch_rails()
+            .iter()
+            .map(|s| s.actuator_id())
+            .collect();
+        let mut listed = order.clone();
+        expected.sort();
+        listed.sort();
+        if listed != expected {
+            let key = format!("{}.switch_order.{}", prefix, serialized_key(segment_id));
+            return Err((
+                key,
+                format!("must list each of {:?} exactly once", expected),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Builds the configuration out of the layered sources. Every key remembers
+ * where its value comes from, so that an error can point at the offending
+ * file line, environment variable or command line argument.
+ */
+pub struct ConfigLoader {
+    defaults: Value,
+    value: Value,
+    origins: HashMap<String, Origin>,
+}
+
+impl Default for ConfigLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        // Safe to unwrap since Config only contains plain serializable types
+        let defaults = serde_json::to_value(Config::default()).unwrap();
+
+        ConfigLoader {
+            value: defaults.clone(),
+            defaults,
+            origins: HashMap::new(),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: Value, origin: Origin) -> Result<()> {
+        let mut current = &mut self.value;
+        let mut path = String::new();
+        for segment in key.split('.') {
+            if in_open_map(&path) {
+                if current.is_null() {
+                    *current = Value::Object(Map::new());
+                }
+                if let Some(map) = current.as_object_mut() {
+                    map.entry(segment).or_insert(Value::Null);
+                }
+            }
+            current = match current.get_mut(segment) {
+                Some(v) => v,
+                None => return Err(Error::UnknownKey(key.to_string(), origin)),
+            };
+            if !path.is_empty() {
+                path.push('.');
+            }
+            path.push_str(segment);
+        }
+        if current.is_object() && !in_open_map(key) {
+            return Err(Error::UnknownKey(key.to_string(), origin));
+        }
+
+        *current = value;
+        self.origins.insert(key.to_string(), origin);
+
+        Ok(())
+    }
+
+    fn origin(&self, key: &str) -> Origin {
+        self.origins.get(key).cloned().unwrap_or(Origin::Default)
+    }
+
+    pub fn load_file(&mut self, path: &str) -> Result<()> {
+        debug!("ConfigLoader::load_file(): {}", path);
+
+        let content = fs::read_to_string(path).map_err(|e| Error::ReadFile(path.to_string(), e))?;
+        let file_value: Value =
+            serde_json::from_str(&content).map_err(|e| Error::ParseFile(path.to_string(), e))?;
+
+        let mut leaves = Vec::new();
+        flatten(&file_value, String::new(), &mut leaves);
+        for (key, value) in leaves {
+            let line = find_key_line(&content, &key);
+            self.set(&key, value, Origin::File(path.to_string(), line))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn load_env(&mut self) -> Result<()> {
+        let mut leaves = Vec::new();
+        flatten(&self.defaults, String::new(), &mut leaves);
+        for (key, _) in leaves {
+            let var = format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase());
+            if let Ok(raw) = std::env::var(&var) {
+                debug!("ConfigLoader::load_env(): {} overridden by {}", key, var);
+                self.set(&key, parse_raw_value(&raw), Origin::Env(var))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn load_override(&mut self, key_value: &str) -> Result<()> {
+        let (key, raw) = key_value
+            .split_once('=')
+            .ok_or_else(|| Error::ParseOverride(key_value.to_string()))?;
+
+        self.set(key.trim(), parse_raw_value(raw.trim()), Origin::CommandLine)
+    }
+
+    pub fn build(self) -> Result<Config> {
+        if let Err(e) = serde_json::from_value::<Config>(self.value.clone()) {
+            // Find out which key is responsible by applying the overridden
+            // keys one by one on top of the defaults.
+            for (key, origin) in self.origins.iter() {
+                let mut single = ConfigLoader::new();
+                single.set(key, lookup(&self.value, key).clone(), origin.clone())?;
+                if let Err(e) = serde_json::from_value::<Config>(single.value) {
+                    return Err(Error::InvalidValue(key.clone(), origin.clone(), e));
+                }
+            }
+            return Err(Error::Deserialize(e));
+        }
+
+        // Safe to unwrap since deserialization succeeded right above
+        let config: Config = serde_json::from_value(self.value.clone()).unwrap();
+        config
+            .validate()
+            .map_err(|(key, reason)| Error::Validation(key.clone(), self.origin(&key), reason))?;
+
+        Ok(config)
+    }
+}
+
+// Name of an identifier once serialized, as used for the configuration keys
+fn serialized_key<T: Serialize>(id: &T) -> String {
+    match serde_json::to_value(id) {
+        Ok(Value::String(s)) => s,
+        _ => String::new(),
+    }
+}
+
+// Values coming from the environment or the command line are plain strings,
+// which are interpreted as JSON whenever possible so that numbers and booleans
+// get their expected types.
+fn parse_raw_value(raw: &str) -> Value {
+    serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
+
+fn flatten(value: &Value, prefix: String, leaves: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(map) if !map.is_empty() || prefix.is_empty() => {
+            flatten_map(map, &prefix, leaves)
+        }
+        _ => leaves.push((prefix, value.clone())),
+    }
+}
+
+fn flatten_map(map: &Map<String, Value>, prefix: &str, leaves: &mut Vec<(String, Value)>) {
+    for (k, v) in map {
+        let key = if prefix.is_empty() {
+            k.clone()
+        } else {
+            format!("{}.{}", prefix, k)
+        };
+        flatten(v, key, leaves);
+    }
+}
+
+fn lookup<'a>(value: &'a Value, key: &str) -> &'a Value {
+    key.split('.')
+        .fold(value, |v, segment| v.get(segment).unwrap_or(&Value::Null))
+}
+
+// Locates the line defining a key in a JSON file, by looking for every segment
+// of the key one after the other. This is only used to report errors, hence a
+// best effort is good enough.
+fn find_key_line(content: &str, key: &str) -> usize {
+    let mut offset = 0;
+    for segment in key.split('.') {
+        if let Some(pos) = content[offset..].find(&format!("\"{}\"", segment)) {
+            offset += pos;
+        }
+    }
+
+    content[..offset].matches('\n').count() + 1
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use loco_protocol::{LocoId, SensorId};
+use log::debug;
+use serde::Serialize;
+
+use crate::{
+    backend::{Backend, Event},
+    config::ConsistsConfig,
+};
+
+// How often the trains are checked for wagons left behind, between the events
+pub const CHECK_PERIOD: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConsistIssue {
+    // Wagons which didn't follow their loco by a checkpoint in time
+    LostWagons {
+        loco_id: LocoId,
+        sensor_id: SensorId,
+        wagons: Vec<String>,
+    },
+    // Wagon going by a checkpoint out of order, or behind another loco
+    UnexpectedWagon {
+        wagon: String,
+        sensor_id: SensorId,
+        following: Option<LocoId>,
+        expected: Option<String>,
+    },
+}
+
+// A train going by a checkpoint, the loco first and then its wagons
+struct Passage {
+    sensor_id: SensorId,
+    since_us: u64,
+    seen: Vec<String>,
+    // Whether the missing wagons have already been reported
+    reported: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ConsistStatus {
+    wagons: Vec<String>,
+    // Last checkpoint the loco went by, along with the wagons which followed
+    sensor_id: Option<SensorId>,
+    seen: Vec<String>,
+    missing: Vec<String>,
+}
+
+/**
+ * Checks that every loco is followed by its wagons, in the configured order,
+ * as the train goes by the checkpoints. Whenever the loco is detected, its
+ * wagons are expected at the same checkpoint before the tail timeout, and
+ * they're reported as lost otherwise. Wagons showing up in the wrong order or
+ * behind another loco are reported as well.
+ */
+pub struct ConsistTracker {
+    backend: Arc<Backend>,
+    config: ConsistsConfig,
+    passages: Mutex<BTreeMap<LocoId, Passage>>,
+}
+
+impl ConsistTracker {
+    pub fn new(backend: Arc<Backend>, config: &ConsistsConfig) -> Self {
+        ConsistTracker {
+            backend,
+            config: config.clone(),
+            passages: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn missing(&self, loco_id: LocoId, passage: &Passage) -> Vec<String> {
+        self.config.wagons[&loco_id]
+            .iter()
+            .filter(|w| !passage.seen.contains(w))
+            .cloned()
+            .collect()
+    }
+
+    // Next wagon expected behind the loco, skipping the ones which already
+    // went by, out of order or not
+    fn expected(&self, loco_id: LocoId, passage: &Passage) -> Option<String> {
+        self.config.wagons[&loco_id]
+            .iter()
+            .find(|w| !passage.seen.contains(w))
+            .cloned()
+    }
+
+    fn report_lost(&self, loco_id: LocoId, passage: &mut Passage) {
+        let missing = self.missing(loco_id, passage);
+        if missing.is_empty() || passage.reported {
+            return;
+        }
+
+        passage.reported = true;
+        self.backend.report_consist_issue(ConsistIssue::LostWagons {
+            loco_id,
+            sensor_id: passage.sensor_id,
+            wagons: missing,
+        });
+    }
+
+    fn loco_hit(&self, loco_id: LocoId, sensor_id: SensorId, timestamp_us: u64) {
+        if self
+            .config
+            .wagons
+            .get(&loco_id)
+            .is_none_or(|w| w.is_empty())
+        {
+            return;
+        }
+
+        let mut passages = self.passages.lock().unwrap();
+        if let Some(passage) = passages.get_mut(&loco_id) {
+            // Read again by the same reader, such as when stopping over it
+            if passage.sensor_id == sensor_id {
+                return;
+            }
+            // The tail never showed up before the loco reached the next
+            // checkpoint
+            self.report_lost(loco_id, passage);
+        }
+
+        passages.insert(
+            loco_id,
+            Passage {
+                sensor_id,
+                since_us: timestamp_us,
+                seen: Vec::new(),
+                reported: false,
+            },
+        );
+    }
+
+    fn wagon_hit(&self, wagon: &str, sensor_id: SensorId) {
+        let Some(loco_id) = self
+            .config
+            .wagons
+            .iter()
+            .find(|(_, wagons)| wagons.iter().any(|w| w == wagon))
+            .map(|(loco_id, _)| *loco_id)
+        else {
+            debug!(
+                "ConsistTracker::wagon_hit(): {} isn't part of any consist",
+                wagon
+            );
+            return;
+        };
+
+        let mut passages = self.passages.lock().unwrap();
+        let issue = match passages
+            .get_mut(&loco_id)
+            .filter(|p| p.sensor_id == sensor_id)
+        {
+            Some(passage) => {
+                if passage.seen.iter().any(|w| w == wagon) {
+                    return;
+                }
+                let expected = self.expected(loco_id, passage);
+                passage.seen.push(wagon.to_string());
+                if expected.as_deref() == Some(wagon) {
+                    return;
+                }
+
+                ConsistIssue::UnexpectedWagon {
+                    wagon: wagon.to_string(),
+                    sensor_id,
+                    following: Some(loco_id),
+                    expected,
+                }
+            }
+            // Its loco went by another checkpoint last, hence the wagon got
+            // coupled to another train, or left behind
+            None => {
+                let following = passages
+                    .iter()
+                    .filter(|(_, p)| p.sensor_id == sensor_id)
+                    .max_by_key(|(_, p)| p.since_us)
+                    .map(|(loco_id, _)| *loco_id);
+                // Nothing to tell until the loco has been located, such as
+                // right after starting
+                if following.is_none() && !passages.contains_key(&loco_id) {
+                    return;
+                }
+                let expected = following.and_then(|id| self.expected(id, &passages[&id]));
+
+                ConsistIssue::UnexpectedWagon {
+                    wagon: wagon.to_string(),
+                    sensor_id,
+                    following,
+                    expected,
+                }
+            }
+        };
+        drop(passages);
+
+        self.backend.report_consist_issue(issue);
+    }
+
+    pub fn apply(&self, event: &Event) {
+        match event {
+            Event::SensorHit {
+                loco_id,
+                sensor_id,
+                timestamp_us,
+            } => self.loco_hit(*loco_id, *sensor_id, *timestamp_us),
+            Event::WagonHit {
+                wagon, sensor_id, ..
+            } => self.wagon_hit(wagon, *sensor_id),
+            _ => {}
+        }
+    }
+
+    // Reports the wagons which haven't followed their loco before the tail
+    // timeout
+    pub fn check(&self, now_us: u64) {
+        let timeout_us = self.config.tail_timeout().as_micros() as u64;
+
+        let mut passages = self.passages.lock().unwrap();
+        for (loco_id, passage) in passages.iter_mut() {
+            if now_us.saturating_sub(passage.since_us) > timeout_us {
+                self.report_lost(*loco_id, passage);
+            }
+        }
+    }
+
+    pub fn status(&self) -> BTreeMap<LocoId, ConsistStatus> {
+        let passages = self.passages.lock().unwrap();
+
+        self.config
+            .wagons
+            .iter()
+            .map(|(loco_id, wagons)| {
+                let passage = passages.get(loco_id);
+                (
+                    *loco_id,
+                    ConsistStatus {
+                        wagons: wagons.clone(),
+                        sensor_id: passage.map(|p| p.sensor_id),
+                        seen: passage.map(|p| p.seen.clone()).unwrap_or_default(),
+                        missing: passage
+                            .map(|p| self.missing(*loco_id, p))
+                            .unwrap_or_default(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    fmt,
+    sync::{
+        LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+};
+use log::info;
+use serde::{Serialize, Serializer};
+
+use crate::rate_limit::client_id;
+
+// Header telling the client the ID of its request, to be quoted in reports
+const CORRELATION_ID_HEADER: HeaderName = HeaderName::from_static("x-correlation-id");
+
+tokio::task_local! {
+    static CURRENT: CorrelationId;
+}
+
+// Starts from the wall clock, so that IDs don't repeat across restarts as
+// long as the clock doesn't go back
+static NEXT_ID: LazyLock<AtomicU64> = LazyLock::new(|| {
+    let now_us = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0);
+    AtomicU64::new(now_us)
+});
+
+/**
+ * ID of the HTTP request or of the Oracle cycle something is done on behalf
+ * of. It prefixes the logs, is recorded along with the events and alarms, and
+ * can be sent along with the commands, so that a switch rails moving
+ * unexpectedly can be traced back to what asked for it.
+ */
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    pub fn next() -> Self {
+        CorrelationId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+// Written the same as in the logs, so that one can be searched for the other
+impl Serialize for CorrelationId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// ID of whatever is being done, if it's done within a scope
+pub fn current() -> Option<CorrelationId> {
+    CURRENT.try_with(|id| *id).ok()
+}
+
+pub async fn scope<F: Future>(id: CorrelationId, f: F) -> F::Output {
+    CURRENT.scope(id, f).await
+}
+
+pub fn sync_scope<R>(id: CorrelationId, f: impl FnOnce() -> R) -> R {
+    CURRENT.sync_scope(id, f)
+}
+
+// Every HTTP request gets its own ID, along with a log telling what it was
+pub async fn correlate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = CorrelationId::next();
+    scope(id, async move {
+        info!(
+            "correlate(): {} {} from {}",
+            req.method(),
+            req.path(),
+            client_id(req.request())
+        );
+        let mut res = next.call(req).await?;
+        if let Ok(value) = HeaderValue::from_str(&id.to_string()) {
+            res.headers_mut().insert(CORRELATION_ID_HEADER, value);
+        }
+        Ok(res)
+    })
+    .await
+}
@@ -0,0 +1,223 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use loco_protocol::{LocoFunctions, LocoId};
+use log::{info, warn};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    backend::{Backend, Event},
+    config::{DayConfig, DayProgram},
+    maintenance::TimeOfDay,
+};
+
+// The timetable is set to the minute of the fast clock, which may go by
+// quicker than the real one
+pub const CHECK_PERIOD: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Fast clock ratio can't be 0")]
+    InvalidRatio,
+    #[error("Unknown day program {0}")]
+    UnknownProgram(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DayProgramStatus {
+    clock: TimeOfDay,
+    fast_clock_ratio: u32,
+    active: Option<String>,
+    // Set by hand, the timetable being ignored until resumed
+    held: bool,
+    available: Vec<String>,
+}
+
+struct FastClock {
+    // Real time at which the clock was last set, and what it showed then
+    set_at: Instant,
+    set_to: TimeOfDay,
+    ratio: u32,
+}
+
+impl FastClock {
+    fn now(&self) -> TimeOfDay {
+        let minutes = self.set_at.elapsed().as_secs() * self.ratio as u64 / 60;
+        TimeOfDay::from_minutes(self.set_to.minutes() + (minutes % (24 * 60)) as u32)
+    }
+}
+
+struct DayState {
+    clock: FastClock,
+    active: Option<String>,
+    held: bool,
+}
+
+/**
+ * Runs the day programs along a fast clock: at the times listed in the
+ * timetable, the layout switches to another program, which sets the speed
+ * scale and the functions of the locos. A program can also be switched in by
+ * hand, holding it until the timetable is resumed. Locos connecting while a
+ * program is active get its functions as well.
+ */
+pub struct DayPrograms {
+    backend: Arc<Backend>,
+    config: DayConfig,
+    state: Mutex<DayState>,
+}
+
+impl DayPrograms {
+    pub fn new(backend: Arc<Backend>, config: &DayConfig) -> Self {
+        let mut config = config.clone();
+        config.timetable.sort_by_key(|entry| entry.at);
+
+        DayPrograms {
+            backend,
+            state: Mutex::new(DayState {
+                clock: FastClock {
+                    set_at: Instant::now(),
+                    set_to: config.start,
+                    ratio: config.fast_clock_ratio,
+                },
+                active: None,
+                held: false,
+            }),
+            config,
+        }
+    }
+
+    // Program the timetable calls for at the given time, the last one of the
+    // day still running past midnight
+    fn scheduled(&self, clock: TimeOfDay) -> Option<&str> {
+        self.config
+            .timetable
+            .iter()
+            .rev()
+            .find(|entry| entry.at <= clock)
+            .or(self.config.timetable.last())
+            .map(|entry| entry.program.as_str())
+    }
+
+    // Setting the functions of the program is left to the caller, so that the
+    // state isn't locked while waiting for the locos
+    fn switch(&self, state: &mut DayState, name: &str) -> &DayProgram {
+        let clock = state.clock.now();
+        info!("DayPrograms::switch(): {} at {}", name, clock);
+
+        // The names have been checked by the callers
+        let program = &self.config.programs[name];
+        if let Some(scale_percent) = program.speed_scale_percent
+            && let Err(e) = self.backend.set_speed_scale(scale_percent)
+        {
+            warn!("DayPrograms::switch(): speed scale left as it is: {}", e);
+        }
+        state.active = Some(name.to_string());
+        self.backend.notify(Event::DayProgramSwitched {
+            program: name.to_string(),
+            clock,
+        });
+
+        program
+    }
+
+    fn set_functions<'a>(&self, functions: impl Iterator<Item = (&'a LocoId, &'a LocoFunctions)>) {
+        for (loco_id, functions) in functions {
+            if let Err(e) = self
+                .backend
+                .block_on(self.backend.control_loco_functions(*loco_id, *functions))
+            {
+                warn!(
+                    "DayPrograms::set_functions(): {} keeps its functions: {}",
+                    loco_id, e
+                );
+            }
+        }
+    }
+
+    // Switches to the program the timetable calls for, unless one is held
+    pub fn check(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.held {
+            return;
+        }
+
+        let Some(name) = self.scheduled(state.clock.now()) else {
+            return;
+        };
+        if state.active.as_deref() == Some(name) {
+            return;
+        }
+
+        let program = self.switch(&mut state, name);
+        drop(state);
+        self.set_functions(program.functions.iter());
+    }
+
+    pub fn apply(&self, event: &Event) {
+        if let Event::LocoConnected { loco_id, .. } = event {
+            let state = self.state.lock().unwrap();
+            let Some(name) = &state.active else {
+                return;
+            };
+            let functions = self.config.programs[name].functions.get_key_value(loco_id);
+            drop(state);
+            self.set_functions(functions.into_iter());
+        }
+    }
+
+    pub fn activate(&self, name: &str) -> Result<()> {
+        if !self.config.programs.contains_key(name) {
+            return Err(Error::UnknownProgram(name.to_string()));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.held = true;
+        let program = self.switch(&mut state, name);
+        drop(state);
+        self.set_functions(program.functions.iter());
+
+        Ok(())
+    }
+
+    pub fn resume(&self) {
+        info!("DayPrograms::resume(): back to the timetable");
+        self.state.lock().unwrap().held = false;
+        self.check();
+    }
+
+    // The program the timetable calls for at the new time is switched in
+    // right away, unless one is held
+    pub fn set_clock(&self, clock: TimeOfDay, ratio: Option<u32>) -> Result<()> {
+        if ratio == Some(0) {
+            return Err(Error::InvalidRatio);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        info!("DayPrograms::set_clock(): {}, ratio {:?}", clock, ratio);
+        state.clock = FastClock {
+            set_at: Instant::now(),
+            set_to: clock,
+            ratio: ratio.unwrap_or(state.clock.ratio),
+        };
+        drop(state);
+        self.check();
+
+        Ok(())
+    }
+
+    pub fn status(&self) -> DayProgramStatus {
+        let state = self.state.lock().unwrap();
+        DayProgramStatus {
+            clock: state.clock.now(),
+            fast_clock_ratio: state.clock.ratio,
+            active: state.active.clone(),
+            held: state.held,
+            available: self.config.programs.keys().cloned().collect(),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::{sync::Mutex, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::Event,
+    history::{History, HistoryEntry, HistoryPage, HistoryQuery},
+    state::{StateDiff, StateDiffQuery, StateTracker},
+};
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ReplayQuery {
+    until_seq: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Replay {
+    // Oldest event still in the log, the replay starting from there
+    first_seq: Option<u64>,
+    last_seq: Option<u64>,
+    state: StateDiff,
+}
+
+/**
+ * Append-only log of every event, each one getting the next sequence number.
+ * The state of the Backend is derived from the events once they're logged,
+ * hence replaying the log in order leads to the same state, which tells how
+ * the layout got where it is. Only the most recent events are kept in memory.
+ */
+pub struct EventLog {
+    events: Mutex<History<Event>>,
+}
+
+impl EventLog {
+    pub fn new(max_events: usize, max_age: Duration) -> Self {
+        EventLog {
+            events: Mutex::new(History::new(max_events, max_age)),
+        }
+    }
+
+    pub fn append(&self, event: &Event, now_us: u64) {
+        self.events.lock().unwrap().push(event.clone(), now_us);
+    }
+
+    pub fn events(&self, query: &HistoryQuery) -> HistoryPage<Event> {
+        self.events.lock().unwrap().query(query, |_| true)
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.events.lock().unwrap().last_id()
+    }
+
+    // Unlike events(), never paginated
+    pub fn events_after(&self, seq: u64) -> Vec<HistoryEntry<Event>> {
+        self.events.lock().unwrap().after(seq)
+    }
+
+    pub fn oracle_decisions(&self, query: &HistoryQuery) -> HistoryPage<Event> {
+        self.events
+            .lock()
+            .unwrap()
+            .query(query, |e| matches!(e, Event::OracleDecision { .. }))
+    }
+
+    // State the events lead to, from the oldest one up to the given sequence
+    // number, or up to the last one if none is given
+    pub fn replay(&self, query: &ReplayQuery) -> Replay {
+        let events = self.events.lock().unwrap();
+        let state = StateTracker::new();
+
+        let mut first_seq = None;
+        let mut last_seq = None;
+        for (seq, event) in events
+            .iter_sequenced()
+            .take_while(|(seq, _)| query.until_seq.is_none_or(|s| *seq <= s))
+        {
+            first_seq.get_or_insert(seq);
+            last_seq = Some(seq);
+            state.apply(event);
+        }
+
+        Replay {
+            first_seq,
+            last_seq,
+            state: state.diff(&StateDiffQuery::default()),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::history::{History, HistoryPage, HistoryQuery};
+
+// Frames are way more frequent than anything else kept in a history, hence
+// only the most recent ones are kept
+const MAX_TRACED_FRAMES: usize = 2000;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameDirection {
+    Rx,
+    Tx,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FrameRecord {
+    peer: String,
+    direction: FrameDirection,
+    // Operation of a sent message, or type decoded out of a received frame
+    kind: String,
+    bytes: String,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct FramesFilter {
+    peer: Option<String>,
+    direction: Option<FrameDirection>,
+}
+
+/**
+ * Hex dumps of the frames exchanged with the boards, meant to diagnose
+ * encoding mismatches between the controller and the firmware. Tracing is
+ * off by default and can be toggled at runtime, since it costs a copy of
+ * every frame.
+ */
+pub struct FrameTracer {
+    enabled: AtomicBool,
+    frames: Mutex<History<FrameRecord>>,
+}
+
+impl FrameTracer {
+    pub fn new(max_age: Duration) -> Self {
+        FrameTracer {
+            enabled: AtomicBool::new(false),
+            frames: Mutex::new(History::new(MAX_TRACED_FRAMES, max_age)),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Release);
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    pub fn record(
+        &self,
+        peer: Option<SocketAddr>,
+        direction: FrameDirection,
+        kind: &str,
+        bytes: &[u8],
+        now_us: u64,
+    ) {
+        let peer = match peer {
+            Some(addr) => addr.to_string(),
+            None => "unknown".to_string(),
+        };
+        let bytes = bytes.iter().fold(String::new(), |mut s, b| {
+            // Writing to a String never fails
+            let _ = write!(s, "{:02x}", b);
+            s
+        });
+
+        info!("FrameTracer: {} {:?} {} {}", peer, direction, kind, bytes);
+
+        self.frames.lock().unwrap().push(
+            FrameRecord {
+                peer,
+                direction,
+                kind: kind.to_string(),
+                bytes,
+            },
+            now_us,
+        );
+    }
+
+    pub fn frames(&self, query: &HistoryQuery, filter: &FramesFilter) -> HistoryPage<FrameRecord> {
+        self.frames.lock().unwrap().query(query, |f| {
+            filter.peer.as_ref().is_none_or(|p| *p == f.peer)
+                && filter.direction.is_none_or(|d| d == f.direction)
+        })
+    }
+}
@@ -0,0 +1,148 @@
+use std::{collections::VecDeque, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::correlation::{self, CorrelationId};
+
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 500;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HistoryEntry<T> {
+    id: u64,
+    timestamp_us: u64,
+    // What the entry was pushed on behalf of, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<CorrelationId>,
+    #[serde(flatten)]
+    item: T,
+}
+
+impl<T> HistoryEntry<T> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn item(&self) -> &T {
+        &self.item
+    }
+}
+
+/**
+ * Query parameters shared by every history endpoint. Entries are returned in
+ * chronological order, starting right after the cursor, which is the id of
+ * the last entry from the previous page.
+ */
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct HistoryQuery {
+    cursor: Option<u64>,
+    limit: Option<usize>,
+    since_us: Option<u64>,
+    until_us: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HistoryPage<T> {
+    entries: Vec<HistoryEntry<T>>,
+    next_cursor: Option<u64>,
+}
+
+/**
+ * Bounded history of timestamped entries. The oldest entries are dropped
+ * once there are more than max_entries, or once they're older than max_age,
+ * so that memory remains bounded no matter how long the controller runs.
+ */
+pub struct History<T> {
+    entries: VecDeque<HistoryEntry<T>>,
+    next_id: u64,
+    max_entries: usize,
+    max_age: Duration,
+}
+
+impl<T: Clone> History<T> {
+    pub fn new(max_entries: usize, max_age: Duration) -> Self {
+        History {
+            entries: VecDeque::new(),
+            next_id: 1,
+            max_entries,
+            max_age,
+        }
+    }
+
+    fn prune(&mut self, now_us: u64) {
+        let max_age_us = self.max_age.as_micros() as u64;
+        while let Some(entry) = self.entries.front() {
+            if self.entries.len() <= self.max_entries
+                && now_us.saturating_sub(entry.timestamp_us) <= max_age_us
+            {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, item: T, now_us: u64) {
+        self.entries.push_back(HistoryEntry {
+            id: self.next_id,
+            timestamp_us: now_us,
+            correlation_id: correlation::current(),
+            item,
+        });
+        self.next_id += 1;
+        self.prune(now_us);
+    }
+
+    // Id of the last entry ever pushed, even if it's been dropped since
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    // Every entry pushed after the given id, oldest first
+    pub fn after(&self, id: u64) -> Vec<HistoryEntry<T>> {
+        self.entries.iter().filter(|e| e.id > id).cloned().collect()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.entries.iter_mut().map(|e| &mut e.item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter().map(|e| &e.item)
+    }
+
+    pub fn iter_sequenced(&self) -> impl Iterator<Item = (u64, &T)> {
+        self.entries.iter().map(|e| (e.id, &e.item))
+    }
+
+    pub fn iter_timestamped(&self) -> impl Iterator<Item = (u64, &T)> {
+        self.entries.iter().map(|e| (e.timestamp_us, &e.item))
+    }
+
+    pub fn query<F>(&self, query: &HistoryQuery, filter: F) -> HistoryPage<T>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT);
+
+        let mut matching = self.entries.iter().filter(|e| {
+            query.cursor.is_none_or(|c| e.id > c)
+                && query.since_us.is_none_or(|t| e.timestamp_us >= t)
+                && query.until_us.is_none_or(|t| e.timestamp_us <= t)
+                && filter(&e.item)
+        });
+
+        let entries: Vec<HistoryEntry<T>> = matching.by_ref().take(limit).cloned().collect();
+        let next_cursor = match matching.next() {
+            Some(_) => entries.last().map(|e| e.id),
+            None => None,
+        };
+
+        HistoryPage {
+            entries,
+            next_cursor,
+        }
+    }
+}
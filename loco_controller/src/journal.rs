@@ -0,0 +1,280 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use log::{info, warn};
+use serde::Serialize;
+
+// Anything larger can't be what the controller saved, and reading it could
+// hold the boot up for long
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "outcome", content = "reason")]
+pub enum RecoveryOutcome {
+    // Nothing had been saved yet
+    Empty,
+    Clean,
+    // The last write got interrupted, hence what was saved before it is kept
+    RolledBack,
+    // Failed the consistency check, and moved aside for a post mortem
+    Discarded(String),
+}
+
+/**
+ * How a file saved by the controller was found when booting. Everything but a
+ * discarded file means nothing was lost, a write interrupted by a power loss
+ * only losing the change it was saving.
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct Recovery {
+    path: String,
+    #[serde(flatten)]
+    outcome: RecoveryOutcome,
+    duration_ms: u64,
+}
+
+impl Recovery {
+    pub fn is_lossless(&self) -> bool {
+        !matches!(self.outcome, RecoveryOutcome::Discarded(_))
+    }
+}
+
+/**
+ * File whose whole content is replaced on every save, so that it's either the
+ * previous or the new content after a power loss, and never a mix of both.
+ * The content is written aside and flushed to the disk, then renamed over the
+ * file, the rename itself being flushed along with the directory.
+ */
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(path: &Path) -> Self {
+        Journal {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Appends the suffix to the whole file name, rather than replacing its
+    // extension, so that files only differing by their extension never
+    // share the same sibling
+    fn sibling_path(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        self.sibling_path(".tmp")
+    }
+
+    /**
+     * Loads the content saved last, running it through the consistency check.
+     * A file failing it is renamed with .corrupt appended and reported as
+     * discarded, so that the controller boots anyway rather than getting
+     * stuck on a file it can't use.
+     */
+    pub fn recover<T>(
+        &self,
+        check: impl FnOnce(&str) -> std::result::Result<T, String>,
+    ) -> (Option<T>, Recovery) {
+        let started = Instant::now();
+        let display = self.path.display().to_string();
+
+        // Only a write which didn't complete leaves its content aside
+        let tmp_path = self.tmp_path();
+        let interrupted = tmp_path.exists();
+        if interrupted && let Err(e) = fs::remove_file(&tmp_path) {
+            warn!("Journal::recover(): {}: {}", tmp_path.display(), e);
+        }
+
+        let (value, outcome) = if !self.path.exists() {
+            let outcome = if interrupted {
+                RecoveryOutcome::RolledBack
+            } else {
+                RecoveryOutcome::Empty
+            };
+            (None, outcome)
+        } else {
+            match self.read().and_then(|content| check(&content)) {
+                Ok(value) => {
+                    let outcome = if interrupted {
+                        RecoveryOutcome::RolledBack
+                    } else {
+                        RecoveryOutcome::Clean
+                    };
+                    (Some(value), outcome)
+                }
+                Err(reason) => {
+                    if let Err(e) = fs::rename(&self.path, self.sibling_path(".corrupt")) {
+                        warn!("Journal::recover(): {}: {}", display, e);
+                    }
+                    (None, RecoveryOutcome::Discarded(reason))
+                }
+            }
+        };
+
+        let recovery = Recovery {
+            path: display,
+            outcome,
+            duration_ms: started.elapsed().as_millis() as u64,
+        };
+        if recovery.is_lossless() {
+            info!("Journal::recover(): {:?}", recovery);
+        } else {
+            warn!("Journal::recover(): {:?}", recovery);
+        }
+
+        (value, recovery)
+    }
+
+    fn read(&self) -> std::result::Result<String, String> {
+        let file = File::open(&self.path).map_err(|e| e.to_string())?;
+        let mut content = String::new();
+        // One more byte than allowed tells an oversized file apart
+        file.take(MAX_FILE_SIZE + 1)
+            .read_to_string(&mut content)
+            .map_err(|e| e.to_string())?;
+        if content.len() as u64 > MAX_FILE_SIZE {
+            return Err(format!("larger than {} bytes", MAX_FILE_SIZE));
+        }
+
+        Ok(content)
+    }
+
+    pub fn write(&self, content: &[u8]) -> io::Result<()> {
+        let tmp_path = self.tmp_path();
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        // The rename only survives a power loss once the directory is
+        // flushed too
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    // Directory of its own for every test, since they run in parallel
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("journal-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn check(content: &str) -> std::result::Result<String, String> {
+        if content.starts_with('{') {
+            Ok(content.to_string())
+        } else {
+            Err("not an object".to_string())
+        }
+    }
+
+    #[test]
+    fn recover_empty() {
+        let dir = test_dir("empty");
+        let journal = Journal::new(&dir.join("ids.json"));
+
+        let (value, recovery) = journal.recover(check);
+        assert_eq!(value, None);
+        assert_eq!(recovery.outcome, RecoveryOutcome::Empty);
+        assert!(recovery.is_lossless());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recover_clean() {
+        let dir = test_dir("clean");
+        let journal = Journal::new(&dir.join("ids.json"));
+        journal.write(b"{}").unwrap();
+        assert!(!journal.tmp_path().exists());
+
+        let (value, recovery) = journal.recover(check);
+        assert_eq!(value.as_deref(), Some("{}"));
+        assert_eq!(recovery.outcome, RecoveryOutcome::Clean);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recover_rolled_back() {
+        let dir = test_dir("rolled_back");
+        let journal = Journal::new(&dir.join("ids.json"));
+        journal.write(b"{\"saved\":1}").unwrap();
+        // A write interrupted before its rename
+        fs::write(journal.tmp_path(), b"{\"sa").unwrap();
+
+        let (value, recovery) = journal.recover(check);
+        assert_eq!(value.as_deref(), Some("{\"saved\":1}"));
+        assert_eq!(recovery.outcome, RecoveryOutcome::RolledBack);
+        assert!(recovery.is_lossless());
+        assert!(!journal.tmp_path().exists());
+
+        // Interrupted while saving for the first time
+        let journal = Journal::new(&dir.join("tags.json"));
+        fs::write(journal.tmp_path(), b"{").unwrap();
+        let (value, recovery) = journal.recover(check);
+        assert_eq!(value, None);
+        assert_eq!(recovery.outcome, RecoveryOutcome::RolledBack);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recover_discarded() {
+        let dir = test_dir("discarded");
+        let journal = Journal::new(&dir.join("ids.json"));
+        journal.write(b"garbage").unwrap();
+
+        let (value, recovery) = journal.recover(check);
+        assert_eq!(value, None);
+        assert_eq!(
+            recovery.outcome,
+            RecoveryOutcome::Discarded("not an object".to_string())
+        );
+        assert!(!recovery.is_lossless());
+        assert!(!journal.path().exists());
+        assert_eq!(fs::read(dir.join("ids.json.corrupt")).unwrap(), b"garbage");
+
+        // Nothing is left to discard on the next boot
+        let (_, recovery) = journal.recover(check);
+        assert_eq!(recovery.outcome, RecoveryOutcome::Empty);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn siblings_keep_the_whole_file_name() {
+        let dir = test_dir("siblings");
+        let ids = Journal::new(&dir.join("ids.json"));
+        let tags = Journal::new(&dir.join("ids.db"));
+        assert_eq!(ids.tmp_path(), dir.join("ids.json.tmp"));
+        assert_ne!(ids.tmp_path(), tags.tmp_path());
+
+        // An interrupted write of one file isn't taken for the other one's
+        fs::write(tags.tmp_path(), b"{").unwrap();
+        ids.write(b"{}").unwrap();
+        let (_, recovery) = ids.recover(check);
+        assert_eq!(recovery.outcome, RecoveryOutcome::Clean);
+        let (_, recovery) = tags.recover(check);
+        assert_eq!(recovery.outcome, RecoveryOutcome::RolledBack);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
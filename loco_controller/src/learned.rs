@@ -0,0 +1,254 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+};
+
+use loco_protocol::{Direction, LocoId, Speed};
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::rail_network::{CheckpointId, SegmentId};
+
+// Commands kept per profile, the driver fiddling with the throttle past that
+// only makes the recording dropped
+const MAX_PROFILE_POINTS: usize = 64;
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+pub struct ProfilePoint {
+    // Time since the loco started through the segment
+    offset_ms: u32,
+    duty_cycle: u8,
+}
+
+/**
+ * Duty cycles a driver ran a loco at through a segment, in one direction, as
+ * recorded the last time the loco went all the way through it by hand.
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct DrivingProfile {
+    loco_id: LocoId,
+    from: CheckpointId,
+    to: CheckpointId,
+    direction: Direction,
+    points: Vec<ProfilePoint>,
+    duration_ms: u32,
+}
+
+impl DrivingProfile {
+    fn duty_cycle(&self, elapsed_us: u64) -> Option<u8> {
+        let elapsed_ms = u32::try_from(elapsed_us / 1000).unwrap_or(u32::MAX);
+        self.points
+            .iter()
+            .take_while(|p| p.offset_ms <= elapsed_ms)
+            .last()
+            .map(|p| p.duty_cycle)
+    }
+}
+
+struct Recording {
+    from: CheckpointId,
+    direction: Option<Direction>,
+    // None until the loco moves, a standing start being timed from then on
+    started_us: Option<u64>,
+    points: Vec<ProfilePoint>,
+}
+
+impl Recording {
+    fn new(from: CheckpointId, started_us: u64, last_command: Option<(Direction, u8)>) -> Self {
+        let mut recording = Recording {
+            from,
+            direction: None,
+            started_us: None,
+            points: Vec::new(),
+        };
+        if let Some((direction, duty_cycle)) = last_command {
+            recording.command(direction, duty_cycle, started_us);
+        }
+        recording
+    }
+
+    // Tells whether the recording is still worth going on with
+    fn command(&mut self, direction: Direction, duty_cycle: u8, now_us: u64) -> bool {
+        if self.direction.is_some_and(|d| d != direction) {
+            return false;
+        }
+
+        let Some(started_us) = self.started_us else {
+            if duty_cycle > 0 {
+                self.direction = Some(direction);
+                self.started_us = Some(now_us);
+                self.points.push(ProfilePoint {
+                    offset_ms: 0,
+                    duty_cycle,
+                });
+            }
+            return true;
+        };
+
+        // The Oracle decides where the locos stop, a profile doesn't
+        if duty_cycle == 0 || self.points.len() == MAX_PROFILE_POINTS {
+            return false;
+        }
+
+        let offset_ms = u32::try_from(now_us.saturating_sub(started_us) / 1000).unwrap_or(u32::MAX);
+        match self.points.last_mut() {
+            Some(last) if last.offset_ms == offset_ms => last.duty_cycle = duty_cycle,
+            _ => self.points.push(ProfilePoint {
+                offset_ms,
+                duty_cycle,
+            }),
+        }
+        true
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct LearnedDrivingDescription {
+    recording: BTreeSet<LocoId>,
+    profiles: Vec<DrivingProfile>,
+}
+
+#[derive(Default)]
+struct Recorder {
+    // Locos whose driving is being learned
+    recording: BTreeSet<LocoId>,
+    recordings: BTreeMap<LocoId, Recording>,
+    last_commands: BTreeMap<LocoId, (Direction, u8)>,
+    profiles: BTreeMap<(LocoId, CheckpointId, CheckpointId), DrivingProfile>,
+}
+
+/**
+ * Learns how a driver runs a loco through each segment while the Oracle is
+ * off, so that the Oracle can run the loco the same way through the segments
+ * it has a profile for, rather than at a steady speed. Only the locos asked
+ * for are recorded, which keeps the calibration runs and such from being
+ * learned. A recording starts when the loco goes by a checkpoint, and becomes
+ * the profile of the segment when it reaches the next one without having
+ * stopped or reversed in between.
+ */
+#[derive(Default)]
+pub struct LearnedDriving {
+    recorder: Mutex<Recorder>,
+}
+
+impl LearnedDriving {
+    pub fn hit(
+        &self,
+        loco_id: LocoId,
+        checkpoint_id: CheckpointId,
+        timestamp_us: u64,
+        manual: bool,
+    ) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if !manual || !recorder.recording.contains(&loco_id) {
+            recorder.recordings.remove(&loco_id);
+            return;
+        }
+
+        if let Some(recording) = recorder.recordings.remove(&loco_id)
+            && let (Some(direction), Some(started_us)) = (recording.direction, recording.started_us)
+            && recording.from != checkpoint_id
+            && TryInto::<SegmentId>::try_into((recording.from, checkpoint_id)).is_ok()
+        {
+            info!(
+                "LearnedDriving::hit(): {} learned {:?} to {:?}",
+                loco_id, recording.from, checkpoint_id
+            );
+            recorder.profiles.insert(
+                (loco_id, recording.from, checkpoint_id),
+                DrivingProfile {
+                    loco_id,
+                    from: recording.from,
+                    to: checkpoint_id,
+                    direction,
+                    points: recording.points,
+                    duration_ms: u32::try_from(timestamp_us.saturating_sub(started_us) / 1000)
+                        .unwrap_or(u32::MAX),
+                },
+            );
+        }
+
+        let last_command = recorder.last_commands.get(&loco_id).copied();
+        recorder.recordings.insert(
+            loco_id,
+            Recording::new(checkpoint_id, timestamp_us, last_command),
+        );
+    }
+
+    pub fn command(
+        &self,
+        loco_id: LocoId,
+        direction: Direction,
+        speed: Speed,
+        now_us: u64,
+        manual: bool,
+    ) {
+        let mut recorder = self.recorder.lock().unwrap();
+        let duty_cycle = speed.duty_cycle();
+        recorder
+            .last_commands
+            .insert(loco_id, (direction, duty_cycle));
+        if !manual || !recorder.recording.contains(&loco_id) {
+            recorder.recordings.remove(&loco_id);
+            return;
+        }
+
+        if let Some(recording) = recorder.recordings.get_mut(&loco_id)
+            && !recording.command(direction, duty_cycle, now_us)
+        {
+            debug!("LearnedDriving::command(): {} recording dropped", loco_id);
+            recorder.recordings.remove(&loco_id);
+        }
+    }
+
+    // Whatever the loco did while nobody was watching doesn't make a profile
+    pub fn interrupt(&self, loco_id: LocoId) {
+        let mut recorder = self.recorder.lock().unwrap();
+        recorder.recordings.remove(&loco_id);
+        recorder.last_commands.remove(&loco_id);
+    }
+
+    // Speed at which the loco ran through the segment by then, if it ever did
+    pub fn speed(
+        &self,
+        loco_id: LocoId,
+        from: CheckpointId,
+        to: CheckpointId,
+        elapsed_us: u64,
+    ) -> Option<Speed> {
+        self.recorder
+            .lock()
+            .unwrap()
+            .profiles
+            .get(&(loco_id, from, to))?
+            .duty_cycle(elapsed_us)
+            .map(Speed::PwmDutyCycle)
+    }
+
+    // Recording starts with the next checkpoint the loco goes by
+    pub fn set_recording(&self, loco_id: LocoId, recording: bool) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if recording {
+            recorder.recording.insert(loco_id);
+        } else {
+            recorder.recording.remove(&loco_id);
+            recorder.recordings.remove(&loco_id);
+        }
+    }
+
+    pub fn describe(&self) -> LearnedDrivingDescription {
+        let recorder = self.recorder.lock().unwrap();
+        LearnedDrivingDescription {
+            recording: recorder.recording.clone(),
+            profiles: recorder.profiles.values().cloned().collect(),
+        }
+    }
+
+    pub fn forget(&self, loco_id: LocoId) {
+        self.recorder
+            .lock()
+            .unwrap()
+            .profiles
+            .retain(|(id, _, _), _| *id != loco_id);
+    }
+}
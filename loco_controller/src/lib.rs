@@ -0,0 +1,37 @@
+pub mod backend;
+pub mod buffer_stops;
+pub mod calibration;
+pub mod command_ids;
+pub mod config;
+pub mod consist;
+pub mod correlation;
+pub mod day_program;
+pub mod event_log;
+pub mod frame_trace;
+pub mod history;
+pub mod journal;
+pub mod learned;
+pub mod live;
+pub mod log_filter;
+pub mod maintenance;
+pub mod oracle;
+pub mod oracle_trace;
+pub mod passing;
+pub mod plugin;
+pub mod power;
+pub mod profile;
+pub mod public;
+pub mod rail_network;
+pub mod rate_limit;
+pub mod routes;
+pub mod safety;
+pub mod scripts;
+pub mod serial_bus;
+pub mod signals;
+pub mod snapshot;
+pub mod startup;
+pub mod state;
+pub mod stats;
+pub mod supervisor;
+pub mod switch_order;
+pub mod tags;
@@ -0,0 +1,143 @@
+use std::{sync::Arc, thread};
+
+use actix_ws::{Closed, Message, MessageStream, Session};
+use log::{debug, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    backend::{Backend, Event},
+    history::HistoryEntry,
+};
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct LiveQuery {
+    // Sequence number of the last event the client got, either from
+    // /events or before reconnecting, for the ones it missed to come first
+    cursor: Option<u64>,
+    // Comma separated event types, i.e sensor_hit,oracle_decision
+    types: Option<String>,
+}
+
+struct LiveFilter {
+    types: Option<Vec<String>>,
+}
+
+impl LiveFilter {
+    fn new(types: Option<&str>) -> Self {
+        LiveFilter {
+            types: types.map(|t| t.split(',').map(|s| s.trim().to_string()).collect()),
+        }
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        let Some(types) = self.types.as_ref() else {
+            return true;
+        };
+        // The type is only known once serialized
+        serde_json::to_value(event)
+            .ok()
+            .and_then(|v| v.get("type")?.as_str().map(str::to_string))
+            .is_some_and(|t| types.contains(&t))
+    }
+}
+
+// Sends the events logged since the cursor, which then moves past them
+async fn flush(
+    session: &mut Session,
+    entries: Vec<HistoryEntry<Event>>,
+    filter: &LiveFilter,
+    cursor: &mut u64,
+) -> Result<(), Closed> {
+    for entry in entries {
+        *cursor = entry.id();
+        if !filter.matches(entry.item()) {
+            continue;
+        }
+        match serde_json::to_string(&entry) {
+            Ok(text) => session.text(text).await?,
+            Err(e) => warn!(
+                "live::flush(): Error serializing event {}: {}",
+                entry.id(),
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Pushes the events to a WebSocket client as they happen, each one along with
+ * its sequence number and timestamp from the event log. Events are read from
+ * the log, the subscription only waking the stream up, so that none is missed
+ * or sent twice, and a client coming back with the last sequence number it
+ * got resumes where it left off as long as the log still has it.
+ */
+pub async fn stream_events(
+    backend: Arc<Backend>,
+    mut session: Session,
+    mut messages: MessageStream,
+    query: LiveQuery,
+) {
+    // Subscribing before reading the log, so that nothing logged in between
+    // is left waiting for the next event
+    let events = backend.subscribe();
+    let filter = LiveFilter::new(query.types.as_deref());
+    let mut cursor = query.cursor.unwrap_or_else(|| backend.last_event_seq());
+    debug!("live::stream_events(): Streaming from {}", cursor);
+
+    // The subscription blocks, hence it's waited on by a thread of its own,
+    // which goes away with the client on the next event
+    let (wake, mut woken) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for _ in events.iter() {
+            if wake.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut result = flush(
+        &mut session,
+        backend.events_after(cursor),
+        &filter,
+        &mut cursor,
+    )
+    .await;
+    while result.is_ok() {
+        tokio::select! {
+            wake = woken.recv() => {
+                if wake.is_none() {
+                    break;
+                }
+                // A single read of the log covers every event already there
+                while woken.try_recv().is_ok() {}
+                result = flush(
+                    &mut session,
+                    backend.events_after(cursor),
+                    &filter,
+                    &mut cursor,
+                )
+                .await;
+            }
+            message = messages.recv() => match message {
+                Some(Ok(Message::Ping(bytes))) => result = session.pong(&bytes).await,
+                Some(Ok(Message::Close(reason))) => {
+                    debug!("live::stream_events(): Client closed {:?}", reason);
+                    let _ = session.close(reason).await;
+                    return;
+                }
+                // Clients have nothing else to say
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    warn!("live::stream_events(): {}", e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+
+    let _ = session.close(None).await;
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use env_logger::{Builder, Env, Logger};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError, debug};
+
+use crate::correlation;
+
+struct Filtering {
+    logger: Logger,
+    // Levels changed at runtime, by target
+    overrides: BTreeMap<String, LevelFilter>,
+}
+
+/**
+ * Filters the logs as told by RUST_LOG, on top of which the level of any
+ * target can be changed at runtime. Debug logs can then be turned on for a
+ * single module during a show, without restarting and losing the state.
+ */
+pub struct LogFilter {
+    filtering: RwLock<Filtering>,
+}
+
+// The log crate takes ownership of the logger, while the HTTP server keeps
+// changing its levels
+struct SharedLogFilter(Arc<LogFilter>);
+
+impl Log for SharedLogFilter {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.filtering.read().unwrap().logger.enabled(metadata)
+    }
+
+    // Logs made on behalf of an HTTP request or an Oracle cycle are prefixed
+    // with its correlation ID
+    fn log(&self, record: &Record) {
+        let filtering = self.0.filtering.read().unwrap();
+        match correlation::current() {
+            Some(id) => filtering.logger.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", id, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => filtering.logger.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.0.filtering.read().unwrap().logger.flush()
+    }
+}
+
+impl LogFilter {
+    // Installs the filter as the global logger, which can only be done once
+    pub fn init() -> Result<Arc<Self>, SetLoggerError> {
+        let logger = build_logger(&BTreeMap::new());
+        let max_level = logger.filter();
+        let log_filter = Arc::new(LogFilter {
+            filtering: RwLock::new(Filtering {
+                logger,
+                overrides: BTreeMap::new(),
+            }),
+        });
+
+        log::set_boxed_logger(Box::new(SharedLogFilter(log_filter.clone())))?;
+        log::set_max_level(max_level);
+
+        Ok(log_filter)
+    }
+
+    // None goes back to whatever RUST_LOG tells for this target
+    pub fn set_level(&self, target: &str, level: Option<LevelFilter>) {
+        // Before locking, since logging goes through the lock as well
+        debug!("LogFilter::set_level(): {} {:?}", target, level);
+
+        let mut filtering = self.filtering.write().unwrap();
+        match level {
+            Some(level) => filtering.overrides.insert(target.to_string(), level),
+            None => filtering.overrides.remove(target),
+        };
+        filtering.logger = build_logger(&filtering.overrides);
+        log::set_max_level(filtering.logger.filter());
+    }
+
+    pub fn overrides(&self) -> BTreeMap<String, LevelFilter> {
+        self.filtering.read().unwrap().overrides.clone()
+    }
+}
+
+// Same default as env_logger, only errors unless RUST_LOG tells otherwise,
+// while the most specific target wins
+fn build_logger(overrides: &BTreeMap<String, LevelFilter>) -> Logger {
+    let mut builder = Builder::new();
+    builder
+        .filter_level(LevelFilter::Error)
+        .parse_env(Env::default());
+    for (target, level) in overrides {
+        builder.filter_module(target, *level);
+    }
+    builder.build()
+}
@@ -1,44 +1,106 @@
 use actix_web::{
-    App, HttpResponse, HttpServer, Responder, body::BoxBody, get, http::StatusCode, post, web,
+    App, HttpRequest, HttpResponse, HttpServer, Responder, body::BoxBody, delete, get,
+    http::StatusCode, middleware::from_fn, post, put, web,
 };
 use clap::Parser;
-use loco_protocol::{ActuatorId, ActuatorType, Direction, LocoId, Speed, SwitchRailsState};
-use log::{debug, error};
+use loco_protocol::{
+    ActuatorId, ActuatorType, Direction, LocoFunctions, LocoId, SensorId, Speed, SpeedSteps,
+    SwitchRailsState, TrackPowerState,
+};
+use log::{LevelFilter, SetLoggerError, debug, error};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     io,
-    net::TcpListener,
-    sync::Arc,
+    path::PathBuf,
+    sync::{
+        Arc,
+        mpsc::{Receiver, RecvTimeoutError},
+    },
     thread::{self, sleep},
     time::Duration,
 };
 use thiserror::Error;
+use tokio::{net::TcpListener, runtime::Builder as RuntimeBuilder};
 
-mod backend;
-mod oracle;
-mod rail_network;
-use crate::{
-    backend::{Backend, LocoIntent, OracleMode},
+use loco_controller::{
+    backend::{Alarm, AlarmsFilter, Backend, Error as BackendError, Event, LocoIntent, OracleMode},
+    buffer_stops::{BufferStops, Error as BufferStopsError},
+    calibration::Calibration,
+    command_ids::{CommandIds, Error as CommandIdsError},
+    config::{self, Config, ConfigLoader, OracleConfig, SafetyConfig},
+    consist::{CHECK_PERIOD as CONSISTS_CHECK_PERIOD, ConsistTracker},
+    correlation::{self, CorrelationId, correlate},
+    day_program::{CHECK_PERIOD as DAY_CHECK_PERIOD, DayPrograms},
+    event_log::ReplayQuery,
+    frame_trace::FramesFilter,
+    history::HistoryQuery,
+    live::{LiveQuery, stream_events},
+    log_filter::LogFilter,
+    maintenance::{MaintenanceSchedule, TimeOfDay},
     oracle::Oracle,
+    oracle_trace::{Error as OracleTraceError, OracleTracer},
+    plugin::{PluginHost, builtin},
+    power::PowerDistricts,
+    profile::Profiles,
+    public,
+    rail_network::CheckpointId,
+    rate_limit::{RateLimiter, client_id, rate_limit},
+    routes::{CHECK_PERIOD as ROUTES_CHECK_PERIOD, RoutePrograms},
+    safety::SafetyMonitor,
+    scripts::{Error as ScriptsError, Scripts},
+    signals::Signals,
+    snapshot::{Error as SnapshotError, SnapshotParams, Snapshots},
+    startup::StartupSequence,
+    state::{StateDiffQuery, StateTracker},
+    stats::{StatsQuery, StatsTracker},
+    supervisor::{Heartbeat, Supervisor},
+    switch_order::SwitchOrder,
+    tags::{Error as TagsError, TagDatabase, TagEntry, TagUid},
 };
 
 #[derive(Debug, Error)]
 enum Error {
     #[error("Error binding listener {0}")]
     BindListener(#[source] io::Error),
+    #[error("Error loading the command IDs: {0}")]
+    LoadCommandIds(#[source] CommandIdsError),
+    #[error("Error loading configuration: {0}")]
+    Config(#[source] config::Error),
     #[error("Error running HTTP server {0}")]
     HttpServer(#[source] io::Error),
-    #[error("Error setting stream read timeout {0}")]
-    StreamSetReadTimeout(#[source] io::Error),
+    #[error("Error setting up the logger: {0}")]
+    InitLogger(#[source] SetLoggerError),
+    #[error("Error starting the backend runtime: {0}")]
+    Runtime(#[source] io::Error),
+    #[error("Error setting up the Oracle trace: {0}")]
+    TraceOracle(#[source] OracleTraceError),
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+// Speed can either be given as a coarse level, or as a DCC speed step
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[serde(untagged)]
+enum SpeedParam {
+    Level(Speed),
+    Steps(SpeedSteps),
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 struct ControlLocoParams {
     loco_id: LocoId,
     direction: Direction,
-    speed: Speed,
+    speed: SpeedParam,
+    // Time over which the loco reaches the new speed, right away if not set
+    #[serde(default)]
+    ramp_ms: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct ControlLocoFunctionsParams {
+    loco_id: LocoId,
+    functions: LocoFunctions,
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
@@ -54,23 +116,139 @@ struct DriveSwitchRailsParams {
     state: SwitchRailsState,
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct DriveTrackPowerParams {
+    state: TrackPowerState,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct PrepareRestartParams {
+    hold_secs: u8,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ActivateProfileParams {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ActivateDayProgramParams {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct SetFastClockParams {
+    time: TimeOfDay,
+    // Ratio left as it is if not set
+    #[serde(default)]
+    fast_clock_ratio: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct FrameTracingParams {
+    enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+#[serde(default)]
+struct LocoStatusQuery {
+    // Ask the loco rather than returning the status it last reported
+    fresh: bool,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct SetLocationParams {
+    checkpoint: CheckpointId,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct SpeedScaleParams {
+    percent: u8,
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, Default)]
+#[serde(default)]
+struct AlarmsQuery {
+    // Lists the records of the active alarms, along with the codes of the
+    // errors reported by the devices
+    details: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct LogLevelParams {
+    target: String,
+    // None goes back to what RUST_LOG tells
+    level: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct TrimParams {
+    loco_id: LocoId,
+    trim_percent: u8,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct LearnedDrivingParams {
+    loco_id: LocoId,
+    recording: bool,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct AutoTrimParams {
+    locos: [LocoId; 2],
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+struct SensorEventParams {
+    loco: LocoId,
+    sensor: SensorId,
+}
+
+// Locos missing from the roster are reported as not found, while requests
+// failed or rejected by the loco itself are blamed on the loco
+fn loco_error_status(e: &BackendError) -> StatusCode {
+    match e {
+        BackendError::UnknownLoco(_) => StatusCode::NOT_FOUND,
+        BackendError::DeviceFailed(..) | BackendError::RequestRejected(..) => {
+            StatusCode::BAD_GATEWAY
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Actuators the board doesn't have are reported as not found, and a turnout
+// decoder failing to answer is blamed on the serial bus
+fn actuator_error_status(e: &BackendError) -> StatusCode {
+    match e {
+        BackendError::ActuatorNotPresent(..) => StatusCode::NOT_FOUND,
+        BackendError::SerialBus(_) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 #[get("/")]
 async fn index(_data: web::Data<Arc<Backend>>) -> impl Responder {
     HttpResponse::Ok().body("Loco controller running!")
 }
 
 #[get("/loco_status/{loco_id}")]
-async fn loco_status(path: web::Path<LocoId>, data: web::Data<Arc<Backend>>) -> impl Responder {
+async fn loco_status(
+    path: web::Path<LocoId>,
+    query: web::Query<LocoStatusQuery>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
     let loco_id = path.into_inner();
 
-    match data.loco_status(loco_id) {
+    let status = match query.fresh {
+        true => data.loco_status(loco_id).await,
+        false => data.cached_loco_status(loco_id).await,
+    };
+
+    match status {
         Ok(status) => HttpResponse::Ok().json(status),
         Err(e) => {
             error!("loco_status(): {}", e);
-            HttpResponse::with_body(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                BoxBody::new(format!("{}", e)),
-            )
+            HttpResponse::with_body(loco_error_status(&e), BoxBody::new(format!("{}", e)))
         }
     }
 }
@@ -79,35 +257,115 @@ async fn loco_status(path: web::Path<LocoId>, data: web::Data<Arc<Backend>>) ->
 async fn control_loco(
     form: web::Json<ControlLocoParams>,
     data: web::Data<Arc<Backend>>,
+    buffer_stops: web::Data<Arc<BufferStops>>,
 ) -> impl Responder {
     if data.oracle_enabled() {
         let e = "Oracle is running, can't manually control the loco";
         error!("control_loco(): {}", e);
         return HttpResponse::with_body(
             StatusCode::INTERNAL_SERVER_ERROR,
-            BoxBody::new(format!("{}", e)),
+            BoxBody::new(e.to_string()),
         );
     }
 
-    if let Err(e) = data.control_loco(form.loco_id, form.direction, form.speed) {
+    let speed = match form.speed {
+        SpeedParam::Level(speed) => speed,
+        SpeedParam::Steps(steps) => data.speed_from_steps(form.loco_id, steps),
+    };
+
+    let speed = match buffer_stops.guard(form.loco_id, form.direction, speed) {
+        Ok(speed) => speed,
+        Err(e) => {
+            error!("control_loco(): {}", e);
+            let status = match &e {
+                BufferStopsError::LocoLocation(e) => loco_error_status(e),
+                BufferStopsError::BeyondBufferStop(..) => StatusCode::CONFLICT,
+            };
+            return HttpResponse::with_body(status, BoxBody::new(format!("{}", e)));
+        }
+    };
+
+    let ramp = form.ramp_ms.map(|ms| Duration::from_millis(ms.into()));
+    if let Err(e) = data
+        .control_loco(form.loco_id, form.direction, speed, ramp)
+        .await
+    {
         error!("control_loco(): {}", e);
-        return HttpResponse::with_body(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            BoxBody::new(format!("{}", e)),
-        );
+        return HttpResponse::with_body(loco_error_status(&e), BoxBody::new(format!("{}", e)));
     }
 
     HttpResponse::Ok().body(format!(
         "Move {:?} loco {:?} at {:?} speed",
-        form.direction, form.loco_id, form.speed
+        form.direction, form.loco_id, speed
     ))
 }
 
+// Functions don't affect how the loco runs, hence they remain available while
+// the Oracle is running
+#[post("/control_loco_functions")]
+async fn control_loco_functions(
+    form: web::Json<ControlLocoFunctionsParams>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    match data
+        .control_loco_functions(form.loco_id, form.functions)
+        .await
+    {
+        Ok(functions) => HttpResponse::Ok().body(format!(
+            "Set functions {:?} on loco {:?}",
+            functions, form.loco_id
+        )),
+        Err(e) => {
+            error!("control_loco_functions(): {}", e);
+            HttpResponse::with_body(loco_error_status(&e), BoxBody::new(format!("{}", e)))
+        }
+    }
+}
+
+#[post("/loco/{loco_id}/set_location")]
+async fn set_loco_location(
+    req: HttpRequest,
+    path: web::Path<LocoId>,
+    form: web::Json<SetLocationParams>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    let loco_id = path.into_inner();
+    if let Err(e) = data.check_loco(loco_id) {
+        error!("set_loco_location(): {}", e);
+        return HttpResponse::with_body(loco_error_status(&e), BoxBody::new(format!("{}", e)));
+    }
+
+    data.set_loco_location(loco_id, Some(form.checkpoint), &client_id(&req));
+    HttpResponse::Ok().body(format!(
+        "Loco {:?} located at {:?}",
+        loco_id, form.checkpoint
+    ))
+}
+
+#[post("/occupancy/clear")]
+async fn clear_occupancy(req: HttpRequest, data: web::Data<Arc<Backend>>) -> impl Responder {
+    data.clear_occupancy(&client_id(&req));
+    HttpResponse::Ok().body("Occupancy cleared")
+}
+
+#[get("/occupancy/corrections")]
+async fn location_corrections(
+    query: web::Query<HistoryQuery>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(data.location_corrections(&query))
+}
+
 #[post("/loco_intent")]
 async fn loco_intent(
     form: web::Json<LocoIntentParams>,
     data: web::Data<Arc<Backend>>,
 ) -> impl Responder {
+    if let Err(e) = data.check_loco(form.loco_id) {
+        error!("loco_intent(): {}", e);
+        return HttpResponse::with_body(loco_error_status(&e), BoxBody::new(format!("{}", e)));
+    }
+
     data.set_loco_intent(form.loco_id, form.loco_intent);
     HttpResponse::Ok().body(format!(
         "Setting loco intent {:?} for {:?}",
@@ -125,146 +383,1421 @@ async fn drive_switch_rails(
         error!("drive_switch_rails(): {}", e);
         return HttpResponse::with_body(
             StatusCode::INTERNAL_SERVER_ERROR,
-            BoxBody::new(format!("{}", e)),
+            BoxBody::new(e.to_string()),
         );
     }
 
-    if let Err(e) = data.drive_actuator(
-        form.actuator_id,
-        ActuatorType::SwitchRails,
-        form.state.into(),
-    ) {
+    if let Err(e) = data
+        .drive_actuator(
+            form.actuator_id,
+            ActuatorType::SwitchRails,
+            form.state.into(),
+        )
+        .await
+    {
         error!("drive_switch_rails(): {}", e);
+        return HttpResponse::with_body(actuator_error_status(&e), BoxBody::new(format!("{}", e)));
+    }
+
+    HttpResponse::Ok().body(format!("Drive {:?} to {:?}", form.actuator_id, form.state))
+}
+
+#[post("/drive_track_power")]
+async fn drive_track_power(
+    form: web::Json<DriveTrackPowerParams>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    if let Err(e) = data
+        .drive_actuator(
+            ActuatorId::TrackPower,
+            ActuatorType::TrackPower,
+            form.state.into(),
+        )
+        .await
+    {
+        error!("drive_track_power(): {}", e);
+        return HttpResponse::with_body(actuator_error_status(&e), BoxBody::new(format!("{}", e)));
+    }
+
+    HttpResponse::Ok().body(format!("Drive track power to {:?}", form.state))
+}
+
+#[post("/prepare_restart")]
+async fn prepare_restart(
+    form: web::Json<PrepareRestartParams>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    if let Err(e) = data.prepare_restart(form.hold_secs).await {
+        error!("prepare_restart(): {}", e);
         return HttpResponse::with_body(
             StatusCode::INTERNAL_SERVER_ERROR,
             BoxBody::new(format!("{}", e)),
         );
     }
 
-    HttpResponse::Ok().body(format!("Drive {:?} to {:?}", form.actuator_id, form.state))
+    HttpResponse::Ok().body(format!(
+        "Locos hold their state for {}s on disconnect",
+        form.hold_secs
+    ))
+}
+
+#[get("/network")]
+async fn network(profiles: web::Data<Arc<Profiles>>) -> impl Responder {
+    HttpResponse::Ok().json(profiles.network())
+}
+
+#[get("/profile")]
+async fn active_profile(profiles: web::Data<Arc<Profiles>>) -> impl Responder {
+    HttpResponse::Ok().json(profiles.describe())
+}
+
+#[post("/profile/activate")]
+async fn activate_profile(
+    form: web::Json<ActivateProfileParams>,
+    profiles: web::Data<Arc<Profiles>>,
+) -> impl Responder {
+    // Waits for the locos to tell whether they're stopped, which mustn't
+    // hold up the HTTP worker
+    let profiles = profiles.get_ref().clone();
+    let name = form.name.clone();
+    let result = match web::block(move || profiles.activate(&name)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        error!("activate_profile(): {}", e);
+        return HttpResponse::with_body(StatusCode::INTERNAL_SERVER_ERROR, BoxBody::new(e));
+    }
+
+    HttpResponse::Ok().body(format!("Profile {} activated", form.name))
+}
+
+#[get("/alarms")]
+async fn alarms(query: web::Query<AlarmsQuery>, data: web::Data<Arc<Backend>>) -> impl Responder {
+    if query.details {
+        HttpResponse::Ok().json(data.active_alarms())
+    } else {
+        HttpResponse::Ok().json(data.alarms())
+    }
+}
+
+#[get("/alarms_history")]
+async fn alarms_history(
+    query: web::Query<HistoryQuery>,
+    filter: web::Query<AlarmsFilter>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(data.alarms_history(&query, &filter))
+}
+
+#[get("/devices")]
+async fn devices(data: web::Data<Arc<Backend>>) -> impl Responder {
+    HttpResponse::Ok().json(data.devices())
+}
+
+#[get("/inputs_status")]
+async fn inputs_status(data: web::Data<Arc<Backend>>) -> impl Responder {
+    HttpResponse::Ok().json(data.inputs_status())
+}
+
+#[post("/clear_alarms")]
+async fn clear_alarms(data: web::Data<Arc<Backend>>) -> impl Responder {
+    data.clear_alarms();
+    HttpResponse::Ok().body("Alarms cleared")
+}
+
+// Never refused, whatever drives the locos, so that anyone can stop them
+#[post("/emergency_stop")]
+async fn emergency_stop(req: HttpRequest, data: web::Data<Arc<Backend>>) -> impl Responder {
+    data.emergency_stop(
+        &format!("requested by {}", client_id(&req)),
+        Alarm::EmergencyStop,
+    )
+    .await;
+    HttpResponse::Ok().body("Emergency stop sent to every connected loco")
+}
+
+// Reasons not to hand the layout over to the Oracle, if any
+fn auto_mode_refusal(
+    backend: &Backend,
+    startup: &StartupSequence,
+    restoring: Option<u32>,
+) -> Option<HttpResponse> {
+    // The Oracle can't be trusted with a layout in an unknown state
+    if !startup.is_ready() {
+        return Some(HttpResponse::with_body(
+            StatusCode::SERVICE_UNAVAILABLE,
+            BoxBody::new("Startup sequence not completed yet".to_string()),
+        ));
+    }
+
+    let mut blockers = backend.auto_mode_blockers();
+    if let Some(id) = restoring {
+        blockers.push(format!("snapshot {} restore not confirmed", id));
+    }
+    if !blockers.is_empty() {
+        let e = format!("Can't enable the Oracle: {}", blockers.join(", "));
+        error!("auto_mode_refusal(): {}", e);
+        return Some(HttpResponse::with_body(
+            StatusCode::CONFLICT,
+            BoxBody::new(e),
+        ));
+    }
+
+    None
 }
 
 #[post("/oracle_mode")]
-async fn oracle_mode(form: web::Json<OracleMode>, data: web::Data<Arc<Backend>>) -> impl Responder {
+async fn oracle_mode(
+    form: web::Json<OracleMode>,
+    data: web::Data<Arc<Backend>>,
+    startup: web::Data<Arc<StartupSequence>>,
+    snapshots: web::Data<Arc<Snapshots>>,
+) -> impl Responder {
+    if matches!(form.0, OracleMode::Auto)
+        && let Some(refusal) = auto_mode_refusal(&data, &startup, snapshots.restoring())
+    {
+        return refusal;
+    }
+
     data.set_oracle_mode(form.0);
     HttpResponse::Ok().body(format!("Setting Oracle to mode {:?}", form.0))
 }
 
+fn snapshot_error_status(e: &SnapshotError) -> StatusCode {
+    match e {
+        SnapshotError::UnknownSnapshot(_) => StatusCode::NOT_FOUND,
+        SnapshotError::NotRestoring(_) => StatusCode::CONFLICT,
+        SnapshotError::LocoState(_, e) => loco_error_status(e),
+        SnapshotError::DriveSwitchRails(e) => actuator_error_status(e),
+    }
+}
+
+#[post("/snapshot")]
+async fn take_snapshot(
+    form: web::Json<SnapshotParams>,
+    snapshots: web::Data<Arc<Snapshots>>,
+) -> impl Responder {
+    match snapshots.take(&form) {
+        Ok(snapshot) => HttpResponse::Ok().json(snapshot),
+        Err(e) => {
+            error!("take_snapshot(): {}", e);
+            HttpResponse::with_body(snapshot_error_status(&e), BoxBody::new(format!("{}", e)))
+        }
+    }
+}
+
+#[get("/snapshots")]
+async fn list_snapshots(snapshots: web::Data<Arc<Snapshots>>) -> impl Responder {
+    HttpResponse::Ok().json(snapshots.snapshots())
+}
+
+#[post("/snapshot/{id}/restore")]
+async fn restore_snapshot(
+    req: HttpRequest,
+    path: web::Path<u32>,
+    snapshots: web::Data<Arc<Snapshots>>,
+) -> impl Responder {
+    match snapshots.restore(path.into_inner(), &client_id(&req)).await {
+        Ok(snapshot) => HttpResponse::Ok().json(snapshot),
+        Err(e) => {
+            error!("restore_snapshot(): {}", e);
+            HttpResponse::with_body(snapshot_error_status(&e), BoxBody::new(format!("{}", e)))
+        }
+    }
+}
+
+#[post("/snapshot/{id}/confirm")]
+async fn confirm_snapshot(
+    req: HttpRequest,
+    path: web::Path<u32>,
+    data: web::Data<Arc<Backend>>,
+    startup: web::Data<Arc<StartupSequence>>,
+    snapshots: web::Data<Arc<Snapshots>>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let mode = match snapshots.pending_oracle_mode(id) {
+        Ok(mode) => mode,
+        Err(e) => {
+            error!("confirm_snapshot(): {}", e);
+            return HttpResponse::with_body(
+                snapshot_error_status(&e),
+                BoxBody::new(format!("{}", e)),
+            );
+        }
+    };
+
+    // Same checks as asking for the Oracle directly, but for the restore
+    // being confirmed right now
+    if matches!(mode, OracleMode::Auto)
+        && let Some(refusal) = auto_mode_refusal(&data, &startup, None)
+    {
+        return refusal;
+    }
+
+    match snapshots.confirm(id, &client_id(&req)) {
+        Ok(mode) => HttpResponse::Ok().body(format!(
+            "Snapshot {} restored, setting Oracle to mode {:?}",
+            id, mode
+        )),
+        Err(e) => {
+            error!("confirm_snapshot(): {}", e);
+            HttpResponse::with_body(snapshot_error_status(&e), BoxBody::new(format!("{}", e)))
+        }
+    }
+}
+
+#[get("/readyz")]
+async fn readyz(startup: web::Data<Arc<StartupSequence>>) -> impl Responder {
+    let status = startup.status();
+    if startup.is_ready() {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
+
+#[get("/healthz")]
+async fn healthz(supervisor: web::Data<Arc<Supervisor>>) -> impl Responder {
+    let health = supervisor.health();
+    if health.is_healthy() {
+        HttpResponse::Ok().json(health)
+    } else {
+        HttpResponse::ServiceUnavailable().json(health)
+    }
+}
+
+#[get("/power_districts")]
+async fn power_districts_status(districts: web::Data<Arc<PowerDistricts>>) -> impl Responder {
+    HttpResponse::Ok().json(districts.status())
+}
+
+#[get("/tags")]
+async fn list_tags(tags: web::Data<Arc<TagDatabase>>) -> impl Responder {
+    HttpResponse::Ok().json(tags.describe())
+}
+
+#[post("/tags")]
+async fn register_tag(
+    form: web::Json<TagEntry>,
+    tags: web::Data<Arc<TagDatabase>>,
+) -> impl Responder {
+    if let Err(e) = tags.add(form.0) {
+        error!("register_tag(): {}", e);
+        let status = match e {
+            TagsError::Collision(..) => StatusCode::CONFLICT,
+            TagsError::UnknownLoco(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return HttpResponse::with_body(status, BoxBody::new(format!("{}", e)));
+    }
+
+    HttpResponse::Ok().body("Tag registered")
+}
+
+#[post("/tags/{uid}/remove")]
+async fn remove_tag(path: web::Path<TagUid>, tags: web::Data<Arc<TagDatabase>>) -> impl Responder {
+    let uid = path.into_inner();
+    if let Err(e) = tags.remove(uid) {
+        error!("remove_tag(): {}", e);
+        let status = match e {
+            TagsError::FirmwareTag(..) | TagsError::RosterTag(..) => StatusCode::CONFLICT,
+            TagsError::UnknownTag(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return HttpResponse::with_body(status, BoxBody::new(format!("{}", e)));
+    }
+
+    HttpResponse::Ok().body(format!("Tag {} removed", uid))
+}
+
+#[get("/consists")]
+async fn consists_status(consists: web::Data<Arc<ConsistTracker>>) -> impl Responder {
+    HttpResponse::Ok().json(consists.status())
+}
+
+#[get("/program")]
+async fn day_program_status(day_programs: web::Data<Arc<DayPrograms>>) -> impl Responder {
+    HttpResponse::Ok().json(day_programs.status())
+}
+
+#[post("/program/activate")]
+async fn activate_day_program(
+    form: web::Json<ActivateDayProgramParams>,
+    day_programs: web::Data<Arc<DayPrograms>>,
+) -> impl Responder {
+    // Sets the functions of the locos, which mustn't hold up the HTTP worker
+    let day_programs = day_programs.get_ref().clone();
+    let name = form.name.clone();
+    let result = match web::block(move || day_programs.activate(&name)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        error!("activate_day_program(): {}", e);
+        return HttpResponse::with_body(StatusCode::INTERNAL_SERVER_ERROR, BoxBody::new(e));
+    }
+
+    HttpResponse::Ok().body(format!("Day program {} held", form.name))
+}
+
+#[post("/program/resume")]
+async fn resume_day_programs(day_programs: web::Data<Arc<DayPrograms>>) -> impl Responder {
+    // Switching back to the timetable might set the functions of the locos
+    let day_programs = day_programs.get_ref().clone();
+    if let Err(e) = web::block(move || day_programs.resume()).await {
+        error!("resume_day_programs(): {}", e);
+        return HttpResponse::with_body(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            BoxBody::new(format!("{}", e)),
+        );
+    }
+
+    HttpResponse::Ok().body("Day programs follow the timetable")
+}
+
+#[post("/program/clock")]
+async fn set_fast_clock(
+    form: web::Json<SetFastClockParams>,
+    day_programs: web::Data<Arc<DayPrograms>>,
+) -> impl Responder {
+    // Same as resume_day_programs()
+    let day_programs = day_programs.get_ref().clone();
+    let (time, ratio) = (form.time, form.fast_clock_ratio);
+    let result = match web::block(move || day_programs.set_clock(time, ratio)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        error!("set_fast_clock(): {}", e);
+        return HttpResponse::with_body(StatusCode::INTERNAL_SERVER_ERROR, BoxBody::new(e));
+    }
+
+    HttpResponse::Ok().body(format!("Fast clock set to {}", form.time))
+}
+
+#[get("/route_programs")]
+async fn route_programs_status(routes: web::Data<Arc<RoutePrograms>>) -> impl Responder {
+    HttpResponse::Ok().json(routes.status())
+}
+
+#[get("/safety")]
+async fn safety_status(monitor: web::Data<Arc<SafetyMonitor>>) -> impl Responder {
+    HttpResponse::Ok().json(monitor.status())
+}
+
+#[get("/speed_scale")]
+async fn get_speed_scale(data: web::Data<Arc<Backend>>) -> impl Responder {
+    HttpResponse::Ok().json(SpeedScaleParams {
+        percent: data.speed_scale(),
+    })
+}
+
+#[post("/speed_scale")]
+async fn set_speed_scale(
+    form: web::Json<SpeedScaleParams>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    if let Err(e) = data.set_speed_scale(form.percent) {
+        error!("set_speed_scale(): {}", e);
+        return HttpResponse::with_body(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            BoxBody::new(format!("{}", e)),
+        );
+    }
+
+    HttpResponse::Ok().body(format!("Speed scale set to {}%", form.percent))
+}
+
+#[get("/log_level")]
+async fn log_levels(log_filter: web::Data<Arc<LogFilter>>) -> impl Responder {
+    let overrides: BTreeMap<String, String> = log_filter
+        .overrides()
+        .into_iter()
+        .map(|(target, level)| (target, level.as_str().to_lowercase()))
+        .collect();
+    HttpResponse::Ok().json(overrides)
+}
+
+#[put("/log_level")]
+async fn set_log_level(
+    form: web::Json<LogLevelParams>,
+    log_filter: web::Data<Arc<LogFilter>>,
+) -> impl Responder {
+    let level = match form
+        .level
+        .as_deref()
+        .map(str::parse::<LevelFilter>)
+        .transpose()
+    {
+        Ok(level) => level,
+        Err(e) => {
+            error!("set_log_level(): {:?} {}", form.level, e);
+            return HttpResponse::with_body(
+                StatusCode::BAD_REQUEST,
+                BoxBody::new(format!("Invalid log level {:?}: {}", form.level, e)),
+            );
+        }
+    };
+
+    log_filter.set_level(&form.target, level);
+    match level {
+        Some(level) => {
+            HttpResponse::Ok().body(format!("Log level of {} set to {}", form.target, level))
+        }
+        None => HttpResponse::Ok().body(format!("Log level of {} reset", form.target)),
+    }
+}
+
+#[get("/calibration")]
+async fn get_calibration(calibration: web::Data<Arc<Calibration>>) -> impl Responder {
+    HttpResponse::Ok().json(calibration.describe())
+}
+
+#[post("/calibration")]
+async fn set_trim(
+    form: web::Json<TrimParams>,
+    calibration: web::Data<Arc<Calibration>>,
+) -> impl Responder {
+    if let Err(e) = calibration.set_trim(form.loco_id, form.trim_percent) {
+        error!("set_trim(): {}", e);
+        return HttpResponse::with_body(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            BoxBody::new(format!("{}", e)),
+        );
+    }
+
+    HttpResponse::Ok().body(format!(
+        "Trim of {:?} set to {}%",
+        form.loco_id, form.trim_percent
+    ))
+}
+
+#[post("/calibration/auto_trim")]
+async fn auto_trim(
+    form: web::Json<AutoTrimParams>,
+    calibration: web::Data<Arc<Calibration>>,
+) -> impl Responder {
+    let [first, second] = form.locos;
+    match calibration.auto_trim(first, second) {
+        Ok(trims) => HttpResponse::Ok().json(trims),
+        Err(e) => {
+            error!("auto_trim(): {}", e);
+            HttpResponse::with_body(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                BoxBody::new(format!("{}", e)),
+            )
+        }
+    }
+}
+
+#[post("/calibration/run/{loco_id}")]
+async fn calibration_run(
+    path: web::Path<LocoId>,
+    calibration: web::Data<Arc<Calibration>>,
+) -> impl Responder {
+    let loco_id = path.into_inner();
+    // Makes sure the layout is ready for the run, which mustn't hold up the
+    // HTTP worker
+    let calibration = calibration.get_ref().clone();
+    let result = match web::block(move || calibration.start_run(loco_id)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        error!("calibration_run(): {}", e);
+        return HttpResponse::with_body(StatusCode::INTERNAL_SERVER_ERROR, BoxBody::new(e));
+    }
+
+    HttpResponse::Accepted().body(format!("Calibration run started for loco {:?}", loco_id))
+}
+
+#[get("/learned_driving")]
+async fn get_learned_driving(data: web::Data<Arc<Backend>>) -> impl Responder {
+    HttpResponse::Ok().json(data.learned_driving())
+}
+
+#[post("/learned_driving")]
+async fn set_learned_driving(
+    form: web::Json<LearnedDrivingParams>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    if let Err(e) = data.set_learned_driving_recording(form.loco_id, form.recording) {
+        error!("set_learned_driving(): {}", e);
+        return HttpResponse::with_body(loco_error_status(&e), BoxBody::new(format!("{}", e)));
+    }
+
+    HttpResponse::Ok().body(format!(
+        "Recording of {:?} {}",
+        form.loco_id,
+        if form.recording { "started" } else { "stopped" }
+    ))
+}
+
+#[post("/learned_driving/{loco_id}/forget")]
+async fn forget_learned_driving(
+    path: web::Path<LocoId>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    let loco_id = path.into_inner();
+    if let Err(e) = data.forget_learned_driving(loco_id) {
+        error!("forget_learned_driving(): {}", e);
+        return HttpResponse::with_body(loco_error_status(&e), BoxBody::new(format!("{}", e)));
+    }
+
+    HttpResponse::Ok().body(format!("Profiles of {:?} forgotten", loco_id))
+}
+
+#[get("/events")]
+async fn list_events(
+    query: web::Query<HistoryQuery>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(data.events(&query))
+}
+
+// Upgrades to a WebSocket pushing the events as they happen
+#[get("/ws")]
+async fn live_events(
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<LiveQuery>,
+    data: web::Data<Arc<Backend>>,
+) -> actix_web::Result<HttpResponse> {
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(stream_events(
+        data.get_ref().clone(),
+        session,
+        messages,
+        query.into_inner(),
+    ));
+
+    Ok(response)
+}
+
+#[get("/oracle/decisions")]
+async fn oracle_decisions(
+    query: web::Query<HistoryQuery>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(data.oracle_decisions(&query))
+}
+
+#[get("/events/replay")]
+async fn replay_events(
+    query: web::Query<ReplayQuery>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(data.replay_events(&query))
+}
+
+#[get("/state/diff")]
+async fn state_diff(
+    query: web::Query<StateDiffQuery>,
+    state: web::Data<Arc<StateTracker>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(state.diff(&query))
+}
+
+#[get("/stats/utilization")]
+async fn stats_utilization(
+    query: web::Query<StatsQuery>,
+    backend: web::Data<Arc<Backend>>,
+    stats: web::Data<Arc<StatsTracker>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(stats.utilization(&query, backend.now_us()))
+}
+
+#[get("/stats/risk")]
+async fn stats_risk(
+    query: web::Query<StatsQuery>,
+    backend: web::Data<Arc<Backend>>,
+    stats: web::Data<Arc<StatsTracker>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(stats.risk(&query, backend.now_us()))
+}
+
+#[get("/rate_limits")]
+async fn rate_limits(limiter: web::Data<RateLimiter>) -> impl Responder {
+    HttpResponse::Ok().json(limiter.metrics())
+}
+
+#[get("/scripts")]
+async fn list_scripts(scripts: web::Data<Arc<Scripts>>) -> impl Responder {
+    HttpResponse::Ok().json(scripts.scripts())
+}
+
+fn script_error_status(e: &ScriptsError) -> StatusCode {
+    match e {
+        ScriptsError::InvalidName(_) | ScriptsError::Compile(_) | ScriptsError::Instantiate(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        ScriptsError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        ScriptsError::TooMany(_) => StatusCode::CONFLICT,
+        ScriptsError::UnknownScript(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+// The body is the WebAssembly module itself
+#[post("/scripts/{name}")]
+async fn upload_script(
+    path: web::Path<String>,
+    body: web::Bytes,
+    scripts: web::Data<Arc<Scripts>>,
+) -> impl Responder {
+    let name = path.into_inner();
+    // Runs the start function of the script, which may call into the Backend
+    // and mustn't hold up the HTTP worker
+    let scripts = scripts.get_ref().clone();
+    let script_name = name.clone();
+    let result = match web::block(move || scripts.load(&script_name, &body)).await {
+        Ok(result) => result.map_err(|e| (script_error_status(&e), e.to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    if let Err((status, e)) = result {
+        error!("upload_script(): {}", e);
+        return HttpResponse::with_body(status, BoxBody::new(e));
+    }
+
+    HttpResponse::Ok().body(format!("Script {} loaded", name))
+}
+
+#[delete("/scripts/{name}")]
+async fn delete_script(
+    path: web::Path<String>,
+    scripts: web::Data<Arc<Scripts>>,
+) -> impl Responder {
+    let name = path.into_inner();
+
+    if let Err(e) = scripts.unload(&name) {
+        error!("delete_script(): {}", e);
+        return HttpResponse::with_body(script_error_status(&e), BoxBody::new(format!("{}", e)));
+    }
+
+    HttpResponse::Ok().body(format!("Script {} unloaded", name))
+}
+
+#[post("/debug/sensor_event")]
+async fn debug_sensor_event(
+    form: web::Json<SensorEventParams>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    if let Err(e) = data.check_loco(form.loco) {
+        error!("debug_sensor_event(): {}", e);
+        return HttpResponse::with_body(loco_error_status(&e), BoxBody::new(format!("{}", e)));
+    }
+
+    data.inject_sensor_event(form.loco, form.sensor);
+    HttpResponse::Ok().body(format!(
+        "Injected detection of {:?} at {:?}",
+        form.loco, form.sensor
+    ))
+}
+
+#[get("/debug/frames")]
+async fn debug_frames(
+    query: web::Query<HistoryQuery>,
+    filter: web::Query<FramesFilter>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(data.traced_frames(&query, &filter))
+}
+
+#[post("/debug/frames")]
+async fn debug_frame_tracing(
+    form: web::Json<FrameTracingParams>,
+    data: web::Data<Arc<Backend>>,
+) -> impl Responder {
+    data.set_frame_tracing(form.enabled);
+    HttpResponse::Ok().body(format!("Frame tracing enabled: {}", form.enabled))
+}
+
+// Everything the HTTP handlers share with the backend threads
+struct Shared {
+    backend: Arc<Backend>,
+    buffer_stops: Arc<BufferStops>,
+    calibration: Arc<Calibration>,
+    consists: Arc<ConsistTracker>,
+    day_programs: Arc<DayPrograms>,
+    log_filter: Arc<LogFilter>,
+    safety: Arc<SafetyMonitor>,
+    power_districts: Arc<PowerDistricts>,
+    profiles: Arc<Profiles>,
+    routes: Arc<RoutePrograms>,
+    scripts: Arc<Scripts>,
+    snapshots: Arc<Snapshots>,
+    startup: Arc<StartupSequence>,
+    state: Arc<StateTracker>,
+    stats: Arc<StatsTracker>,
+    supervisor: Arc<Supervisor>,
+    tags: Arc<TagDatabase>,
+}
+
 #[actix_web::main]
-async fn http_main(port: u16, backend: Arc<Backend>) -> std::io::Result<()> {
+async fn http_main(
+    port: u16,
+    shared: Shared,
+    rate_limiter: RateLimiter,
+    debug_api: bool,
+) -> std::io::Result<()> {
     debug!("http_main(): Waiting for incoming connection...");
+    // Shared by every worker, so that a client is limited across all of them
+    let rate_limiter = web::Data::new(rate_limiter);
     HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(backend.clone()))
+            .wrap(from_fn(rate_limit))
+            .wrap(from_fn(correlate))
+            .app_data(web::Data::new(shared.backend.clone()))
+            .app_data(web::Data::new(shared.buffer_stops.clone()))
+            .app_data(web::Data::new(shared.calibration.clone()))
+            .app_data(web::Data::new(shared.consists.clone()))
+            .app_data(web::Data::new(shared.day_programs.clone()))
+            .app_data(web::Data::new(shared.routes.clone()))
+            .app_data(web::Data::new(shared.log_filter.clone()))
+            .app_data(web::Data::new(shared.safety.clone()))
+            .app_data(web::Data::new(shared.power_districts.clone()))
+            .app_data(web::Data::new(shared.profiles.clone()))
+            .app_data(web::Data::new(shared.scripts.clone()))
+            .app_data(web::Data::new(shared.snapshots.clone()))
+            .app_data(web::Data::new(shared.startup.clone()))
+            .app_data(web::Data::new(shared.state.clone()))
+            .app_data(web::Data::new(shared.stats.clone()))
+            .app_data(web::Data::new(shared.supervisor.clone()))
+            .app_data(web::Data::new(shared.tags.clone()))
+            .app_data(rate_limiter.clone())
+            // Scripts are uploaded as a whole
+            .app_data(web::PayloadConfig::new(shared.scripts.max_module_bytes()))
             .service(index)
             .service(loco_status)
             .service(control_loco)
+            .service(control_loco_functions)
             .service(loco_intent)
             .service(drive_switch_rails)
+            .service(drive_track_power)
+            .service(alarms)
+            .service(clear_alarms)
+            .service(emergency_stop)
+            .service(alarms_history)
+            .service(list_events)
+            .service(live_events)
+            .service(replay_events)
+            .service(oracle_decisions)
+            .service(set_loco_location)
+            .service(clear_occupancy)
+            .service(location_corrections)
+            .service(inputs_status)
+            .service(devices)
             .service(oracle_mode)
+            .service(take_snapshot)
+            .service(list_snapshots)
+            .service(restore_snapshot)
+            .service(confirm_snapshot)
+            .service(readyz)
+            .service(healthz)
+            .service(safety_status)
+            .service(power_districts_status)
+            .service(prepare_restart)
+            .service(network)
+            .service(active_profile)
+            .service(activate_profile)
+            .service(rate_limits)
+            .service(list_scripts)
+            .service(upload_script)
+            .service(delete_script)
+            .service(state_diff)
+            .service(stats_utilization)
+            .service(stats_risk)
+            .service(get_speed_scale)
+            .service(set_speed_scale)
+            .service(get_calibration)
+            .service(set_trim)
+            .service(auto_trim)
+            .service(calibration_run)
+            .service(get_learned_driving)
+            .service(set_learned_driving)
+            .service(forget_learned_driving)
+            .service(list_tags)
+            .service(register_tag)
+            .service(remove_tag)
+            .service(consists_status)
+            .service(day_program_status)
+            .service(activate_day_program)
+            .service(resume_day_programs)
+            .service(set_fast_clock)
+            .service(route_programs_status)
+            .service(log_levels)
+            .service(set_log_level)
+            .configure(|cfg| {
+                // Testing hooks, never exposed unless explicitly requested
+                if debug_api {
+                    cfg.service(debug_sensor_event)
+                        .service(debug_frames)
+                        .service(debug_frame_tracing);
+                }
+            })
     })
     .bind(("0.0.0.0", port))?
     .run()
     .await
 }
 
-fn backend_locos(port: u16, backend: Arc<Backend>) -> Result<()> {
-    let listener = TcpListener::bind(("0.0.0.0", port)).map_err(Error::BindListener)?;
+// Each loco is served by a task of its own, so that a slow loco only delays
+// the requests sent to it
+async fn backend_locos(port: u16, backend: Arc<Backend>, heartbeat: &Heartbeat) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(Error::BindListener)?;
 
     loop {
+        heartbeat.beat();
         debug!("backend_locos(): Waiting for incoming connection...");
-        let (stream, _) = listener.accept().map_err(Error::BindListener)?;
-        stream
-            .set_read_timeout(Some(Duration::new(1, 0)))
-            .map_err(Error::StreamSetReadTimeout)?;
+        let (stream, _) = listener.accept().await.map_err(Error::BindListener)?;
         debug!("backend_locos(): Connected");
-        if let Err(e) = backend.handle_loco_connection(stream) {
-            error!("backend_locos(): {}", e);
-        }
+        let backend = backend.clone();
+        tokio::spawn(async move {
+            if let Err(e) = backend.handle_loco_connection(stream).await {
+                error!("backend_locos(): {}", e);
+            }
+        });
     }
 }
 
-fn backend_sensors(port: u16, backend: Arc<Backend>) -> Result<()> {
-    let listener = TcpListener::bind(("0.0.0.0", port)).map_err(Error::BindListener)?;
+// There's a single sensors board, whose new connection waits for the previous
+// one to be over
+async fn backend_sensors(port: u16, backend: Arc<Backend>, heartbeat: &Heartbeat) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(Error::BindListener)?;
 
     loop {
+        heartbeat.beat();
         debug!("backend_sensors(): Waiting for incoming connection...");
-        let (stream, _) = listener.accept().map_err(Error::BindListener)?;
-        stream
-            .set_read_timeout(Some(Duration::new(2, 0)))
-            .map_err(Error::StreamSetReadTimeout)?;
+        let (stream, _) = listener.accept().await.map_err(Error::BindListener)?;
         debug!("backend_sensors(): Connected");
-        if let Err(e) = backend.serve_sensors(stream) {
+        if let Err(e) = backend.serve_sensors(stream).await {
             error!("backend_sensors(): {}", e);
         }
     }
 }
 
-fn backend_actuators(port: u16, backend: Arc<Backend>) -> Result<()> {
-    let listener = TcpListener::bind(("0.0.0.0", port)).map_err(Error::BindListener)?;
+// Same as the sensors board, the actuators board is served one connection at
+// a time
+async fn backend_actuators(port: u16, backend: Arc<Backend>, heartbeat: &Heartbeat) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(Error::BindListener)?;
 
     loop {
+        heartbeat.beat();
         debug!("backend_actuators(): Waiting for incoming connection...");
-        let (stream, _) = listener.accept().map_err(Error::BindListener)?;
-        stream
-            .set_read_timeout(Some(Duration::new(1, 0)))
-            .map_err(Error::StreamSetReadTimeout)?;
+        let (stream, _) = listener.accept().await.map_err(Error::BindListener)?;
         debug!("backend_actuators(): Connected");
-        if let Err(e) = backend.handle_actuators_connection(stream) {
+        if let Err(e) = backend.serve_actuators(stream).await {
             error!("backend_actuators(): {}", e);
         }
     }
 }
 
-fn backend_oracle(backend: Arc<Backend>) -> Result<()> {
-    debug!("backend_oracle()");
-    let mut oracle = Oracle::new(backend);
+fn backend_pacer(backend: Arc<Backend>, heartbeat: &Heartbeat) -> Result<()> {
+    debug!("backend_pacer()");
     loop {
-        if let Err(e) = oracle.process() {
-            error!("backend_oracle(): {}", e);
+        heartbeat.beat();
+        if let Err(e) = backend.block_on(backend.flush_loco_commands()) {
+            error!("backend_pacer(): {}", e);
         }
         sleep(Duration::from_millis(10));
     }
 }
 
+fn backend_locos_poller(
+    backend: Arc<Backend>,
+    period: Duration,
+    heartbeat: &Heartbeat,
+) -> Result<()> {
+    debug!("backend_locos_poller()");
+    loop {
+        heartbeat.beat();
+        backend.block_on(backend.refresh_loco_statuses());
+        sleep(period);
+    }
+}
+
+fn backend_oracle(mut oracle: Oracle, config: &OracleConfig, heartbeat: &Heartbeat) -> Result<()> {
+    debug!("backend_oracle()");
+    loop {
+        heartbeat.beat();
+        // Everything decided within a cycle shares its correlation ID
+        correlation::sync_scope(CorrelationId::next(), || {
+            if let Err(e) = oracle.process() {
+                error!("backend_oracle(): {}", e);
+            }
+        });
+        sleep(config.period());
+    }
+}
+
+fn backend_buffer_stops(events: Receiver<Event>, buffer_stops: Arc<BufferStops>) -> Result<()> {
+    debug!("backend_buffer_stops()");
+    for event in events.iter() {
+        buffer_stops.apply(&event);
+    }
+    Ok(())
+}
+
+fn backend_safety(
+    monitor: Arc<SafetyMonitor>,
+    config: SafetyConfig,
+    heartbeat: &Heartbeat,
+) -> Result<()> {
+    debug!("backend_safety()");
+    loop {
+        heartbeat.beat();
+        monitor.process();
+        sleep(config.period());
+    }
+}
+
+fn backend_maintenance(schedule: MaintenanceSchedule) -> Result<()> {
+    debug!("backend_maintenance()");
+    schedule.run();
+    Ok(())
+}
+
+fn backend_startup(startup: Arc<StartupSequence>) -> Result<()> {
+    debug!("backend_startup()");
+    startup.run();
+    Ok(())
+}
+
+fn backend_state(events: Receiver<Event>, state: Arc<StateTracker>) -> Result<()> {
+    debug!("backend_state()");
+    for event in events.iter() {
+        state.apply(&event);
+    }
+    Ok(())
+}
+
+fn backend_stats(backend: Arc<Backend>, stats: Arc<StatsTracker>) -> Result<()> {
+    debug!("backend_stats()");
+    let events = backend.subscribe();
+    for event in events.iter() {
+        stats.apply(&event, backend.now_us());
+    }
+    Ok(())
+}
+
+fn backend_calibration(events: Receiver<Event>, calibration: Arc<Calibration>) -> Result<()> {
+    debug!("backend_calibration()");
+    for event in events.iter() {
+        calibration.apply(&event);
+    }
+    Ok(())
+}
+
+fn backend_consists(
+    backend: Arc<Backend>,
+    events: Receiver<Event>,
+    consists: Arc<ConsistTracker>,
+) -> Result<()> {
+    debug!("backend_consists()");
+    loop {
+        match events.recv_timeout(CONSISTS_CHECK_PERIOD) {
+            Ok(event) => consists.apply(&event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        consists.check(backend.now_us());
+    }
+    Ok(())
+}
+
+fn backend_day_programs(events: Receiver<Event>, day_programs: Arc<DayPrograms>) -> Result<()> {
+    debug!("backend_day_programs()");
+    loop {
+        match events.recv_timeout(DAY_CHECK_PERIOD) {
+            Ok(event) => day_programs.apply(&event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        day_programs.check();
+    }
+    Ok(())
+}
+
+fn backend_routes(
+    backend: Arc<Backend>,
+    events: Receiver<Event>,
+    routes: Arc<RoutePrograms>,
+) -> Result<()> {
+    debug!("backend_routes()");
+    loop {
+        match events.recv_timeout(ROUTES_CHECK_PERIOD) {
+            Ok(event) => routes.apply(&event),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        routes.check(backend.now_us());
+    }
+    Ok(())
+}
+
+fn backend_plugins(backend: Arc<Backend>, enabled: Vec<String>) -> Result<()> {
+    debug!("backend_plugins()");
+    let events = backend.subscribe();
+    // Names have been validated along with the configuration
+    let plugins = enabled.iter().filter_map(|name| builtin(name)).collect();
+    let mut plugin_host = PluginHost::new(backend, plugins);
+    for event in events.iter() {
+        plugin_host.dispatch(&event);
+    }
+    Ok(())
+}
+
+fn backend_scripts(backend: Arc<Backend>, scripts: Arc<Scripts>) -> Result<()> {
+    debug!("backend_scripts()");
+    let events = backend.subscribe();
+    for event in events.iter() {
+        scripts.dispatch(&event);
+    }
+    Ok(())
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[arg(long, default_value_t = 8080)]
-    http_port: u16,
-    #[arg(long, default_value_t = 8004)]
-    backend_locos_port: u16,
-    #[arg(long, default_value_t = 8005)]
-    backend_sensors_port: u16,
-    #[arg(long, default_value_t = 8006)]
-    backend_actuators_port: u16,
+    /// JSON configuration file
+    #[arg(long)]
+    config: Option<String>,
+    /// Override a configuration key, i.e --set oracle.period_ms=20
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+    #[arg(long)]
+    http_port: Option<u16>,
+    #[arg(long)]
+    backend_locos_port: Option<u16>,
+    #[arg(long)]
+    backend_sensors_port: Option<u16>,
+    #[arg(long)]
+    backend_actuators_port: Option<u16>,
+    /// Token the boards must give when connecting
+    #[arg(long, value_name = "TOKEN")]
+    auth_token: Option<String>,
+    /// Layout profile to activate on startup
+    #[arg(long)]
+    profile: Option<String>,
+    /// Enable the /debug endpoints, meant for testing without any firmware
+    #[arg(long)]
+    debug_api: bool,
+    /// Dump the inputs and outputs of every Oracle cycle as CSV files into
+    /// this directory
+    #[arg(long, value_name = "DIR")]
+    trace_oracle: Option<PathBuf>,
+}
+
+// Layers the configuration sources, from the lowest to the highest priority
+fn load_config(args: &Args) -> std::result::Result<Config, config::Error> {
+    let mut loader = ConfigLoader::new();
+
+    if let Some(path) = &args.config {
+        loader.load_file(path)?;
+    }
+
+    loader.load_env()?;
+
+    let ports = [
+        ("ports.http", args.http_port),
+        ("ports.locos", args.backend_locos_port),
+        ("ports.sensors", args.backend_sensors_port),
+        ("ports.actuators", args.backend_actuators_port),
+    ];
+    for (key, port) in ports {
+        if let Some(port) = port {
+            loader.load_override(&format!("{}={}", key, port))?;
+        }
+    }
+
+    // Quoted, so that a token made of digits isn't taken for a number
+    if let Some(auth_token) = &args.auth_token {
+        loader.load_override(&format!(
+            "backend.auth_token={}",
+            serde_json::Value::from(auth_token.as_str())
+        ))?;
+    }
+
+    if let Some(profile) = &args.profile {
+        loader.load_override(&format!("profile={}", profile))?;
+    }
+
+    for key_value in args.overrides.iter() {
+        loader.load_override(key_value)?;
+    }
+
+    loader.build()
 }
 
 fn main() -> Result<()> {
-    env_logger::init();
+    let log_filter = LogFilter::init().map_err(Error::InitLogger)?;
 
     let args = Args::parse();
+    let config = load_config(&args).map_err(Error::Config)?;
+    debug!("main(): {:?}", config);
 
     // Initialize backend
-    let backend = Arc::new(Backend::new());
+    let tags = Arc::new(TagDatabase::load(&config.tags, &config.locos.roster));
+    let command_ids = CommandIds::load(&config.backend).map_err(Error::LoadCommandIds)?;
+    let recovery: Vec<_> = [tags.recovery(), command_ids.recovery()]
+        .into_iter()
+        .flatten()
+        .collect();
+    // The connections of the boards are served by tasks, while everything
+    // else keeps running on its own thread
+    let runtime = RuntimeBuilder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::Runtime)?;
+    let backend = Arc::new(Backend::new(
+        &config.backend,
+        &config.history,
+        &config.locos.roster,
+        &config.network,
+        tags.clone(),
+        command_ids,
+        runtime.handle().clone(),
+    ));
     let shared_backend_locos = backend.clone();
     let shared_backend_sensors = backend.clone();
     let shared_backend_actuators = backend.clone();
     let shared_backend_oracle = backend.clone();
+    let shared_backend_pacer = backend.clone();
+    let shared_backend_locos_poller = backend.clone();
+
+    // The workers the whole layout depends on are restarted if they crash
+    let supervisor = Arc::new(Supervisor::new());
 
     // Start backend server, waiting for incoming connections from locos
-    thread::spawn(move || backend_locos(args.backend_locos_port, shared_backend_locos));
+    let locos_port = config.ports.locos;
+    supervisor.spawn("locos", None, move |heartbeat| {
+        shared_backend_locos.block_on(backend_locos(
+            locos_port,
+            shared_backend_locos.clone(),
+            heartbeat,
+        ))
+    });
 
     // Start backend server, waiting for updates on locos' positions
-    thread::spawn(move || backend_sensors(args.backend_sensors_port, shared_backend_sensors));
+    let sensors_port = config.ports.sensors;
+    supervisor.spawn("sensors", None, move |heartbeat| {
+        shared_backend_sensors.block_on(backend_sensors(
+            sensors_port,
+            shared_backend_sensors.clone(),
+            heartbeat,
+        ))
+    });
 
     // Start backend server, waiting for incoming connection from actuators
-    thread::spawn(move || backend_actuators(args.backend_actuators_port, shared_backend_actuators));
+    let actuators_port = config.ports.actuators;
+    supervisor.spawn("actuators", None, move |heartbeat| {
+        shared_backend_actuators.block_on(backend_actuators(
+            actuators_port,
+            shared_backend_actuators.clone(),
+            heartbeat,
+        ))
+    });
 
     // Start railway network automation process
-    thread::spawn(move || backend_oracle(shared_backend_oracle));
+    let power_districts = Arc::new(PowerDistricts::new(backend.clone(), &config.network));
+    let shared_power_districts = power_districts.clone();
+    let buffer_stops = Arc::new(BufferStops::new(backend.clone(), &config.network));
+    let shared_buffer_stops = buffer_stops.clone();
+    let signals = Arc::new(Signals::new(&config.network));
+    let shared_signals = signals.clone();
+    let switch_order = Arc::new(SwitchOrder::new(&config.network));
+    let shared_switch_order = switch_order.clone();
+    let oracle_config = config.oracle.clone();
+    let mut oracle_tracer = match &args.trace_oracle {
+        Some(dir) => Some(OracleTracer::new(dir).map_err(Error::TraceOracle)?),
+        None => None,
+    };
+    let trace_oracle = args.trace_oracle.clone();
+    supervisor.spawn("oracle", Some(oracle_config.period()), move |heartbeat| {
+        // A restarted Oracle traces into new files, leaving the ones of the
+        // crashed run as they were
+        let tracer = oracle_tracer.take().or_else(|| {
+            let dir = trace_oracle.as_ref()?;
+            OracleTracer::new(dir)
+                .inspect_err(|e| error!("backend_oracle(): {}", e))
+                .ok()
+        });
+        let oracle = Oracle::new(
+            shared_backend_oracle.clone(),
+            shared_power_districts.clone(),
+            shared_buffer_stops.clone(),
+            shared_signals.clone(),
+            shared_switch_order.clone(),
+            &oracle_config,
+            tracer,
+        );
+        backend_oracle(oracle, &oracle_config, heartbeat)
+    });
+
+    // Start protecting the buffer stops, whoever drives the locos
+    let buffer_stops_events = backend.subscribe();
+    let shared_buffer_stops = buffer_stops.clone();
+    thread::spawn(move || backend_buffer_stops(buffer_stops_events, shared_buffer_stops));
+
+    // Start double checking what the Oracle does
+    let safety = Arc::new(SafetyMonitor::new(backend.clone(), &config.safety));
+    if config.safety.enabled {
+        let shared_safety = safety.clone();
+        let safety_config = config.safety.clone();
+        supervisor.spawn("safety", Some(safety_config.period()), move |heartbeat| {
+            backend_safety(shared_safety.clone(), safety_config.clone(), heartbeat)
+        });
+    }
+
+    // Start sending loco commands delayed by the pacing
+    supervisor.spawn("pacer", Some(Duration::from_millis(10)), move |heartbeat| {
+        backend_pacer(shared_backend_pacer.clone(), heartbeat)
+    });
+
+    // Start keeping the cached locos status up to date
+    let loco_status_refresh = config.backend.loco_status_refresh();
+    supervisor.spawn(
+        "locos_poller",
+        Some(loco_status_refresh),
+        move |heartbeat| {
+            backend_locos_poller(
+                shared_backend_locos_poller.clone(),
+                loco_status_refresh,
+                heartbeat,
+            )
+        },
+    );
+
+    // Start keeping track of the state for clients to sync with
+    let state = Arc::new(StateTracker::new());
+    let state_events = backend.subscribe();
+    let shared_state = state.clone();
+    thread::spawn(move || backend_state(state_events, shared_state));
+
+    // Start serving the public viewers, if enabled
+    if let Some(port) = config.public.port {
+        let shared_state = state.clone();
+        let rate_limiter =
+            RateLimiter::public(&config.public.rate_limit, config.public.behind_proxy);
+        thread::spawn(move || {
+            if let Err(e) = public::public_main(port, shared_state, rate_limiter) {
+                error!("public_main(): {}", e);
+            }
+        });
+    }
+
+    // Start gathering the rail network usage statistics
+    let stats = Arc::new(StatsTracker::new(&config.history));
+    let shared_backend_stats = backend.clone();
+    let shared_stats = stats.clone();
+    thread::spawn(move || backend_stats(shared_backend_stats, shared_stats));
+
+    // Start measuring the laps for the locos calibration
+    let calibration = Arc::new(Calibration::new(backend.clone(), &config.calibration));
+    let calibration_events = backend.subscribe();
+    let shared_calibration = calibration.clone();
+    thread::spawn(move || backend_calibration(calibration_events, shared_calibration));
+
+    // Start checking the wagons follow their locos, if any
+    let consists = Arc::new(ConsistTracker::new(backend.clone(), &config.consists));
+    if !config.consists.wagons.is_empty() {
+        let shared_backend_consists = backend.clone();
+        let consists_events = backend.subscribe();
+        let shared_consists = consists.clone();
+        thread::spawn(move || {
+            backend_consists(shared_backend_consists, consists_events, shared_consists)
+        });
+    }
+
+    // Start following the timetable of the day programs, if any
+    let day_programs = Arc::new(DayPrograms::new(backend.clone(), &config.day));
+    if !config.day.programs.is_empty() {
+        let day_events = backend.subscribe();
+        let shared_day_programs = day_programs.clone();
+        thread::spawn(move || backend_day_programs(day_events, shared_day_programs));
+    }
+
+    // Start running the route programs, if any
+    let routes = Arc::new(RoutePrograms::new(backend.clone(), &config.routes));
+    if !config.routes.programs.is_empty() {
+        let shared_backend_routes = backend.clone();
+        let routes_events = backend.subscribe();
+        let shared_routes = routes.clone();
+        thread::spawn(move || backend_routes(shared_backend_routes, routes_events, shared_routes));
+    }
+
+    // Start running the custom automation hooks, if any
+    if !config.plugins.enabled.is_empty() {
+        let shared_backend_plugins = backend.clone();
+        let enabled_plugins = config.plugins.enabled.clone();
+        thread::spawn(move || backend_plugins(shared_backend_plugins, enabled_plugins));
+    }
+
+    // Start running the uploaded automation scripts, as they come
+    let scripts = Arc::new(Scripts::new(backend.clone(), &config.scripts));
+    let shared_backend_scripts = backend.clone();
+    let shared_scripts = scripts.clone();
+    thread::spawn(move || backend_scripts(shared_backend_scripts, shared_scripts));
+
+    // Start following the maintenance windows, once everything listening to
+    // the events is there
+    if !config.maintenance.windows.is_empty() {
+        let schedule = MaintenanceSchedule::new(backend.clone(), &config.maintenance);
+        thread::spawn(move || backend_maintenance(schedule));
+    }
+
+    // Apply the layout profile selected at startup, if any
+    let profiles = Arc::new(Profiles::new(
+        backend.clone(),
+        power_districts.clone(),
+        buffer_stops.clone(),
+        signals,
+        switch_order,
+        &config,
+    ));
+
+    // Start bringing the layout into a known state, now that every event
+    // subscriber is there to follow the progress
+    let startup = Arc::new(StartupSequence::new(
+        backend.clone(),
+        &config.startup,
+        recovery,
+    ));
+    let snapshots = Arc::new(Snapshots::new(backend.clone()));
+    let shared_startup = startup.clone();
+    thread::spawn(move || backend_startup(shared_startup));
 
-    http_main(args.http_port, backend).map_err(Error::HttpServer)?;
+    http_main(
+        config.ports.http,
+        Shared {
+            backend,
+            buffer_stops,
+            calibration,
+            consists,
+            day_programs,
+            log_filter,
+            safety,
+            power_districts,
+            profiles,
+            routes,
+            scripts,
+            snapshots,
+            startup,
+            state,
+            stats,
+            supervisor,
+            tags,
+        },
+        RateLimiter::new(&config.rate_limit),
+        args.debug_api,
+    )
+    .map_err(Error::HttpServer)?;
 
     Ok(())
 }
@@ -0,0 +1,201 @@
+use std::{
+    fmt,
+    sync::Arc,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{Backend, Event, OracleMode},
+    config::{MaintenanceConfig, MaintenanceWindow},
+};
+
+// Windows are set to the minute, hence there's no point checking more often
+const POLL_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+
+    // 1970-01-01 was a Thursday
+    fn from_days_since_epoch(days: i64) -> Self {
+        Weekday::ALL[(days + 3).rem_euclid(7) as usize]
+    }
+
+    fn previous(self) -> Self {
+        Weekday::ALL[(self as usize + 6) % 7]
+    }
+}
+
+/**
+ * Time of the day to the minute, written as "HH:MM".
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    minutes: u32,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid time of day {:?}, expecting HH:MM", value);
+
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        let hours: u32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+
+        Ok(TimeOfDay {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl TimeOfDay {
+    // Minutes past midnight, wrapping around after a day
+    pub fn from_minutes(minutes: u32) -> Self {
+        TimeOfDay {
+            minutes: minutes % (24 * 60),
+        }
+    }
+
+    pub fn minutes(&self) -> u32 {
+        self.minutes
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(value: TimeOfDay) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceBanner {
+    reason: String,
+    until: TimeOfDay,
+}
+
+impl fmt::Display for MaintenanceBanner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "maintenance until {}", self.until)?;
+        if !self.reason.is_empty() {
+            write!(f, " ({})", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl MaintenanceWindow {
+    // A window ending earlier than it starts goes on past midnight, the days
+    // being the ones it starts on
+    fn contains(&self, day: Weekday, minutes: u32) -> bool {
+        let on = |d: Weekday| self.days.is_empty() || self.days.contains(&d);
+
+        if self.start.minutes < self.end.minutes {
+            on(day) && (self.start.minutes..self.end.minutes).contains(&minutes)
+        } else {
+            (on(day) && minutes >= self.start.minutes)
+                || (on(day.previous()) && minutes < self.end.minutes)
+        }
+    }
+}
+
+/**
+ * Schedule of the maintenance windows, during which the crew services the
+ * layout. When a window opens, the Oracle is disabled and can't be enabled
+ * again until it closes, and plugins stop receiving events. Windows are
+ * reported through the events, so that clients can show a banner.
+ */
+pub struct MaintenanceSchedule {
+    backend: Arc<Backend>,
+    config: MaintenanceConfig,
+}
+
+impl MaintenanceSchedule {
+    pub fn new(backend: Arc<Backend>, config: &MaintenanceConfig) -> Self {
+        MaintenanceSchedule {
+            backend,
+            config: config.clone(),
+        }
+    }
+
+    // Window the layout is in at the given local time, if any
+    fn current(&self, now: SystemTime) -> Option<MaintenanceBanner> {
+        let secs = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default()
+            + self.config.utc_offset_minutes as i64 * 60;
+        let day = Weekday::from_days_since_epoch(secs.div_euclid(86400));
+        let minutes = (secs.rem_euclid(86400) / 60) as u32;
+
+        self.config
+            .windows
+            .iter()
+            .find(|w| w.contains(day, minutes))
+            .map(|w| MaintenanceBanner {
+                reason: w.reason.clone(),
+                until: w.end,
+            })
+    }
+
+    // Never returns, following the schedule
+    pub fn run(&self) {
+        let mut current: Option<MaintenanceBanner> = None;
+
+        loop {
+            let next = self.current(SystemTime::now());
+            if next != current {
+                match &next {
+                    Some(banner) => {
+                        info!("MaintenanceSchedule::run(): {} started", banner);
+                        self.backend.set_oracle_mode(OracleMode::Off);
+                        self.backend.notify(Event::MaintenanceStarted {
+                            banner: banner.clone(),
+                        });
+                    }
+                    None => {
+                        info!("MaintenanceSchedule::run(): maintenance ended");
+                        self.backend.notify(Event::MaintenanceEnded);
+                    }
+                }
+                current = next;
+            }
+
+            sleep(POLL_PERIOD);
+        }
+    }
+}
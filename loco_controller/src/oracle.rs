@@ -1,14 +1,25 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
 
 use loco_protocol::{ActuatorId, ActuatorType, Direction, LocoId, Speed};
-use log::debug;
+use log::{debug, error};
 use thiserror::Error;
 
 use crate::{
-    backend::{Backend, Error as BackendError, LocoIntent},
+    backend::{Backend, Error as BackendError, Event, LocoIntent},
+    buffer_stops::BufferStops,
+    config::OracleConfig,
+    oracle_trace::{OracleTracer, TracedLoco},
+    passing::{LocoCourse, Maneuver, PassingPlanner},
+    power::{PowerBudget, PowerDistricts},
     rail_network::{
         CheckpointId, Error as RailNetworkError, RailNetwork, Segment, SegmentId, SegmentPriority,
     },
+    signals::Signals,
+    switch_order::SwitchOrder,
 };
 
 #[derive(Debug, Error)]
@@ -27,47 +38,114 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+type ActuatorControl = (ActuatorId, ActuatorType, u8);
+type LocoControl = (LocoId, Direction, Speed);
+
 #[derive(Clone, Debug)]
 struct ActiveSegment {
     id: Option<SegmentId>,
     segment: Option<Segment>,
     direction: Direction,
     loco_id: LocoId,
+    // Speed at which the loco runs through the segment
+    speed: Speed,
 }
 
 struct ActiveLoco {
     id: LocoId,
     speed: Speed,
     location: Option<CheckpointId>,
+    // When the loco was detected at its location, which may be well before
+    // the status was polled since detections are batched by the sensors
+    location_timestamp_us: Option<u64>,
+    on_checkpoint: bool,
     intent: Option<LocoIntent>,
+    command_rtt: Option<Duration>,
+    // Whether the intent is the one of a maneuver rather than the loco's own
+    maneuvering: bool,
 }
 
 pub struct Oracle {
     backend: Arc<Backend>,
+    power_districts: Arc<PowerDistricts>,
+    buffer_stops: Arc<BufferStops>,
+    signals: Arc<Signals>,
+    switch_order: Arc<SwitchOrder>,
     rail_network: RailNetwork,
     last_segment_id: BTreeMap<LocoId, SegmentId>,
+    approach_max_latency: Duration,
+    follow_min_gap: usize,
+    start_ramp: Option<Duration>,
+    max_moving_locos: Option<usize>,
+    // Locos waiting at a station for being allowed to move, by order of
+    // arrival so that they take turns
+    station_queue: VecDeque<LocoId>,
+    completed_intents: BTreeMap<LocoId, LocoIntent>,
+    // When each loco started through the segment it's in, from its location
+    segment_starts: BTreeMap<LocoId, (CheckpointId, u64)>,
+    passing_planner: Option<PassingPlanner>,
+    last_decision: (
+        Vec<ActuatorControl>,
+        Vec<LocoControl>,
+        Vec<LocoId>,
+        Vec<Maneuver>,
+    ),
+    tracer: Option<OracleTracer>,
 }
 
 impl Oracle {
-    pub fn new(backend: Arc<Backend>) -> Self {
+    pub fn new(
+        backend: Arc<Backend>,
+        power_districts: Arc<PowerDistricts>,
+        buffer_stops: Arc<BufferStops>,
+        signals: Arc<Signals>,
+        switch_order: Arc<SwitchOrder>,
+        config: &OracleConfig,
+        tracer: Option<OracleTracer>,
+    ) -> Self {
         debug!("Oracle::new()");
+        let passing_planner = config
+            .passing_loops
+            .then(|| PassingPlanner::new(backend.loco_priorities()));
         Oracle {
             backend,
+            power_districts,
+            buffer_stops,
+            signals,
+            switch_order,
             rail_network: RailNetwork::new(),
             last_segment_id: BTreeMap::new(),
+            approach_max_latency: config.approach_max_latency(),
+            follow_min_gap: config.follow_min_gap,
+            start_ramp: config.start_ramp(),
+            max_moving_locos: config.max_moving_locos,
+            station_queue: VecDeque::new(),
+            completed_intents: BTreeMap::new(),
+            segment_starts: BTreeMap::new(),
+            passing_planner,
+            last_decision: (Vec::new(), Vec::new(), Vec::new(), Vec::new()),
+            tracer,
         }
     }
 
+    // The statuses are the cached ones, as asking every loco on every cycle
+    // would saturate the WiFi. Locos report the speed they're commanded at
+    // in their response, hence the cache is always up to date with that.
     fn active_locos(&self) -> Result<Vec<ActiveLoco>> {
         let mut active_locos = Vec::new();
-        for loco_id in self.backend.loco_ids() {
-            match self.backend.loco_status(loco_id) {
+        let statuses = self.backend.block_on(self.backend.cached_loco_statuses());
+        for (loco_id, status) in statuses {
+            match status {
                 Ok(status) => {
                     active_locos.push(ActiveLoco {
                         id: loco_id,
                         speed: status.speed(),
                         location: status.location().map(|l| l.into()),
+                        location_timestamp_us: status.location_timestamp_us(),
+                        on_checkpoint: status.on_checkpoint(),
                         intent: status.intent(),
+                        command_rtt: status.command_rtt(),
+                        maneuvering: false,
                     });
                 }
                 Err(BackendError::LocoNotConnected(_)) => continue,
@@ -77,18 +155,64 @@ impl Oracle {
         Ok(active_locos)
     }
 
-    fn determine_active_segments(&self) -> Result<Vec<ActiveSegment>> {
+    // Has the locos giving way to another one follow their maneuver instead
+    // of their own intent, returning the maneuvers going on
+    fn plan_maneuvers(&mut self, active_locos: &mut [ActiveLoco]) -> Vec<Maneuver> {
+        let Some(passing_planner) = self.passing_planner.as_mut() else {
+            return Vec::new();
+        };
+
+        let courses: Vec<LocoCourse> = active_locos
+            .iter()
+            .map(|l| LocoCourse {
+                id: l.id,
+                location: l.location,
+                intent: l.intent,
+            })
+            .collect();
+        passing_planner.update(&self.rail_network, &courses);
+
+        for active_loco in active_locos.iter_mut() {
+            if let Some(intent) = passing_planner.intent(active_loco.id) {
+                active_loco.intent = Some(intent);
+                active_loco.maneuvering = true;
+            }
+        }
+
+        passing_planner.maneuvers()
+    }
+
+    // Reports an intent as completed once, when the loco reaches its target,
+    // and again only if the loco leaves the target and gets back to it.
+    fn update_completed_intent(&mut self, loco_id: LocoId, intent: LocoIntent, completed: bool) {
+        if !completed {
+            self.completed_intents.remove(&loco_id);
+            return;
+        }
+
+        if self.completed_intents.insert(loco_id, intent) != Some(intent) {
+            self.backend
+                .notify(Event::IntentCompleted { loco_id, intent });
+        }
+    }
+
+    fn determine_active_segments(
+        &mut self,
+        active_locos: &[ActiveLoco],
+    ) -> Result<Vec<ActiveSegment>> {
         let mut active_segments: Vec<ActiveSegment> = Vec::new();
         let mut busy_checkpoint_ids: Vec<CheckpointId> = Vec::new();
-        let active_locos = self.active_locos()?;
 
         // For every loco:
-        //  - Check if loco is stopped to identify a busy checkpoint
+        //  - Check if loco is stopped, or still standing over its checkpoint,
+        //    to identify a busy checkpoint. A fast loco may only be told to
+        //    stop once over the checkpoint, and then still be standing there
+        //    while it hasn't reported its new speed yet.
         for active_loco in active_locos.iter() {
-            if let Some(location) = active_loco.location {
-                if active_loco.speed == Speed::Stop {
-                    busy_checkpoint_ids.push(location);
-                }
+            if let Some(location) = active_loco.location
+                && (active_loco.speed == Speed::Stop || active_loco.on_checkpoint)
+            {
+                busy_checkpoint_ids.push(location);
             }
         }
 
@@ -108,7 +232,30 @@ impl Oracle {
             let checkpoint_id = active_loco.location.unwrap();
             let intent = active_loco.intent.unwrap();
 
-            let (next_checkpoint_id, direction) = match intent {
+            // Reaching the passing loop of a maneuver completes nothing the
+            // loco has been asked for
+            if !active_loco.maneuvering {
+                let completed = matches!(intent, LocoIntent::Stop(_, target_checkpoint_id)
+                    if target_checkpoint_id == checkpoint_id);
+                self.update_completed_intent(active_loco.id, intent, completed);
+            }
+
+            // Nowhere to go past a buffer stop, whatever the intent
+            if self
+                .buffer_stops
+                .is_terminal(checkpoint_id, intent.direction())
+            {
+                active_segments.push(ActiveSegment {
+                    id: None,
+                    segment: None,
+                    direction: intent.direction(),
+                    loco_id: active_loco.id,
+                    speed: Speed::Stop,
+                });
+                continue;
+            }
+
+            let (next_checkpoint_id, direction, speed) = match intent {
                 LocoIntent::Drive(direction, target_track_id) => (
                     self.rail_network
                         .next_checkpoint_id_for_track_id_target(
@@ -119,6 +266,7 @@ impl Oracle {
                         )
                         .ok_or(Error::NextCheckpointNotFound)?,
                     direction,
+                    Speed::Normal,
                 ),
                 LocoIntent::Stop(direction, target_checkpoint_id) => {
                     if target_checkpoint_id == checkpoint_id {
@@ -127,30 +275,68 @@ impl Oracle {
                             segment: None,
                             direction,
                             loco_id: active_loco.id,
+                            speed: Speed::Stop,
                         });
                         continue;
                     }
 
-                    (
-                        self.rail_network
-                            .next_checkpoint_id_for_checkpoint_id_target(
-                                0,
-                                checkpoint_id,
+                    let next_checkpoint_id = self
+                        .rail_network
+                        .next_checkpoint_id_for_checkpoint_id_target(
+                            0,
+                            checkpoint_id,
+                            direction,
+                            target_checkpoint_id,
+                        )
+                        .ok_or(Error::NextCheckpointNotFound)?;
+
+                    // The Stop command reaches the loco half a round-trip after
+                    // the detection, hence the loco keeps going for that long.
+                    // Until a command has been measured, there's nothing to
+                    // account for.
+                    let speed = if next_checkpoint_id == target_checkpoint_id {
+                        self.approach_speed(active_loco.command_rtt.unwrap_or_default() / 2)
+                    } else {
+                        Speed::Normal
+                    };
+
+                    (next_checkpoint_id, direction, speed)
+                }
+                LocoIntent::Follow(direction, leader_id) => {
+                    match self.follow_leader(checkpoint_id, direction, leader_id, active_locos) {
+                        Some((next_checkpoint_id, speed)) => (next_checkpoint_id, direction, speed),
+                        None => {
+                            active_segments.push(ActiveSegment {
+                                id: None,
+                                segment: None,
                                 direction,
-                                target_checkpoint_id,
-                            )
-                            .ok_or(Error::NextCheckpointNotFound)?,
-                        direction,
-                    )
+                                loco_id: active_loco.id,
+                                speed: Speed::Stop,
+                            });
+                            continue;
+                        }
+                    }
                 }
             };
 
+            let speed = self.learned_speed(active_loco, checkpoint_id, next_checkpoint_id, speed);
+
+            // Slow down through the protection zone of a buffer stop
+            let speed = if self.buffer_stops.is_terminal(next_checkpoint_id, direction)
+                && speed != Speed::Stop
+            {
+                Speed::Slow
+            } else {
+                speed
+            };
+
             if busy_checkpoint_ids.contains(&next_checkpoint_id) {
                 active_segments.push(ActiveSegment {
                     id: None,
                     segment: None,
                     direction,
                     loco_id: active_loco.id,
+                    speed: Speed::Stop,
                 });
                 continue;
             }
@@ -163,12 +349,77 @@ impl Oracle {
                 segment: Some(self.rail_network.segment(&active_segment_id).clone()),
                 direction,
                 loco_id: active_loco.id,
+                speed,
             });
         }
 
         Ok(active_segments)
     }
 
+    // Enforces the cap on moving locos. Locos out on the line can't be held
+    // anywhere, hence they always get to move, and whatever is left is given
+    // to the locos about to leave a station, first come first served. The
+    // others stay at the station, and the queue of those is returned.
+    fn hold_extra_locos(
+        &mut self,
+        active_locos: &[ActiveLoco],
+        active_segments: &mut [ActiveSegment],
+    ) -> Vec<LocoId> {
+        let Some(max_moving_locos) = self.max_moving_locos else {
+            self.station_queue.clear();
+            return Vec::new();
+        };
+
+        let mut running = 0;
+        let mut departing = Vec::new();
+        for segment in active_segments.iter().filter(|s| s.speed != Speed::Stop) {
+            // A loco which already left keeps running until it's seen at
+            // another checkpoint
+            let waiting = active_locos.iter().any(|l| {
+                l.id == segment.loco_id
+                    && l.speed == Speed::Stop
+                    && l.location.is_some_and(|c| c.is_station())
+            });
+            if waiting {
+                departing.push(segment.loco_id);
+            } else {
+                running += 1;
+            }
+        }
+
+        self.station_queue.retain(|id| departing.contains(id));
+        for loco_id in departing {
+            if !self.station_queue.contains(&loco_id) {
+                self.station_queue.push_back(loco_id);
+            }
+        }
+
+        let free_slots = max_moving_locos.saturating_sub(running);
+        let held: Vec<LocoId> = self
+            .station_queue
+            .iter()
+            .skip(free_slots)
+            .copied()
+            .collect();
+        for segment in active_segments
+            .iter_mut()
+            .filter(|s| held.contains(&s.loco_id))
+        {
+            debug!(
+                "Oracle::hold_extra_locos(): holding {} at the station",
+                segment.loco_id
+            );
+            *segment = ActiveSegment {
+                id: None,
+                segment: None,
+                speed: Speed::Stop,
+                ..segment.clone()
+            };
+        }
+
+        held
+    }
+
     fn sort_active_segments(&self, active_segments: Vec<ActiveSegment>) -> Vec<ActiveSegment> {
         // First, let's re-order so that two identical active segments are
         // correctly ordered with the first one being the first loco on the
@@ -178,22 +429,23 @@ impl Oracle {
         // sharing the same active segments.
         let mut sorted_active_segments: Vec<ActiveSegment> = Vec::new();
         for segment in active_segments.iter() {
-            if let Some(sid) = segment.id {
-                if let Some(last_segment_id) = self.last_segment_id.get(&segment.loco_id) {
-                    let mut insertion_idx = None;
-                    for (i, sorted_segment) in sorted_active_segments.iter().enumerate() {
-                        if let Some(sorted_sid) = sorted_segment.id {
-                            if (sid == sorted_sid) && (sid == *last_segment_id) {
-                                insertion_idx = Some(i);
-                                break;
-                            }
-                        }
-                    }
-                    if let Some(i) = insertion_idx {
-                        sorted_active_segments.insert(i, segment.clone());
-                        continue;
+            if let Some(sid) = segment.id
+                && let Some(last_segment_id) = self.last_segment_id.get(&segment.loco_id)
+            {
+                let mut insertion_idx = None;
+                for (i, sorted_segment) in sorted_active_segments.iter().enumerate() {
+                    if let Some(sorted_sid) = sorted_segment.id
+                        && (sid == sorted_sid)
+                        && (sid == *last_segment_id)
+                    {
+                        insertion_idx = Some(i);
+                        break;
                     }
                 }
+                if let Some(i) = insertion_idx {
+                    sorted_active_segments.insert(i, segment.clone());
+                    continue;
+                }
             }
             sorted_active_segments.push(segment.clone());
         }
@@ -214,19 +466,122 @@ impl Oracle {
         sorted_active_segments
     }
 
+    // Runs the loco the way it has been driven by hand through the segment,
+    // timed from when it went by its checkpoint, or from when it left it
+    // after standing there
+    fn learned_speed(
+        &mut self,
+        active_loco: &ActiveLoco,
+        checkpoint_id: CheckpointId,
+        next_checkpoint_id: CheckpointId,
+        speed: Speed,
+    ) -> Speed {
+        let now_us = self.backend.now_us();
+        let started_us = match self.segment_starts.get(&active_loco.id) {
+            _ if active_loco.speed == Speed::Stop => now_us,
+            Some((location, started_us)) if *location == checkpoint_id => *started_us,
+            _ => active_loco.location_timestamp_us.unwrap_or(now_us),
+        };
+        self.segment_starts
+            .insert(active_loco.id, (checkpoint_id, started_us));
+
+        if speed != Speed::Normal {
+            return speed;
+        }
+        self.backend
+            .learned_speed(
+                active_loco.id,
+                checkpoint_id,
+                next_checkpoint_id,
+                now_us.saturating_sub(started_us),
+            )
+            .unwrap_or(speed)
+    }
+
+    // Picks the speed for running through a segment. When the loco has to
+    // stop at the end of it, the distance it covers between the detection and
+    // the Stop command being applied grows with the command latency. Slowing
+    // down on approach keeps that distance, hence the stop position, about
+    // the same no matter how congested the WiFi is.
+    fn approach_speed(&self, latency: Duration) -> Speed {
+        if latency > self.approach_max_latency {
+            debug!(
+                "Oracle::approach_speed(): slowing down, command latency {}ms",
+                latency.as_millis()
+            );
+            Speed::Slow
+        } else {
+            Speed::Normal
+        }
+    }
+
+    // Drives a loco behind its leader, keeping at least follow_min_gap
+    // segments between them. The follower runs slowly through the last
+    // segment before reaching that gap, and holds its position once reached,
+    // as well as whenever the leader isn't heading the same way.
+    fn follow_leader(
+        &self,
+        checkpoint_id: CheckpointId,
+        direction: Direction,
+        leader_id: LocoId,
+        active_locos: &[ActiveLoco],
+    ) -> Option<(CheckpointId, Speed)> {
+        let leader = active_locos.iter().find(|l| l.id == leader_id)?;
+        let leader_checkpoint_id = leader.location?;
+        if leader.intent?.direction() != direction {
+            return None;
+        }
+
+        let gap = self
+            .rail_network
+            .distance(checkpoint_id, direction, leader_checkpoint_id)?;
+        if gap <= self.follow_min_gap {
+            return None;
+        }
+
+        let next_checkpoint_id = self
+            .rail_network
+            .next_checkpoint_id_for_checkpoint_id_target(
+                0,
+                checkpoint_id,
+                direction,
+                leader_checkpoint_id,
+            )?;
+        let speed = if gap == self.follow_min_gap + 1 {
+            Speed::Slow
+        } else {
+            Speed::Normal
+        };
+
+        Some((next_checkpoint_id, speed))
+    }
+
     fn determine_controls(
         &mut self,
         active_segments: Vec<ActiveSegment>,
-    ) -> (
-        Vec<(ActuatorId, ActuatorType, u8)>,
-        Vec<(LocoId, Direction, Speed)>,
-    ) {
-        let mut actuator_controls: Vec<(ActuatorId, ActuatorType, u8)> = Vec::new();
-        let mut loco_controls: Vec<(LocoId, Direction, Speed)> = Vec::new();
+        power_budget: &mut PowerBudget,
+    ) -> (Vec<ActuatorControl>, Vec<LocoControl>) {
+        let mut actuator_controls: Vec<ActuatorControl> = Vec::new();
+        let mut loco_controls: Vec<LocoControl> = Vec::new();
         let mut busy_segment_ids: Vec<SegmentId> = Vec::new();
+        let mut granted: BTreeMap<SegmentId, Speed> = BTreeMap::new();
+
+        // Locos already running through their segment draw their current no
+        // matter what, the budget left is for the ones entering a segment
+        let mut running_loco_ids: Vec<LocoId> = Vec::new();
+        for active_segment in active_segments.iter() {
+            if let Some(segment_id) = active_segment.id
+                && active_segment.speed != Speed::Stop
+                && self.last_segment_id.get(&active_segment.loco_id) == Some(&segment_id)
+                && power_budget.reserve(active_segment.loco_id, segment_id)
+            {
+                running_loco_ids.push(active_segment.loco_id);
+            }
+        }
 
         // For every active segment:
         //  - Find out if the segment conflicts with an already busy segment
+        //  - Find out if the power district can feed one more loco
         //  - Determine if some actuator control needs to be applied
         //  - Determine the control that should be applied for the loco
         for active_segment in active_segments.iter() {
@@ -235,61 +590,147 @@ impl Oracle {
 
             if let (Some(segment_id), Some(segment)) =
                 (active_segment.id, active_segment.segment.as_ref())
+                && !busy_segment_ids.contains(&segment_id)
             {
-                if !busy_segment_ids.contains(&segment_id) {
-                    let mut conflict_found = false;
-                    for conflict_segment_id in segment.conflicts().iter() {
-                        if busy_segment_ids.contains(conflict_segment_id) {
-                            conflict_found = true;
-                            break;
-                        }
+                let mut conflict_found = false;
+                for conflict_segment_id in segment.conflicts().iter() {
+                    if busy_segment_ids.contains(conflict_segment_id) {
+                        conflict_found = true;
+                        break;
                     }
+                }
 
-                    if !conflict_found {
-                        for switch_rails in segment.switch_rails().iter() {
-                            actuator_controls.push((
-                                switch_rails.actuator_id(),
-                                ActuatorType::SwitchRails,
-                                switch_rails.state().into(),
-                            ));
-                        }
-
-                        loco_controls.push((loco_id, direction, Speed::Normal));
-                        busy_segment_ids.push(segment_id);
-                        self.last_segment_id.insert(loco_id, segment_id);
-                        continue;
+                let powered = active_segment.speed == Speed::Stop
+                    || running_loco_ids.contains(&loco_id)
+                    || power_budget.try_reserve(loco_id, segment_id);
+
+                if !conflict_found && powered {
+                    // Thrown in the order the segment requires, which the
+                    // batch preserves all the way to the actuators board
+                    for switch_rails in self
+                        .switch_order
+                        .sort(segment_id, segment.switch_rails())
+                        .iter()
+                    {
+                        actuator_controls.push((
+                            switch_rails.actuator_id(),
+                            ActuatorType::SwitchRails,
+                            switch_rails.state().into(),
+                        ));
                     }
+
+                    loco_controls.push((loco_id, direction, active_segment.speed));
+                    busy_segment_ids.push(segment_id);
+                    granted.insert(segment_id, active_segment.speed);
+                    self.last_segment_id.insert(loco_id, segment_id);
+                    continue;
                 }
             }
 
             loco_controls.push((loco_id, direction, Speed::Stop));
         }
 
+        actuator_controls.extend(self.signals.aspects(&granted));
+
         (actuator_controls, loco_controls)
     }
 
+    // Tracing is given up on the first error, as it must never prevent the
+    // Oracle from driving the locos
+    fn trace(
+        &mut self,
+        active_locos: &[ActiveLoco],
+        actuator_controls: &[ActuatorControl],
+        loco_controls: &[LocoControl],
+    ) {
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+
+        let locos: Vec<TracedLoco> = active_locos
+            .iter()
+            .map(|l| TracedLoco {
+                loco_id: l.id,
+                location: l.location,
+                location_timestamp_us: l.location_timestamp_us,
+                intent: l.intent,
+                speed: l.speed,
+                command_rtt_us: l.command_rtt.map(|d| d.as_micros() as u64),
+            })
+            .collect();
+
+        if let Err(e) = tracer.record(
+            self.backend.now_us(),
+            &locos,
+            actuator_controls,
+            loco_controls,
+        ) {
+            error!("Oracle::trace(): {}, tracing stopped", e);
+            self.tracer = None;
+        }
+    }
+
     pub fn process(&mut self) -> Result<()> {
         if !self.backend.oracle_enabled() {
+            self.power_districts.record(self.power_districts.budget());
             return Ok(());
         }
 
         // Get the active segments
-        let active_segments = self.determine_active_segments()?;
+        let mut active_locos = self.active_locos()?;
+        let maneuvers = self.plan_maneuvers(&mut active_locos);
+        let mut active_segments = self.determine_active_segments(&active_locos)?;
+        let queue = self.hold_extra_locos(&active_locos, &mut active_segments);
         // Sort the segments by order of loco on the same segment, and by overall priority
         let sorted_active_segments = self.sort_active_segments(active_segments);
-        let (actuator_controls, loco_controls) = self.determine_controls(sorted_active_segments);
+        let mut power_budget = self.power_districts.budget();
+        let (actuator_controls, loco_controls) =
+            self.determine_controls(sorted_active_segments, &mut power_budget);
+        self.power_districts.record(power_budget);
+
+        self.trace(&active_locos, &actuator_controls, &loco_controls);
+
+        // The same decision is usually taken over and over, only report when
+        // something changes
+        if (&actuator_controls, &loco_controls, &queue, &maneuvers)
+            != (
+                &self.last_decision.0,
+                &self.last_decision.1,
+                &self.last_decision.2,
+                &self.last_decision.3,
+            )
+        {
+            self.last_decision = (
+                actuator_controls.clone(),
+                loco_controls.clone(),
+                queue.clone(),
+                maneuvers.clone(),
+            );
+            self.backend.notify(Event::OracleDecision {
+                actuators: actuator_controls.clone(),
+                locos: loco_controls.clone(),
+                queue,
+                maneuvers,
+            });
+        }
 
-        // Apply controls for actuators
-        for (actuator_id, actuator_type, actuator_state) in actuator_controls {
+        // Apply controls for actuators, all at once so that a segment never
+        // ends up with only part of its switch rails set
+        if !actuator_controls.is_empty() {
             self.backend
-                .drive_actuator(actuator_id, actuator_type, actuator_state)
+                .block_on(self.backend.drive_actuators(&actuator_controls))
                 .map_err(Error::DriveActuator)?;
         }
 
-        // Apply controls for locos
+        // Apply controls for locos, ramping up the ones which were stopped
         for (loco_id, direction, speed) in loco_controls {
+            let starting = speed != Speed::Stop
+                && active_locos
+                    .iter()
+                    .any(|l| l.id == loco_id && l.speed == Speed::Stop);
+            let ramp = if starting { self.start_ramp } else { None };
             self.backend
-                .control_loco(loco_id, direction, speed)
+                .block_on(self.backend.control_loco(loco_id, direction, speed, ramp))
                 .map_err(Error::ControlLoco)?;
         }
 
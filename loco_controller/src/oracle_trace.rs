@@ -0,0 +1,163 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use loco_protocol::{ActuatorId, ActuatorType, Direction, LocoId, Speed};
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{backend::LocoIntent, rail_network::CheckpointId};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error creating trace directory {0}: {1}")]
+    CreateDir(String, #[source] io::Error),
+    #[error("Error creating trace file {0}: {1}")]
+    CreateFile(String, #[source] io::Error),
+    #[error("Error writing trace: {0}")]
+    Write(#[source] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// What the Oracle knew about a loco when taking a decision
+pub struct TracedLoco {
+    pub loco_id: LocoId,
+    pub location: Option<CheckpointId>,
+    pub location_timestamp_us: Option<u64>,
+    pub intent: Option<LocoIntent>,
+    pub speed: Speed,
+    pub command_rtt_us: Option<u64>,
+}
+
+// Serialized name of plain values, and JSON for anything more complex, quoted
+// so that its commas don't split the field
+fn csv_field<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::Null) | Err(_) => String::new(),
+        Ok(Value::String(s)) => s,
+        Ok(Value::Number(n)) => n.to_string(),
+        Ok(other) => format!("\"{}\"", other.to_string().replace('"', "\"\"")),
+    }
+}
+
+fn create(dir: &Path, prefix: &str, name: &str, header: &str) -> Result<BufWriter<File>> {
+    let path = dir.join(format!("{}-{}.csv", prefix, name));
+    let file = File::create(&path).map_err(|e| Error::CreateFile(path.display().to_string(), e))?;
+
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "{}", header).map_err(Error::Write)?;
+    writer.flush().map_err(Error::Write)?;
+    Ok(writer)
+}
+
+/**
+ * Dumps the inputs and outputs of every Oracle cycle as CSV, one file for the
+ * locos as seen by the Oracle, one for the locos controls and one for the
+ * actuators controls. Rows of the same cycle share the cycle number, so that
+ * the files can be joined for analyzing the decisions offline.
+ */
+pub struct OracleTracer {
+    cycle: u64,
+    inputs: BufWriter<File>,
+    loco_controls: BufWriter<File>,
+    actuator_controls: BufWriter<File>,
+}
+
+impl OracleTracer {
+    pub fn new(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).map_err(|e| Error::CreateDir(dir.display().to_string(), e))?;
+
+        // Every run gets its own files rather than overwriting the last ones
+        let started_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let prefix = format!("oracle-{}", started_secs);
+
+        Ok(OracleTracer {
+            cycle: 0,
+            inputs: create(
+                dir,
+                &prefix,
+                "inputs",
+                "cycle,timestamp_us,loco_id,location,location_timestamp_us,intent,speed,command_rtt_us",
+            )?,
+            loco_controls: create(
+                dir,
+                &prefix,
+                "loco_controls",
+                "cycle,timestamp_us,loco_id,direction,speed",
+            )?,
+            actuator_controls: create(
+                dir,
+                &prefix,
+                "actuator_controls",
+                "cycle,timestamp_us,actuator_id,actuator_type,actuator_state",
+            )?,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        timestamp_us: u64,
+        locos: &[TracedLoco],
+        actuator_controls: &[(ActuatorId, ActuatorType, u8)],
+        loco_controls: &[(LocoId, Direction, Speed)],
+    ) -> Result<()> {
+        self.cycle += 1;
+        let cycle = self.cycle;
+
+        for loco in locos.iter() {
+            writeln!(
+                self.inputs,
+                "{},{},{},{},{},{},{},{}",
+                cycle,
+                timestamp_us,
+                csv_field(&loco.loco_id),
+                csv_field(&loco.location),
+                csv_field(&loco.location_timestamp_us),
+                csv_field(&loco.intent),
+                csv_field(&loco.speed),
+                csv_field(&loco.command_rtt_us),
+            )
+            .map_err(Error::Write)?;
+        }
+
+        for (loco_id, direction, speed) in loco_controls.iter() {
+            writeln!(
+                self.loco_controls,
+                "{},{},{},{},{}",
+                cycle,
+                timestamp_us,
+                csv_field(loco_id),
+                csv_field(direction),
+                csv_field(speed),
+            )
+            .map_err(Error::Write)?;
+        }
+
+        for (actuator_id, actuator_type, actuator_state) in actuator_controls.iter() {
+            writeln!(
+                self.actuator_controls,
+                "{},{},{},{},{}",
+                cycle,
+                timestamp_us,
+                csv_field(actuator_id),
+                csv_field(actuator_type),
+                actuator_state,
+            )
+            .map_err(Error::Write)?;
+        }
+
+        // Flushed every cycle, so that the files can be read while the
+        // controller runs
+        self.inputs.flush().map_err(Error::Write)?;
+        self.loco_controls.flush().map_err(Error::Write)?;
+        self.actuator_controls.flush().map_err(Error::Write)
+    }
+}
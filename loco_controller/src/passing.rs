@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+
+use loco_protocol::{Direction, LocoId};
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    backend::LocoIntent,
+    rail_network::{CheckpointId, RailNetwork},
+};
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ManeuverStep {
+    // Heading for the passing loop
+    Entering,
+    // Standing in the passing loop until the other loco is past it
+    Waiting,
+}
+
+/**
+ * A loco giving way to another one heading towards it, by pulling into a
+ * passing loop. Until the other loco is past the loop, the Oracle drives the
+ * loco through the intent of the maneuver instead of its own one, which it
+ * then resumes.
+ */
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+pub struct Maneuver {
+    loco_id: LocoId,
+    // Loco let through
+    passing: LocoId,
+    intent: LocoIntent,
+    step: ManeuverStep,
+    #[serde(skip)]
+    own_intent: LocoIntent,
+    // Checkpoint on the way in to the loop, which the other loco goes by
+    // once past it
+    #[serde(skip)]
+    clear: CheckpointId,
+}
+
+// What the planner needs to know about a loco
+pub struct LocoCourse {
+    pub id: LocoId,
+    pub location: Option<CheckpointId>,
+    pub intent: Option<LocoIntent>,
+}
+
+impl LocoCourse {
+    // Locos following another one never head towards anyone on their own,
+    // and locos which reached their stop aren't going anywhere
+    fn heading(&self) -> Option<(CheckpointId, Direction)> {
+        let location = self.location?;
+        match self.intent? {
+            LocoIntent::Drive(direction, _) => Some((location, direction)),
+            LocoIntent::Stop(direction, target) if target != location => {
+                Some((location, direction))
+            }
+            LocoIntent::Stop(..) | LocoIntent::Follow(..) => None,
+        }
+    }
+}
+
+fn reverse(direction: Direction) -> Direction {
+    match direction {
+        Direction::Forward => Direction::Backward,
+        Direction::Backward => Direction::Forward,
+    }
+}
+
+/**
+ * Plans the maneuvers of the locos heading towards each other on the same
+ * line, which would otherwise end up facing each other with nowhere to go.
+ * The loco with the lowest priority pulls into the nearest passing loop it
+ * can reach before running into the other one, or the other one does if it
+ * can't. On equal priorities, the loco closest to a loop gives way.
+ */
+pub struct PassingPlanner {
+    priorities: BTreeMap<LocoId, u8>,
+    maneuvers: BTreeMap<LocoId, Maneuver>,
+}
+
+impl PassingPlanner {
+    pub fn new(priorities: &BTreeMap<LocoId, u8>) -> Self {
+        PassingPlanner {
+            priorities: priorities.clone(),
+            maneuvers: BTreeMap::new(),
+        }
+    }
+
+    // Intent the Oracle drives the loco through instead of its own one, if
+    // the loco is giving way
+    pub fn intent(&self, loco_id: LocoId) -> Option<LocoIntent> {
+        self.maneuvers.get(&loco_id).map(|m| m.intent)
+    }
+
+    pub fn maneuvers(&self) -> Vec<Maneuver> {
+        self.maneuvers.values().copied().collect()
+    }
+
+    // Must be called on every cycle of the Oracle, with the locos as they
+    // report themselves
+    pub fn update(&mut self, rail_network: &RailNetwork, locos: &[LocoCourse]) {
+        self.maneuvers
+            .retain(|_, maneuver| Self::progress(maneuver, locos));
+
+        for (i, a) in locos.iter().enumerate() {
+            for b in locos.iter().skip(i + 1) {
+                if self.involved(a.id) || self.involved(b.id) {
+                    continue;
+                }
+                if let Some(maneuver) = self.plan(rail_network, locos, a, b) {
+                    info!(
+                        "PassingPlanner::update(): {} gives way to {} at {:?}",
+                        maneuver.loco_id, maneuver.passing, maneuver.intent
+                    );
+                    self.maneuvers.insert(maneuver.loco_id, maneuver);
+                }
+            }
+        }
+    }
+
+    fn involved(&self, loco_id: LocoId) -> bool {
+        self.maneuvers
+            .values()
+            .any(|m| m.loco_id == loco_id || m.passing == loco_id)
+    }
+
+    // Moves the maneuver along, telling whether it's still going on
+    fn progress(maneuver: &mut Maneuver, locos: &[LocoCourse]) -> bool {
+        let find = |loco_id| locos.iter().find(|l| l.id == loco_id);
+        let (Some(loco), Some(passing)) = (find(maneuver.loco_id), find(maneuver.passing)) else {
+            info!(
+                "PassingPlanner::progress(): {} resumes, {} is gone",
+                maneuver.loco_id, maneuver.passing
+            );
+            return false;
+        };
+
+        // The loco was given something else to do in the meantime
+        if loco.intent != Some(maneuver.own_intent) {
+            info!(
+                "PassingPlanner::progress(): {} got a new intent",
+                maneuver.loco_id
+            );
+            return false;
+        }
+
+        let direction = maneuver.intent.direction();
+        let past = match passing.heading() {
+            Some((location, passing_direction)) => {
+                passing_direction == direction || location == maneuver.clear
+            }
+            None => true,
+        };
+        if past {
+            info!(
+                "PassingPlanner::progress(): {} resumes, {} is past",
+                maneuver.loco_id, maneuver.passing
+            );
+            return false;
+        }
+
+        if let LocoIntent::Stop(_, passing_loop) = maneuver.intent
+            && loco.location == Some(passing_loop)
+        {
+            maneuver.step = ManeuverStep::Waiting;
+        }
+
+        true
+    }
+
+    fn plan(
+        &self,
+        rail_network: &RailNetwork,
+        locos: &[LocoCourse],
+        a: &LocoCourse,
+        b: &LocoCourse,
+    ) -> Option<Maneuver> {
+        let (_, a_direction) = a.heading()?;
+        let (_, b_direction) = b.heading()?;
+        if a_direction == b_direction {
+            return None;
+        }
+
+        // Whichever has a loop within reach, by order of priority
+        let a_loop = self.nearest_loop(rail_network, locos, a, b);
+        let b_loop = self.nearest_loop(rail_network, locos, b, a);
+        let a_priority = self.priorities.get(&a.id).copied().unwrap_or_default();
+        let b_priority = self.priorities.get(&b.id).copied().unwrap_or_default();
+        let a_gives_way = match (a_loop, b_loop) {
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some((_, a_distance)), Some((_, b_distance))) => {
+                (a_priority, a_distance) <= (b_priority, b_distance)
+            }
+            (None, None) => return None,
+        };
+        let (loco, direction, passing, (passing_loop, _)) = if a_gives_way {
+            (a, a_direction, b, a_loop?)
+        } else {
+            (b, b_direction, a, b_loop?)
+        };
+
+        Some(Maneuver {
+            loco_id: loco.id,
+            passing: passing.id,
+            intent: LocoIntent::Stop(direction, passing_loop),
+            step: ManeuverStep::Entering,
+            // Safe to unwrap since the loco is heading somewhere
+            own_intent: loco.intent.unwrap(),
+            clear: *rail_network
+                .next_checkpoint_ids(passing_loop, reverse(direction))
+                .first()?,
+        })
+    }
+
+    // Passing loop the loco reaches before the other loco's location, along
+    // with how far it is. Loops taken by another loco, or where the other
+    // loco is heading, are left alone.
+    fn nearest_loop(
+        &self,
+        rail_network: &RailNetwork,
+        locos: &[LocoCourse],
+        loco: &LocoCourse,
+        other: &LocoCourse,
+    ) -> Option<(CheckpointId, usize)> {
+        let (location, direction) = loco.heading()?;
+        let (other_location, _) = other.heading()?;
+        let other_distance = rail_network.distance(location, direction, other_location)?;
+
+        rail_network
+            .station_ids()
+            .into_iter()
+            .filter(|station| {
+                !locos
+                    .iter()
+                    .any(|l| l.id != loco.id && l.location == Some(*station))
+                    && !self.maneuvers.values().any(
+                        |m| matches!(m.intent, LocoIntent::Stop(_, target) if target == *station),
+                    )
+                    && !matches!(other.intent, Some(LocoIntent::Stop(_, target)) if target == *station)
+                    && !matches!(other.intent, Some(LocoIntent::Drive(_, track))
+                        if rail_network.track_id(*station) == track)
+            })
+            .filter_map(|station| {
+                let distance = rail_network.distance(location, direction, station)?;
+                (distance <= other_distance).then_some((station, distance))
+            })
+            .min_by_key(|(_, distance)| *distance)
+    }
+}
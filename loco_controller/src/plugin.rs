@@ -0,0 +1,207 @@
+use std::sync::Arc;
+
+use loco_protocol::{ActuatorId, ActuatorType, Direction, LocoId, SensorId, Speed};
+use log::{debug, info};
+
+use crate::{
+    backend::{Backend, Error as BackendError, Event, LocoIntent, LocoStatus},
+    rail_network::CheckpointId,
+};
+
+type Result<T> = std::result::Result<T, BackendError>;
+
+/**
+ * Commands available to plugins. This is a subset of the Backend, which goes
+ * through the Oracle rather than driving locos and actuators directly, so
+ * that a plugin can't bypass the safety of the automation.
+ */
+pub struct CommandApi {
+    backend: Arc<Backend>,
+}
+
+impl CommandApi {
+    pub fn loco_status(&self, loco_id: LocoId) -> Result<LocoStatus> {
+        self.backend.block_on(self.backend.loco_status(loco_id))
+    }
+
+    pub fn set_loco_intent(&self, loco_id: LocoId, intent: LocoIntent) {
+        self.backend.set_loco_intent(loco_id, intent)
+    }
+
+    pub fn oracle_enabled(&self) -> bool {
+        self.backend.oracle_enabled()
+    }
+}
+
+/**
+ * Custom automation hooked onto the rail network events. Every hook does
+ * nothing by default, so that a plugin only implements the ones it cares
+ * about. Hooks are called from a dedicated thread, hence a slow plugin delays
+ * the next events but never the Oracle.
+ *
+ * Plugins are compiled into the loco_controller: a new plugin implements this
+ * trait and gets registered in builtin(), to be enabled through the
+ * plugins.enabled configuration key.
+ */
+pub trait Plugin: Send {
+    fn name(&self) -> &'static str;
+
+    fn on_sensor_hit(&mut self, _api: &CommandApi, _loco_id: LocoId, _sensor_id: SensorId) {}
+
+    fn on_intent_completed(&mut self, _api: &CommandApi, _loco_id: LocoId, _intent: LocoIntent) {}
+
+    fn on_oracle_decision(
+        &mut self,
+        _api: &CommandApi,
+        _actuators: &[(ActuatorId, ActuatorType, u8)],
+        _locos: &[(LocoId, Direction, Speed)],
+    ) {
+    }
+
+    fn on_location_corrected(
+        &mut self,
+        _api: &CommandApi,
+        _loco_id: LocoId,
+        _sensor_id: Option<SensorId>,
+    ) {
+    }
+}
+
+// Logs every event, which is mostly useful as a starting point for writing a
+// new plugin
+struct EventLogger;
+
+impl Plugin for EventLogger {
+    fn name(&self) -> &'static str {
+        "event_logger"
+    }
+
+    fn on_sensor_hit(&mut self, _api: &CommandApi, loco_id: LocoId, sensor_id: SensorId) {
+        info!("EventLogger: {} detected at {}", loco_id, sensor_id);
+    }
+
+    fn on_intent_completed(&mut self, api: &CommandApi, loco_id: LocoId, intent: LocoIntent) {
+        match api.loco_status(loco_id) {
+            Ok(status) => info!(
+                "EventLogger: {} completed {:?}, now {:?} at {:?}",
+                loco_id,
+                intent,
+                status.speed(),
+                status.location()
+            ),
+            Err(e) => info!("EventLogger: {} completed {:?} ({})", loco_id, intent, e),
+        }
+    }
+
+    fn on_oracle_decision(
+        &mut self,
+        _api: &CommandApi,
+        actuators: &[(ActuatorId, ActuatorType, u8)],
+        locos: &[(LocoId, Direction, Speed)],
+    ) {
+        info!("EventLogger: Oracle decided {:?} {:?}", actuators, locos);
+    }
+
+    fn on_location_corrected(
+        &mut self,
+        _api: &CommandApi,
+        loco_id: LocoId,
+        sensor_id: Option<SensorId>,
+    ) {
+        info!("EventLogger: {} relocated to {:?}", loco_id, sensor_id);
+    }
+}
+
+// Sends locos back and forth between both stations, as long as the Oracle
+// is running
+struct StationShuttle;
+
+impl Plugin for StationShuttle {
+    fn name(&self) -> &'static str {
+        "station_shuttle"
+    }
+
+    fn on_intent_completed(&mut self, api: &CommandApi, loco_id: LocoId, intent: LocoIntent) {
+        if !api.oracle_enabled() {
+            return;
+        }
+
+        let next_station = match intent {
+            LocoIntent::Stop(_, CheckpointId::Station1) => CheckpointId::Station2,
+            LocoIntent::Stop(_, CheckpointId::Station2) => CheckpointId::Station1,
+            _ => return,
+        };
+
+        info!("StationShuttle: sending {} to {:?}", loco_id, next_station);
+        api.set_loco_intent(loco_id, LocoIntent::Stop(intent.direction(), next_station));
+    }
+}
+
+pub fn builtin(name: &str) -> Option<Box<dyn Plugin>> {
+    match name {
+        "event_logger" => Some(Box::new(EventLogger)),
+        "station_shuttle" => Some(Box::new(StationShuttle)),
+        _ => None,
+    }
+}
+
+pub struct PluginHost {
+    plugins: Vec<Box<dyn Plugin>>,
+    api: CommandApi,
+}
+
+impl PluginHost {
+    pub fn new(backend: Arc<Backend>, plugins: Vec<Box<dyn Plugin>>) -> Self {
+        for plugin in plugins.iter() {
+            info!("PluginHost::new(): {} enabled", plugin.name());
+        }
+
+        PluginHost {
+            plugins,
+            api: CommandApi { backend },
+        }
+    }
+
+    // Automation is paused during maintenance, so that it doesn't get in the
+    // way of the crew
+    pub fn dispatch(&mut self, event: &Event) {
+        if self.api.backend.maintenance().is_some() {
+            return;
+        }
+
+        for plugin in self.plugins.iter_mut() {
+            debug!("PluginHost::dispatch(): {:?} to {}", event, plugin.name());
+            match event {
+                Event::SensorHit {
+                    loco_id, sensor_id, ..
+                } => plugin.on_sensor_hit(&self.api, *loco_id, *sensor_id),
+                Event::IntentCompleted { loco_id, intent } => {
+                    plugin.on_intent_completed(&self.api, *loco_id, *intent)
+                }
+                Event::OracleDecision {
+                    actuators, locos, ..
+                } => plugin.on_oracle_decision(&self.api, actuators, locos),
+                Event::LocationCorrected { loco_id, sensor_id } => {
+                    plugin.on_location_corrected(&self.api, *loco_id, *sensor_id)
+                }
+                Event::SensorDeparture { .. }
+                | Event::LocoIntentSet { .. }
+                | Event::LocoCommandApplied { .. }
+                | Event::ActuatorsDriven { .. }
+                | Event::StartupProgress { .. }
+                | Event::LocoConnected { .. }
+                | Event::LocoDisconnected { .. }
+                | Event::ActuatorsConnected
+                | Event::ActuatorsDisconnected
+                | Event::EmergencyStop { .. }
+                | Event::MaintenanceStarted { .. }
+                | Event::MaintenanceEnded
+                | Event::DayProgramSwitched { .. }
+                | Event::UnknownTag { .. }
+                | Event::WagonHit { .. }
+                | Event::ConsistIssue { .. }
+                | Event::SafeCrawl { .. } => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use loco_protocol::{LocoId, TrackPowerState};
+use serde::Serialize;
+
+use crate::{backend::Backend, config::NetworkConfig, rail_network::SegmentId};
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DistrictStatus {
+    budget_ma: u32,
+    load_ma: u32,
+    // Locos moving through the district
+    locos: Vec<LocoId>,
+    // False once cut from its supply by its track power actuator
+    powered: bool,
+}
+
+/**
+ * Current drawn from every district during an Oracle cycle, as the Oracle
+ * lets locos go through the segments.
+ */
+pub struct PowerBudget {
+    loco_current_ma: u32,
+    districts: BTreeMap<SegmentId, String>,
+    status: BTreeMap<String, DistrictStatus>,
+}
+
+impl PowerBudget {
+    // Accounts for a loco which is already running through the segment,
+    // hence can't be held anymore. Only fails when the district has been
+    // isolated, in which case the loco must be stopped before the power
+    // comes back.
+    pub fn reserve(&mut self, loco_id: LocoId, segment_id: SegmentId) -> bool {
+        let Some(status) = self
+            .districts
+            .get(&segment_id)
+            .and_then(|name| self.status.get_mut(name))
+        else {
+            return true;
+        };
+        if !status.powered {
+            return false;
+        }
+
+        status.load_ma += self.loco_current_ma;
+        status.locos.push(loco_id);
+        true
+    }
+
+    // Accounts for a loco about to enter the segment, unless its district
+    // can't take one more loco
+    pub fn try_reserve(&mut self, loco_id: LocoId, segment_id: SegmentId) -> bool {
+        if let Some(status) = self
+            .districts
+            .get(&segment_id)
+            .and_then(|name| self.status.get(name))
+            && status.load_ma + self.loco_current_ma > status.budget_ma
+        {
+            return false;
+        }
+
+        self.reserve(loco_id, segment_id)
+    }
+}
+
+/**
+ * Power districts of the active layout. The Oracle checks every loco entering
+ * a segment against the budget of its district, and keeps locos out of the
+ * districts which have been isolated, such as when the track power is cut
+ * after an overcurrent. What the Oracle decided last is kept for reporting.
+ */
+pub struct PowerDistricts {
+    backend: Arc<Backend>,
+    network: RwLock<NetworkConfig>,
+    status: Mutex<BTreeMap<String, DistrictStatus>>,
+}
+
+impl PowerDistricts {
+    pub fn new(backend: Arc<Backend>, network: &NetworkConfig) -> Self {
+        PowerDistricts {
+            backend,
+            network: RwLock::new(network.clone()),
+            status: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Called when switching to another layout
+    pub fn set_network(&self, network: &NetworkConfig) {
+        *self.network.write().unwrap() = network.clone();
+        self.status.lock().unwrap().clear();
+    }
+
+    // Empty budget for a new Oracle cycle
+    pub fn budget(&self) -> PowerBudget {
+        let network = self.network.read().unwrap();
+
+        let status = network
+            .power_districts
+            .iter()
+            .map(|(name, district)| {
+                let powered = district.track_power.is_none_or(|id| {
+                    !matches!(
+                        self.backend.track_power_state(id),
+                        Some(TrackPowerState::Off)
+                    )
+                });
+                (
+                    name.clone(),
+                    DistrictStatus {
+                        budget_ma: district.budget_ma,
+                        load_ma: 0,
+                        locos: Vec::new(),
+                        powered,
+                    },
+                )
+            })
+            .collect();
+
+        PowerBudget {
+            loco_current_ma: network.loco_current_ma,
+            districts: network
+                .power_districts
+                .iter()
+                .flat_map(|(name, district)| district.segments.iter().map(|s| (*s, name.clone())))
+                .collect(),
+            status,
+        }
+    }
+
+    pub fn record(&self, budget: PowerBudget) {
+        *self.status.lock().unwrap() = budget.status;
+    }
+
+    pub fn status(&self) -> BTreeMap<String, DistrictStatus> {
+        self.status.lock().unwrap().clone()
+    }
+}
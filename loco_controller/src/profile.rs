@@ -0,0 +1,149 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use loco_protocol::{LocoId, SpeedCurve};
+use log::info;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    backend::{Backend, Error as BackendError},
+    buffer_stops::BufferStops,
+    config::{Config, NetworkConfig, ProfileConfig},
+    power::PowerDistricts,
+    rail_network::{Label, NetworkDescription, RailNetwork},
+    signals::Signals,
+    switch_order::SwitchOrder,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error checking locos are stopped: {0}")]
+    LocoStatus(#[source] BackendError),
+    #[error("Locos must be stopped to switch profiles")]
+    LocosNotStopped,
+    #[error("Unknown profile {0}")]
+    UnknownProfile(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ProfileDescription {
+    active: Option<String>,
+    available: Vec<String>,
+    roster: BTreeMap<LocoId, Label>,
+}
+
+struct ActiveProfile {
+    name: Option<String>,
+    roster: BTreeMap<LocoId, Label>,
+    network: NetworkDescription,
+}
+
+/**
+ * Named profiles, one per layout, each with its own network labels, roster
+ * and speed calibration. Whatever a profile doesn't set is taken from the
+ * main configuration, which is what's in use when no profile is active.
+ */
+pub struct Profiles {
+    backend: Arc<Backend>,
+    power_districts: Arc<PowerDistricts>,
+    buffer_stops: Arc<BufferStops>,
+    signals: Arc<Signals>,
+    switch_order: Arc<SwitchOrder>,
+    profiles: BTreeMap<String, ProfileConfig>,
+    network: NetworkConfig,
+    speed_curves: BTreeMap<LocoId, SpeedCurve>,
+    active: RwLock<ActiveProfile>,
+}
+
+impl Profiles {
+    pub fn new(
+        backend: Arc<Backend>,
+        power_districts: Arc<PowerDistricts>,
+        buffer_stops: Arc<BufferStops>,
+        signals: Arc<Signals>,
+        switch_order: Arc<SwitchOrder>,
+        config: &Config,
+    ) -> Self {
+        let profiles = Profiles {
+            backend,
+            power_districts,
+            buffer_stops,
+            signals,
+            switch_order,
+            profiles: config.profiles.clone(),
+            network: config.network.clone(),
+            speed_curves: config.backend.speed_curves.clone(),
+            active: RwLock::new(ActiveProfile {
+                name: None,
+                roster: BTreeMap::new(),
+                network: RailNetwork::new()
+                    .describe(&config.network.checkpoints, &config.network.tracks),
+            }),
+        };
+
+        // The startup profile has been validated along with the configuration
+        if let Some(name) = &config.profile {
+            profiles.apply(name, &config.profiles[name]);
+        }
+
+        profiles
+    }
+
+    fn apply(&self, name: &str, profile: &ProfileConfig) {
+        info!("Profiles::apply(): activating {}", name);
+
+        let network = profile.network.as_ref().unwrap_or(&self.network);
+        let speed_curves = profile.speed_curves.as_ref().unwrap_or(&self.speed_curves);
+
+        self.backend.set_speed_curves(speed_curves.clone());
+        self.backend.set_network(network);
+        self.power_districts.set_network(network);
+        self.buffer_stops.set_network(network);
+        self.signals.set_network(network);
+        self.switch_order.set_network(network);
+        *self.active.write().unwrap() = ActiveProfile {
+            name: Some(name.to_string()),
+            roster: profile.roster.clone(),
+            network: RailNetwork::new().describe(&network.checkpoints, &network.tracks),
+        };
+    }
+
+    // Switching the layout under moving trains would make no sense, hence
+    // this is only allowed while every loco is stopped.
+    pub fn activate(&self, name: &str) -> Result<()> {
+        let profile = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| Error::UnknownProfile(name.to_string()))?;
+
+        if !self
+            .backend
+            .block_on(self.backend.locos_stopped())
+            .map_err(Error::LocoStatus)?
+        {
+            return Err(Error::LocosNotStopped);
+        }
+
+        self.apply(name, profile);
+
+        Ok(())
+    }
+
+    pub fn network(&self) -> NetworkDescription {
+        self.active.read().unwrap().network.clone()
+    }
+
+    pub fn describe(&self) -> ProfileDescription {
+        let active = self.active.read().unwrap();
+        ProfileDescription {
+            active: active.name.clone(),
+            available: self.profiles.keys().cloned().collect(),
+            roster: active.roster.clone(),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use actix_web::{App, HttpResponse, HttpServer, Responder, get, middleware::from_fn, web};
+use log::debug;
+
+use crate::{
+    rate_limit::{RateLimiter, rate_limit},
+    state::StateTracker,
+};
+
+#[get("/status")]
+async fn public_status(state: web::Data<Arc<StateTracker>>) -> impl Responder {
+    HttpResponse::Ok().json(state.public())
+}
+
+/**
+ * Read-only API for public viewers, kept apart from the control API: it has
+ * its own port and its own rate limits, and nothing but the StateTracker to
+ * read from. Whatever isn't GET /status gets a 404.
+ */
+#[actix_web::main]
+pub async fn public_main(
+    port: u16,
+    state: Arc<StateTracker>,
+    rate_limiter: RateLimiter,
+) -> std::io::Result<()> {
+    debug!("public_main(): Waiting for incoming connection...");
+    let rate_limiter = web::Data::new(rate_limiter);
+    HttpServer::new(move || {
+        App::new()
+            .wrap(from_fn(rate_limit))
+            .app_data(web::Data::new(state.clone()))
+            .app_data(rate_limiter.clone())
+            .service(public_status)
+    })
+    .bind(("0.0.0.0", port))?
+    .run()
+    .await
+}
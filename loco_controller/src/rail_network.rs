@@ -1,6 +1,7 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
-use loco_protocol::{ActuatorId, Direction, SensorId, SwitchRailsState};
+pub use loco_protocol::route::{CheckpointId, SegmentPriority, TrackId};
+use loco_protocol::{ActuatorId, Direction, SensorId, SwitchRailsState, route::RouteGraph};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -12,50 +13,49 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub enum SegmentPriority {
-    Priority0,
-    Priority1,
-    Priority2,
+/**
+ * Human friendly metadata attached to checkpoints and tracks, so that UIs
+ * don't have to hardcode how the internal identifiers should be displayed.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Label {
+    pub name: String,
+    pub description: String,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
-#[serde(rename_all = "lowercase")]
-pub enum TrackId {
-    Track1,
-    Station1,
-    Station2,
+impl Label {
+    pub fn new(name: &str) -> Self {
+        Label {
+            name: name.to_string(),
+            description: String::new(),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
-#[serde(rename_all = "lowercase")]
-pub enum CheckpointId {
-    Checkpoint1,
-    Checkpoint2,
-    Checkpoint3,
-    Checkpoint4,
-    Checkpoint5,
-    Checkpoint6,
-    Station1,
-    Station2,
+#[derive(Serialize, Clone, Debug)]
+pub struct CheckpointDescription {
+    id: CheckpointId,
+    sensor_id: SensorId,
+    track_id: TrackId,
+    label: Label,
+    next: BTreeMap<Direction, Vec<CheckpointId>>,
 }
 
-impl Into<CheckpointId> for SensorId {
-    fn into(self) -> CheckpointId {
-        match self {
-            SensorId::RfidReader1 => CheckpointId::Checkpoint1,
-            SensorId::RfidReader2 => CheckpointId::Checkpoint2,
-            SensorId::RfidReader3 => CheckpointId::Checkpoint3,
-            SensorId::RfidReader4 => CheckpointId::Checkpoint4,
-            SensorId::RfidReader5 => CheckpointId::Checkpoint5,
-            SensorId::RfidReader6 => CheckpointId::Checkpoint6,
-            SensorId::RfidReader7 => CheckpointId::Station1,
-            SensorId::RfidReader8 => CheckpointId::Station2,
-        }
-    }
+#[derive(Serialize, Clone, Debug)]
+pub struct TrackDescription {
+    id: TrackId,
+    label: Label,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NetworkDescription {
+    checkpoints: Vec<CheckpointDescription>,
+    tracks: Vec<TrackDescription>,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
 pub enum SegmentId {
     Segment1,
     Segment2,
@@ -136,130 +136,21 @@ impl Segment {
     }
 }
 
-struct Checkpoint {
-    checkpoint_ids: BTreeMap<Direction, Vec<CheckpointId>>,
-    track_id: TrackId,
-    priority: SegmentPriority,
+pub struct RailNetwork {
+    graph: RouteGraph,
+    segments: BTreeMap<SegmentId, Segment>,
 }
 
-impl Checkpoint {
-    fn checkpoint_ids(&self, direction: &Direction) -> &Vec<CheckpointId> {
-        // Safe to unwrap since checkpoint_ids has been filled with every Direction
-        self.checkpoint_ids.get(direction).unwrap()
+impl Default for RailNetwork {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub struct RailNetwork {
-    checkpoints: BTreeMap<CheckpointId, Checkpoint>,
-    segments: BTreeMap<SegmentId, Segment>,
-    longest_path: usize,
-}
-
 impl RailNetwork {
     pub fn new() -> Self {
         RailNetwork {
-            checkpoints: BTreeMap::from([
-                (
-                    CheckpointId::Checkpoint1,
-                    Checkpoint {
-                        checkpoint_ids: BTreeMap::from([
-                            (Direction::Forward, Vec::from([CheckpointId::Checkpoint2])),
-                            (Direction::Backward, Vec::from([CheckpointId::Checkpoint6])),
-                        ]),
-                        track_id: TrackId::Track1,
-                        priority: SegmentPriority::Priority0,
-                    },
-                ),
-                (
-                    CheckpointId::Checkpoint2,
-                    Checkpoint {
-                        checkpoint_ids: BTreeMap::from([
-                            (Direction::Forward, Vec::from([CheckpointId::Checkpoint3])),
-                            (
-                                Direction::Backward,
-                                Vec::from([CheckpointId::Checkpoint1, CheckpointId::Station1]),
-                            ),
-                        ]),
-                        track_id: TrackId::Track1,
-                        priority: SegmentPriority::Priority0,
-                    },
-                ),
-                (
-                    CheckpointId::Checkpoint3,
-                    Checkpoint {
-                        checkpoint_ids: BTreeMap::from([
-                            (
-                                Direction::Forward,
-                                Vec::from([CheckpointId::Checkpoint4, CheckpointId::Station2]),
-                            ),
-                            (Direction::Backward, Vec::from([CheckpointId::Checkpoint2])),
-                        ]),
-                        track_id: TrackId::Track1,
-                        priority: SegmentPriority::Priority0,
-                    },
-                ),
-                (
-                    CheckpointId::Checkpoint4,
-                    Checkpoint {
-                        checkpoint_ids: BTreeMap::from([
-                            (Direction::Forward, Vec::from([CheckpointId::Checkpoint5])),
-                            (Direction::Backward, Vec::from([CheckpointId::Checkpoint3])),
-                        ]),
-                        track_id: TrackId::Track1,
-                        priority: SegmentPriority::Priority0,
-                    },
-                ),
-                (
-                    CheckpointId::Checkpoint5,
-                    Checkpoint {
-                        checkpoint_ids: BTreeMap::from([
-                            (Direction::Forward, Vec::from([CheckpointId::Checkpoint6])),
-                            (
-                                Direction::Backward,
-                                Vec::from([CheckpointId::Checkpoint4, CheckpointId::Station2]),
-                            ),
-                        ]),
-                        track_id: TrackId::Track1,
-                        priority: SegmentPriority::Priority0,
-                    },
-                ),
-                (
-                    CheckpointId::Checkpoint6,
-                    Checkpoint {
-                        checkpoint_ids: BTreeMap::from([
-                            (
-                                Direction::Forward,
-                                Vec::from([CheckpointId::Checkpoint1, CheckpointId::Station1]),
-                            ),
-                            (Direction::Backward, Vec::from([CheckpointId::Checkpoint5])),
-                        ]),
-                        track_id: TrackId::Track1,
-                        priority: SegmentPriority::Priority0,
-                    },
-                ),
-                (
-                    CheckpointId::Station1,
-                    Checkpoint {
-                        checkpoint_ids: BTreeMap::from([
-                            (Direction::Forward, Vec::from([CheckpointId::Checkpoint2])),
-                            (Direction::Backward, Vec::from([CheckpointId::Checkpoint6])),
-                        ]),
-                        track_id: TrackId::Station1,
-                        priority: SegmentPriority::Priority1,
-                    },
-                ),
-                (
-                    CheckpointId::Station2,
-                    Checkpoint {
-                        checkpoint_ids: BTreeMap::from([
-                            (Direction::Forward, Vec::from([CheckpointId::Checkpoint5])),
-                            (Direction::Backward, Vec::from([CheckpointId::Checkpoint3])),
-                        ]),
-                        track_id: TrackId::Station2,
-                        priority: SegmentPriority::Priority1,
-                    },
-                ),
-            ]),
+            graph: RouteGraph::new(),
             segments: BTreeMap::from([
                 (
                     SegmentId::Segment1,
@@ -366,7 +257,43 @@ impl RailNetwork {
                     },
                 ),
             ]),
-            longest_path: 6,
+        }
+    }
+
+    pub fn describe(
+        &self,
+        checkpoint_labels: &BTreeMap<CheckpointId, Label>,
+        track_labels: &BTreeMap<TrackId, Label>,
+    ) -> NetworkDescription {
+        let checkpoints: Vec<CheckpointDescription> = self
+            .graph
+            .checkpoint_ids()
+            .map(|id| CheckpointDescription {
+                id,
+                sensor_id: id.into(),
+                track_id: self.graph.track_id(id),
+                label: checkpoint_labels.get(&id).cloned().unwrap_or_default(),
+                next: [Direction::Forward, Direction::Backward]
+                    .into_iter()
+                    .map(|d| (d, self.graph.next_checkpoint_ids(id, d).to_vec()))
+                    .collect(),
+            })
+            .collect();
+
+        let mut track_ids: Vec<TrackId> = checkpoints.iter().map(|c| c.track_id).collect();
+        track_ids.sort();
+        track_ids.dedup();
+        let tracks = track_ids
+            .into_iter()
+            .map(|id| TrackDescription {
+                id,
+                label: track_labels.get(&id).cloned().unwrap_or_default(),
+            })
+            .collect();
+
+        NetworkDescription {
+            checkpoints,
+            tracks,
         }
     }
 
@@ -375,9 +302,29 @@ impl RailNetwork {
         self.segments.get(segment_id).unwrap()
     }
 
-    fn checkpoint(&self, checkpoint_id: &CheckpointId) -> &Checkpoint {
-        // Safe to unwrap since checkpoints has been filled with every CheckpointId
-        self.checkpoints.get(checkpoint_id).unwrap()
+    pub fn next_checkpoint_ids(
+        &self,
+        cp_id: CheckpointId,
+        direction: Direction,
+    ) -> &[CheckpointId] {
+        self.graph.next_checkpoint_ids(cp_id, direction)
+    }
+
+    // Every switch rails the network relies on
+    pub fn switch_rails_ids(&self) -> BTreeSet<ActuatorId> {
+        self.segments
+            .values()
+            .flat_map(|s| s.switch_rails.iter().map(|r| r.actuator_id))
+            .collect()
+    }
+
+    // Checkpoints of the passing loops, where a loco can stand aside
+    pub fn station_ids(&self) -> Vec<CheckpointId> {
+        self.graph.station_ids().into_iter().collect()
+    }
+
+    pub fn track_id(&self, cp_id: CheckpointId) -> TrackId {
+        self.graph.track_id(cp_id)
     }
 
     pub fn next_checkpoint_id_for_track_id_target(
@@ -387,36 +334,23 @@ impl RailNetwork {
         direction: Direction,
         target_track_id: TrackId,
     ) -> Option<CheckpointId> {
-        let mut next_cp_ids = self.checkpoint(&cp_id).checkpoint_ids(&direction).clone();
-
-        for next_cp_id in next_cp_ids.iter() {
-            if self.checkpoint(next_cp_id).track_id == target_track_id {
-                return Some(*next_cp_id);
-            }
-        }
-
-        // Order the checkpoints by priority
-        next_cp_ids.sort_by_key(|cid| self.checkpoint(cid).priority);
-
-        for next_cp_id in next_cp_ids.iter() {
-            if iteration >= self.longest_path {
-                return None;
-            }
-
-            if self
-                .next_checkpoint_id_for_track_id_target(
-                    iteration + 1,
-                    *next_cp_id,
-                    direction,
-                    target_track_id,
-                )
-                .is_some()
-            {
-                return Some(*next_cp_id);
-            }
-        }
+        self.graph.next_checkpoint_id_for_track_id_target(
+            iteration,
+            cp_id,
+            direction,
+            target_track_id,
+        )
+    }
 
-        return None;
+    // Number of segments to go through from a checkpoint to reach another one
+    // in the given direction, if it can be reached at all.
+    pub fn distance(
+        &self,
+        cp_id: CheckpointId,
+        direction: Direction,
+        target_cp_id: CheckpointId,
+    ) -> Option<usize> {
+        self.graph.distance(cp_id, direction, target_cp_id)
     }
 
     pub fn next_checkpoint_id_for_checkpoint_id_target(
@@ -426,35 +360,11 @@ impl RailNetwork {
         direction: Direction,
         target_cp_id: CheckpointId,
     ) -> Option<CheckpointId> {
-        let mut next_cp_ids = self.checkpoint(&cp_id).checkpoint_ids(&direction).clone();
-
-        for next_cp_id in next_cp_ids.iter() {
-            if *next_cp_id == target_cp_id {
-                return Some(*next_cp_id);
-            }
-        }
-
-        // Order the checkpoints by priority
-        next_cp_ids.sort_by_key(|cid| self.checkpoint(cid).priority);
-
-        for next_cp_id in next_cp_ids.iter() {
-            if iteration >= self.longest_path {
-                return None;
-            }
-
-            if self
-                .next_checkpoint_id_for_checkpoint_id_target(
-                    iteration + 1,
-                    *next_cp_id,
-                    direction,
-                    target_cp_id,
-                )
-                .is_some()
-            {
-                return Some(*next_cp_id);
-            }
-        }
-
-        return None;
+        self.graph.next_checkpoint_id_for_checkpoint_id_target(
+            iteration,
+            cp_id,
+            direction,
+            target_cp_id,
+        )
     }
 }
@@ -0,0 +1,345 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    HttpRequest, HttpResponse,
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    web,
+};
+use log::debug;
+use serde::Serialize;
+
+use crate::config::RateLimitConfig;
+
+// Header through which a client can identify itself, rather than being
+// identified by its IP address
+const CLIENT_TOKEN_HEADER: &str = "X-Client-Token";
+
+// Requests never rate limited, since they keep the layout safe
+const EXEMPT_PATHS: [&str; 1] = ["/emergency_stop"];
+
+// Header to which a reverse proxy appends the address of the client
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+// How long the bucket of a client which stopped sending requests is kept,
+// mostly for its metrics. Buckets which couldn't have refilled by then are
+// kept until they did, so that forgetting one never gives tokens back early.
+const IDLE_CLIENT_EXPIRY: Duration = Duration::from_secs(600);
+
+// How the clients are told apart
+enum ClientIdentity {
+    // By the token they give, or by their address
+    Token,
+    // By their address only, as they can't be trusted to identify themselves
+    Address,
+    // By the address the reverse proxy forwards
+    Forwarded,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ClientMetrics {
+    allowed: u64,
+    rejected: u64,
+}
+
+struct Client {
+    tokens: f64,
+    last_refill: Instant,
+    metrics: ClientMetrics,
+}
+
+struct Clients {
+    buckets: BTreeMap<String, Client>,
+    last_eviction: Instant,
+}
+
+/**
+ * Token bucket rate limiter, with one bucket per client. Every request takes
+ * a token, and tokens are given back at a steady rate up to the burst size.
+ * This prevents a single client from hogging the loco streams, which are
+ * shared with the Oracle. Buckets of idle clients are evicted, since clients
+ * come and go, and could otherwise grow the map with made up tokens.
+ */
+pub struct RateLimiter {
+    clients: Mutex<Clients>,
+    identity: ClientIdentity,
+    requests_per_sec: f64,
+    burst: f64,
+    idle_expiry: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let requests_per_sec = config.requests_per_sec as f64;
+        let burst = config.burst as f64;
+        RateLimiter {
+            clients: Mutex::new(Clients {
+                buckets: BTreeMap::new(),
+                last_eviction: Instant::now(),
+            }),
+            identity: ClientIdentity::Token,
+            requests_per_sec,
+            burst,
+            idle_expiry: IDLE_CLIENT_EXPIRY.max(Duration::from_secs_f64(burst / requests_per_sec)),
+        }
+    }
+
+    // For the public API, whose clients could dodge the limit by making up
+    // a new token for every request
+    pub fn public(config: &RateLimitConfig, behind_proxy: bool) -> Self {
+        RateLimiter {
+            identity: if behind_proxy {
+                ClientIdentity::Forwarded
+            } else {
+                ClientIdentity::Address
+            },
+            ..RateLimiter::new(config)
+        }
+    }
+
+    fn identify(&self, req: &HttpRequest) -> String {
+        match self.identity {
+            ClientIdentity::Token => client_id(req),
+            ClientIdentity::Address => peer_id(req),
+            // Only the last address is the one the proxy saw, the ones before
+            // it are whatever the client sent
+            ClientIdentity::Forwarded => match req
+                .headers()
+                .get(FORWARDED_FOR_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit(',').next())
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+            {
+                Some(addr) => format!("ip:{}", addr),
+                None => peer_id(req),
+            },
+        }
+    }
+
+    // Takes a token from the client bucket, or returns how long to wait
+    // until the next one is available.
+    fn acquire(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        // Sweeping at most once per expiry period is enough to bound the
+        // map to the clients seen within the last two periods
+        if now.duration_since(clients.last_eviction) >= self.idle_expiry {
+            clients
+                .buckets
+                .retain(|_, client| now.duration_since(client.last_refill) < self.idle_expiry);
+            clients.last_eviction = now;
+        }
+
+        let client = clients.buckets.entry(client.to_string()).or_insert(Client {
+            tokens: self.burst,
+            last_refill: now,
+            metrics: ClientMetrics::default(),
+        });
+
+        let elapsed = now.duration_since(client.last_refill).as_secs_f64();
+        client.tokens = (client.tokens + elapsed * self.requests_per_sec).min(self.burst);
+        client.last_refill = now;
+
+        if client.tokens < 1.0 {
+            client.metrics.rejected += 1;
+            return Err(Duration::from_secs_f64(
+                (1.0 - client.tokens) / self.requests_per_sec,
+            ));
+        }
+
+        client.tokens -= 1.0;
+        client.metrics.allowed += 1;
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> BTreeMap<String, ClientMetrics> {
+        self.clients
+            .lock()
+            .unwrap()
+            .buckets
+            .iter()
+            .map(|(id, client)| (id.clone(), client.metrics.clone()))
+            .collect()
+    }
+}
+
+// Identifies the client by its token if it gave one, or by its address.
+// The token is qualified by the address, so that clients on different hosts
+// never share a bucket by giving the same token.
+pub fn client_id(req: &HttpRequest) -> String {
+    if let Some(token) = req
+        .headers()
+        .get(CLIENT_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        return format!("token:{}@{}", token, peer_id(req));
+    }
+
+    peer_id(req)
+}
+
+fn peer_id(req: &HttpRequest) -> String {
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "unknown".to_string(),
+    }
+}
+
+// Retry-After only takes whole seconds, rounded up so that a client waiting
+// as told always finds a token
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs_f64().ceil() as u64
+}
+
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>()
+        && !EXEMPT_PATHS.contains(&req.path())
+    {
+        let client = limiter.identify(req.request());
+        if let Err(retry_after) = limiter.acquire(&client, Instant::now()) {
+            debug!("rate_limit(): too many requests from {}", client);
+            let response = HttpResponse::TooManyRequests()
+                .insert_header((
+                    header::RETRY_AFTER,
+                    retry_after_secs(retry_after).to_string(),
+                ))
+                .body("Too many requests");
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn limiter(requests_per_sec: u32, burst: u32) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            requests_per_sec,
+            burst,
+        })
+    }
+
+    #[test]
+    fn refill() {
+        let limiter = limiter(2, 2);
+        let start = Instant::now();
+        assert!(limiter.acquire("ui", start).is_ok());
+        assert!(limiter.acquire("ui", start).is_ok());
+        assert_eq!(
+            limiter.acquire("ui", start),
+            Err(Duration::from_millis(500))
+        );
+
+        // Other clients have buckets of their own
+        assert!(limiter.acquire("other", start).is_ok());
+
+        // A token is given back every 500ms, up to the burst size
+        assert!(
+            limiter
+                .acquire("ui", start + Duration::from_millis(500))
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .acquire("ui", start + Duration::from_millis(500))
+                .is_err()
+        );
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.acquire("ui", later).is_ok());
+        assert!(limiter.acquire("ui", later).is_ok());
+        assert!(limiter.acquire("ui", later).is_err());
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics["ui"].allowed, 5);
+        assert_eq!(metrics["ui"].rejected, 3);
+    }
+
+    #[test]
+    fn retry_after() {
+        let limiter = limiter(4, 1);
+        let start = Instant::now();
+        assert!(limiter.acquire("ui", start).is_ok());
+        let retry_after = limiter.acquire("ui", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(250));
+        assert_eq!(retry_after_secs(retry_after), 1);
+
+        assert_eq!(retry_after_secs(Duration::from_secs(1)), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1001)), 2);
+    }
+
+    #[test]
+    fn idle_clients_evicted() {
+        let limiter = limiter(1, 1);
+        let start = Instant::now();
+        assert!(limiter.acquire("gone", start).is_ok());
+        assert!(limiter.acquire("active", start).is_ok());
+        let later = start + IDLE_CLIENT_EXPIRY;
+        assert!(
+            limiter
+                .acquire("active", later - Duration::from_secs(1))
+                .is_ok()
+        );
+        assert!(limiter.acquire("new", later).is_ok());
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.keys().collect::<Vec<_>>(), vec!["active", "new"]);
+    }
+
+    #[test]
+    fn drained_clients_kept_until_refilled() {
+        // Takes longer than IDLE_CLIENT_EXPIRY to refill
+        let burst = 2 * IDLE_CLIENT_EXPIRY.as_secs() as u32;
+        let limiter = limiter(1, burst);
+        let start = Instant::now();
+        for _ in 0..burst {
+            assert!(limiter.acquire("ui", start).is_ok());
+        }
+        assert!(limiter.acquire("ui", start).is_err());
+
+        let later = start + IDLE_CLIENT_EXPIRY;
+        assert!(limiter.acquire("other", later).is_ok());
+        assert!(limiter.metrics().contains_key("ui"));
+        // Only the tokens given back in the meantime are available
+        let tokens = IDLE_CLIENT_EXPIRY.as_secs();
+        for _ in 0..tokens {
+            assert!(limiter.acquire("ui", later).is_ok());
+        }
+        assert!(limiter.acquire("ui", later).is_err());
+    }
+
+    #[test]
+    fn client_token_qualified_by_address() {
+        let req = TestRequest::default()
+            .peer_addr("192.168.1.10:4321".parse().unwrap())
+            .insert_header((CLIENT_TOKEN_HEADER, "ui"))
+            .to_http_request();
+        assert_eq!(client_id(&req), "token:ui@ip:192.168.1.10");
+
+        let req = TestRequest::default()
+            .peer_addr("192.168.1.11:4321".parse().unwrap())
+            .insert_header((CLIENT_TOKEN_HEADER, "ui"))
+            .to_http_request();
+        assert_eq!(client_id(&req), "token:ui@ip:192.168.1.11");
+
+        let req = TestRequest::default()
+            .peer_addr("192.168.1.10:4321".parse().unwrap())
+            .to_http_request();
+        assert_eq!(client_id(&req), "ip:192.168.1.10");
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use loco_protocol::LocoId;
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::{
+    backend::{Backend, Error as BackendError, Event, LocoIntent},
+    config::{RouteProgram, RouteStep, RouteTrigger, RoutesConfig},
+    rail_network::CheckpointId,
+};
+
+// How often the running programs are moved along, between the events
+pub const CHECK_PERIOD: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RouteProgramStatus {
+    // Laps counted since the program was last triggered
+    laps: u32,
+    // Times the program has been triggered
+    runs: u64,
+    // Step being run, None when the program is idle
+    step: Option<usize>,
+}
+
+#[derive(Default)]
+struct ProgramState {
+    status: RouteProgramStatus,
+    // When the period of the program started
+    period_start_us: Option<u64>,
+}
+
+struct Programs {
+    states: BTreeMap<String, ProgramState>,
+    // Intents completed by the locos, and not replaced since
+    completed_intents: BTreeMap<LocoId, LocoIntent>,
+}
+
+/**
+ * Runs the route programs: sequences of steps setting a group of switch rails
+ * or the intent of a loco, started every few laps of a loco or periodically.
+ * The switch rails are never thrown while the Oracle relies on them, and the
+ * locos are moved through their intents, hence the Oracle keeps the final say
+ * on what runs where. Programs only run along with the Oracle, and are paused
+ * during maintenance.
+ */
+pub struct RoutePrograms {
+    backend: Arc<Backend>,
+    config: RoutesConfig,
+    programs: Mutex<Programs>,
+}
+
+impl RoutePrograms {
+    pub fn new(backend: Arc<Backend>, config: &RoutesConfig) -> Self {
+        RoutePrograms {
+            backend,
+            config: config.clone(),
+            programs: Mutex::new(Programs {
+                states: config
+                    .programs
+                    .keys()
+                    .map(|name| (name.clone(), ProgramState::default()))
+                    .collect(),
+                completed_intents: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.backend.oracle_enabled() && self.backend.maintenance().is_none()
+    }
+
+    pub fn apply(&self, event: &Event) {
+        let mut programs = self.programs.lock().unwrap();
+        match event {
+            Event::SensorHit {
+                loco_id, sensor_id, ..
+            } => {
+                if !self.active() {
+                    return;
+                }
+                let checkpoint_id = CheckpointId::from(*sensor_id);
+                for (name, program) in self.config.programs.iter() {
+                    if let RouteTrigger::Laps {
+                        loco_id: lap_loco_id,
+                        checkpoint,
+                        every,
+                    } = program.trigger
+                        && lap_loco_id == *loco_id
+                        && checkpoint == checkpoint_id
+                    {
+                        let state = programs.states.get_mut(name).unwrap();
+                        state.status.laps += 1;
+                        if state.status.laps >= every {
+                            state.status.laps = 0;
+                            start(name, state);
+                        }
+                    }
+                }
+            }
+            Event::IntentCompleted { loco_id, intent } => {
+                programs.completed_intents.insert(*loco_id, *intent);
+            }
+            Event::LocoIntentSet { loco_id, .. } => {
+                programs.completed_intents.remove(loco_id);
+            }
+            _ => {}
+        }
+    }
+
+    // Starts the programs whose period elapsed, and runs the steps of every
+    // running program until one has to wait
+    pub fn check(&self, now_us: u64) {
+        let mut programs = self.programs.lock().unwrap();
+        let active = self.active();
+
+        for (name, program) in self.config.programs.iter() {
+            let Programs {
+                states,
+                completed_intents,
+            } = &mut *programs;
+            // Safe to unwrap since every program got its state when created
+            let state = states.get_mut(name).unwrap();
+
+            // Periods start over once the Oracle is back
+            if !active {
+                state.period_start_us = None;
+                continue;
+            }
+
+            if let RouteTrigger::Period { every_secs } = program.trigger {
+                let period_start_us = *state.period_start_us.get_or_insert(now_us);
+                if now_us.saturating_sub(period_start_us) >= every_secs * 1_000_000 {
+                    state.period_start_us = Some(now_us);
+                    start(name, state);
+                }
+            }
+
+            self.run(name, program, state, completed_intents);
+        }
+    }
+
+    fn run(
+        &self,
+        name: &str,
+        program: &RouteProgram,
+        state: &mut ProgramState,
+        completed_intents: &mut BTreeMap<LocoId, LocoIntent>,
+    ) {
+        while let Some(step) = state.status.step {
+            let Some(route_step) = program.steps.get(step) else {
+                info!("RoutePrograms::run(): {} completed", name);
+                state.status.step = None;
+                return;
+            };
+
+            match route_step {
+                RouteStep::SwitchRails(switch_rails) => {
+                    match self
+                        .backend
+                        .block_on(self.backend.drive_free_switch_rails(switch_rails))
+                    {
+                        Ok(()) => {}
+                        Err(BackendError::SwitchRailsHeld(actuator_id)) => {
+                            debug!("RoutePrograms::run(): {} waiting for {}", name, actuator_id);
+                            return;
+                        }
+                        Err(e) => {
+                            warn!(
+                                "RoutePrograms::run(): {} aborted at step {}: {}",
+                                name, step, e
+                            );
+                            state.status.step = None;
+                            return;
+                        }
+                    }
+                }
+                RouteStep::Intent { loco_id, intent } => {
+                    // Whatever the loco completed before doesn't count for
+                    // the steps waiting for this new intent
+                    completed_intents.remove(loco_id);
+                    self.backend.set_loco_intent(*loco_id, *intent);
+                }
+                RouteStep::WaitIntent { loco_id } => {
+                    if !completed_intents.contains_key(loco_id) {
+                        return;
+                    }
+                }
+            }
+
+            state.status.step = Some(step + 1);
+        }
+    }
+
+    pub fn status(&self) -> BTreeMap<String, RouteProgramStatus> {
+        self.programs
+            .lock()
+            .unwrap()
+            .states
+            .iter()
+            .map(|(name, state)| (name.clone(), state.status.clone()))
+            .collect()
+    }
+}
+
+// A program being triggered again while it's still running goes on with its
+// current run
+fn start(name: &str, state: &mut ProgramState) {
+    if state.status.step.is_some() {
+        debug!("RoutePrograms::start(): {} already running", name);
+        return;
+    }
+
+    info!("RoutePrograms::start(): {} triggered", name);
+    state.status.runs += 1;
+    state.status.step = Some(0);
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use loco_protocol::{Direction, LocoId, Speed};
+use log::{debug, error};
+use serde::Serialize;
+
+use crate::{
+    backend::{Alarm, Backend},
+    config::SafetyConfig,
+    rail_network::{CheckpointId, RailNetwork, SegmentId},
+};
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Violation {
+    ConflictingSegments {
+        locos: (LocoId, LocoId),
+        segments: (SegmentId, SegmentId),
+    },
+    NextCheckpointOccupied {
+        loco_id: LocoId,
+        checkpoint_id: CheckpointId,
+        occupied_by: LocoId,
+    },
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SafetyStatus {
+    // Violations seen during the last check, which haven't lasted long
+    // enough yet to stop everything
+    pending: Vec<Violation>,
+    // Violations which caused the last emergency stop
+    tripped: Vec<Violation>,
+}
+
+// What the monitor knows about a loco, as reported by the loco itself
+struct ObservedLoco {
+    id: LocoId,
+    location: CheckpointId,
+    direction: Direction,
+    moving: bool,
+}
+
+/**
+ * Safety net running alongside the Oracle, without sharing anything with it
+ * but the state of the Backend. It checks the locos' own reports against the
+ * rail network, and stops everything when the Oracle lets two locos run into
+ * each other. A violation has to last for the grace period first, since the
+ * reported statuses lag behind the commands.
+ */
+pub struct SafetyMonitor {
+    backend: Arc<Backend>,
+    rail_network: RailNetwork,
+    grace: Duration,
+    first_seen: Mutex<BTreeMap<Violation, Instant>>,
+    status: Mutex<SafetyStatus>,
+}
+
+impl SafetyMonitor {
+    pub fn new(backend: Arc<Backend>, config: &SafetyConfig) -> Self {
+        SafetyMonitor {
+            backend,
+            rail_network: RailNetwork::new(),
+            grace: config.grace(),
+            first_seen: Mutex::new(BTreeMap::new()),
+            status: Mutex::new(SafetyStatus::default()),
+        }
+    }
+
+    fn observed_locos(&self) -> Vec<ObservedLoco> {
+        self.backend
+            .loco_ids()
+            .into_iter()
+            .filter_map(|id| {
+                let status = self
+                    .backend
+                    .block_on(self.backend.cached_loco_status(id))
+                    .ok()?;
+                Some(ObservedLoco {
+                    id,
+                    location: status.location()?.into(),
+                    direction: status.direction(),
+                    moving: status.speed() != Speed::Stop,
+                })
+            })
+            .collect()
+    }
+
+    // Checkpoint a moving loco is heading to, given the position of the
+    // switch rails. None if that can't be told for sure.
+    fn next_checkpoint_id(&self, loco: &ObservedLoco) -> Option<CheckpointId> {
+        let positions = self.backend.switch_rails_positions();
+        let mut candidates = self
+            .rail_network
+            .next_checkpoint_ids(loco.location, loco.direction)
+            .iter()
+            .filter(|next| {
+                let Ok(segment_id) = TryInto::<SegmentId>::try_into((loco.location, **next)) else {
+                    return false;
+                };
+                self.rail_network
+                    .segment(&segment_id)
+                    .switch_rails()
+                    .iter()
+                    .all(|s| positions.get(&s.actuator_id()) == Some(&s.state()))
+            });
+
+        match (candidates.next(), candidates.next()) {
+            (Some(next), None) => Some(*next),
+            _ => None,
+        }
+    }
+
+    /**
+     * Only what the locos report and the position of the switch rails are
+     * checked, not the segments the Oracle reserved. Reservations are the
+     * Oracle's own reading of the same locations, hence checking against
+     * them would share whatever mistake the Oracle makes, which is what the
+     * monitor is there to catch. They're not needed either: two locos can
+     * only collide where they actually are and where the switch rails lead
+     * them, and a reservation no loco is running into is harmless.
+     */
+    fn violations(&self) -> Vec<Violation> {
+        let locos = self.observed_locos();
+        let mut violations = Vec::new();
+
+        let mut segments: Vec<(LocoId, SegmentId)> = Vec::new();
+        for loco in locos.iter().filter(|l| l.moving) {
+            let Some(next) = self.next_checkpoint_id(loco) else {
+                continue;
+            };
+
+            if let Some(other) = locos
+                .iter()
+                .find(|o| o.id != loco.id && o.location == next && !o.moving)
+            {
+                violations.push(Violation::NextCheckpointOccupied {
+                    loco_id: loco.id,
+                    checkpoint_id: next,
+                    occupied_by: other.id,
+                });
+            }
+
+            if let Ok(segment_id) = (loco.location, next).try_into() {
+                segments.push((loco.id, segment_id));
+            }
+        }
+
+        for (i, (loco_id, segment_id)) in segments.iter().enumerate() {
+            for (other_id, other_segment_id) in segments[i + 1..].iter() {
+                if segment_id == other_segment_id
+                    || self
+                        .rail_network
+                        .segment(segment_id)
+                        .conflicts()
+                        .contains(other_segment_id)
+                {
+                    violations.push(Violation::ConflictingSegments {
+                        locos: (*loco_id, *other_id),
+                        segments: (*segment_id, *other_segment_id),
+                    });
+                }
+            }
+        }
+
+        violations
+    }
+
+    // Only the Oracle is watched over, an operator driving by hand is on
+    // their own
+    pub fn process(&self) {
+        if !self.backend.oracle_enabled() {
+            self.first_seen.lock().unwrap().clear();
+            self.status.lock().unwrap().pending.clear();
+            return;
+        }
+
+        let violations = self.violations();
+        let tripped = lasting_violations(
+            &mut self.first_seen.lock().unwrap(),
+            &violations,
+            Instant::now(),
+            self.grace,
+        );
+
+        if !violations.is_empty() {
+            debug!("SafetyMonitor::process(): {:?}", violations);
+        }
+        self.status.lock().unwrap().pending = violations;
+
+        if tripped.is_empty() {
+            return;
+        }
+
+        error!("SafetyMonitor::process(): {:?}", tripped);
+        self.backend.block_on(
+            self.backend
+                .emergency_stop(&format!("{:?}", tripped), Alarm::SafetyViolation),
+        );
+        self.first_seen.lock().unwrap().clear();
+        *self.status.lock().unwrap() = SafetyStatus {
+            pending: Vec::new(),
+            tripped,
+        };
+    }
+
+    pub fn status(&self) -> SafetyStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+// Follows when every violation was first seen, forgetting the ones which
+// went away, and returns the ones which lasted for the grace period
+fn lasting_violations(
+    first_seen: &mut BTreeMap<Violation, Instant>,
+    violations: &[Violation],
+    now: Instant,
+    grace: Duration,
+) -> Vec<Violation> {
+    first_seen.retain(|v, _| violations.contains(v));
+    for violation in violations.iter() {
+        first_seen.entry(*violation).or_insert(now);
+    }
+
+    first_seen
+        .iter()
+        .filter(|(_, since)| now.duration_since(**since) >= grace)
+        .map(|(v, _)| *v)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRACE: Duration = Duration::from_millis(500);
+
+    fn loco(id: u8) -> LocoId {
+        LocoId::try_from(id).unwrap()
+    }
+
+    fn occupied(loco_id: u8, occupied_by: u8) -> Violation {
+        Violation::NextCheckpointOccupied {
+            loco_id: loco(loco_id),
+            checkpoint_id: CheckpointId::Checkpoint1,
+            occupied_by: loco(occupied_by),
+        }
+    }
+
+    #[test]
+    fn grace_period() {
+        let mut first_seen = BTreeMap::new();
+        let start = Instant::now();
+        let violations = [occupied(1, 2)];
+
+        assert!(lasting_violations(&mut first_seen, &violations, start, GRACE).is_empty());
+        let almost = start + GRACE - Duration::from_millis(1);
+        assert!(lasting_violations(&mut first_seen, &violations, almost, GRACE).is_empty());
+
+        // Another violation showing up doesn't make the first one younger,
+        // nor does it trip along with it
+        let violations = [occupied(1, 2), occupied(3, 2)];
+        assert_eq!(
+            lasting_violations(&mut first_seen, &violations, start + GRACE, GRACE),
+            vec![occupied(1, 2)]
+        );
+    }
+
+    #[test]
+    fn grace_period_restarts() {
+        let mut first_seen = BTreeMap::new();
+        let start = Instant::now();
+        let violations = [occupied(1, 2)];
+
+        assert!(lasting_violations(&mut first_seen, &violations, start, GRACE).is_empty());
+        // Gone for a single check, as when a loco reports it stopped in time
+        let gap = start + GRACE / 2;
+        assert!(lasting_violations(&mut first_seen, &[], gap, GRACE).is_empty());
+        assert!(first_seen.is_empty());
+
+        let back = start + GRACE;
+        assert!(lasting_violations(&mut first_seen, &violations, back, GRACE).is_empty());
+        assert_eq!(
+            lasting_violations(&mut first_seen, &violations, back + GRACE, GRACE),
+            vec![occupied(1, 2)]
+        );
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use loco_protocol::{ActuatorId, ActuatorType, LocoId, Speed, SwitchRailsState};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use thiserror::Error;
+use wasmi::{
+    Caller, Config, Engine, Extern, Instance, Linker, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc, core::TrapCode,
+};
+
+use crate::{
+    backend::{Backend, Error as BackendError, Event, LocoIntent},
+    config::ScriptsConfig,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid script name {0}")]
+    InvalidName(String),
+    #[error("Script of {0} bytes too large")]
+    TooLarge(usize),
+    #[error("Too many scripts, can't add {0}")]
+    TooMany(String),
+    #[error("Unknown script {0}")]
+    UnknownScript(String),
+    #[error("Error compiling script: {0}")]
+    Compile(#[source] wasmi::Error),
+    #[error("Error instantiating script: {0}")]
+    Instantiate(#[source] wasmi::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// Module the host functions are imported from by the scripts
+const HOST_MODULE: &str = "loco";
+
+// Returned by the host functions to the scripts, but for loco_status() which
+// returns the length of the status on success
+const HOST_OK: i32 = 0;
+const HOST_INVALID_ARGUMENT: i32 = -1;
+const HOST_FAILED: i32 = -2;
+const HOST_INTERLOCKED: i32 = -3;
+
+struct HostState {
+    backend: Arc<Backend>,
+    limits: StoreLimits,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ScriptMetrics {
+    calls: u64,
+    out_of_fuel: u64,
+    traps: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ScriptDescription {
+    name: String,
+    size: usize,
+    metrics: ScriptMetrics,
+}
+
+struct Script {
+    store: Store<HostState>,
+    size: usize,
+    on_sensor_hit: Option<TypedFunc<(i32, i32), ()>>,
+    on_intent_completed: Option<TypedFunc<i32, ()>>,
+    metrics: ScriptMetrics,
+}
+
+/**
+ * User automation scripts, compiled to WebAssembly and uploaded at runtime.
+ * Unlike the plugins, a script runs in a sandbox: it only sees the host
+ * functions of the "loco" module, its memory is capped and every hook is given
+ * a fixed amount of fuel, so that a script looping forever is stopped instead
+ * of stalling the events.
+ *
+ * Scripts export the hooks they care about, out of on_sensor_hit(loco_id,
+ * sensor_id) and on_intent_completed(loco_id), and act on the rail network
+ * through the host functions:
+ * - oracle_enabled() -> i32
+ * - loco_status(loco_id, ptr, len) -> i32, writing the status as JSON
+ * - set_loco_intent(loco_id, ptr, len) -> i32, reading the intent as JSON
+ * - drive_switch_rails(actuator_id, state) -> i32
+ * - log(ptr, len)
+ */
+pub struct Scripts {
+    backend: Arc<Backend>,
+    engine: Engine,
+    linker: Linker<HostState>,
+    config: ScriptsConfig,
+    scripts: Mutex<BTreeMap<String, Script>>,
+}
+
+impl Scripts {
+    pub fn new(backend: Arc<Backend>, config: &ScriptsConfig) -> Self {
+        let mut engine_config = Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+
+        Scripts {
+            backend,
+            linker: host_linker(&engine),
+            engine,
+            config: config.clone(),
+            scripts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Replaces the script of the same name, if any
+    pub fn load(&self, name: &str, wasm: &[u8]) -> Result<()> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(Error::InvalidName(name.to_string()));
+        }
+        if wasm.len() > self.config.max_module_bytes {
+            return Err(Error::TooLarge(wasm.len()));
+        }
+        {
+            let scripts = self.scripts.lock().unwrap();
+            if !scripts.contains_key(name) && scripts.len() >= self.config.max_scripts {
+                return Err(Error::TooMany(name.to_string()));
+            }
+        }
+
+        let module = Module::new(&self.engine, wasm).map_err(Error::Compile)?;
+        let mut store = Store::new(
+            &self.engine,
+            HostState {
+                backend: self.backend.clone(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(self.config.max_memory_bytes)
+                    .instances(1)
+                    .memories(1)
+                    .tables(1)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        // The start function, if any, runs on the same budget as a hook
+        store
+            .set_fuel(self.config.fuel_per_hook)
+            .map_err(|e| Error::Instantiate(e.into()))?;
+        let instance = self
+            .linker
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(Error::Instantiate)?;
+
+        let script = Script {
+            on_sensor_hit: hook(&instance, &store, "on_sensor_hit"),
+            on_intent_completed: hook(&instance, &store, "on_intent_completed"),
+            store,
+            size: wasm.len(),
+            metrics: ScriptMetrics::default(),
+        };
+        info!("Scripts::load(): {} loaded ({} bytes)", name, wasm.len());
+        self.scripts
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), script);
+
+        Ok(())
+    }
+
+    pub fn max_module_bytes(&self) -> usize {
+        self.config.max_module_bytes
+    }
+
+    pub fn unload(&self, name: &str) -> Result<()> {
+        self.scripts
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or(Error::UnknownScript(name.to_string()))?;
+        info!("Scripts::unload(): {} unloaded", name);
+
+        Ok(())
+    }
+
+    pub fn scripts(&self) -> Vec<ScriptDescription> {
+        self.scripts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, script)| ScriptDescription {
+                name: name.clone(),
+                size: script.size,
+                metrics: script.metrics.clone(),
+            })
+            .collect()
+    }
+
+    pub fn dispatch(&self, event: &Event) {
+        let mut scripts = self.scripts.lock().unwrap();
+        for (name, script) in scripts.iter_mut() {
+            debug!("Scripts::dispatch(): {:?} to {}", event, name);
+            if let Err(e) = script.store.set_fuel(self.config.fuel_per_hook) {
+                error!("Scripts::dispatch(): {} {}", name, e);
+                continue;
+            }
+            let result = match event {
+                Event::SensorHit {
+                    loco_id, sensor_id, ..
+                } => script.on_sensor_hit.as_ref().map(|f| {
+                    f.call(
+                        &mut script.store,
+                        (u8::from(*loco_id).into(), u8::from(*sensor_id).into()),
+                    )
+                }),
+                Event::IntentCompleted { loco_id, .. } => script
+                    .on_intent_completed
+                    .as_ref()
+                    .map(|f| f.call(&mut script.store, u8::from(*loco_id).into())),
+                _ => None,
+            };
+
+            match result {
+                None => continue,
+                Some(Ok(())) => {}
+                Some(Err(e)) if e.as_trap_code() == Some(TrapCode::OutOfFuel) => {
+                    warn!("Scripts::dispatch(): {} ran out of fuel", name);
+                    script.metrics.out_of_fuel += 1;
+                }
+                Some(Err(e)) => {
+                    error!("Scripts::dispatch(): {} {}", name, e);
+                    script.metrics.traps += 1;
+                }
+            }
+            script.metrics.calls += 1;
+        }
+    }
+}
+
+fn hook<Params, Results>(
+    instance: &Instance,
+    store: &Store<HostState>,
+    name: &str,
+) -> Option<TypedFunc<Params, Results>>
+where
+    Params: wasmi::WasmParams,
+    Results: wasmi::WasmResults,
+{
+    instance.get_typed_func(store, name).ok()
+}
+
+fn read_memory(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = caller.get_export("memory").and_then(Extern::into_memory)?;
+    let mut buf = vec![0u8; usize::try_from(len).ok()?];
+    memory
+        .read(caller, usize::try_from(ptr).ok()?, &mut buf)
+        .ok()?;
+    Some(buf)
+}
+
+fn write_memory(caller: &mut Caller<'_, HostState>, ptr: i32, data: &[u8]) -> Option<()> {
+    let memory = caller.get_export("memory").and_then(Extern::into_memory)?;
+    memory.write(caller, usize::try_from(ptr).ok()?, data).ok()
+}
+
+// A switch rails can't be thrown under a train. Locos aren't tracked between
+// sensors, hence every switch rails is locked while any loco is moving, or
+// might be. The Oracle owns the switch rails while it's running.
+fn switch_rails_interlocked(backend: &Backend) -> bool {
+    if backend.oracle_enabled() {
+        return true;
+    }
+
+    backend.loco_ids().into_iter().any(|loco_id| {
+        match backend.block_on(backend.loco_status(loco_id)) {
+            Ok(status) => status.speed() != Speed::Stop,
+            Err(BackendError::LocoNotConnected(_)) => false,
+            Err(_) => true,
+        }
+    })
+}
+
+// The host API is a restricted subset of the Backend, just like the plugins
+// CommandApi, so that a script can't bypass the safety of the automation.
+// Defining a host function only fails when it's already defined, hence the
+// unwraps.
+fn host_linker(engine: &Engine) -> Linker<HostState> {
+    let mut linker = Linker::new(engine);
+
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "oracle_enabled",
+            |caller: Caller<'_, HostState>| -> i32 {
+                caller.data().backend.oracle_enabled().into()
+            },
+        )
+        .unwrap();
+
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "loco_status",
+            |mut caller: Caller<'_, HostState>, loco_id: i32, ptr: i32, len: i32| -> i32 {
+                let Some(loco_id) = u8::try_from(loco_id)
+                    .ok()
+                    .and_then(|id| LocoId::try_from(id).ok())
+                else {
+                    return HOST_INVALID_ARGUMENT;
+                };
+                let backend = &caller.data().backend;
+                let status = match backend.block_on(backend.loco_status(loco_id)) {
+                    Ok(status) => status,
+                    Err(e) => {
+                        debug!("scripts::loco_status(): {} {}", loco_id, e);
+                        return HOST_FAILED;
+                    }
+                };
+                let Ok(json) = serde_json::to_vec(&status) else {
+                    return HOST_FAILED;
+                };
+                // Telling the length needed, for the script to try again
+                // with a buffer large enough
+                if json.len() > usize::try_from(len).unwrap_or(0) {
+                    return json.len() as i32;
+                }
+                match write_memory(&mut caller, ptr, &json) {
+                    Some(()) => json.len() as i32,
+                    None => HOST_INVALID_ARGUMENT,
+                }
+            },
+        )
+        .unwrap();
+
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "set_loco_intent",
+            |caller: Caller<'_, HostState>, loco_id: i32, ptr: i32, len: i32| -> i32 {
+                let Some(loco_id) = u8::try_from(loco_id)
+                    .ok()
+                    .and_then(|id| LocoId::try_from(id).ok())
+                else {
+                    return HOST_INVALID_ARGUMENT;
+                };
+                let Some(intent) = read_memory(&caller, ptr, len)
+                    .and_then(|json| serde_json::from_slice::<LocoIntent>(&json).ok())
+                else {
+                    return HOST_INVALID_ARGUMENT;
+                };
+                caller.data().backend.set_loco_intent(loco_id, intent);
+                HOST_OK
+            },
+        )
+        .unwrap();
+
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "drive_switch_rails",
+            |caller: Caller<'_, HostState>, actuator_id: i32, state: i32| -> i32 {
+                let Some(actuator_id) = u8::try_from(actuator_id)
+                    .ok()
+                    .and_then(|id| ActuatorId::try_from(id).ok())
+                    .filter(|id| *id != ActuatorId::TrackPower)
+                else {
+                    return HOST_INVALID_ARGUMENT;
+                };
+                let Some(state) = u8::try_from(state)
+                    .ok()
+                    .and_then(|state| SwitchRailsState::try_from(state).ok())
+                else {
+                    return HOST_INVALID_ARGUMENT;
+                };
+                let backend = &caller.data().backend;
+                if switch_rails_interlocked(backend) {
+                    debug!("scripts::drive_switch_rails(): {:?} locked", actuator_id);
+                    return HOST_INTERLOCKED;
+                }
+                match backend.block_on(backend.drive_actuator(
+                    actuator_id,
+                    ActuatorType::SwitchRails,
+                    state.into(),
+                )) {
+                    Ok(()) => HOST_OK,
+                    Err(e) => {
+                        debug!("scripts::drive_switch_rails(): {:?} {}", actuator_id, e);
+                        HOST_FAILED
+                    }
+                }
+            },
+        )
+        .unwrap();
+
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "log",
+            |caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+                if let Some(message) = read_memory(&caller, ptr, len) {
+                    info!("script: {}", String::from_utf8_lossy(&message));
+                }
+            },
+        )
+        .unwrap();
+
+    linker
+}
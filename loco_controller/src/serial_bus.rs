@@ -0,0 +1,215 @@
+use std::{
+    io::{self, Read, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use loco_protocol::{ActuatorId, SwitchRailsState};
+use log::{debug, info, warn};
+use serialport::{ClearBuffer, SerialPort};
+use thiserror::Error;
+
+use crate::config::SerialBusConfig;
+
+// Reads give up that often while waiting for an acknowledgement, for the
+// deadline to be checked
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error opening serial port {0}: {1}")]
+    Open(String, #[source] serialport::Error),
+    #[error("Error writing to the serial bus: {0}")]
+    Write(#[source] io::Error),
+    #[error("Error reading from the serial bus: {0}")]
+    Read(#[source] io::Error),
+    #[error("Turnout {0} not on the serial bus")]
+    UnknownTurnout(ActuatorId),
+    #[error("Decoder {0} didn't acknowledge within {1:?}")]
+    AckTimeout(u16, Duration),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/**
+ * Throws the switch rails wired to legacy turnout decoders on an RS485 bus,
+ * rather than to the actuators board. Commands are written one at a time, and
+ * the next one only goes once the decoder acknowledged the previous one, if
+ * acknowledgements are expected.
+ */
+pub struct SerialBus {
+    config: SerialBusConfig,
+    // Opened on first use, and again after failing, since a USB adapter can
+    // be unplugged and plugged back
+    port: Mutex<Option<Box<dyn SerialPort>>>,
+}
+
+impl SerialBus {
+    pub fn new(config: &SerialBusConfig) -> Self {
+        let serial_bus = SerialBus {
+            config: config.clone(),
+            port: Mutex::new(None),
+        };
+
+        // Failing now is only worth a warning, the port being opened again
+        // on the next command
+        match serial_bus.open() {
+            Ok(port) => *serial_bus.port.lock().unwrap() = Some(port),
+            Err(e) => warn!("SerialBus::new(): {}", e),
+        }
+
+        serial_bus
+    }
+
+    fn open(&self) -> Result<Box<dyn SerialPort>> {
+        let port = serialport::new(&self.config.port, self.config.baud_rate)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .map_err(|e| Error::Open(self.config.port.clone(), e))?;
+        info!(
+            "SerialBus::open(): {} at {} bauds",
+            self.config.port, self.config.baud_rate
+        );
+
+        Ok(port)
+    }
+
+    pub fn has_turnout(&self, actuator_id: ActuatorId) -> bool {
+        self.config.turnouts.contains_key(&actuator_id)
+    }
+
+    pub fn throw(&self, actuator_id: ActuatorId, state: SwitchRailsState) -> Result<()> {
+        let turnout = self
+            .config
+            .turnouts
+            .get(&actuator_id)
+            .ok_or(Error::UnknownTurnout(actuator_id))?;
+        let value = match state {
+            SwitchRailsState::Direct => &turnout.direct,
+            SwitchRailsState::Diverted => &turnout.diverted,
+        };
+        let expand = |template: &str| {
+            template
+                .replace("{address}", &turnout.address.to_string())
+                .replace("{state}", value)
+        };
+        let command = expand(&self.config.command);
+        debug!(
+            "SerialBus::throw(): {} {:?} through {:?}",
+            actuator_id, state, command
+        );
+
+        let mut port = self.port.lock().unwrap();
+        if port.is_none() {
+            *port = Some(self.open()?);
+        }
+        // Safe to unwrap since the port has just been opened
+        let result = self.send(
+            port.as_mut().unwrap(),
+            turnout.address,
+            &command,
+            self.config.ack.as_deref().map(expand),
+        );
+
+        // A port failing a write or a read is likely gone, hence it's opened
+        // again for the next command
+        if let Err(e @ (Error::Write(_) | Error::Read(_))) = &result {
+            warn!("SerialBus::throw(): closing {}: {}", self.config.port, e);
+            *port = None;
+        }
+
+        result
+    }
+
+    fn send(
+        &self,
+        port: &mut Box<dyn SerialPort>,
+        address: u16,
+        command: &str,
+        ack: Option<String>,
+    ) -> Result<()> {
+        // Whatever the decoders said before doesn't answer this command
+        port.clear(ClearBuffer::Input)
+            .map_err(|e| Error::Read(e.into()))?;
+        port.write_all(command.as_bytes()).map_err(Error::Write)?;
+        port.flush().map_err(Error::Write)?;
+
+        let Some(ack) = ack else {
+            return Ok(());
+        };
+
+        // Other decoders may talk on the bus meanwhile, hence lines which
+        // aren't the acknowledgement are skipped
+        let timeout = self.config.ack_timeout();
+        let deadline = Instant::now() + timeout;
+        let mut pending = Vec::new();
+        let mut buf = [0u8; 64];
+        while Instant::now() < deadline {
+            match port.read(&mut buf) {
+                Ok(0) => return Err(Error::Read(io::ErrorKind::UnexpectedEof.into())),
+                Ok(len) => pending.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(Error::Read(e)),
+            }
+
+            if take_ack(&mut pending, &ack) {
+                return Ok(());
+            }
+        }
+
+        Err(Error::AckTimeout(address, timeout))
+    }
+}
+
+// Consumes the complete lines received so far, telling whether one of them
+// is the acknowledgement. A line still being received is left pending.
+fn take_ack(pending: &mut Vec<u8>, ack: &str) -> bool {
+    while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = pending.drain(..=end).collect();
+        let line = String::from_utf8_lossy(&line);
+        if line.trim() == ack.trim() {
+            return true;
+        }
+        debug!("take_ack(): skipping {:?}", line.trim());
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_line() {
+        let mut pending = b"OK 3\r\n".to_vec();
+        assert!(take_ack(&mut pending, "OK 3"));
+        assert!(pending.is_empty());
+
+        // Surrounding whitespaces of the template don't matter either
+        let mut pending = b"OK 3\n".to_vec();
+        assert!(take_ack(&mut pending, " OK 3\n"));
+    }
+
+    #[test]
+    fn ack_line_split_across_reads() {
+        let mut pending = b"OK".to_vec();
+        assert!(!take_ack(&mut pending, "OK 3"));
+        assert_eq!(pending, b"OK");
+
+        pending.extend_from_slice(b" 3\n");
+        assert!(take_ack(&mut pending, "OK 3"));
+    }
+
+    #[test]
+    fn other_lines_skipped() {
+        let mut pending = b"OK 31\nBUSY 5\n\xff\xfe\nOK 3\nOK 4".to_vec();
+        assert!(take_ack(&mut pending, "OK 3"));
+        // Whatever follows is left for the next read, if any
+        assert_eq!(pending, b"OK 4");
+
+        let mut pending = b"OK 31\nOK\n".to_vec();
+        assert!(!take_ack(&mut pending, "OK 3"));
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+use std::{collections::BTreeMap, sync::RwLock};
+
+use loco_protocol::{ActuatorId, ActuatorType, SignalState, Speed};
+
+use crate::{config::NetworkConfig, rail_network::SegmentId};
+
+/**
+ * Signal lights of the active layout, each one guarding the entrance of a
+ * segment. A signal shows what the Oracle granted to the loco entering its
+ * segment: Green to run through it at full speed, Yellow to run through it
+ * slowly, and Red whenever no loco is allowed in.
+ */
+pub struct Signals {
+    segments: RwLock<BTreeMap<ActuatorId, SegmentId>>,
+}
+
+impl Signals {
+    pub fn new(network: &NetworkConfig) -> Self {
+        Signals {
+            segments: RwLock::new(network.signals.clone()),
+        }
+    }
+
+    // Called when switching to another layout
+    pub fn set_network(&self, network: &NetworkConfig) {
+        *self.segments.write().unwrap() = network.signals.clone();
+    }
+
+    // Every signal gets driven, so that a segment released since the last
+    // Oracle cycle gets its signal back to Red
+    pub fn aspects(
+        &self,
+        granted: &BTreeMap<SegmentId, Speed>,
+    ) -> Vec<(ActuatorId, ActuatorType, u8)> {
+        self.segments
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(actuator_id, segment_id)| {
+                let state = match granted.get(segment_id) {
+                    Some(speed) if speed.duty_cycle() > Speed::Slow.duty_cycle() => {
+                        SignalState::Green
+                    }
+                    Some(speed) if speed.duty_cycle() > 0 => SignalState::Yellow,
+                    _ => SignalState::Red,
+                };
+                (*actuator_id, ActuatorType::Signal, state.into())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use loco_protocol::{ActuatorId, ActuatorType, LocoId, Speed, SwitchRailsState};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    backend::{Backend, Error as BackendError, LocoIntent, OracleMode},
+    rail_network::CheckpointId,
+};
+
+// Snapshots kept, the oldest one being dropped past that
+const MAX_SNAPSHOTS: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unknown snapshot {0}")]
+    UnknownSnapshot(u32),
+    #[error("Snapshot {0} isn't waiting for a confirmation")]
+    NotRestoring(u32),
+    #[error("Error reading the state of {0}: {1}")]
+    LocoState(LocoId, #[source] BackendError),
+    #[error("Error driving the switch rails: {0}")]
+    DriveSwitchRails(#[source] BackendError),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotParams {
+    name: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LocoSnapshot {
+    loco_id: LocoId,
+    location: Option<CheckpointId>,
+    intent: Option<LocoIntent>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Snapshot {
+    id: u32,
+    name: String,
+    taken_at_us: u64,
+    locos: Vec<LocoSnapshot>,
+    switch_rails: BTreeMap<ActuatorId, SwitchRailsState>,
+    oracle_mode: OracleMode,
+}
+
+#[derive(Default)]
+struct Store {
+    snapshots: BTreeMap<u32, Snapshot>,
+    next_id: u32,
+    // Snapshot restored along with the mode of the Oracle it was taken in,
+    // until the operator confirms the locos actually are where it says
+    restoring: Option<(u32, OracleMode)>,
+}
+
+/**
+ * Snapshots of the layout, telling where every loco is and what it's up to,
+ * along with the position of the switch rails and whether the Oracle is
+ * running, so that a known configuration such as a demo can be returned to.
+ * Restoring a snapshot stops the Oracle and the locos, throws the switch rails
+ * and sets the locations and intents back. Since only the operator can put the
+ * locos back where they were, the Oracle stays off until the operator confirms
+ * they are.
+ */
+pub struct Snapshots {
+    backend: Arc<Backend>,
+    store: Mutex<Store>,
+}
+
+impl Snapshots {
+    pub fn new(backend: Arc<Backend>) -> Self {
+        Snapshots {
+            backend,
+            store: Mutex::new(Store {
+                next_id: 1,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn take(&self, params: &SnapshotParams) -> Result<Snapshot> {
+        let locos = self
+            .backend
+            .loco_ids()
+            .into_iter()
+            .map(|loco_id| {
+                Ok(LocoSnapshot {
+                    loco_id,
+                    location: self
+                        .backend
+                        .loco_location(loco_id)
+                        .map_err(|e| Error::LocoState(loco_id, e))?,
+                    intent: self
+                        .backend
+                        .loco_intent(loco_id)
+                        .map_err(|e| Error::LocoState(loco_id, e))?,
+                })
+            })
+            .collect::<Result<Vec<LocoSnapshot>>>()?;
+        let oracle_mode = if self.backend.oracle_enabled() {
+            OracleMode::Auto
+        } else {
+            OracleMode::Off
+        };
+
+        let mut store = self.store.lock().unwrap();
+        let snapshot = Snapshot {
+            id: store.next_id,
+            name: params.name.clone(),
+            taken_at_us: self.backend.now_us(),
+            locos,
+            switch_rails: self.backend.switch_rails_positions(),
+            oracle_mode,
+        };
+        info!(
+            "Snapshots::take(): Snapshot {} {:?}",
+            snapshot.id, snapshot.name
+        );
+        store.next_id += 1;
+        store.snapshots.insert(snapshot.id, snapshot.clone());
+        while store.snapshots.len() > MAX_SNAPSHOTS {
+            store.snapshots.pop_first();
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn snapshots(&self) -> Vec<Snapshot> {
+        self.store
+            .lock()
+            .unwrap()
+            .snapshots
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    // Snapshot waiting for the operator to confirm the locos are back where
+    // they were, the Oracle being kept off until then
+    pub fn restoring(&self) -> Option<u32> {
+        self.store.lock().unwrap().restoring.map(|(id, _)| id)
+    }
+
+    pub async fn restore(&self, id: u32, client: &str) -> Result<Snapshot> {
+        let snapshot = self
+            .store
+            .lock()
+            .unwrap()
+            .snapshots
+            .get(&id)
+            .cloned()
+            .ok_or(Error::UnknownSnapshot(id))?;
+        warn!("Snapshots::restore(): Restoring {} for {}", id, client);
+
+        // Nothing may move while the locos are being put back
+        self.backend.set_oracle_mode(OracleMode::Off);
+        for (loco_id, status) in self.backend.cached_loco_statuses().await {
+            let Ok(status) = status else {
+                continue;
+            };
+            if let Err(e) = self
+                .backend
+                .control_loco(loco_id, status.direction(), Speed::Stop, None)
+                .await
+            {
+                warn!("Snapshots::restore(): {} {}", loco_id, e);
+            }
+        }
+
+        let switch_rails: Vec<(ActuatorId, ActuatorType, u8)> = snapshot
+            .switch_rails
+            .iter()
+            .map(|(actuator_id, state)| (*actuator_id, ActuatorType::SwitchRails, (*state).into()))
+            .collect();
+        if !switch_rails.is_empty() {
+            self.backend
+                .drive_actuators(&switch_rails)
+                .await
+                .map_err(Error::DriveSwitchRails)?;
+        }
+
+        for loco in snapshot.locos.iter() {
+            self.backend
+                .set_loco_location(loco.loco_id, loco.location, client);
+            if let Some(intent) = loco.intent {
+                self.backend.set_loco_intent(loco.loco_id, intent);
+            }
+        }
+
+        self.store.lock().unwrap().restoring = Some((id, snapshot.oracle_mode));
+        Ok(snapshot)
+    }
+
+    // Mode the Oracle is to be set back to, once the restore is confirmed
+    pub fn pending_oracle_mode(&self, id: u32) -> Result<OracleMode> {
+        match self.store.lock().unwrap().restoring {
+            Some((restoring, oracle_mode)) if restoring == id => Ok(oracle_mode),
+            _ => Err(Error::NotRestoring(id)),
+        }
+    }
+
+    pub fn confirm(&self, id: u32, client: &str) -> Result<OracleMode> {
+        let oracle_mode = self.pending_oracle_mode(id)?;
+        info!(
+            "Snapshots::confirm(): {} confirmed by {}, Oracle {:?}",
+            id, client, oracle_mode
+        );
+        self.store.lock().unwrap().restoring = None;
+        self.backend.set_oracle_mode(oracle_mode);
+
+        Ok(oracle_mode)
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    sync::{Arc, RwLock},
+    thread::sleep,
+};
+
+use loco_protocol::{ActuatorType, LocoId};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{
+    backend::{Backend, Error as BackendError, Event},
+    config::StartupConfig,
+    journal::Recovery,
+};
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StartupStep {
+    WaitingForActuators,
+    SettingSwitchRails,
+    WaitingForLocosStopped,
+    Ready,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StartupStatus {
+    step: StartupStep,
+    ready: bool,
+    // Locos preventing the sequence from completing, if any
+    moving_locos: Vec<LocoId>,
+    // How the files saved before the controller went down were found
+    recovery: Vec<Recovery>,
+}
+
+/**
+ * Safety sequence run once when the controller boots. Until it completes,
+ * the Oracle can't be enabled, so that it never starts driving a layout left
+ * in an unknown state. Every step is reported through the events, and the
+ * current one through the status.
+ */
+pub struct StartupSequence {
+    backend: Arc<Backend>,
+    config: StartupConfig,
+    status: RwLock<StartupStatus>,
+}
+
+impl StartupSequence {
+    pub fn new(backend: Arc<Backend>, config: &StartupConfig, recovery: Vec<Recovery>) -> Self {
+        StartupSequence {
+            backend,
+            config: config.clone(),
+            status: RwLock::new(StartupStatus {
+                step: StartupStep::WaitingForActuators,
+                ready: false,
+                moving_locos: Vec::new(),
+                recovery,
+            }),
+        }
+    }
+
+    fn enter(&self, step: StartupStep) {
+        info!("StartupSequence::enter(): {:?}", step);
+
+        {
+            let mut status = self.status.write().unwrap();
+            status.step = step;
+            status.ready = step == StartupStep::Ready;
+            status.moving_locos.clear();
+        }
+        self.backend.notify(Event::StartupProgress { step });
+    }
+
+    // Blocks until the whole sequence completes
+    pub fn run(&self) {
+        if self.config.wait_for_actuators {
+            self.enter(StartupStep::WaitingForActuators);
+            while !self.backend.actuators_connected() {
+                sleep(self.config.poll_period());
+            }
+        }
+
+        // Without the actuators board, there's no way to set anything
+        if !self.config.switch_rails.is_empty() && self.backend.actuators_connected() {
+            self.enter(StartupStep::SettingSwitchRails);
+            let actuators: Vec<_> = self
+                .config
+                .switch_rails
+                .iter()
+                .map(|(id, state)| (*id, ActuatorType::SwitchRails, (*state).into()))
+                .collect();
+            loop {
+                match self
+                    .backend
+                    .block_on(self.backend.drive_actuators(&actuators))
+                {
+                    Ok(()) => break,
+                    // Retrying won't make the switch appear on the board
+                    Err(e @ BackendError::ActuatorNotPresent(..)) => {
+                        error!("StartupSequence::run(): {}", e);
+                        break;
+                    }
+                    Err(e) => {
+                        error!("StartupSequence::run(): {}", e);
+                        sleep(self.config.poll_period());
+                    }
+                }
+            }
+        }
+
+        if self.config.require_locos_stopped {
+            self.enter(StartupStep::WaitingForLocosStopped);
+            loop {
+                match self.backend.block_on(self.backend.moving_locos()) {
+                    Ok(moving) if moving.is_empty() => break,
+                    Ok(moving) => self.status.write().unwrap().moving_locos = moving,
+                    Err(e) => error!("StartupSequence::run(): {}", e),
+                }
+                sleep(self.config.poll_period());
+            }
+        }
+
+        self.enter(StartupStep::Ready);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.status.read().unwrap().ready
+    }
+
+    pub fn status(&self) -> StartupStatus {
+        self.status.read().unwrap().clone()
+    }
+}
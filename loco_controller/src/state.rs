@@ -0,0 +1,298 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use loco_protocol::{
+    ActuatorId, ActuatorType, Direction, LocoId, SensorId, SignalState, Speed, SwitchRailsState,
+    TrackPowerState,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{Event, LocoIntent},
+    maintenance::MaintenanceBanner,
+    rail_network::CheckpointId,
+};
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct StateDiffQuery {
+    since: Option<u64>,
+}
+
+#[derive(Serialize, Copy, Clone, Debug)]
+#[serde(untagged)]
+pub enum ActuatorState {
+    SwitchRails(SwitchRailsState),
+    TrackPower(TrackPowerState),
+    Signal(SignalState),
+}
+
+// Value along with the sequence number of its last change
+#[derive(Copy, Clone, Debug)]
+struct Versioned<T> {
+    value: T,
+    seq: u64,
+}
+
+impl<T: Clone> Versioned<T> {
+    fn since(field: &Option<Versioned<T>>, seq: u64) -> Option<T> {
+        field
+            .as_ref()
+            .filter(|f| f.seq > seq)
+            .map(|f| f.value.clone())
+    }
+}
+
+#[derive(Default)]
+struct LocoState {
+    location: Option<Versioned<Option<SensorId>>>,
+    intent: Option<Versioned<LocoIntent>>,
+    direction: Option<Versioned<Direction>>,
+    speed: Option<Versioned<Speed>>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct LocoStateDiff {
+    // Null once the location has been cleared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<Option<SensorId>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    intent: Option<LocoIntent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    direction: Option<Direction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed: Option<Speed>,
+}
+
+impl LocoStateDiff {
+    fn is_empty(&self) -> bool {
+        self.location.is_none()
+            && self.intent.is_none()
+            && self.direction.is_none()
+            && self.speed.is_none()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct StateDiff {
+    seq: u64,
+    locos: BTreeMap<LocoId, LocoStateDiff>,
+    actuators: BTreeMap<ActuatorId, ActuatorState>,
+    // Null once the maintenance window is over
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maintenance: Option<Option<MaintenanceBanner>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    day_program: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PublicLoco {
+    location: Option<CheckpointId>,
+    direction: Option<Direction>,
+    moving: bool,
+}
+
+// What public viewers are allowed to see: where the locos are and what the
+// signals show, without any sensor, speed or intent
+#[derive(Serialize, Debug)]
+pub struct PublicState {
+    seq: u64,
+    locos: BTreeMap<LocoId, PublicLoco>,
+    signals: BTreeMap<ActuatorId, SignalState>,
+}
+
+#[derive(Default)]
+struct State {
+    seq: u64,
+    locos: BTreeMap<LocoId, LocoState>,
+    actuators: BTreeMap<ActuatorId, Versioned<ActuatorState>>,
+    maintenance: Option<Versioned<Option<MaintenanceBanner>>>,
+    day_program: Option<Versioned<String>>,
+}
+
+/**
+ * State of the rail network as known from the events, so that clients can
+ * catch up with only what changed since the last sequence number they saw.
+ * Every field remembers the sequence number of its last change, which means
+ * a diff can be computed from any sequence number without keeping a log of
+ * the changes.
+ */
+#[derive(Default)]
+pub struct StateTracker {
+    state: Mutex<State>,
+}
+
+impl StateTracker {
+    pub fn new() -> Self {
+        StateTracker::default()
+    }
+
+    pub fn apply(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.seq + 1;
+
+        match event {
+            Event::SensorHit {
+                loco_id, sensor_id, ..
+            } => {
+                state.locos.entry(*loco_id).or_default().location = Some(Versioned {
+                    value: Some(*sensor_id),
+                    seq,
+                });
+            }
+            Event::LocationCorrected { loco_id, sensor_id } => {
+                state.locos.entry(*loco_id).or_default().location = Some(Versioned {
+                    value: *sensor_id,
+                    seq,
+                });
+            }
+            Event::LocoIntentSet { loco_id, intent } => {
+                state.locos.entry(*loco_id).or_default().intent = Some(Versioned {
+                    value: *intent,
+                    seq,
+                });
+            }
+            Event::LocoCommandApplied {
+                loco_id,
+                direction,
+                speed,
+            } => {
+                let loco = state.locos.entry(*loco_id).or_default();
+                loco.direction = Some(Versioned {
+                    value: *direction,
+                    seq,
+                });
+                loco.speed = Some(Versioned { value: *speed, seq });
+            }
+            Event::ActuatorsDriven { actuators } => {
+                for (actuator_id, actuator_type, actuator_state) in actuators.iter() {
+                    let value = match actuator_type {
+                        ActuatorType::SwitchRails => {
+                            match SwitchRailsState::try_from(*actuator_state) {
+                                Ok(s) => ActuatorState::SwitchRails(s),
+                                Err(_) => continue,
+                            }
+                        }
+                        ActuatorType::TrackPower => {
+                            match TrackPowerState::try_from(*actuator_state) {
+                                Ok(s) => ActuatorState::TrackPower(s),
+                                Err(_) => continue,
+                            }
+                        }
+                        ActuatorType::Signal => match SignalState::try_from(*actuator_state) {
+                            Ok(s) => ActuatorState::Signal(s),
+                            Err(_) => continue,
+                        },
+                        // Reported as the switch rails they throw instead
+                        ActuatorType::Servo => continue,
+                    };
+                    state
+                        .actuators
+                        .insert(*actuator_id, Versioned { value, seq });
+                }
+            }
+            Event::MaintenanceStarted { banner } => {
+                state.maintenance = Some(Versioned {
+                    value: Some(banner.clone()),
+                    seq,
+                });
+            }
+            Event::MaintenanceEnded => {
+                state.maintenance = Some(Versioned { value: None, seq });
+            }
+            Event::DayProgramSwitched { program, .. } => {
+                state.day_program = Some(Versioned {
+                    value: program.clone(),
+                    seq,
+                });
+            }
+            // Nothing which isn't already reported by the other events
+            Event::SensorDeparture { .. }
+            | Event::IntentCompleted { .. }
+            | Event::OracleDecision { .. }
+            | Event::StartupProgress { .. }
+            | Event::LocoConnected { .. }
+            | Event::LocoDisconnected { .. }
+            | Event::ActuatorsConnected
+            | Event::ActuatorsDisconnected
+            | Event::EmergencyStop { .. }
+            | Event::UnknownTag { .. }
+            | Event::WagonHit { .. }
+            | Event::ConsistIssue { .. }
+            | Event::SafeCrawl { .. } => return,
+        }
+
+        state.seq = seq;
+    }
+
+    // Everything which changed after the given sequence number, or the whole
+    // state if none is given
+    pub fn diff(&self, query: &StateDiffQuery) -> StateDiff {
+        let state = self.state.lock().unwrap();
+        let since = query.since.unwrap_or(0);
+
+        let locos = state
+            .locos
+            .iter()
+            .map(|(id, loco)| {
+                (
+                    *id,
+                    LocoStateDiff {
+                        location: Versioned::since(&loco.location, since),
+                        intent: Versioned::since(&loco.intent, since),
+                        direction: Versioned::since(&loco.direction, since),
+                        speed: Versioned::since(&loco.speed, since),
+                    },
+                )
+            })
+            .filter(|(_, diff)| !diff.is_empty())
+            .collect();
+
+        let actuators = state
+            .actuators
+            .iter()
+            .filter(|(_, actuator)| actuator.seq > since)
+            .map(|(id, actuator)| (*id, actuator.value))
+            .collect();
+
+        StateDiff {
+            seq: state.seq,
+            locos,
+            actuators,
+            maintenance: Versioned::since(&state.maintenance, since),
+            day_program: Versioned::since(&state.day_program, since),
+        }
+    }
+
+    pub fn public(&self) -> PublicState {
+        let state = self.state.lock().unwrap();
+
+        let locos = state
+            .locos
+            .iter()
+            .map(|(id, loco)| {
+                (
+                    *id,
+                    PublicLoco {
+                        location: loco.location.and_then(|l| l.value).map(CheckpointId::from),
+                        direction: loco.direction.map(|d| d.value),
+                        moving: loco.speed.is_some_and(|s| s.value.duty_cycle() > 0),
+                    },
+                )
+            })
+            .collect();
+
+        let signals = state
+            .actuators
+            .iter()
+            .filter_map(|(id, actuator)| match actuator.value {
+                ActuatorState::Signal(signal_state) => Some((*id, signal_state)),
+                ActuatorState::SwitchRails(_) | ActuatorState::TrackPower(_) => None,
+            })
+            .collect();
+
+        PublicState {
+            seq: state.seq,
+            locos,
+            signals,
+        }
+    }
+}
@@ -0,0 +1,628 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use loco_protocol::{ActuatorId, ActuatorType, Direction, LocoId, Speed, SwitchRailsState};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::Event,
+    config::HistoryConfig,
+    history::History,
+    rail_network::{CheckpointId, RailNetwork, SegmentId},
+};
+
+// Upper bounds of the dwell distribution buckets, the last bucket gathering
+// every dwell longer than the last bound
+const DWELL_BUCKETS_SECS: [u64; 4] = [10, 30, 60, 300];
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct StatsQuery {
+    since_us: Option<u64>,
+    until_us: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+enum Sample {
+    Hit {
+        loco_id: LocoId,
+        checkpoint_id: CheckpointId,
+    },
+    Command {
+        loco_id: LocoId,
+        direction: Direction,
+        speed: Speed,
+    },
+    // Switch rails driven to a new position, which is a throw unless it's
+    // the first position known
+    SwitchRails {
+        actuator_id: ActuatorId,
+        state: SwitchRailsState,
+        throw: bool,
+    },
+    Relocation {
+        loco_id: LocoId,
+        checkpoint_id: Option<CheckpointId>,
+    },
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct SegmentUtilization {
+    traversals: u64,
+    occupied_us: u64,
+    // Share of the window during which the segment was occupied, which can
+    // exceed 1 when several locos run on it at the same time
+    utilization: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DwellBucket {
+    // None for the last bucket, which has no upper bound
+    up_to_secs: Option<u64>,
+    count: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DwellDistribution {
+    dwells: u64,
+    min_us: u64,
+    max_us: u64,
+    mean_us: u64,
+    buckets: Vec<DwellBucket>,
+}
+
+impl DwellDistribution {
+    fn new(dwells_us: &[u64]) -> Self {
+        let mut buckets: Vec<DwellBucket> = DWELL_BUCKETS_SECS
+            .iter()
+            .map(|secs| DwellBucket {
+                up_to_secs: Some(*secs),
+                count: 0,
+            })
+            .chain([DwellBucket {
+                up_to_secs: None,
+                count: 0,
+            }])
+            .collect();
+
+        for dwell_us in dwells_us.iter() {
+            let bucket = DWELL_BUCKETS_SECS
+                .iter()
+                .position(|secs| *dwell_us <= secs * 1_000_000)
+                .unwrap_or(DWELL_BUCKETS_SECS.len());
+            buckets[bucket].count += 1;
+        }
+
+        DwellDistribution {
+            dwells: dwells_us.len() as u64,
+            min_us: dwells_us.iter().copied().min().unwrap_or(0),
+            max_us: dwells_us.iter().copied().max().unwrap_or(0),
+            mean_us: dwells_us.iter().sum::<u64>() / (dwells_us.len().max(1) as u64),
+            buckets,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Utilization {
+    since_us: u64,
+    until_us: u64,
+    segments: BTreeMap<SegmentId, SegmentUtilization>,
+    checkpoints: BTreeMap<CheckpointId, DwellDistribution>,
+    switch_throws: BTreeMap<ActuatorId, u64>,
+}
+
+#[derive(Serialize, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum IncidentKind {
+    // Detected further than the next checkpoint, which it went by unnoticed
+    Skipped,
+    // Lost while moving, until the crew relocated it
+    Relocated,
+}
+
+/**
+ * Loco missing its next checkpoint, along with everything known about how it
+ * was running at the time. Switch rails are the ones of the segment it was
+ * expected to run through.
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct Incident {
+    timestamp_us: u64,
+    kind: IncidentKind,
+    loco_id: LocoId,
+    from: CheckpointId,
+    expected: CheckpointId,
+    detected: Option<CheckpointId>,
+    direction: Direction,
+    speed: Speed,
+    segment_id: Option<SegmentId>,
+    switch_rails: BTreeMap<ActuatorId, SwitchRailsState>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct ComponentRisk {
+    traversals: u64,
+    // Indexes of the incidents in the report
+    incidents: Vec<usize>,
+    // Share of the runs which ended up missing a checkpoint
+    risk: f64,
+}
+
+impl ComponentRisk {
+    fn record(&mut self, incident: Option<usize>) {
+        match incident {
+            Some(index) => self.incidents.push(index),
+            None => self.traversals += 1,
+        }
+    }
+
+    fn update(&mut self) {
+        let runs = self.traversals + self.incidents.len() as u64;
+        self.risk = match runs {
+            0 => 0.0,
+            _ => self.incidents.len() as f64 / runs as f64,
+        };
+    }
+}
+
+// Circumstances shared by several runs through a segment
+#[derive(Serialize, Debug)]
+pub struct RiskPattern {
+    segment_id: SegmentId,
+    direction: Direction,
+    speed: Speed,
+    switch_rails: BTreeMap<ActuatorId, SwitchRailsState>,
+    #[serde(flatten)]
+    risk: ComponentRisk,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RiskReport {
+    since_us: u64,
+    until_us: u64,
+    segments: BTreeMap<SegmentId, ComponentRisk>,
+    switch_rails: BTreeMap<ActuatorId, ComponentRisk>,
+    // Patterns which led to at least one incident, riskiest first
+    patterns: Vec<RiskPattern>,
+    incidents: Vec<Incident>,
+}
+
+// Run of a loco from a checkpoint through a segment, whether it reached the
+// next checkpoint or not
+struct Run {
+    segment_id: SegmentId,
+    direction: Direction,
+    speed: Speed,
+    switch_rails: BTreeMap<ActuatorId, SwitchRailsState>,
+    incident: Option<usize>,
+}
+
+fn is_moving(speed: Speed) -> bool {
+    speed.duty_cycle() > 0
+}
+
+// Next checkpoint a loco is heading to, preferably the one the switch rails
+// are lined up for
+fn expected_checkpoint(
+    rail_network: &RailNetwork,
+    from: CheckpointId,
+    direction: Direction,
+    positions: &BTreeMap<ActuatorId, SwitchRailsState>,
+) -> Option<(CheckpointId, Option<SegmentId>)> {
+    let next_ids = rail_network.next_checkpoint_ids(from, direction);
+    let lined_up = next_ids.iter().find(|id| {
+        TryInto::<SegmentId>::try_into((from, **id)).is_ok_and(|segment_id| {
+            rail_network
+                .segment(&segment_id)
+                .switch_rails()
+                .iter()
+                .all(|s| positions.get(&s.actuator_id()) == Some(&s.state()))
+        })
+    });
+    let expected = *lined_up.or(next_ids.first())?;
+
+    Some((expected, (from, expected).try_into().ok()))
+}
+
+// Known positions of the switch rails a segment relies on
+fn segment_positions(
+    rail_network: &RailNetwork,
+    segment_id: SegmentId,
+    positions: &BTreeMap<ActuatorId, SwitchRailsState>,
+) -> BTreeMap<ActuatorId, SwitchRailsState> {
+    rail_network
+        .segment(&segment_id)
+        .switch_rails()
+        .iter()
+        .filter_map(|s| {
+            positions
+                .get(&s.actuator_id())
+                .map(|state| (s.actuator_id(), *state))
+        })
+        .collect()
+}
+
+struct Stats {
+    samples: History<Sample>,
+    // Last known position of every switch rails, so that only actual throws
+    // are recorded rather than every time the Oracle drives them
+    switch_rails: BTreeMap<ActuatorId, SwitchRailsState>,
+}
+
+impl Stats {
+    fn window(&self, query: &StatsQuery, now_us: u64) -> (u64, u64) {
+        let until_us = query.until_us.unwrap_or(now_us);
+        let since_us = query.since_us.unwrap_or_else(|| {
+            self.samples
+                .iter_timestamped()
+                .next()
+                .map_or(until_us, |(t, _)| t)
+        });
+        (since_us, until_us)
+    }
+}
+
+/**
+ * Usage of the rail network, computed from a bounded history of the sensors
+ * hits, loco commands and switch rails throws. A loco occupies a segment from
+ * the time it leaves a checkpoint until it's detected at the next one, and
+ * dwells at a checkpoint from the time it's stopped there until it's sent
+ * moving again. The same history points at the trouble spots of the track,
+ * where locos tend to miss their next checkpoint.
+ */
+pub struct StatsTracker {
+    stats: Mutex<Stats>,
+}
+
+impl StatsTracker {
+    pub fn new(config: &HistoryConfig) -> Self {
+        StatsTracker {
+            stats: Mutex::new(Stats {
+                samples: History::new(config.max_entries, config.max_age()),
+                switch_rails: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn apply(&self, event: &Event, now_us: u64) {
+        let mut stats = self.stats.lock().unwrap();
+
+        match event {
+            Event::SensorHit {
+                loco_id,
+                sensor_id,
+                timestamp_us,
+            } => stats.samples.push(
+                Sample::Hit {
+                    loco_id: *loco_id,
+                    checkpoint_id: (*sensor_id).into(),
+                },
+                *timestamp_us,
+            ),
+            Event::LocoCommandApplied {
+                loco_id,
+                direction,
+                speed,
+            } => stats.samples.push(
+                Sample::Command {
+                    loco_id: *loco_id,
+                    direction: *direction,
+                    speed: *speed,
+                },
+                now_us,
+            ),
+            Event::ActuatorsDriven { actuators } => {
+                for (actuator_id, actuator_type, actuator_state) in actuators.iter() {
+                    if *actuator_type != ActuatorType::SwitchRails {
+                        continue;
+                    }
+                    let Ok(state) = SwitchRailsState::try_from(*actuator_state) else {
+                        continue;
+                    };
+                    // The initial position isn't a throw
+                    match stats.switch_rails.insert(*actuator_id, state) {
+                        Some(previous) if previous == state => {}
+                        previous => stats.samples.push(
+                            Sample::SwitchRails {
+                                actuator_id: *actuator_id,
+                                state,
+                                throw: previous.is_some(),
+                            },
+                            now_us,
+                        ),
+                    }
+                }
+            }
+            Event::LocationCorrected { loco_id, sensor_id } => stats.samples.push(
+                Sample::Relocation {
+                    loco_id: *loco_id,
+                    checkpoint_id: sensor_id.map(CheckpointId::from),
+                },
+                now_us,
+            ),
+            Event::SensorDeparture { .. }
+            | Event::IntentCompleted { .. }
+            | Event::OracleDecision { .. }
+            | Event::LocoIntentSet { .. }
+            | Event::StartupProgress { .. }
+            | Event::LocoConnected { .. }
+            | Event::LocoDisconnected { .. }
+            | Event::ActuatorsConnected
+            | Event::ActuatorsDisconnected
+            | Event::EmergencyStop { .. }
+            | Event::MaintenanceStarted { .. }
+            | Event::MaintenanceEnded
+            | Event::DayProgramSwitched { .. }
+            | Event::UnknownTag { .. }
+            | Event::WagonHit { .. }
+            | Event::ConsistIssue { .. }
+            | Event::SafeCrawl { .. } => {}
+        }
+    }
+
+    pub fn utilization(&self, query: &StatsQuery, now_us: u64) -> Utilization {
+        let stats = self.stats.lock().unwrap();
+
+        let (since_us, until_us) = stats.window(query, now_us);
+        let window_us = until_us.saturating_sub(since_us);
+
+        let mut segments: BTreeMap<SegmentId, SegmentUtilization> = BTreeMap::new();
+        let mut dwells: BTreeMap<CheckpointId, Vec<u64>> = BTreeMap::new();
+        let mut switch_throws: BTreeMap<ActuatorId, u64> = BTreeMap::new();
+
+        // Checkpoint every loco is at, and since when it's either moving away
+        // from it or stopped there
+        let mut locations: BTreeMap<LocoId, (CheckpointId, u64)> = BTreeMap::new();
+        let mut stopped: BTreeMap<LocoId, bool> = BTreeMap::new();
+
+        for (timestamp_us, sample) in stats
+            .samples
+            .iter_timestamped()
+            .filter(|(t, _)| *t >= since_us && *t <= until_us)
+        {
+            match sample {
+                Sample::Hit {
+                    loco_id,
+                    checkpoint_id,
+                } => {
+                    if let Some((from, left_us)) = locations.get(loco_id)
+                        && let Ok(segment_id) =
+                            TryInto::<SegmentId>::try_into((*from, *checkpoint_id))
+                    {
+                        let segment = segments.entry(segment_id).or_default();
+                        segment.traversals += 1;
+                        segment.occupied_us += timestamp_us.saturating_sub(*left_us);
+                    }
+                    locations.insert(*loco_id, (*checkpoint_id, timestamp_us));
+                }
+                Sample::Command { loco_id, speed, .. } => {
+                    let was_stopped = stopped.insert(*loco_id, *speed == Speed::Stop);
+                    let Some((checkpoint_id, since)) = locations.get_mut(loco_id) else {
+                        continue;
+                    };
+                    match (was_stopped, *speed == Speed::Stop) {
+                        // Starts dwelling at the checkpoint
+                        (Some(false) | None, true) => *since = timestamp_us,
+                        // Leaves the checkpoint
+                        (Some(true), false) => {
+                            dwells
+                                .entry(*checkpoint_id)
+                                .or_default()
+                                .push(timestamp_us.saturating_sub(*since));
+                            *since = timestamp_us;
+                        }
+                        _ => {}
+                    }
+                }
+                Sample::SwitchRails {
+                    actuator_id, throw, ..
+                } => {
+                    if *throw {
+                        *switch_throws.entry(*actuator_id).or_default() += 1;
+                    }
+                }
+                // The loco didn't run there by itself, so neither the segment
+                // nor the dwell it was in the middle of are accounted for
+                Sample::Relocation {
+                    loco_id,
+                    checkpoint_id,
+                } => match checkpoint_id {
+                    Some(checkpoint_id) => {
+                        locations.insert(*loco_id, (*checkpoint_id, timestamp_us));
+                    }
+                    None => {
+                        locations.remove(loco_id);
+                    }
+                },
+            }
+        }
+
+        for segment in segments.values_mut() {
+            segment.utilization = match window_us {
+                0 => 0.0,
+                _ => segment.occupied_us as f64 / window_us as f64,
+            };
+        }
+
+        Utilization {
+            since_us,
+            until_us,
+            segments,
+            checkpoints: dwells
+                .iter()
+                .map(|(id, dwells_us)| (*id, DwellDistribution::new(dwells_us)))
+                .collect(),
+            switch_throws,
+        }
+    }
+
+    // Replays the history looking for the locos which missed their next
+    // checkpoint, and correlates these incidents with the circumstances of
+    // every run through the same segments and switch rails. Samples older
+    // than the time range are replayed as well, so that the positions of the
+    // switch rails are known from the start.
+    pub fn risk(&self, query: &StatsQuery, now_us: u64) -> RiskReport {
+        let stats = self.stats.lock().unwrap();
+
+        let (since_us, until_us) = stats.window(query, now_us);
+        let rail_network = RailNetwork::new();
+
+        let mut runs: Vec<Run> = Vec::new();
+        let mut incidents: Vec<Incident> = Vec::new();
+        let mut locations: BTreeMap<LocoId, CheckpointId> = BTreeMap::new();
+        let mut commands: BTreeMap<LocoId, (Direction, Speed)> = BTreeMap::new();
+        let mut positions: BTreeMap<ActuatorId, SwitchRailsState> = BTreeMap::new();
+
+        for (timestamp_us, sample) in stats
+            .samples
+            .iter_timestamped()
+            .filter(|(t, _)| *t <= until_us)
+        {
+            let (loco_id, from, detected, kind) = match sample {
+                Sample::Hit {
+                    loco_id,
+                    checkpoint_id,
+                } => (
+                    loco_id,
+                    locations.insert(*loco_id, *checkpoint_id),
+                    Some(*checkpoint_id),
+                    IncidentKind::Skipped,
+                ),
+                Sample::Relocation {
+                    loco_id,
+                    checkpoint_id,
+                } => (
+                    loco_id,
+                    match checkpoint_id {
+                        Some(checkpoint_id) => locations.insert(*loco_id, *checkpoint_id),
+                        None => locations.remove(loco_id),
+                    },
+                    *checkpoint_id,
+                    IncidentKind::Relocated,
+                ),
+                Sample::Command {
+                    loco_id,
+                    direction,
+                    speed,
+                } => {
+                    commands.insert(*loco_id, (*direction, *speed));
+                    continue;
+                }
+                Sample::SwitchRails {
+                    actuator_id, state, ..
+                } => {
+                    positions.insert(*actuator_id, *state);
+                    continue;
+                }
+            };
+
+            let (Some(from), Some((direction, speed))) = (from, commands.get(loco_id).copied())
+            else {
+                continue;
+            };
+            if timestamp_us < since_us || detected == Some(from) {
+                continue;
+            }
+
+            // Reaching a neighbour checkpoint is a successful run, and a
+            // parked loco being moved by hand isn't an incident
+            let reached = detected.and_then(|id| TryInto::<SegmentId>::try_into((from, id)).ok());
+            if let (IncidentKind::Skipped, Some(segment_id)) = (kind, reached) {
+                runs.push(Run {
+                    segment_id,
+                    direction,
+                    speed,
+                    switch_rails: segment_positions(&rail_network, segment_id, &positions),
+                    incident: None,
+                });
+                continue;
+            }
+            if matches!(kind, IncidentKind::Relocated) && !is_moving(speed) {
+                continue;
+            }
+
+            let Some((expected, segment_id)) =
+                expected_checkpoint(&rail_network, from, direction, &positions)
+            else {
+                continue;
+            };
+            let switch_rails = segment_id
+                .map(|id| segment_positions(&rail_network, id, &positions))
+                .unwrap_or_default();
+
+            if let Some(segment_id) = segment_id {
+                runs.push(Run {
+                    segment_id,
+                    direction,
+                    speed,
+                    switch_rails: switch_rails.clone(),
+                    incident: Some(incidents.len()),
+                });
+            }
+            incidents.push(Incident {
+                timestamp_us,
+                kind,
+                loco_id: *loco_id,
+                from,
+                expected,
+                detected,
+                direction,
+                speed,
+                segment_id,
+                switch_rails,
+            });
+        }
+
+        let mut segments: BTreeMap<SegmentId, ComponentRisk> = BTreeMap::new();
+        let mut switch_rails: BTreeMap<ActuatorId, ComponentRisk> = BTreeMap::new();
+        let mut patterns: Vec<RiskPattern> = Vec::new();
+
+        for run in runs {
+            segments
+                .entry(run.segment_id)
+                .or_default()
+                .record(run.incident);
+            for actuator_id in run.switch_rails.keys() {
+                switch_rails
+                    .entry(*actuator_id)
+                    .or_default()
+                    .record(run.incident);
+            }
+
+            let index = match patterns.iter().position(|p| {
+                (p.segment_id, p.direction, p.speed, &p.switch_rails)
+                    == (run.segment_id, run.direction, run.speed, &run.switch_rails)
+            }) {
+                Some(index) => index,
+                None => {
+                    patterns.push(RiskPattern {
+                        segment_id: run.segment_id,
+                        direction: run.direction,
+                        speed: run.speed,
+                        switch_rails: run.switch_rails,
+                        risk: ComponentRisk::default(),
+                    });
+                    patterns.len() - 1
+                }
+            };
+            patterns[index].risk.record(run.incident);
+        }
+
+        for risk in segments.values_mut().chain(switch_rails.values_mut()) {
+            risk.update();
+        }
+        patterns.retain(|p| !p.risk.incidents.is_empty());
+        for pattern in patterns.iter_mut() {
+            pattern.risk.update();
+        }
+        patterns.sort_by(|a, b| b.risk.risk.total_cmp(&a.risk.risk));
+
+        RiskReport {
+            since_us,
+            until_us,
+            segments,
+            switch_rails,
+            patterns,
+            incidents,
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fmt::Display,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error};
+use serde::Serialize;
+
+// Delay before restarting a crashed worker, so that a worker failing right
+// away doesn't spin
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// A periodic worker is considered stalled once it missed this many periods,
+// and at least STALL_MIN, since a single iteration may wait on the network
+const STALL_PERIODS: u32 = 10;
+const STALL_MIN: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerState {
+    Running,
+    // Alive but not looping anymore, which can't be fixed by a restart since
+    // a thread can't be killed
+    Stalled,
+    // Crashed, waiting to be started again
+    Restarting,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct WorkerStatus {
+    state: WorkerState,
+    // Since the worker last told it was alive
+    last_beat_age_us: u64,
+    restarts: u32,
+    last_error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    healthy: bool,
+    workers: BTreeMap<&'static str, WorkerStatus>,
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+}
+
+struct Worker {
+    stall_after: Option<Duration>,
+    last_beat: Instant,
+    restarting: bool,
+    restarts: u32,
+    last_error: Option<String>,
+}
+
+impl Worker {
+    fn state(&self) -> WorkerState {
+        if self.restarting {
+            WorkerState::Restarting
+        } else if self
+            .stall_after
+            .is_some_and(|stall_after| self.last_beat.elapsed() > stall_after)
+        {
+            WorkerState::Stalled
+        } else {
+            WorkerState::Running
+        }
+    }
+}
+
+/**
+ * Handed to a supervised worker, which must beat it on every iteration of
+ * its loop to tell it's alive.
+ */
+pub struct Heartbeat {
+    supervisor: Arc<Supervisor>,
+    name: &'static str,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        if let Some(worker) = self.supervisor.workers.lock().unwrap().get_mut(self.name) {
+            worker.last_beat = Instant::now();
+        }
+    }
+}
+
+/**
+ * Runs the backend workers, each one in its own thread, restarting them
+ * whenever they return or panic rather than letting them die silently. The
+ * workers beat a Heartbeat, which tells the ones which got stuck.
+ */
+#[derive(Default)]
+pub struct Supervisor {
+    workers: Mutex<BTreeMap<&'static str, Worker>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Supervisor::default()
+    }
+
+    // The period is how often the worker beats while alive, or None for the
+    // workers blocking until something happens, which never stall
+    pub fn spawn<F, E>(
+        self: &Arc<Self>,
+        name: &'static str,
+        period: Option<Duration>,
+        mut worker: F,
+    ) where
+        F: FnMut(&Heartbeat) -> Result<(), E> + Send + 'static,
+        E: Display,
+    {
+        debug!("Supervisor::spawn(): {}", name);
+
+        self.workers.lock().unwrap().insert(
+            name,
+            Worker {
+                stall_after: period.map(|period| (period * STALL_PERIODS).max(STALL_MIN)),
+                last_beat: Instant::now(),
+                restarting: false,
+                restarts: 0,
+                last_error: None,
+            },
+        );
+
+        let heartbeat = Heartbeat {
+            supervisor: self.clone(),
+            name,
+        };
+        thread::spawn(move || {
+            loop {
+                let error = match panic::catch_unwind(AssertUnwindSafe(|| worker(&heartbeat))) {
+                    Ok(Ok(())) => "returned".to_string(),
+                    Ok(Err(e)) => e.to_string(),
+                    Err(payload) => format!("panicked: {}", panic_message(&*payload)),
+                };
+                error!("Supervisor: {} {}, restarting", name, error);
+                heartbeat.supervisor.crashed(name, error);
+
+                sleep(RESTART_DELAY);
+                heartbeat.supervisor.restarted(name);
+            }
+        });
+    }
+
+    fn crashed(&self, name: &'static str, error: String) {
+        if let Some(worker) = self.workers.lock().unwrap().get_mut(name) {
+            worker.restarting = true;
+            worker.last_error = Some(error);
+        }
+    }
+
+    fn restarted(&self, name: &'static str) {
+        if let Some(worker) = self.workers.lock().unwrap().get_mut(name) {
+            worker.restarting = false;
+            worker.restarts += 1;
+            worker.last_beat = Instant::now();
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        let workers: BTreeMap<&'static str, WorkerStatus> = self
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, worker)| {
+                (
+                    *name,
+                    WorkerStatus {
+                        state: worker.state(),
+                        last_beat_age_us: worker.last_beat.elapsed().as_micros() as u64,
+                        restarts: worker.restarts,
+                        last_error: worker.last_error.clone(),
+                    },
+                )
+            })
+            .collect();
+
+        Health {
+            healthy: workers
+                .values()
+                .all(|status| status.state == WorkerState::Running),
+            workers,
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    // Long enough for a restart to happen, not to hold the tests for long
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn wait_for(supervisor: &Supervisor, name: &str, done: impl Fn(&WorkerStatus) -> bool) {
+        let started = Instant::now();
+        while !done(&supervisor.health().workers[name]) {
+            assert!(started.elapsed() < TIMEOUT, "timed out waiting on {}", name);
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Registers a periodic worker whose last beat is that old, without
+    // running anything
+    fn beaten_ago(supervisor: &Supervisor, name: &'static str, age: Duration) {
+        supervisor.workers.lock().unwrap().insert(
+            name,
+            Worker {
+                stall_after: Some(STALL_MIN),
+                last_beat: Instant::now() - age,
+                restarting: false,
+                restarts: 0,
+                last_error: None,
+            },
+        );
+    }
+
+    #[test]
+    fn failed_worker_restarted() {
+        let supervisor = Arc::new(Supervisor::new());
+        let runs = Arc::new(AtomicU32::new(0));
+        let worker_runs = runs.clone();
+        supervisor.spawn("failing", None, move |_| {
+            if worker_runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err("lost the stream");
+            }
+            loop {
+                sleep(TIMEOUT);
+            }
+        });
+
+        wait_for(&supervisor, "failing", |status| status.restarts == 1);
+        let status = &supervisor.health().workers["failing"];
+        assert_eq!(status.state, WorkerState::Running);
+        assert_eq!(status.last_error.as_deref(), Some("lost the stream"));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn panicked_worker_restarted() {
+        let supervisor = Arc::new(Supervisor::new());
+        let runs = Arc::new(AtomicU32::new(0));
+        let worker_runs = runs.clone();
+        supervisor.spawn("panicking", None, move |_| -> Result<(), String> {
+            if worker_runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("unexpected frame");
+            }
+            loop {
+                sleep(TIMEOUT);
+            }
+        });
+
+        wait_for(&supervisor, "panicking", |status| {
+            status.state == WorkerState::Restarting
+        });
+        assert!(!supervisor.health().is_healthy());
+        wait_for(&supervisor, "panicking", |status| status.restarts == 1);
+        let status = &supervisor.health().workers["panicking"];
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some("panicked: unexpected frame")
+        );
+        assert!(supervisor.health().is_healthy());
+    }
+
+    #[test]
+    fn stall_detected() {
+        let supervisor = Arc::new(Supervisor::new());
+        beaten_ago(&supervisor, "fresh", Duration::ZERO);
+        beaten_ago(&supervisor, "stuck", STALL_MIN * 2);
+
+        let health = supervisor.health();
+        assert_eq!(health.workers["fresh"].state, WorkerState::Running);
+        assert_eq!(health.workers["stuck"].state, WorkerState::Stalled);
+        assert!(!health.is_healthy());
+
+        // Beating again is all it takes to be back to running
+        Heartbeat {
+            supervisor: supervisor.clone(),
+            name: "stuck",
+        }
+        .beat();
+        let health = supervisor.health();
+        assert_eq!(health.workers["stuck"].state, WorkerState::Running);
+        assert!(health.is_healthy());
+    }
+
+    #[test]
+    fn blocking_worker_never_stalls() {
+        let supervisor = Arc::new(Supervisor::new());
+        supervisor.spawn("events", None, |_| -> Result<(), String> {
+            loop {
+                sleep(TIMEOUT);
+            }
+        });
+        supervisor
+            .workers
+            .lock()
+            .unwrap()
+            .get_mut("events")
+            .unwrap()
+            .last_beat -= STALL_MIN * 2;
+
+        assert_eq!(
+            supervisor.health().workers["events"].state,
+            WorkerState::Running
+        );
+    }
+
+    #[test]
+    fn stall_threshold() {
+        let supervisor = Arc::new(Supervisor::new());
+        supervisor.spawn("fast", Some(Duration::from_millis(10)), |_| {
+            Ok::<(), String>(())
+        });
+        supervisor.spawn("slow", Some(Duration::from_secs(1)), |_| {
+            Ok::<(), String>(())
+        });
+
+        let workers = supervisor.workers.lock().unwrap();
+        // A fast worker is still given STALL_MIN, a slow one STALL_PERIODS
+        assert_eq!(workers["fast"].stall_after, Some(STALL_MIN));
+        assert_eq!(
+            workers["slow"].stall_after,
+            Some(Duration::from_secs(1) * STALL_PERIODS)
+        );
+    }
+}
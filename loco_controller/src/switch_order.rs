@@ -0,0 +1,40 @@
+use std::{collections::BTreeMap, sync::RwLock};
+
+use loco_protocol::ActuatorId;
+
+use crate::{
+    config::NetworkConfig,
+    rail_network::{SegmentId, SwitchRails},
+};
+
+/**
+ * Order in which the switch rails of a segment get thrown, since some routes
+ * need the farthest switch rails to be thrown first. Segments which aren't
+ * listed throw their switch rails in the order of the rail network.
+ */
+pub struct SwitchOrder {
+    segments: RwLock<BTreeMap<SegmentId, Vec<ActuatorId>>>,
+}
+
+impl SwitchOrder {
+    pub fn new(network: &NetworkConfig) -> Self {
+        SwitchOrder {
+            segments: RwLock::new(network.switch_order.clone()),
+        }
+    }
+
+    // Called when switching to another layout
+    pub fn set_network(&self, network: &NetworkConfig) {
+        *self.segments.write().unwrap() = network.switch_order.clone();
+    }
+
+    // The configuration lists every switch rails of the segment, hence none
+    // of them ends up out of order
+    pub fn sort(&self, segment_id: SegmentId, switch_rails: &[SwitchRails]) -> Vec<SwitchRails> {
+        let mut sorted = switch_rails.to_vec();
+        if let Some(order) = self.segments.read().unwrap().get(&segment_id) {
+            sorted.sort_by_key(|s| order.iter().position(|id| *id == s.actuator_id()));
+        }
+        sorted
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    sync::Mutex,
+};
+
+use loco_protocol::{LOCO_UIDS, LocoId, SensorId};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    config::{LocoConfig, TagsConfig},
+    journal::{Journal, Recovery},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error writing tag database {0}: {1}")]
+    WriteFile(String, #[source] io::Error),
+    #[error("Error serializing tag database: {0}")]
+    Serialize(#[source] serde_json::Error),
+    #[error("Tag {0} already belongs to {1}")]
+    Collision(TagUid, TagOwner),
+    #[error("Tag {0} of {1} is known to the sensors firmware")]
+    FirmwareTag(TagUid, LocoId),
+    #[error("Tag {0} of {1} is part of the roster configuration")]
+    RosterTag(TagUid, LocoId),
+    #[error("{0} not in the roster")]
+    UnknownLoco(LocoId),
+    #[error("Tag {0} not in the database")]
+    UnknownTag(TagUid),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/**
+ * UID of an RFID tag, written as 8 hexadecimal digits.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct TagUid([u8; 4]);
+
+impl From<[u8; 4]> for TagUid {
+    fn from(uid: [u8; 4]) -> Self {
+        TagUid(uid)
+    }
+}
+
+impl TryFrom<String> for TagUid {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let invalid = || format!("invalid tag UID {:?}, expecting 8 hex digits", value);
+
+        if value.len() != 8 {
+            return Err(invalid());
+        }
+        let mut uid = [0u8; 4];
+        for (i, byte) in uid.iter_mut().enumerate() {
+            *byte = value
+                .get(i * 2..i * 2 + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(invalid)?;
+        }
+
+        Ok(TagUid(uid))
+    }
+}
+
+impl From<TagUid> for String {
+    fn from(value: TagUid) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for TagUid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TagOwner {
+    Loco(LocoId),
+    Wagon(String),
+}
+
+impl fmt::Display for TagOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagOwner::Loco(loco_id) => write!(f, "{}", loco_id),
+            TagOwner::Wagon(name) => write!(f, "wagon {}", name),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TagEntry {
+    uid: TagUid,
+    owner: TagOwner,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct UnknownTag {
+    uid: TagUid,
+    // Last sensor which detected it
+    sensor_id: SensorId,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TagsDescription {
+    tags: Vec<TagEntry>,
+    // Fitted under the locos, and known to the sensors firmware
+    firmware_tags: Vec<TagEntry>,
+    // Given along with the locos of the roster
+    roster_tags: Vec<TagEntry>,
+    unknown: Vec<UnknownTag>,
+}
+
+fn firmware_tags() -> impl Iterator<Item = TagEntry> {
+    LOCO_UIDS.iter().map(|(loco_id, uid)| TagEntry {
+        uid: TagUid(*uid),
+        owner: TagOwner::Loco(*loco_id),
+    })
+}
+
+fn roster_tags(roster: &BTreeMap<LocoId, Vec<TagUid>>) -> impl Iterator<Item = TagEntry> {
+    roster.iter().flat_map(|(loco_id, tags)| {
+        tags.iter().map(|uid| TagEntry {
+            uid: *uid,
+            owner: TagOwner::Loco(*loco_id),
+        })
+    })
+}
+
+/**
+ * Database of the tags fitted under the rolling stock, persisted as a JSON
+ * file so that tags can be registered without touching the firmware. The
+ * sensors board only knows the tags of the first locos, and reports the other
+ * ones to the controller, which looks them up here. Tags given by the roster
+ * are always there. A tag can only belong to one loco or wagon: colliding
+ * entries are rejected, and reported when found in the file.
+ */
+pub struct TagDatabase {
+    journal: Option<Journal>,
+    recovery: Option<Recovery>,
+    roster: BTreeMap<LocoId, Vec<TagUid>>,
+    tags: Mutex<BTreeMap<TagUid, TagOwner>>,
+    unknown: Mutex<HashMap<TagUid, SensorId>>,
+}
+
+impl TagDatabase {
+    // A missing file is an empty database, which gets created on the first
+    // change. An unreadable one is set aside, leaving only the built in tags.
+    pub fn load(config: &TagsConfig, roster: &BTreeMap<LocoId, LocoConfig>) -> Self {
+        let roster: BTreeMap<LocoId, Vec<TagUid>> = roster
+            .iter()
+            .map(|(loco_id, loco)| (*loco_id, loco.tags.clone()))
+            .collect();
+        // The roster has been validated along with the configuration, hence
+        // its tags don't collide
+        let mut tags: BTreeMap<TagUid, TagOwner> = firmware_tags()
+            .chain(roster_tags(&roster))
+            .map(|e| (e.uid, e.owner))
+            .collect();
+        let builtin = tags.len();
+
+        let journal = config.database_path.as_deref().map(Journal::new);
+        let (entries, recovery) = match &journal {
+            Some(journal) => {
+                let (entries, recovery) = journal.recover(|content| {
+                    serde_json::from_str::<Vec<TagEntry>>(content).map_err(|e| e.to_string())
+                });
+                (entries, Some(recovery))
+            }
+            None => (None, None),
+        };
+
+        if let Some(entries) = entries {
+            for entry in entries {
+                if let TagOwner::Loco(loco_id) = entry.owner
+                    && !roster.contains_key(&loco_id)
+                {
+                    warn!(
+                        "TagDatabase::load(): {} belongs to {}, which isn't in the roster",
+                        entry.uid, loco_id
+                    );
+                }
+                match tags.get(&entry.uid) {
+                    Some(owner) if *owner != entry.owner => warn!(
+                        "TagDatabase::load(): {} shared by {} and {}, keeping {}",
+                        entry.uid, owner, entry.owner, owner
+                    ),
+                    Some(_) => {}
+                    None => {
+                        tags.insert(entry.uid, entry.owner);
+                    }
+                }
+            }
+            info!("TagDatabase::load(): {} tags", tags.len() - builtin);
+        }
+
+        TagDatabase {
+            journal,
+            recovery,
+            roster,
+            tags: Mutex::new(tags),
+            unknown: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn recovery(&self) -> Option<Recovery> {
+        self.recovery.clone()
+    }
+
+    // Tags hardcoded in the firmware or given by the roster
+    fn is_builtin(&self, uid: TagUid) -> bool {
+        LOCO_UIDS.iter().any(|(_, u)| TagUid(*u) == uid)
+            || self.roster.values().any(|tags| tags.contains(&uid))
+    }
+
+    // Only the entries which aren't built in are saved
+    fn save(&self, tags: &BTreeMap<TagUid, TagOwner>) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+
+        let entries: Vec<TagEntry> = tags
+            .iter()
+            .filter(|(uid, _)| !self.is_builtin(**uid))
+            .map(|(uid, owner)| TagEntry {
+                uid: *uid,
+                owner: owner.clone(),
+            })
+            .collect();
+        let content = serde_json::to_string_pretty(&entries).map_err(Error::Serialize)?;
+
+        journal
+            .write(content.as_bytes())
+            .map_err(|e| Error::WriteFile(journal.path().display().to_string(), e))
+    }
+
+    pub fn owner(&self, uid: TagUid) -> Option<TagOwner> {
+        self.tags.lock().unwrap().get(&uid).cloned()
+    }
+
+    pub fn add(&self, entry: TagEntry) -> Result<()> {
+        if let TagOwner::Loco(loco_id) = entry.owner
+            && !self.roster.contains_key(&loco_id)
+        {
+            return Err(Error::UnknownLoco(loco_id));
+        }
+
+        let mut tags = self.tags.lock().unwrap();
+        if let Some(owner) = tags.get(&entry.uid)
+            && *owner != entry.owner
+        {
+            return Err(Error::Collision(entry.uid, owner.clone()));
+        }
+
+        tags.insert(entry.uid, entry.owner);
+        self.save(&tags)?;
+        self.unknown.lock().unwrap().remove(&entry.uid);
+
+        Ok(())
+    }
+
+    pub fn remove(&self, uid: TagUid) -> Result<()> {
+        // The firmware would keep on recognizing them anyway
+        if let Some((loco_id, _)) = LOCO_UIDS.iter().find(|(_, u)| TagUid(*u) == uid) {
+            return Err(Error::FirmwareTag(uid, *loco_id));
+        }
+        // Would be back on restart
+        if let Some((loco_id, _)) = self.roster.iter().find(|(_, tags)| tags.contains(&uid)) {
+            return Err(Error::RosterTag(uid, *loco_id));
+        }
+
+        let mut tags = self.tags.lock().unwrap();
+        if tags.remove(&uid).is_none() {
+            return Err(Error::UnknownTag(uid));
+        }
+
+        self.save(&tags)
+    }
+
+    // Remembers where a tag missing from the database was seen, telling
+    // whether this is news, as a tag standing on a reader keeps on being
+    // reported
+    pub fn sighted_unknown(&self, uid: TagUid, sensor_id: SensorId) -> bool {
+        self.unknown.lock().unwrap().insert(uid, sensor_id) != Some(sensor_id)
+    }
+
+    pub fn describe(&self) -> TagsDescription {
+        let tags = self.tags.lock().unwrap();
+        TagsDescription {
+            tags: tags
+                .iter()
+                .map(|(uid, owner)| TagEntry {
+                    uid: *uid,
+                    owner: owner.clone(),
+                })
+                .filter(|e| !self.is_builtin(e.uid))
+                .collect(),
+            firmware_tags: firmware_tags().collect(),
+            roster_tags: roster_tags(&self.roster).collect(),
+            unknown: self
+                .unknown
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(uid, sensor_id)| UnknownTag {
+                    uid: *uid,
+                    sensor_id: *sensor_id,
+                })
+                .collect(),
+        }
+    }
+}
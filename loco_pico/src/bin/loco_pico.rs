@@ -2,25 +2,45 @@
 #![no_main]
 #![allow(async_fn_in_trait)]
 
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicI8, AtomicU16, Ordering};
+
 use bincode::config::{Configuration, Fixint, LittleEndian, NoLimit};
 use bincode::error::{DecodeError, EncodeError};
 use bincode::{decode_from_slice, encode_into_slice};
 use common_pico::{
-    HEADER_SIZE, PAYLOAD_MAX_SIZE, REQUEST_MAX_SIZE, RESPONSE_MAX_SIZE, SERVER_IP_ADDRESS,
-    SERVER_TCP_PORT_LOCOS, connect_loco_controller, initialize_logger, initialize_program,
-    initialize_wifi,
+    AUTH_TOKEN, CODEC, FrameError, HEARTBEAT_PERIOD_MS, REQUEST_MAX_SIZE, RESPONSE_MAX_SIZE,
+    SERVER_TCP_PORT_LOCOS, connect_loco_controller, disconnect_loco_controller, firmware_version,
+    initialize_logger, initialize_program, initialize_wifi, read_frame, send_error, send_heartbeat,
+    send_nack,
 };
+use cyw43::Control;
+use defmt::*;
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_net::tcp::TcpSocket;
-use embassy_rp::Peri;
+use embassy_rp::adc::{Adc, Blocking, Channel as AdcChannel, Config as AdcConfig};
+use embassy_rp::gpio::{Input, Level, Output, Pull};
 use embassy_rp::peripherals::{PIN_0, PWM_SLICE0};
 use embassy_rp::peripherals::{PIN_3, PWM_SLICE1};
+use embassy_rp::peripherals::{PIN_4, PWM_SLICE2};
+use embassy_rp::peripherals::{PIN_7, PWM_SLICE3};
 use embassy_rp::pwm::{Config as PwmConfig, Pwm, PwmError, SetDutyCycle};
-use embassy_time::Timer;
+use embassy_rp::{Peri, otp};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, TimeoutError, Timer, with_deadline, with_timeout};
 use embedded_io_async::{Read, ReadExactError, Write as _};
 use loco_protocol::{
-    BACKEND_PROTOCOL_MAGIC_NUMBER, ConnectPayload, ControlLocoPayload, Direction,
-    Error as LocoProtocolError, Header, LocoStatusResponse, Operation, Speed,
+    AUTH_TOKEN_EXT_ID, BACKEND_PROTOCOL_VERSION, ConnectPayload, ControlLocoFunctionsPayload,
+    ControlLocoFunctionsResponse, ControlLocoPayload, ControlLocoResponse, Direction,
+    DisconnectReason, Error as LocoProtocolError, ErrorCode, ErrorPayload, Extensions, HEADER_SIZE,
+    HoldOnDisconnectPayload, LOCO_TELEMETRY_EXT_BATTERY_MV, LOCO_TELEMETRY_EXT_POWER_LOST,
+    LOCO_TELEMETRY_EXT_RSSI, LOCO_TELEMETRY_EXT_SUPPLY, LOCO_TELEMETRY_EXT_TEMPERATURE,
+    LocoFunctions, LocoStatusResponse, MotorStatus, Operation, SafeCrawlReportPayload,
+    SelectWireFormatPayload, SequenceCheck, SequenceCounter, SequenceTracker, Speed,
+    WIRE_FORMATS_EXT_ID, WireFormat, decode_command_id, decode_payload, decode_payload_as,
+    decode_ramp_ms, encode_extension_field, encode_payload,
 };
 use {defmt_rtt as _, panic_probe as _};
 
@@ -29,36 +49,108 @@ async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
     initialize_logger(&spawner, p.USB);
     initialize_program("LocoPico").await;
-    let (mut control, stack) = initialize_wifi(
-        &spawner, p.PIN_23, p.PIN_25, p.PIO0, p.PIN_24, p.PIN_29, p.DMA_CH0,
+    let (mut control, stack, servers) = initialize_wifi(
+        &spawner, p.FLASH, p.PIN_23, p.PIN_25, p.PIO0, p.PIN_24, p.PIN_29, p.DMA_CH0,
     )
     .await;
 
-    let pwm_ctrl = PwmController::new(p.PWM_SLICE0, p.PIN_0, p.PWM_SLICE1, p.PIN_3).unwrap();
+    let second_motor_pins = if SECOND_MOTOR_TRIM.is_some() {
+        Some((p.PWM_SLICE2, p.PIN_4, p.PWM_SLICE3, p.PIN_7))
+    } else {
+        None
+    };
+    let pwm_ctrl = PwmController::new(
+        p.PWM_SLICE0,
+        p.PIN_0,
+        p.PWM_SLICE1,
+        p.PIN_3,
+        second_motor_pins,
+    )
+    .unwrap();
+    PWM_CTRL.lock(|c| c.borrow_mut().replace(pwm_ctrl));
+
+    // Spawn a dedicated task that periodically monitors the motors current,
+    // along with the temperature, the battery if any and the track power
+    unwrap!(spawner.spawn(stall_monitor_task(
+        Adc::new_blocking(p.ADC, AdcConfig::default()),
+        AdcChannel::new_pin(p.PIN_26, Pull::None),
+        AdcChannel::new_temp_sensor(p.ADC_TEMP_SENSOR),
+        BATTERY_DIVIDER_RATIO.map(|_| AdcChannel::new_pin(p.PIN_28, Pull::None)),
+        POWER_LOSS_ADC_THRESHOLD.map(|_| AdcChannel::new_pin(p.PIN_27, Pull::None)),
+    )));
+
+    // Spawn a dedicated task that moves the motors along the requested ramps
+    unwrap!(spawner.spawn(ramp_task()));
+
+    // Unique identifier of this board, letting the controller tell apart two
+    // boards claiming the same LocoId.
+    let device_id = otp::get_chipid().unwrap_or_else(|_| {
+        log::warn!("Couldn't read chip ID");
+        0
+    });
 
-    let mut loco = Loco::new(pwm_ctrl);
+    // Lights and horn are driven through spare GPIOs, all off until the
+    // controller switches them on
+    let function_outputs = FunctionOutputs {
+        headlight: Output::new(p.PIN_10, Level::Low),
+        rear_light: Output::new(p.PIN_11, Level::Low),
+        horn: Output::new(p.PIN_12, Level::Low),
+        cabin_light: Output::new(p.PIN_13, Level::Low),
+    };
+
+    // Hall sensor pulled low by the magnet laid at every checkpoint, only
+    // looked at while crawling on its own
+    let mut hall_sensor = Input::new(p.PIN_14, Pull::Up);
+
+    let mut loco = Loco::new(device_id, function_outputs);
 
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
 
     control.gpio_set(0, false).await;
 
+    // Deadline until which the loco keeps going while disconnected, as
+    // requested by the controller before restarting.
+    let mut hold_deadline: Option<Instant> = None;
+
     loop {
-        // Reset the loco to a well known state
-        if let Err(e) = loco.reset() {
-            log::error!("{:?}", e);
-            continue;
+        // Reset the loco to a well known state, unless it's still being held
+        if hold_deadline.is_none_or(|d| Instant::now() >= d) {
+            hold_deadline = None;
+            if let Err(e) = loco.reset() {
+                log::error!("{:?}", e);
+                continue;
+            }
         }
 
-        let mut socket = match connect_loco_controller(
+        // Nothing can be driven on a flat battery, there's no point in
+        // getting back under the control of the controller
+        while BATTERY_LOW.load(Ordering::Relaxed) {
+            Timer::after_secs(1).await;
+        }
+
+        let connect = connect_loco_controller(
             stack,
             &mut rx_buffer,
             &mut tx_buffer,
-            SERVER_IP_ADDRESS,
+            &servers,
             SERVER_TCP_PORT_LOCOS,
-        )
-        .await
-        {
+        );
+        let connect_result = match hold_deadline {
+            Some(d) => match with_deadline(d, connect).await {
+                Ok(r) => r,
+                Err(TimeoutError) => {
+                    log::warn!("controller didn't come back in time, stopping");
+                    if let Err(e) = loco.safe_crawl(&mut hall_sensor).await {
+                        log::error!("{:?}", e);
+                    }
+                    continue;
+                }
+            },
+            None => connect.await,
+        };
+
+        let mut socket = match connect_result {
             Ok(s) => s,
             Err(e) => {
                 log::warn!("connection error: {:?}", e);
@@ -67,6 +159,9 @@ async fn main(spawner: Spawner) {
             }
         };
 
+        // The controller is back, the loco is under its control again
+        hold_deadline = None;
+
         control.gpio_set(0, true).await;
 
         // Send CONNECT operation
@@ -75,9 +170,25 @@ async fn main(spawner: Spawner) {
             continue;
         }
 
+        if let Err(e) = loco.send_safe_crawl_report(&mut socket).await {
+            log::error!("{:?}", e);
+            continue;
+        }
+
         // Handle incoming messages from the server
-        if let Err(e) = loco.handle_messages(&mut socket).await {
+        if let Err(e) = loco.handle_messages(&mut socket, &mut control).await {
             log::error!("{:?}", e);
+            hold_deadline = loco.take_hold_deadline();
+            if hold_deadline.is_none()
+                && let Err(e) = loco.safe_crawl(&mut hall_sensor).await
+            {
+                log::error!("{:?}", e);
+            }
+            // Being rejected won't resolve by itself, there's no point in
+            // hammering the controller.
+            if let Error::Rejected(_) = e {
+                Timer::after_secs(REJECTED_RETRY_DELAY_SECS).await;
+            }
             continue;
         }
 
@@ -90,12 +201,13 @@ pub enum Error {
     ConvertLocoProtocolType(LocoProtocolError),
     DecodeFromSlice(DecodeError),
     EncodeIntoSlice(EncodeError),
-    InvalidBackendProtocolMagicNumber(u8),
-    InvalidEncodedHeaderSize(usize),
     ReadEof,
+    ReadFrame(FrameError),
     ReadLessThanExpected,
+    Rejected(ErrorCode),
     SetPwmDutyCycle(PwmError),
     TcpRead(ReadExactError<embassy_net::tcp::Error>),
+    PwmControllerNotInitialized,
     TcpWrite(embassy_net::tcp::Error),
     UnknownDirection(u8),
     UnknownOperation(u8),
@@ -103,136 +215,836 @@ pub enum Error {
     UnsupportedOperation(Operation),
 }
 
+impl Error {
+    // What's reported to the controller when a request fails, which isn't
+    // possible when the connection itself is broken
+    fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::ConvertLocoProtocolType(e) => Some(ErrorCode::from(e)),
+            Error::UnknownOperation(_) | Error::UnsupportedOperation(_) => {
+                Some(ErrorCode::UnsupportedOperation)
+            }
+            Error::DecodeFromSlice(_) | Error::UnknownDirection(_) | Error::UnknownSpeed(_) => {
+                Some(ErrorCode::InvalidPayload)
+            }
+            Error::SetPwmDutyCycle(_) | Error::PwmControllerNotInitialized => {
+                Some(ErrorCode::PwmError)
+            }
+            _ => None,
+        }
+    }
+
+    // Requests which only fail on their own, without anything going wrong
+    // with the connection or the loco
+    fn is_malformed_request(&self) -> bool {
+        matches!(
+            self,
+            Error::ConvertLocoProtocolType(_)
+                | Error::DecodeFromSlice(_)
+                | Error::UnknownDirection(_)
+                | Error::UnknownSpeed(_)
+                | Error::UnknownOperation(_)
+                | Error::UnsupportedOperation(_)
+        )
+    }
+}
+
 type Result<T> = core::result::Result<T, Error>;
 
+// Overridden by the roster of the loco_controller when it lists the chip ID of
+// this board
 const LOCO_ID: u8 = 0x1;
+const REJECTED_RETRY_DELAY_SECS: u64 = 30;
+
+/**
+ * Motors trim, expressed as a percentage applied to the commanded duty cycle.
+ * This allows for compensating mechanical differences between two motors so
+ * that both bogies run at the same effective speed. Setting the second motor
+ * trim to None means the loco has a single motor.
+ */
+const FIRST_MOTOR_TRIM: u8 = 100;
+const SECOND_MOTOR_TRIM: Option<u8> = None;
+
+/**
+ * Constants related to the motor stall detection. The current drawn by the
+ * motors is measured through a shunt resistor connected to the ADC. A stall is
+ * reported when the measured value stays above the threshold for long enough,
+ * which prevents inrush current at startup from being seen as a stall.
+ */
+const STALL_ADC_THRESHOLD: u16 = 2048;
+const STALL_SAMPLING_PERIOD_MS: u64 = 10;
+const STALL_SAMPLES_THRESHOLD: u32 = 20;
+
+/**
+ * Constants related to the battery monitoring. The battery of a loco running
+ * on its own power is measured through a voltage divider on PIN_28, whose
+ * ratio turns the voltage seen by the ADC back into the battery voltage.
+ * Setting the ratio to None means the loco has no battery. Once the battery
+ * stays below LOW_BATTERY_MV for long enough, the loco stops and tells the
+ * controller it's going away, rather than dying in the middle of the layout.
+ * It stays off the network until the battery is back above the threshold.
+ */
+const BATTERY_DIVIDER_RATIO: Option<u32> = None;
+const LOW_BATTERY_MV: u32 = 6400;
+const LOW_BATTERY_SAMPLES_THRESHOLD: u32 = 100;
+// Reference voltage of the 12 bits ADC
+const ADC_REF_MV: u32 = 3300;
+const ADC_RESOLUTION: u32 = 4096;
+
+/**
+ * Constants related to the track power detection. The motor supply, taken
+ * from the track, is measured through a voltage divider on PIN_27. The power
+ * is considered lost when the measured value stays below the threshold for
+ * long enough, so that dirty track isn't seen as a power cut, and back once
+ * it stays above the threshold for as long. A hold-up capacitor on the Pico
+ * supply keeps it running long enough to tell the controller. Setting the
+ * threshold to None disables the detection.
+ */
+const POWER_LOSS_ADC_THRESHOLD: Option<u16> = Some(1024);
+const POWER_LOSS_SAMPLES_THRESHOLD: u32 = 5;
+
+/**
+ * Constants related to the safe crawl. A loco losing the controller while
+ * moving carries on at the crawl speed in the same direction, until its hall
+ * sensor sees the magnet of the next checkpoint, rather than stopping wherever
+ * it is, such as in a tunnel. It gives up after a while, should the magnet be
+ * missed. Setting the speed to None disables the safe crawl, the loco then
+ * stops right away.
+ */
+const SAFE_CRAWL_SPEED: Option<Speed> = Some(Speed::PwmDutyCycle(15));
+const SAFE_CRAWL_RAMP_MS: u64 = 1000;
+const SAFE_CRAWL_MAX_SECS: u64 = 30;
+
+static PWM_CTRL: Mutex<CriticalSectionRawMutex, RefCell<Option<PwmController<'static>>>> =
+    Mutex::new(RefCell::new(None));
+static MOTOR_STALLED: AtomicBool = AtomicBool::new(false);
+// Last sampled by stall_monitor_task, for the telemetry sent to the controller
+static BATTERY_MV: AtomicU16 = AtomicU16::new(0);
+static TEMPERATURE: AtomicI8 = AtomicI8::new(0);
+static SUPPLY: AtomicU16 = AtomicU16::new(0);
+static POWER_LOST: AtomicBool = AtomicBool::new(false);
+static BATTERY_LOW: AtomicBool = AtomicBool::new(false);
+// Raised whenever the track power goes away or comes back, or the battery
+// runs low, so that the controller gets told right away
+static POWER_EVENT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Period at which the duty cycle is updated while ramping
+const RAMP_STEP_MS: u64 = 10;
+
+fn control_motors(direction: Direction, speed: Speed, ramp: Option<Duration>) -> Result<()> {
+    PWM_CTRL.lock(|c| {
+        c.borrow_mut()
+            .as_mut()
+            .ok_or(Error::PwmControllerNotInitialized)?
+            .control_loco(direction, speed, ramp)
+    })
+}
+
+#[embassy_executor::task]
+async fn ramp_task() {
+    loop {
+        let result = PWM_CTRL.lock(|c| {
+            c.borrow_mut()
+                .as_mut()
+                .ok_or(Error::PwmControllerNotInitialized)?
+                .step_ramp()
+        });
+        if let Err(e) = result {
+            log::error!("ramp_task(): {:?}", e);
+        }
+
+        Timer::after_millis(RAMP_STEP_MS).await;
+    }
+}
+
+#[embassy_executor::task]
+async fn stall_monitor_task(
+    mut adc: Adc<'static, Blocking>,
+    mut shunt: AdcChannel<'static>,
+    mut temperature: AdcChannel<'static>,
+    mut battery: Option<AdcChannel<'static>>,
+    mut supply: Option<AdcChannel<'static>>,
+) {
+    let mut overcurrent_samples: u32 = 0;
+    // Supply samples contradicting the current power state
+    let mut power_samples: u32 = 0;
+    // Same for the battery samples
+    let mut battery_samples: u32 = 0;
+
+    loop {
+        match adc.blocking_read(&mut shunt) {
+            Ok(value) if value > STALL_ADC_THRESHOLD => overcurrent_samples += 1,
+            Ok(_) => overcurrent_samples = 0,
+            Err(e) => log::error!("stall_monitor_task(): Error reading ADC: {:?}", e),
+        }
+
+        if overcurrent_samples >= STALL_SAMPLES_THRESHOLD
+            && !MOTOR_STALLED.swap(true, Ordering::AcqRel)
+        {
+            log::error!("stall_monitor_task(): Motor stall detected, cutting PWM");
+            if let Err(e) = control_motors(Direction::default(), Speed::Stop, None) {
+                log::error!("stall_monitor_task(): {:?}", e);
+            }
+        }
+
+        match adc.blocking_read(&mut temperature) {
+            Ok(value) => TEMPERATURE.store(adc_to_celsius(value), Ordering::Relaxed),
+            Err(e) => log::error!("stall_monitor_task(): Error reading ADC: {:?}", e),
+        }
+
+        if let (Some(battery), Some(ratio)) = (battery.as_mut(), BATTERY_DIVIDER_RATIO) {
+            match adc.blocking_read(battery) {
+                Ok(value) => {
+                    let battery_mv = adc_to_mv(value) * ratio;
+                    BATTERY_MV.store(
+                        u16::try_from(battery_mv).unwrap_or(u16::MAX),
+                        Ordering::Relaxed,
+                    );
+                    let battery_low = BATTERY_LOW.load(Ordering::Relaxed);
+                    if (battery_mv < LOW_BATTERY_MV) != battery_low {
+                        battery_samples += 1;
+                    } else {
+                        battery_samples = 0;
+                    }
+
+                    if battery_samples >= LOW_BATTERY_SAMPLES_THRESHOLD {
+                        battery_samples = 0;
+                        BATTERY_LOW.store(!battery_low, Ordering::Relaxed);
+                        if battery_low {
+                            log::info!("stall_monitor_task(): Battery back ({} mV)", battery_mv);
+                        } else {
+                            log::warn!("stall_monitor_task(): Battery low ({} mV)", battery_mv);
+                            if let Err(e) = control_motors(Direction::default(), Speed::Stop, None)
+                            {
+                                log::error!("stall_monitor_task(): {:?}", e);
+                            }
+                            POWER_EVENT.signal(());
+                        }
+                    }
+                }
+                Err(e) => log::error!("stall_monitor_task(): Error reading ADC: {:?}", e),
+            }
+        }
+
+        if let (Some(supply), Some(threshold)) = (supply.as_mut(), POWER_LOSS_ADC_THRESHOLD) {
+            match adc.blocking_read(supply) {
+                Ok(value) => {
+                    SUPPLY.store(value, Ordering::Relaxed);
+                    let power_lost = POWER_LOST.load(Ordering::Relaxed);
+                    if (value < threshold) != power_lost {
+                        power_samples += 1;
+                    } else {
+                        power_samples = 0;
+                    }
+
+                    if power_samples >= POWER_LOSS_SAMPLES_THRESHOLD {
+                        power_samples = 0;
+                        POWER_LOST.store(!power_lost, Ordering::Relaxed);
+                        if power_lost {
+                            log::info!("stall_monitor_task(): Track power back ({})", value);
+                        } else {
+                            log::warn!("stall_monitor_task(): Track power lost ({})", value);
+                        }
+                        POWER_EVENT.signal(());
+                    }
+                }
+                Err(e) => log::error!("stall_monitor_task(): Error reading ADC: {:?}", e),
+            }
+        }
+
+        Timer::after_millis(STALL_SAMPLING_PERIOD_MS).await;
+    }
+}
 
-struct Loco<'a> {
+fn adc_to_mv(value: u16) -> u32 {
+    u32::from(value) * ADC_REF_MV / ADC_RESOLUTION
+}
+
+// The sensor of the RP2350 reads 706 mV at 27 degrees Celsius, dropping by
+// 1.721 mV per degree
+fn adc_to_celsius(value: u16) -> i8 {
+    let celsius = 27 - (adc_to_mv(value) as i32 - 706) * 1000 / 1721;
+    celsius.clamp(i8::MIN.into(), i8::MAX.into()) as i8
+}
+
+struct FunctionOutputs {
+    headlight: Output<'static>,
+    rear_light: Output<'static>,
+    horn: Output<'static>,
+    cabin_light: Output<'static>,
+}
+
+impl FunctionOutputs {
+    fn set(&mut self, functions: LocoFunctions) {
+        let level = |on: bool| if on { Level::High } else { Level::Low };
+        self.headlight.set_level(level(functions.headlight));
+        self.rear_light.set_level(level(functions.rear_light));
+        self.horn.set_level(level(functions.horn));
+        self.cabin_light.set_level(level(functions.cabin_light));
+    }
+}
+
+struct Loco {
     direction: Direction,
     speed: Speed,
+    functions: LocoFunctions,
+    function_outputs: FunctionOutputs,
+    hold_on_disconnect_secs: u8,
+    // Kept until the controller is told about the last safe crawl
+    safe_crawl_report: Option<SafeCrawlReportPayload>,
+    // Kept across connections, so that a command sent again after a
+    // reconnect isn't applied twice
+    last_command_id: Option<u64>,
+    device_id: u64,
     bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    // Selected by the controller for the current connection
+    wire_format: WireFormat,
     response: [u8; RESPONSE_MAX_SIZE],
-    pwm_ctrl: PwmController<'a>,
+    // Sequence numbers of the frames sent through the current connection
+    tx_sequence: SequenceCounter,
 }
 
-impl<'a> Loco<'a> {
-    pub fn new(pwm_ctrl: PwmController<'a>) -> Self {
+impl Loco {
+    pub fn new(device_id: u64, function_outputs: FunctionOutputs) -> Self {
         log::debug!("Loco::new()");
 
         Loco {
             direction: Direction::default(),
             speed: Speed::default(),
+            functions: LocoFunctions::default(),
+            function_outputs,
+            hold_on_disconnect_secs: 0,
+            safe_crawl_report: None,
+            last_command_id: None,
+            device_id,
             bincode_cfg: bincode::config::legacy(),
+            wire_format: WireFormat::default(),
             response: [0u8; RESPONSE_MAX_SIZE],
-            pwm_ctrl,
+            tx_sequence: SequenceCounter::default(),
         }
     }
 
+    // Commands the controller sends again after a reconnect are answered
+    // without being applied, if they already were before the connection broke
+    fn is_duplicate_command(&mut self, extensions: Extensions) -> Result<bool> {
+        let Some(command_id) =
+            decode_command_id(extensions).map_err(Error::ConvertLocoProtocolType)?
+        else {
+            return Ok(false);
+        };
+        if self.last_command_id.is_some_and(|last| command_id <= last) {
+            log::warn!(
+                "Loco::is_duplicate_command(): Skipping command {}",
+                command_id
+            );
+            return Ok(true);
+        }
+
+        self.last_command_id = Some(command_id);
+        Ok(false)
+    }
+
     fn handle_op_control_loco(&mut self, payload: &[u8]) -> Result<Option<usize>> {
         log::debug!("Loco::handle_op_control_loco()");
 
-        let (ctrl_loco_payload, _): (ControlLocoPayload, usize) =
-            decode_from_slice(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
-        self.direction = ctrl_loco_payload
+        let (ctrl_loco_payload, extensions): (ControlLocoPayload, _) =
+            decode_payload_as(payload, self.bincode_cfg, self.wire_format)
+                .map_err(Error::ConvertLocoProtocolType)?;
+        if self.is_duplicate_command(extensions)? {
+            return self.control_loco_response();
+        }
+        let ramp = decode_ramp_ms(extensions)
+            .map_err(Error::ConvertLocoProtocolType)?
+            .map(|ms| Duration::from_millis(ms.into()));
+        let direction: Direction = ctrl_loco_payload
             .direction
             .try_into()
             .map_err(Error::ConvertLocoProtocolType)?;
-        self.speed = ctrl_loco_payload
+        let speed: Speed = ctrl_loco_payload
             .speed
             .try_into()
             .map_err(Error::ConvertLocoProtocolType)?;
 
-        self.pwm_ctrl.control_loco(self.direction, self.speed)?;
+        // Once a stall has been detected, the motors remain off until the
+        // controller explicitly acknowledges it by stopping the loco.
+        if MOTOR_STALLED.load(Ordering::Acquire) {
+            if speed != Speed::Stop {
+                log::warn!("Loco::handle_op_control_loco(): Motor stalled, ignoring command");
+                return self.control_loco_response();
+            }
+            MOTOR_STALLED.store(false, Ordering::Release);
+        }
+
+        self.direction = direction;
+        self.speed = speed;
+        control_motors(self.direction, self.speed, ramp)?;
 
         log::debug!(
-            "Loco::handle_op_control_loco(): Direction {:?}, Speed {:?}",
+            "Loco::handle_op_control_loco(): Direction {:?}, Speed {:?}, Ramp {:?}",
             self.direction,
-            self.speed
+            self.speed,
+            ramp
         );
 
-        Ok(None)
+        self.control_loco_response()
+    }
+
+    // Every command is acknowledged, so that the controller can measure the
+    // latency of its commands.
+    fn control_loco_response(&mut self) -> Result<Option<usize>> {
+        let resp = ControlLocoResponse {
+            direction: self.direction.into(),
+            speed: self.speed.into(),
+        };
+
+        let resp_len = encode_payload(&mut self.response, resp, self.bincode_cfg, self.wire_format)
+            .map_err(Error::ConvertLocoProtocolType)?;
+
+        Ok(Some(resp_len))
+    }
+
+    fn handle_op_control_loco_functions(&mut self, payload: &[u8]) -> Result<Option<usize>> {
+        log::debug!("Loco::handle_op_control_loco_functions()");
+
+        let (functions_payload, extensions): (ControlLocoFunctionsPayload, _) =
+            decode_payload_as(payload, self.bincode_cfg, self.wire_format)
+                .map_err(Error::ConvertLocoProtocolType)?;
+        let functions: LocoFunctions = functions_payload
+            .functions
+            .try_into()
+            .map_err(Error::ConvertLocoProtocolType)?;
+
+        if !self.is_duplicate_command(extensions)? {
+            self.functions = functions;
+            self.function_outputs.set(self.functions);
+        }
+
+        log::debug!(
+            "Loco::handle_op_control_loco_functions(): Functions {:?}",
+            self.functions
+        );
+
+        let resp = ControlLocoFunctionsResponse {
+            functions: self.functions.into(),
+        };
+
+        let resp_len = encode_payload(&mut self.response, resp, self.bincode_cfg, self.wire_format)
+            .map_err(Error::ConvertLocoProtocolType)?;
+
+        Ok(Some(resp_len))
+    }
+
+    // Motors are cut right away, whatever the loco was doing, which the
+    // controller is told the same way as for a ControlLoco
+    fn handle_op_emergency_stop(&mut self) -> Result<Option<usize>> {
+        log::warn!("Loco::handle_op_emergency_stop()");
+
+        self.speed = Speed::Stop;
+        control_motors(self.direction, self.speed, None)?;
+
+        self.control_loco_response()
     }
 
     fn handle_op_loco_status(&mut self, _payload: &[u8]) -> Result<Option<usize>> {
         log::debug!("Loco::handle_op_loco_status()");
 
+        let motor_status = if MOTOR_STALLED.load(Ordering::Acquire) {
+            self.speed = Speed::Stop;
+            MotorStatus::Stalled
+        } else {
+            MotorStatus::Running
+        };
+
         let loco_st_resp = LocoStatusResponse {
             direction: self.direction.into(),
             speed: self.speed.into(),
+            motor_status: motor_status.into(),
         };
 
         log::debug!("Loco::handle_op_loco_status(): Sending {:?}", loco_st_resp);
 
-        let resp_len = encode_into_slice(loco_st_resp, &mut self.response, self.bincode_cfg)
-            .map_err(Error::EncodeIntoSlice)?;
+        let resp_len = encode_payload(
+            &mut self.response,
+            loco_st_resp,
+            self.bincode_cfg,
+            self.wire_format,
+        )
+        .map_err(Error::ConvertLocoProtocolType)?;
 
         Ok(Some(resp_len))
     }
 
-    pub async fn send_connect_op(&self, socket: &mut TcpSocket<'_>) -> Result<()> {
+    fn handle_op_hold_on_disconnect(&mut self, payload: &[u8]) -> Result<Option<usize>> {
+        log::debug!("Loco::handle_op_hold_on_disconnect()");
+
+        let (hold_payload, _): (HoldOnDisconnectPayload, _) =
+            decode_payload_as(payload, self.bincode_cfg, self.wire_format)
+                .map_err(Error::ConvertLocoProtocolType)?;
+        self.hold_on_disconnect_secs = hold_payload.hold_secs;
+
+        log::info!(
+            "Loco::handle_op_hold_on_disconnect(): Holding state for {}s on disconnect",
+            self.hold_on_disconnect_secs
+        );
+
+        Ok(None)
+    }
+
+    fn handle_op_error(&mut self, payload: &[u8]) -> Result<Option<usize>> {
+        log::debug!("Loco::handle_op_error()");
+
+        let (error_payload, _): (ErrorPayload, usize) =
+            decode_from_slice(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        let code =
+            ErrorCode::try_from(error_payload.code).map_err(Error::ConvertLocoProtocolType)?;
+
+        Err(Error::Rejected(code))
+    }
+
+    // The controller answers the Connect with the wire format of the payloads
+    // exchanged through this connection, before anything else
+    fn handle_op_select_wire_format(&mut self, payload: &[u8]) -> Result<Option<usize>> {
+        log::debug!("Loco::handle_op_select_wire_format()");
+
+        let (select_payload, _): (SelectWireFormatPayload, _) =
+            decode_payload(payload, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+        let wire_format =
+            WireFormat::try_from(select_payload.format).map_err(Error::ConvertLocoProtocolType)?;
+        if !wire_format.is_supported() {
+            return Err(Error::ConvertLocoProtocolType(
+                LocoProtocolError::UnsupportedWireFormat(wire_format),
+            ));
+        }
+
+        log::info!(
+            "Loco::handle_op_select_wire_format(): Using {}",
+            wire_format
+        );
+        self.wire_format = wire_format;
+
+        Ok(None)
+    }
+
+    // The hold only applies to the next disconnection, so that any later
+    // unexpected disconnection stops the loco right away.
+    pub fn take_hold_deadline(&mut self) -> Option<Instant> {
+        let hold_secs = core::mem::take(&mut self.hold_on_disconnect_secs);
+        if hold_secs == 0 || MOTOR_STALLED.load(Ordering::Acquire) {
+            return None;
+        }
+
+        Some(Instant::now() + Duration::from_secs(u64::from(hold_secs)))
+    }
+
+    // Only a moving loco crawls, once the controller no longer holds it
+    pub async fn safe_crawl(&mut self, hall_sensor: &mut Input<'_>) -> Result<()> {
+        let Some(crawl_speed) = SAFE_CRAWL_SPEED else {
+            return Ok(());
+        };
+        if self.speed == Speed::Stop || MOTOR_STALLED.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        log::warn!(
+            "Loco::safe_crawl(): Crawling {:?} to the next checkpoint",
+            self.direction
+        );
+        let started_at = Instant::now();
+        control_motors(
+            self.direction,
+            crawl_speed,
+            Some(Duration::from_millis(SAFE_CRAWL_RAMP_MS)),
+        )?;
+
+        // Waiting for an edge, so that a loco right over a magnet goes on to
+        // the next one
+        let reached_checkpoint = with_timeout(
+            Duration::from_secs(SAFE_CRAWL_MAX_SECS),
+            hall_sensor.wait_for_falling_edge(),
+        )
+        .await
+        .is_ok();
+
+        self.speed = Speed::Stop;
+        control_motors(self.direction, self.speed, None)?;
+
+        let crawl_ms = started_at.elapsed().as_millis() as u32;
+        if reached_checkpoint {
+            log::info!(
+                "Loco::safe_crawl(): Reached a checkpoint after {}ms",
+                crawl_ms
+            );
+        } else {
+            log::warn!("Loco::safe_crawl(): Gave up after {}ms", crawl_ms);
+        }
+        self.safe_crawl_report = Some(SafeCrawlReportPayload {
+            direction: self.direction.into(),
+            reached_checkpoint: reached_checkpoint.into(),
+            crawl_ms,
+        });
+
+        Ok(())
+    }
+
+    pub async fn send_safe_crawl_report(&mut self, socket: &mut TcpSocket<'_>) -> Result<()> {
+        let Some(report) = self.safe_crawl_report else {
+            return Ok(());
+        };
+        log::debug!("Loco::send_safe_crawl_report()");
+
+        // Sent before any SelectWireFormat got handled, hence in bincode
+        let mut message = [0u8; REQUEST_MAX_SIZE];
+        let frame_len = CODEC
+            .encode(
+                &mut message,
+                Operation::SafeCrawlReport,
+                report,
+                self.tx_sequence.next_sequence(),
+            )
+            .map_err(Error::ConvertLocoProtocolType)?;
+
+        socket
+            .write_all(&message[..frame_len])
+            .await
+            .map_err(Error::TcpWrite)?;
+        self.safe_crawl_report = None;
+
+        Ok(())
+    }
+
+    // The Connect is the first frame of every connection
+    pub async fn send_connect_op(&mut self, socket: &mut TcpSocket<'_>) -> Result<()> {
         log::debug!("Loco::send_connect_op()");
 
+        self.tx_sequence = SequenceCounter::default();
+        // Until the controller selects another one
+        self.wire_format = WireFormat::default();
+
         let mut message = [0u8; REQUEST_MAX_SIZE];
-        let payload_len = encode_into_slice(
-            ConnectPayload { loco_id: LOCO_ID },
+        let mut payload_len = encode_into_slice(
+            ConnectPayload {
+                loco_id: LOCO_ID,
+                device_id: self.device_id,
+                protocol_version: BACKEND_PROTOCOL_VERSION,
+                firmware_version: firmware_version!(),
+            },
             &mut message[HEADER_SIZE..],
             self.bincode_cfg,
         )
         .map_err(Error::EncodeIntoSlice)?;
 
-        let header_len = encode_into_slice(
-            Header {
-                magic: BACKEND_PROTOCOL_MAGIC_NUMBER,
-                operation: Operation::Connect.into(),
-                payload_len: payload_len as u8,
-            },
-            &mut message[..HEADER_SIZE],
-            self.bincode_cfg,
+        let wire_formats: &[u8] = &[
+            WireFormat::Bincode.into(),
+            #[cfg(feature = "postcard")]
+            WireFormat::Postcard.into(),
+        ];
+        payload_len += encode_extension_field(
+            &mut message[HEADER_SIZE + payload_len..],
+            WIRE_FORMATS_EXT_ID,
+            wire_formats,
         )
-        .map_err(Error::EncodeIntoSlice)?;
+        .map_err(Error::ConvertLocoProtocolType)?;
+        if let Some(auth_token) = AUTH_TOKEN {
+            payload_len += encode_extension_field(
+                &mut message[HEADER_SIZE + payload_len..],
+                AUTH_TOKEN_EXT_ID,
+                auth_token.as_bytes(),
+            )
+            .map_err(Error::ConvertLocoProtocolType)?;
+        }
+
+        let frame_len = CODEC
+            .encode_message(
+                &mut message,
+                Operation::Connect,
+                payload_len,
+                self.tx_sequence.next_sequence(),
+            )
+            .map_err(Error::ConvertLocoProtocolType)?;
 
-        if header_len != HEADER_SIZE {
-            return Err(Error::InvalidEncodedHeaderSize(header_len));
+        socket
+            .write_all(&message[..frame_len])
+            .await
+            .map_err(Error::TcpWrite)?;
+
+        Ok(())
+    }
+
+    // The RSSI is only read here, since the WiFi chip is behind the control.
+    // The battery is left out on a loco without any, and so is the track
+    // power on a loco not detecting it.
+    async fn send_loco_telemetry(
+        &mut self,
+        socket: &mut TcpSocket<'_>,
+        control: &mut Control<'_>,
+    ) -> Result<()> {
+        log::debug!("Loco::send_loco_telemetry()");
+
+        let rssi = control
+            .get_rssi()
+            .await
+            .clamp(i8::MIN.into(), i8::MAX.into()) as i8;
+        let mut message = [0u8; REQUEST_MAX_SIZE];
+        let mut payload_len = 0;
+        if BATTERY_DIVIDER_RATIO.is_some() {
+            payload_len += encode_extension_field(
+                &mut message[HEADER_SIZE + payload_len..],
+                LOCO_TELEMETRY_EXT_BATTERY_MV,
+                &BATTERY_MV.load(Ordering::Relaxed).to_le_bytes(),
+            )
+            .map_err(Error::ConvertLocoProtocolType)?;
         }
+        payload_len += encode_extension_field(
+            &mut message[HEADER_SIZE + payload_len..],
+            LOCO_TELEMETRY_EXT_TEMPERATURE,
+            &TEMPERATURE.load(Ordering::Relaxed).to_le_bytes(),
+        )
+        .map_err(Error::ConvertLocoProtocolType)?;
+        payload_len += encode_extension_field(
+            &mut message[HEADER_SIZE + payload_len..],
+            LOCO_TELEMETRY_EXT_RSSI,
+            &rssi.to_le_bytes(),
+        )
+        .map_err(Error::ConvertLocoProtocolType)?;
+        if POWER_LOSS_ADC_THRESHOLD.is_some() {
+            payload_len += encode_extension_field(
+                &mut message[HEADER_SIZE + payload_len..],
+                LOCO_TELEMETRY_EXT_SUPPLY,
+                &SUPPLY.load(Ordering::Relaxed).to_le_bytes(),
+            )
+            .map_err(Error::ConvertLocoProtocolType)?;
+            payload_len += encode_extension_field(
+                &mut message[HEADER_SIZE + payload_len..],
+                LOCO_TELEMETRY_EXT_POWER_LOST,
+                &[u8::from(POWER_LOST.load(Ordering::Relaxed))],
+            )
+            .map_err(Error::ConvertLocoProtocolType)?;
+        }
+
+        let frame_len = CODEC
+            .encode_message(
+                &mut message,
+                Operation::LocoTelemetry,
+                payload_len,
+                self.tx_sequence.next_sequence(),
+            )
+            .map_err(Error::ConvertLocoProtocolType)?;
 
         socket
-            .write_all(&message[..header_len + payload_len])
+            .write_all(&message[..frame_len])
             .await
             .map_err(Error::TcpWrite)?;
 
         Ok(())
     }
 
-    pub async fn handle_messages(&mut self, socket: &mut TcpSocket<'_>) -> Result<()> {
+    pub async fn handle_messages(
+        &mut self,
+        socket: &mut TcpSocket<'_>,
+        control: &mut Control<'_>,
+    ) -> Result<()> {
+        let mut rx_sequence = SequenceTracker::default();
         loop {
-            log::info!("Loco::handle_messages(): Waiting for incoming bytes...");
-
-            let mut hdr = [0; HEADER_SIZE];
-            socket.read_exact(&mut hdr).await.map_err(Error::TcpRead)?;
+            log::debug!("Loco::handle_messages(): Waiting for incoming bytes...");
 
-            let (header, _): (Header, usize) =
-                decode_from_slice(&hdr, self.bincode_cfg).map_err(Error::DecodeFromSlice)?;
+            // Waiting for the first byte only, as nothing gets lost if the
+            // wait is cancelled, in which case the controller is told we're
+            // still alive, or told about the track power, or told we're going
+            // away on a low battery
+            let mut buf = [0u8; REQUEST_MAX_SIZE];
+            match with_timeout(
+                Duration::from_millis(HEARTBEAT_PERIOD_MS),
+                select(socket.read(&mut buf[..1]), POWER_EVENT.wait()),
+            )
+            .await
+            {
+                Ok(Either::First(Ok(0))) => return Err(Error::ReadEof),
+                Ok(Either::First(Ok(_))) => {}
+                Ok(Either::First(Err(e))) => {
+                    return Err(Error::TcpRead(ReadExactError::Other(e)));
+                }
+                Ok(Either::Second(())) if BATTERY_LOW.load(Ordering::Relaxed) => {
+                    log::warn!("Loco::handle_messages(): Battery low, disconnecting");
+                    return disconnect_loco_controller(
+                        socket,
+                        self.tx_sequence.next_sequence(),
+                        DisconnectReason::LowBattery,
+                    )
+                    .await
+                    .map_err(Error::TcpWrite);
+                }
+                Ok(Either::Second(())) => {
+                    self.send_loco_telemetry(socket, control).await?;
+                    continue;
+                }
+                Err(TimeoutError) => {
+                    send_heartbeat(socket, self.tx_sequence.next_sequence())
+                        .await
+                        .map_err(Error::TcpWrite)?;
+                    continue;
+                }
+            }
+            // Corrupted frames are dropped before anything gets decoded from
+            // them, and so are frames coming again or out of order. The
+            // controller gives up waiting for the response and reconnects.
+            let Some(frame) = read_frame(socket, &mut buf, 1)
+                .await
+                .map_err(Error::ReadFrame)?
+            else {
+                continue;
+            };
+            let header = frame.header;
 
-            if header.magic != BACKEND_PROTOCOL_MAGIC_NUMBER {
-                return Err(Error::InvalidBackendProtocolMagicNumber(header.magic));
+            match rx_sequence.check(header.sequence) {
+                SequenceCheck::InOrder => {}
+                SequenceCheck::Gap(lost) => log::warn!(
+                    "Loco::handle_messages(): {} frames lost before {}",
+                    lost,
+                    header.sequence
+                ),
+                check @ (SequenceCheck::Duplicate | SequenceCheck::Reordered) => {
+                    log::warn!(
+                        "Loco::handle_messages(): Discarding frame {} ({:?})",
+                        header.sequence,
+                        check
+                    );
+                    continue;
+                }
             }
 
-            let op =
-                Operation::try_from(header.operation).map_err(Error::ConvertLocoProtocolType)?;
+            let op = match frame.operation() {
+                Ok(op) => op,
+                Err(e) => {
+                    log::warn!(
+                        "Loco::handle_messages(): Rejecting operation {}",
+                        header.operation
+                    );
+                    self.reject_request(socket, ErrorCode::from(&e)).await?;
+                    continue;
+                }
+            };
             log::info!("Loco::handle_messages(): Operation {:?}", op);
 
-            let mut payload_buf = [0u8; PAYLOAD_MAX_SIZE];
-            let payload = &mut payload_buf[..header.payload_len as usize];
-            if !payload.is_empty() {
-                socket.read_exact(payload).await.map_err(Error::TcpRead)?;
+            let payload = frame.payload;
+
+            // The telemetry goes right before the status, which the controller
+            // reads while waiting for the response
+            if matches!(op, Operation::LocoStatus) {
+                self.send_loco_telemetry(socket, control).await?;
             }
 
-            let send_response = match op {
-                Operation::ControlLoco => self.handle_op_control_loco(payload)?,
-                Operation::LocoStatus => self.handle_op_loco_status(payload)?,
-                Operation::Connect | Operation::SensorsStatus | Operation::DriveActuator => {
-                    return Err(Error::UnsupportedOperation(op));
+            // A malformed request is rejected on its own, so that a moving loco
+            // isn't reset through a reconnection. Otherwise the controller is
+            // told why before the connection goes down.
+            let send_response = match self.handle_request(op, payload) {
+                Ok(send_response) => send_response,
+                Err(e) if e.is_malformed_request() => {
+                    log::warn!("Loco::handle_messages(): Rejecting {:?}: {:?}", op, e);
+                    // Safe to unwrap since every malformed request has a code
+                    self.reject_request(socket, e.code().unwrap()).await?;
+                    continue;
                 }
+                Err(e) => return self.report_error(socket, e).await,
             };
 
             if let Some(resp_len) = send_response {
@@ -247,17 +1059,143 @@ impl<'a> Loco<'a> {
         }
     }
 
+    fn handle_request(&mut self, op: Operation, payload: &[u8]) -> Result<Option<usize>> {
+        match op {
+            Operation::ControlLoco => self.handle_op_control_loco(payload),
+            Operation::ControlLocoFunctions => self.handle_op_control_loco_functions(payload),
+            Operation::LocoStatus => self.handle_op_loco_status(payload),
+            Operation::EmergencyStop => self.handle_op_emergency_stop(),
+            Operation::HoldOnDisconnect => self.handle_op_hold_on_disconnect(payload),
+            Operation::Error => self.handle_op_error(payload),
+            Operation::SelectWireFormat => self.handle_op_select_wire_format(payload),
+            Operation::Connect
+            | Operation::SensorsStatus
+            | Operation::DriveActuator
+            | Operation::DriveActuatorsBatch
+            | Operation::ActuatorsTelemetry
+            | Operation::InputsStatus
+            | Operation::Register
+            | Operation::TimeSync
+            | Operation::Disconnect
+            | Operation::Heartbeat
+            | Operation::LocoTelemetry
+            | Operation::CommandAck
+            | Operation::SafeCrawlReport => Err(Error::UnsupportedOperation(op)),
+        }
+    }
+
+    // The controller is told why the request failed, as far as the
+    // connection still allows it, before the connection gets closed
+    async fn report_error(&mut self, socket: &mut TcpSocket<'_>, e: Error) -> Result<()> {
+        if let Some(code) = e.code() {
+            send_error(socket, self.tx_sequence.next_sequence(), code)
+                .await
+                .map_err(Error::TcpWrite)?;
+        }
+
+        Err(e)
+    }
+
+    // Sent in place of the response, the connection staying up
+    async fn reject_request(&mut self, socket: &mut TcpSocket<'_>, code: ErrorCode) -> Result<()> {
+        send_nack(socket, self.tx_sequence.next_sequence(), code)
+            .await
+            .map_err(Error::TcpWrite)
+    }
+
     pub fn reset(&mut self) -> Result<()> {
         self.direction = Direction::default();
         self.speed = Speed::default();
+        self.functions = LocoFunctions::default();
+        self.function_outputs.set(self.functions);
+        MOTOR_STALLED.store(false, Ordering::Release);
 
-        self.pwm_ctrl.control_loco(self.direction, self.speed)
+        control_motors(self.direction, self.speed, None)
     }
 }
 
-struct PwmController<'a> {
+struct Motor<'a> {
     pwm_forward: Pwm<'a>,
     pwm_backward: Pwm<'a>,
+    trim: u8,
+}
+
+impl<'a> Motor<'a> {
+    fn new(mut pwm_forward: Pwm<'a>, mut pwm_backward: Pwm<'a>, trim: u8) -> Result<Self> {
+        pwm_forward
+            .set_duty_cycle_fully_off()
+            .map_err(Error::SetPwmDutyCycle)?;
+        pwm_backward
+            .set_duty_cycle_fully_off()
+            .map_err(Error::SetPwmDutyCycle)?;
+
+        Ok(Motor {
+            pwm_forward,
+            pwm_backward,
+            trim,
+        })
+    }
+
+    fn control(&mut self, direction: Direction, duty_cycle: u8) -> Result<()> {
+        let (pwm_set, pwm_clear) = match direction {
+            Direction::Forward => (&mut self.pwm_forward, &mut self.pwm_backward),
+            Direction::Backward => (&mut self.pwm_backward, &mut self.pwm_forward),
+        };
+
+        // Apply the trim while making sure we never go beyond a 100% duty cycle
+        let duty_cycle = (duty_cycle as u16 * self.trim as u16 / 100).min(100) as u8;
+
+        pwm_clear
+            .set_duty_cycle_fully_off()
+            .map_err(Error::SetPwmDutyCycle)?;
+        pwm_set
+            .set_duty_cycle_percent(duty_cycle)
+            .map_err(Error::SetPwmDutyCycle)?;
+
+        Ok(())
+    }
+}
+
+type SecondMotorPins = (
+    Peri<'static, PWM_SLICE2>,
+    Peri<'static, PIN_4>,
+    Peri<'static, PWM_SLICE3>,
+    Peri<'static, PIN_7>,
+);
+
+/**
+ * Duty cycles are signed by the direction, forward being positive, so that a
+ * ramp reversing the direction goes through a stop rather than jumping from
+ * one direction to the other.
+ */
+#[derive(Copy, Clone)]
+struct Ramp {
+    from: i16,
+    to: i16,
+    started_at: Instant,
+    duration: Duration,
+}
+
+impl Ramp {
+    fn duty_cycle(&self) -> i16 {
+        let elapsed_ms = self.started_at.elapsed().as_millis() as i32;
+        let duration_ms = self.duration.as_millis() as i32;
+        let delta = i32::from(self.to - self.from);
+
+        self.from + (delta * elapsed_ms / duration_ms) as i16
+    }
+
+    fn is_over(&self) -> bool {
+        self.started_at.elapsed() >= self.duration
+    }
+}
+
+struct PwmController<'a> {
+    first_motor: Motor<'a>,
+    second_motor: Option<Motor<'a>>,
+    // Signed duty cycle currently applied to the motors
+    duty_cycle: i16,
+    ramp: Option<Ramp>,
 }
 
 impl PwmController<'_> {
@@ -266,6 +1204,7 @@ impl PwmController<'_> {
         pin0: Peri<'static, PIN_0>,
         slice1: Peri<'static, PWM_SLICE1>,
         pin3: Peri<'static, PIN_3>,
+        second_motor_pins: Option<SecondMotorPins>,
     ) -> Result<Self> {
         // If we aim for a specific frequency, here is how we can calculate the top value.
         // The top value sets the period of the PWM cycle, so a counter goes from 0 to top and then wraps around to 0.
@@ -282,41 +1221,88 @@ impl PwmController<'_> {
         cfg.top = period;
         cfg.divider = divider.into();
 
-        let mut pwm_forward = Pwm::new_output_a(slice0, pin0, cfg.clone());
-        let mut pwm_backward = Pwm::new_output_b(slice1, pin3, cfg);
-        pwm_forward
-            .set_duty_cycle_fully_off()
-            .map_err(Error::SetPwmDutyCycle)?;
-        pwm_backward
-            .set_duty_cycle_fully_off()
-            .map_err(Error::SetPwmDutyCycle)?;
+        let first_motor = Motor::new(
+            Pwm::new_output_a(slice0, pin0, cfg.clone()),
+            Pwm::new_output_b(slice1, pin3, cfg.clone()),
+            FIRST_MOTOR_TRIM,
+        )?;
+
+        // Both motors share the same PWM configuration so that they remain
+        // synchronized with each other.
+        let second_motor = match (second_motor_pins, SECOND_MOTOR_TRIM) {
+            (Some((slice2, pin4, slice3, pin7)), Some(trim)) => Some(Motor::new(
+                Pwm::new_output_a(slice2, pin4, cfg.clone()),
+                Pwm::new_output_b(slice3, pin7, cfg),
+                trim,
+            )?),
+            _ => None,
+        };
 
         Ok(PwmController {
-            pwm_forward,
-            pwm_backward,
+            first_motor,
+            second_motor,
+            duty_cycle: 0,
+            ramp: None,
         })
     }
 
-    fn control_loco(&mut self, direction: Direction, speed: Speed) -> Result<()> {
-        let (pwm_set, pwm_clear) = match direction {
-            Direction::Forward => (&mut self.pwm_forward, &mut self.pwm_backward),
-            Direction::Backward => (&mut self.pwm_backward, &mut self.pwm_forward),
+    // Without a ramp, the new duty cycle applies right away, cancelling any
+    // ongoing ramp
+    fn control_loco(
+        &mut self,
+        direction: Direction,
+        speed: Speed,
+        ramp: Option<Duration>,
+    ) -> Result<()> {
+        let duty_cycle = match direction {
+            Direction::Forward => i16::from(speed.duty_cycle()),
+            Direction::Backward => -i16::from(speed.duty_cycle()),
         };
 
-        let duty_cycle = match speed {
-            Speed::Stop => 0,
-            Speed::Slow => 25,
-            Speed::Normal => 75,
-            Speed::Fast => 100,
-            Speed::PwmDutyCycle(dc) => dc,
+        match ramp {
+            Some(duration) if duration > Duration::from_ticks(0) => {
+                self.ramp = Some(Ramp {
+                    from: self.duty_cycle,
+                    to: duty_cycle,
+                    started_at: Instant::now(),
+                    duration,
+                });
+                Ok(())
+            }
+            _ => {
+                self.ramp = None;
+                self.apply(duty_cycle)
+            }
+        }
+    }
+
+    // Moves the motors along the ongoing ramp, if any
+    fn step_ramp(&mut self) -> Result<()> {
+        let Some(ramp) = self.ramp else {
+            return Ok(());
         };
 
-        pwm_clear
-            .set_duty_cycle_fully_off()
-            .map_err(Error::SetPwmDutyCycle)?;
-        pwm_set
-            .set_duty_cycle_percent(duty_cycle)
-            .map_err(Error::SetPwmDutyCycle)?;
+        if ramp.is_over() {
+            self.ramp = None;
+            self.apply(ramp.to)
+        } else {
+            self.apply(ramp.duty_cycle())
+        }
+    }
+
+    fn apply(&mut self, duty_cycle: i16) -> Result<()> {
+        let direction = if duty_cycle < 0 {
+            Direction::Backward
+        } else {
+            Direction::Forward
+        };
+        let duty_percent = duty_cycle.unsigned_abs() as u8;
+
+        self.first_motor.control(direction, duty_percent)?;
+        if let Some(second_motor) = self.second_motor.as_mut() {
+            second_motor.control(direction, duty_percent)?;
+        }
+        self.duty_cycle = duty_cycle;
 
         Ok(())
     }
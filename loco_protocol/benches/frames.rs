@@ -0,0 +1,79 @@
+//! Framing hot paths, run by the controller for every message exchanged with
+//! the boards. Run with `cargo bench`.
+
+use std::hint::black_box;
+
+use bincode::config::legacy;
+use criterion::{Criterion, criterion_group, criterion_main};
+use loco_protocol::{
+    Codec, ControlLocoPayload, Direction, FRAME_CRC_SIZE, HEADER_SIZE, Operation, PAYLOAD_MAX_LEN,
+    SensorEvent, SensorId, SensorStatus, SensorType, SensorsStatusBatch, Speed, decode_payload,
+    decode_sensors_status_batch,
+};
+
+const FRAME_MAX_LEN: usize = HEADER_SIZE + PAYLOAD_MAX_LEN + FRAME_CRC_SIZE;
+
+fn control_loco(c: &mut Criterion) {
+    let codec = Codec::new(legacy(), PAYLOAD_MAX_LEN);
+    let payload = ControlLocoPayload {
+        direction: Direction::Forward.into(),
+        speed: Speed::Normal.into(),
+    };
+    let mut buf = [0u8; FRAME_MAX_LEN];
+
+    c.bench_function("frame/encode_control_loco", |b| {
+        b.iter(|| {
+            codec
+                .encode(&mut buf, Operation::ControlLoco, black_box(payload), 0)
+                .unwrap()
+        })
+    });
+
+    let len = codec
+        .encode(&mut buf, Operation::ControlLoco, payload, 0)
+        .unwrap();
+    let frame = &buf[..len];
+    c.bench_function("frame/decode_control_loco", |b| {
+        b.iter(|| {
+            let frame = codec.decode_frame(black_box(frame)).unwrap();
+            decode_payload::<ControlLocoPayload, _>(frame.payload, legacy()).unwrap()
+        })
+    });
+}
+
+// A full batch is what the sensors board sends when every reader fires at
+// once, which is the worst case the controller has to keep up with
+fn sensors_status(c: &mut Criterion) {
+    let codec = Codec::new(legacy(), PAYLOAD_MAX_LEN);
+    let mut buf = [0u8; FRAME_MAX_LEN];
+    let mut batch = SensorsStatusBatch::new(&mut buf[HEADER_SIZE..HEADER_SIZE + PAYLOAD_MAX_LEN]);
+    let mut timestamp_us = 0;
+    while batch
+        .push(SensorStatus {
+            sensor_id: SensorId::RfidReader1.into(),
+            sensor_type: SensorType::Rfid.into(),
+            loco_id: 1,
+            event: SensorEvent::Arrived.into(),
+            timestamp_us,
+        })
+        .is_ok()
+    {
+        timestamp_us += 1;
+    }
+    let payload_len = batch.finish().unwrap();
+    let len = codec
+        .encode_message(&mut buf, Operation::SensorsStatus, payload_len, 0)
+        .unwrap();
+    let frame = &buf[..len];
+
+    c.bench_function("frame/decode_sensors_status_batch", |b| {
+        b.iter(|| {
+            let frame = codec.decode_frame(black_box(frame)).unwrap();
+            let (sensors_status, _) = decode_sensors_status_batch(frame.payload).unwrap();
+            sensors_status.count()
+        })
+    });
+}
+
+criterion_group!(benches, control_loco, sensors_status);
+criterion_main!(benches);
@@ -2,47 +2,127 @@
 
 use core::fmt;
 
-use bincode::{Decode, Encode};
-use serde::{Deserialize, Serialize};
+use bincode::{
+    Decode, Encode, config::Config, decode_from_slice, encode_into_slice, error::DecodeError,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+pub mod route;
 
 #[derive(Debug)]
 pub enum Error {
+    BatchBufferTooSmall,
+    BatchFull,
+    BatchTooLarge(usize),
+    ExtensionBufferTooSmall,
+    ExtensionTooLarge(usize),
+    FrameBufferTooSmall,
+    FrameChecksumMismatch(u16, u16),
+    InvalidCommandId(usize),
+    InvalidExtensionField(u8),
+    InvalidCorrelationId(usize),
+    InvalidMagicNumber(u8),
+    InvalidPayload,
+    InvalidRamp(usize),
+    InvalidServoAngle(u8),
+    PayloadTooLarge(usize),
+    TruncatedBatch,
+    TruncatedExtension,
+    TruncatedFrame,
     UidTooLong,
     UnknownActuatorId(u8),
     UnknownActuatorType(u8),
     UnknownDirection(u8),
+    UnknownDisconnectReason(u8),
+    UnknownErrorCode(u8),
+    UnknownInputId(u8),
+    UnknownInputState(u8),
+    UnknownLocoFunctions(u8),
     UnknownLocoId(u8),
+    UnknownMotorStatus(u8),
     UnknownOperation(u8),
+    UnknownSensorEvent(u8),
     UnknownSensorId(u8),
+    UnknownSensorType(u8),
+    UnknownSignalState(u8),
     UnknownSpeed(u8),
     UnknownSwitchRailsState(u8),
+    UnknownTrackPowerState(u8),
     UnknownUid,
+    UnknownWireFormat(u8),
     UnsupportedOperation(Operation),
+    UnsupportedWireFormat(WireFormat),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
 pub const BACKEND_PROTOCOL_MAGIC_NUMBER: u8 = 0xab;
+pub const BACKEND_PROTOCOL_VERSION: u8 = 19;
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
-#[serde(rename_all = "lowercase")]
-pub enum LocoId {
-    Loco1,
-    Loco2,
-}
+/**
+ * Numeric identifier of a loco, starting from 1. Which locos actually run on
+ * the layout is up to the roster of the loco_controller, the protocol only
+ * carries the number. Serialized as "loco1", "loco2" and so on.
+ */
+#[derive(Copy, Clone, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub struct LocoId(u8);
 
 impl TryFrom<u8> for LocoId {
     type Error = Error;
 
     fn try_from(value: u8) -> Result<Self> {
-        Ok(match value {
-            1 => LocoId::Loco1,
-            2 => LocoId::Loco2,
-            _ => return Err(Error::UnknownLocoId(value)),
-        })
+        match value {
+            0 => Err(Error::UnknownLocoId(value)),
+            _ => Ok(LocoId(value)),
+        }
+    }
+}
+
+impl Serialize for LocoId {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> core::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("loco{}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for LocoId {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> core::result::Result<Self, D::Error> {
+        struct LocoIdVisitor;
+
+        impl serde::de::Visitor<'_> for LocoIdVisitor {
+            type Value = LocoId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a loco identifier such as \"loco1\"")
+            }
+
+            fn visit_str<E: serde::de::Error>(
+                self,
+                value: &str,
+            ) -> core::result::Result<LocoId, E> {
+                value
+                    .strip_prefix("loco")
+                    .and_then(|n| n.parse::<u8>().ok())
+                    .and_then(|n| LocoId::try_from(n).ok())
+                    .ok_or_else(|| E::invalid_value(serde::de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_str(LocoIdVisitor)
     }
 }
 
+// UIDs of the tags fitted under the first locos, known to the sensors
+// firmware. Tags of the other locos are registered with the loco_controller.
+pub const LOCO_UIDS: [(LocoId, [u8; 4]); 2] = [
+    (LocoId(1), [0xe3, 0xa6, 0xaf, 0x05]),
+    (LocoId(2), [0x69, 0xd0, 0x47, 0x06]),
+];
+
 impl TryFrom<&[u8]> for LocoId {
     type Error = Error;
 
@@ -50,30 +130,30 @@ impl TryFrom<&[u8]> for LocoId {
         if uid.len() != 4 {
             return Err(Error::UidTooLong);
         }
-        Ok(match uid {
-            [0xe3, 0xa6, 0xaf, 0x05] => LocoId::Loco1,
-            [0x69, 0xd0, 0x47, 0x06] => LocoId::Loco2,
-            _ => return Err(Error::UnknownUid),
-        })
+        LOCO_UIDS
+            .iter()
+            .find(|(_, u)| u == uid)
+            .map(|(loco_id, _)| *loco_id)
+            .ok_or(Error::UnknownUid)
     }
 }
 
 impl From<LocoId> for u8 {
     fn from(item: LocoId) -> Self {
-        match item {
-            LocoId::Loco1 => 1,
-            LocoId::Loco2 => 2,
-        }
+        item.0
     }
 }
 
 impl fmt::Display for LocoId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let id = match *self {
-            LocoId::Loco1 => "Loco1",
-            LocoId::Loco2 => "Loco2",
-        };
-        write!(f, "{}", id)
+        write!(f, "Loco{}", self.0)
+    }
+}
+
+// Same as Display, as it used to be when locos were hardcoded
+impl fmt::Debug for LocoId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
     }
 }
 
@@ -139,7 +219,99 @@ impl fmt::Display for SensorId {
     }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, Hash, PartialEq)]
+/**
+ * Kind of sensor which made a detection. RFID readers tell which loco they
+ * saw, while hall sensors, reed switches and IR beams only tell that
+ * something went by, which the loco_controller has to attribute to a loco.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorType {
+    #[default]
+    Rfid,
+    Hall,
+    IrBeam,
+}
+
+impl TryFrom<u8> for SensorType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => SensorType::Rfid,
+            2 => SensorType::Hall,
+            3 => SensorType::IrBeam,
+            _ => return Err(Error::UnknownSensorType(value)),
+        })
+    }
+}
+
+impl From<SensorType> for u8 {
+    fn from(item: SensorType) -> Self {
+        match item {
+            SensorType::Rfid => 1,
+            SensorType::Hall => 2,
+            SensorType::IrBeam => 3,
+        }
+    }
+}
+
+impl fmt::Display for SensorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sensor_type = match *self {
+            SensorType::Rfid => "Rfid",
+            SensorType::Hall => "Hall",
+            SensorType::IrBeam => "IrBeam",
+        };
+        write!(f, "{}", sensor_type)
+    }
+}
+
+/**
+ * What a sensor saw happening. A loco standing over a sensor keeps being
+ * reported as arrived, until it leaves the sensor which is then reported
+ * once as departed.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorEvent {
+    #[default]
+    Arrived,
+    Departed,
+}
+
+impl TryFrom<u8> for SensorEvent {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => SensorEvent::Arrived,
+            2 => SensorEvent::Departed,
+            _ => return Err(Error::UnknownSensorEvent(value)),
+        })
+    }
+}
+
+impl From<SensorEvent> for u8 {
+    fn from(item: SensorEvent) -> Self {
+        match item {
+            SensorEvent::Arrived => 1,
+            SensorEvent::Departed => 2,
+        }
+    }
+}
+
+impl fmt::Display for SensorEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let event = match *self {
+            SensorEvent::Arrived => "Arrived",
+            SensorEvent::Departed => "Departed",
+        };
+        write!(f, "{}", event)
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum ActuatorId {
     SwitchRails1,
@@ -150,6 +322,11 @@ pub enum ActuatorId {
     SwitchRails6,
     SwitchRails7,
     SwitchRails8,
+    TrackPower,
+    Signal1,
+    Signal2,
+    Signal3,
+    Signal4,
 }
 
 impl TryFrom<u8> for ActuatorId {
@@ -165,6 +342,11 @@ impl TryFrom<u8> for ActuatorId {
             6 => ActuatorId::SwitchRails6,
             7 => ActuatorId::SwitchRails7,
             8 => ActuatorId::SwitchRails8,
+            9 => ActuatorId::TrackPower,
+            10 => ActuatorId::Signal1,
+            11 => ActuatorId::Signal2,
+            12 => ActuatorId::Signal3,
+            13 => ActuatorId::Signal4,
             _ => return Err(Error::UnknownActuatorId(value)),
         })
     }
@@ -181,6 +363,11 @@ impl From<ActuatorId> for u8 {
             ActuatorId::SwitchRails6 => 6,
             ActuatorId::SwitchRails7 => 7,
             ActuatorId::SwitchRails8 => 8,
+            ActuatorId::TrackPower => 9,
+            ActuatorId::Signal1 => 10,
+            ActuatorId::Signal2 => 11,
+            ActuatorId::Signal3 => 12,
+            ActuatorId::Signal4 => 13,
         }
     }
 }
@@ -196,16 +383,26 @@ impl fmt::Display for ActuatorId {
             ActuatorId::SwitchRails6 => "SwitchRails6",
             ActuatorId::SwitchRails7 => "SwitchRails7",
             ActuatorId::SwitchRails8 => "SwitchRails8",
+            ActuatorId::TrackPower => "TrackPower",
+            ActuatorId::Signal1 => "Signal1",
+            ActuatorId::Signal2 => "Signal2",
+            ActuatorId::Signal3 => "Signal3",
+            ActuatorId::Signal4 => "Signal4",
         };
         write!(f, "{}", id)
     }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum ActuatorType {
     #[default]
     SwitchRails,
+    TrackPower,
+    Signal,
+    // Driven to an angle rather than a state, such as the servo throwing a
+    // switch rails
+    Servo,
 }
 
 impl TryFrom<u8> for ActuatorType {
@@ -214,6 +411,9 @@ impl TryFrom<u8> for ActuatorType {
     fn try_from(value: u8) -> Result<Self> {
         Ok(match value {
             1 => ActuatorType::SwitchRails,
+            2 => ActuatorType::TrackPower,
+            3 => ActuatorType::Signal,
+            4 => ActuatorType::Servo,
             _ => return Err(Error::UnknownActuatorType(value)),
         })
     }
@@ -223,6 +423,9 @@ impl From<ActuatorType> for u8 {
     fn from(item: ActuatorType) -> Self {
         match item {
             ActuatorType::SwitchRails => 1,
+            ActuatorType::TrackPower => 2,
+            ActuatorType::Signal => 3,
+            ActuatorType::Servo => 4,
         }
     }
 }
@@ -231,12 +434,15 @@ impl fmt::Display for ActuatorType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let id = match *self {
             ActuatorType::SwitchRails => "SwitchRails",
+            ActuatorType::TrackPower => "TrackPower",
+            ActuatorType::Signal => "Signal",
+            ActuatorType::Servo => "Servo",
         };
         write!(f, "{}", id)
     }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum SwitchRailsState {
     #[default]
@@ -275,6 +481,356 @@ impl fmt::Display for SwitchRailsState {
     }
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackPowerState {
+    #[default]
+    On,
+    Off,
+}
+
+impl TryFrom<u8> for TrackPowerState {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => TrackPowerState::On,
+            2 => TrackPowerState::Off,
+            _ => return Err(Error::UnknownTrackPowerState(value)),
+        })
+    }
+}
+
+impl From<TrackPowerState> for u8 {
+    fn from(item: TrackPowerState) -> Self {
+        match item {
+            TrackPowerState::On => 1,
+            TrackPowerState::Off => 2,
+        }
+    }
+}
+
+impl fmt::Display for TrackPowerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = match *self {
+            TrackPowerState::On => "On",
+            TrackPowerState::Off => "Off",
+        };
+        write!(f, "{}", id)
+    }
+}
+
+/**
+ * Aspect shown by a signal light. Signals are wired as three separate lamps,
+ * only one of them being lit at a time.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignalState {
+    #[default]
+    Red,
+    Yellow,
+    Green,
+}
+
+impl TryFrom<u8> for SignalState {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => SignalState::Red,
+            2 => SignalState::Yellow,
+            3 => SignalState::Green,
+            _ => return Err(Error::UnknownSignalState(value)),
+        })
+    }
+}
+
+impl From<SignalState> for u8 {
+    fn from(item: SignalState) -> Self {
+        match item {
+            SignalState::Red => 1,
+            SignalState::Yellow => 2,
+            SignalState::Green => 3,
+        }
+    }
+}
+
+impl fmt::Display for SignalState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = match *self {
+            SignalState::Red => "Red",
+            SignalState::Yellow => "Yellow",
+            SignalState::Green => "Green",
+        };
+        write!(f, "{}", id)
+    }
+}
+
+pub const SERVO_ANGLE_MAX: u8 = 180;
+
+/**
+ * Angle a servo is driven to, in degrees from 0 to SERVO_ANGLE_MAX. It's
+ * carried as is in place of the state of the other actuators.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ServoAngle(u8);
+
+impl TryFrom<u8> for ServoAngle {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        if value > SERVO_ANGLE_MAX {
+            return Err(Error::InvalidServoAngle(value));
+        }
+
+        Ok(ServoAngle(value))
+    }
+}
+
+impl From<ServoAngle> for u8 {
+    fn from(item: ServoAngle) -> Self {
+        item.0
+    }
+}
+
+impl fmt::Display for ServoAngle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} degrees", self.0)
+    }
+}
+
+/**
+ * Failures shared by every crate, carried by an Error or a Nack. Either sent
+ * by the controller to reject a connection, or by a device to report what went
+ * wrong on its side, which the controller raises as an alarm.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorCode {
+    DuplicateLocoId,
+    UnknownLocoId,
+    // A message couldn't be decoded
+    InvalidPayload,
+    UnsupportedOperation,
+    // The loco couldn't drive its motors
+    PwmError,
+    // The actuators board was asked to drive an actuator it doesn't have
+    UnknownActuator,
+    // The device didn't give the token the controller expects
+    Unauthorized,
+}
+
+impl TryFrom<u8> for ErrorCode {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => ErrorCode::DuplicateLocoId,
+            2 => ErrorCode::UnknownLocoId,
+            3 => ErrorCode::InvalidPayload,
+            4 => ErrorCode::UnsupportedOperation,
+            5 => ErrorCode::PwmError,
+            6 => ErrorCode::UnknownActuator,
+            7 => ErrorCode::Unauthorized,
+            _ => return Err(Error::UnknownErrorCode(value)),
+        })
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(item: ErrorCode) -> Self {
+        match item {
+            ErrorCode::DuplicateLocoId => 1,
+            ErrorCode::UnknownLocoId => 2,
+            ErrorCode::InvalidPayload => 3,
+            ErrorCode::UnsupportedOperation => 4,
+            ErrorCode::PwmError => 5,
+            ErrorCode::UnknownActuator => 6,
+            ErrorCode::Unauthorized => 7,
+        }
+    }
+}
+
+// Protocol errors are mostly about decoding what was received
+impl From<&Error> for ErrorCode {
+    fn from(item: &Error) -> Self {
+        match item {
+            Error::UnknownActuatorId(_) | Error::UnknownActuatorType(_) => {
+                ErrorCode::UnknownActuator
+            }
+            Error::UnknownOperation(_) | Error::UnsupportedOperation(_) => {
+                ErrorCode::UnsupportedOperation
+            }
+            _ => ErrorCode::InvalidPayload,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = match *self {
+            ErrorCode::DuplicateLocoId => "DuplicateLocoId",
+            ErrorCode::UnknownLocoId => "UnknownLocoId",
+            ErrorCode::InvalidPayload => "InvalidPayload",
+            ErrorCode::UnsupportedOperation => "UnsupportedOperation",
+            ErrorCode::PwmError => "PwmError",
+            ErrorCode::UnknownActuator => "UnknownActuator",
+            ErrorCode::Unauthorized => "Unauthorized",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+/**
+ * Why a device is going away on purpose, carried by the Disconnect
+ * operation.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    Shutdown,
+    // The firmware is about to be replaced
+    Update,
+    // The loco stopped before its battery runs flat
+    LowBattery,
+}
+
+impl TryFrom<u8> for DisconnectReason {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => DisconnectReason::Shutdown,
+            2 => DisconnectReason::Update,
+            3 => DisconnectReason::LowBattery,
+            _ => return Err(Error::UnknownDisconnectReason(value)),
+        })
+    }
+}
+
+impl From<DisconnectReason> for u8 {
+    fn from(item: DisconnectReason) -> Self {
+        match item {
+            DisconnectReason::Shutdown => 1,
+            DisconnectReason::Update => 2,
+            DisconnectReason::LowBattery => 3,
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match *self {
+            DisconnectReason::Shutdown => "Shutdown",
+            DisconnectReason::Update => "Update",
+            DisconnectReason::LowBattery => "LowBattery",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputId {
+    Input1,
+    Input2,
+    Input3,
+    Input4,
+    Input5,
+    Input6,
+    Input7,
+    Input8,
+}
+
+impl TryFrom<u8> for InputId {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => InputId::Input1,
+            2 => InputId::Input2,
+            3 => InputId::Input3,
+            4 => InputId::Input4,
+            5 => InputId::Input5,
+            6 => InputId::Input6,
+            7 => InputId::Input7,
+            8 => InputId::Input8,
+            _ => return Err(Error::UnknownInputId(value)),
+        })
+    }
+}
+
+impl From<InputId> for u8 {
+    fn from(item: InputId) -> Self {
+        match item {
+            InputId::Input1 => 1,
+            InputId::Input2 => 2,
+            InputId::Input3 => 3,
+            InputId::Input4 => 4,
+            InputId::Input5 => 5,
+            InputId::Input6 => 6,
+            InputId::Input7 => 7,
+            InputId::Input8 => 8,
+        }
+    }
+}
+
+impl fmt::Display for InputId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = match *self {
+            InputId::Input1 => "Input1",
+            InputId::Input2 => "Input2",
+            InputId::Input3 => "Input3",
+            InputId::Input4 => "Input4",
+            InputId::Input5 => "Input5",
+            InputId::Input6 => "Input6",
+            InputId::Input7 => "Input7",
+            InputId::Input8 => "Input8",
+        };
+        write!(f, "{}", id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputState {
+    #[default]
+    Inactive,
+    Active,
+}
+
+impl TryFrom<u8> for InputState {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => InputState::Inactive,
+            2 => InputState::Active,
+            _ => return Err(Error::UnknownInputState(value)),
+        })
+    }
+}
+
+impl From<InputState> for u8 {
+    fn from(item: InputState) -> Self {
+        match item {
+            InputState::Inactive => 1,
+            InputState::Active => 2,
+        }
+    }
+}
+
+impl fmt::Display for InputState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = match *self {
+            InputState::Inactive => "Inactive",
+            InputState::Active => "Active",
+        };
+        write!(f, "{}", id)
+    }
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum Direction {
@@ -351,13 +907,246 @@ impl From<Speed> for u8 {
     }
 }
 
-#[derive(Encode, Decode, Copy, Clone, Debug)]
-pub enum Operation {
-    Connect,
-    ControlLoco,
-    LocoStatus,
-    SensorsStatus,
-    DriveActuator,
+impl Speed {
+    // Duty cycle percentage applied by the locos for this speed
+    pub fn duty_cycle(&self) -> u8 {
+        match *self {
+            Speed::Stop => 0,
+            Speed::Slow => 25,
+            Speed::Normal => 75,
+            Speed::Fast => SPEED_PWM_RANGE,
+            Speed::PwmDutyCycle(duty_percent) => duty_percent.min(SPEED_PWM_RANGE),
+        }
+    }
+}
+
+/**
+ * Speed expressed as a DCC speed step, either out of 28 steps or out of the
+ * 126 usable steps of the 128 steps mode. Step 0 always means stop, and any
+ * step above the maximum is considered as the maximum.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeedSteps {
+    Steps28(u8),
+    Steps128(u8),
+}
+
+const SPEED_STEPS_28_MAX: u8 = 28;
+const SPEED_STEPS_128_MAX: u8 = 126;
+
+impl SpeedSteps {
+    fn step_and_max(&self) -> (u8, u8) {
+        match *self {
+            SpeedSteps::Steps28(step) => (step.min(SPEED_STEPS_28_MAX), SPEED_STEPS_28_MAX),
+            SpeedSteps::Steps128(step) => (step.min(SPEED_STEPS_128_MAX), SPEED_STEPS_128_MAX),
+        }
+    }
+
+    pub fn to_speed(&self, curve: &SpeedCurve) -> Speed {
+        let (step, max) = self.step_and_max();
+        if step == 0 {
+            return Speed::Stop;
+        }
+
+        Speed::PwmDutyCycle(curve.duty_cycle(step, max))
+    }
+}
+
+/**
+ * Calibration curve mapping speed steps onto PWM duty cycles, following the
+ * DCC Vstart, Vmid and Vhigh configuration variables. The first step maps to
+ * v_start, the middle step to v_mid and the last step to v_high, with a linear
+ * interpolation in between. Every value is a duty cycle percentage.
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeedCurve {
+    pub v_start: u8,
+    pub v_mid: u8,
+    pub v_high: u8,
+}
+
+impl Default for SpeedCurve {
+    fn default() -> Self {
+        SpeedCurve {
+            v_start: 0,
+            v_mid: SPEED_PWM_RANGE / 2,
+            v_high: SPEED_PWM_RANGE,
+        }
+    }
+}
+
+impl SpeedCurve {
+    fn duty_cycle(&self, step: u8, max: u8) -> u8 {
+        let (step, max) = (u32::from(step), u32::from(max));
+        let mid = max.div_ceil(2);
+        let (v_start, v_mid, v_high) = (
+            u32::from(self.v_start),
+            u32::from(self.v_mid),
+            u32::from(self.v_high),
+        );
+
+        let duty_cycle = if step <= mid {
+            interpolate(step - 1, mid - 1, v_start, v_mid)
+        } else {
+            interpolate(step - mid, max - mid, v_mid, v_high)
+        };
+
+        duty_cycle.min(u32::from(SPEED_PWM_RANGE)) as u8
+    }
+}
+
+fn interpolate(pos: u32, range: u32, from: u32, to: u32) -> u32 {
+    if range == 0 {
+        return to;
+    }
+
+    if to >= from {
+        from + (to - from) * pos / range
+    } else {
+        from - (from - to) * pos / range
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MotorStatus {
+    #[default]
+    Running,
+    Stalled,
+}
+
+impl TryFrom<u8> for MotorStatus {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => MotorStatus::Running,
+            2 => MotorStatus::Stalled,
+            _ => return Err(Error::UnknownMotorStatus(value)),
+        })
+    }
+}
+
+impl From<MotorStatus> for u8 {
+    fn from(item: MotorStatus) -> Self {
+        match item {
+            MotorStatus::Running => 1,
+            MotorStatus::Stalled => 2,
+        }
+    }
+}
+
+impl fmt::Display for MotorStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match *self {
+            MotorStatus::Running => "Running",
+            MotorStatus::Stalled => "Stalled",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/**
+ * Functions of a loco besides its motors, each one being switched on or off.
+ * They're carried as a bitmask, in which a set bit switches the function on:
+ *
+ * | bit 0: headlight | bit 1: rear light | bit 2: horn | bit 3: cabin light |
+ */
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct LocoFunctions {
+    pub headlight: bool,
+    pub rear_light: bool,
+    pub horn: bool,
+    pub cabin_light: bool,
+}
+
+const LOCO_FUNCTION_HEADLIGHT: u8 = 1 << 0;
+const LOCO_FUNCTION_REAR_LIGHT: u8 = 1 << 1;
+const LOCO_FUNCTION_HORN: u8 = 1 << 2;
+const LOCO_FUNCTION_CABIN_LIGHT: u8 = 1 << 3;
+const LOCO_FUNCTIONS_MASK: u8 = LOCO_FUNCTION_HEADLIGHT
+    | LOCO_FUNCTION_REAR_LIGHT
+    | LOCO_FUNCTION_HORN
+    | LOCO_FUNCTION_CABIN_LIGHT;
+
+impl TryFrom<u8> for LocoFunctions {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        if value & !LOCO_FUNCTIONS_MASK != 0 {
+            return Err(Error::UnknownLocoFunctions(value));
+        }
+
+        Ok(LocoFunctions {
+            headlight: value & LOCO_FUNCTION_HEADLIGHT != 0,
+            rear_light: value & LOCO_FUNCTION_REAR_LIGHT != 0,
+            horn: value & LOCO_FUNCTION_HORN != 0,
+            cabin_light: value & LOCO_FUNCTION_CABIN_LIGHT != 0,
+        })
+    }
+}
+
+impl From<LocoFunctions> for u8 {
+    fn from(item: LocoFunctions) -> Self {
+        [
+            (item.headlight, LOCO_FUNCTION_HEADLIGHT),
+            (item.rear_light, LOCO_FUNCTION_REAR_LIGHT),
+            (item.horn, LOCO_FUNCTION_HORN),
+            (item.cabin_light, LOCO_FUNCTION_CABIN_LIGHT),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .fold(0, |value, (_, bit)| value | bit)
+    }
+}
+
+#[derive(Encode, Decode, Copy, Clone, Debug)]
+pub enum Operation {
+    Connect,
+    ControlLoco,
+    LocoStatus,
+    SensorsStatus,
+    DriveActuator,
+    ActuatorsTelemetry,
+    InputsStatus,
+    Register,
+    TimeSync,
+    HoldOnDisconnect,
+    // Sent along with an ErrorPayload, by the controller right before closing
+    // a connection it rejects, or by a Pico right before giving up on a
+    // request it couldn't handle
+    Error,
+    // Sent along with a DisconnectPayload by a Pico going away on purpose,
+    // right before closing its connection
+    Disconnect,
+    DriveActuatorsBatch,
+    // Sent by every Pico when it has had nothing else to send for a while,
+    // without payload, letting the controller spot the stale connections
+    Heartbeat,
+    ControlLocoFunctions,
+    // Sent by a loco right before every LocoStatusResponse, with a payload
+    // only made of extension fields, and whenever the track power goes away
+    // or comes back, without being asked for
+    LocoTelemetry,
+    // Sent by the actuators board once it applied every command up to the
+    // given ID
+    CommandAck,
+    // Sent to the locos without payload, which cut their motors right away
+    // and answer with a ControlLocoResponse
+    EmergencyStop,
+    // Sent to a loco along with a SelectWireFormatPayload right after its
+    // Connect got accepted, when it listed the wire formats it supports
+    SelectWireFormat,
+    // Sent by a Pico along with an ErrorPayload instead of the response to a
+    // request it can't make sense of. Unlike an Error, the connection stays
+    // up.
+    Nack,
+    // Sent by a loco along with a SafeCrawlReportPayload right after it
+    // connected again, when it crawled on its own while disconnected
+    SafeCrawlReport,
 }
 
 impl TryFrom<u8> for Operation {
@@ -370,6 +1159,22 @@ impl TryFrom<u8> for Operation {
             3 => Operation::LocoStatus,
             4 => Operation::SensorsStatus,
             5 => Operation::DriveActuator,
+            6 => Operation::ActuatorsTelemetry,
+            7 => Operation::InputsStatus,
+            8 => Operation::Register,
+            9 => Operation::TimeSync,
+            10 => Operation::HoldOnDisconnect,
+            11 => Operation::Error,
+            12 => Operation::Disconnect,
+            13 => Operation::DriveActuatorsBatch,
+            14 => Operation::Heartbeat,
+            15 => Operation::ControlLocoFunctions,
+            16 => Operation::LocoTelemetry,
+            17 => Operation::CommandAck,
+            18 => Operation::EmergencyStop,
+            19 => Operation::SelectWireFormat,
+            20 => Operation::Nack,
+            21 => Operation::SafeCrawlReport,
             _ => return Err(Error::UnknownOperation(value)),
         })
     }
@@ -383,6 +1188,22 @@ impl From<Operation> for u8 {
             Operation::LocoStatus => 3,
             Operation::SensorsStatus => 4,
             Operation::DriveActuator => 5,
+            Operation::ActuatorsTelemetry => 6,
+            Operation::InputsStatus => 7,
+            Operation::Register => 8,
+            Operation::TimeSync => 9,
+            Operation::HoldOnDisconnect => 10,
+            Operation::Error => 11,
+            Operation::Disconnect => 12,
+            Operation::DriveActuatorsBatch => 13,
+            Operation::Heartbeat => 14,
+            Operation::ControlLocoFunctions => 15,
+            Operation::LocoTelemetry => 16,
+            Operation::CommandAck => 17,
+            Operation::EmergencyStop => 18,
+            Operation::SelectWireFormat => 19,
+            Operation::Nack => 20,
+            Operation::SafeCrawlReport => 21,
         }
     }
 }
@@ -395,49 +1216,946 @@ impl fmt::Display for Operation {
             Operation::LocoStatus => "LocoStatus",
             Operation::SensorsStatus => "SensorsStatus",
             Operation::DriveActuator => "DriveActuator",
+            Operation::ActuatorsTelemetry => "ActuatorsTelemetry",
+            Operation::InputsStatus => "InputsStatus",
+            Operation::Register => "Register",
+            Operation::TimeSync => "TimeSync",
+            Operation::HoldOnDisconnect => "HoldOnDisconnect",
+            Operation::Error => "Error",
+            Operation::Disconnect => "Disconnect",
+            Operation::DriveActuatorsBatch => "DriveActuatorsBatch",
+            Operation::Heartbeat => "Heartbeat",
+            Operation::ControlLocoFunctions => "ControlLocoFunctions",
+            Operation::LocoTelemetry => "LocoTelemetry",
+            Operation::CommandAck => "CommandAck",
+            Operation::EmergencyStop => "EmergencyStop",
+            Operation::SelectWireFormat => "SelectWireFormat",
+            Operation::Nack => "Nack",
+            Operation::SafeCrawlReport => "SafeCrawlReport",
         };
         write!(f, "{}", op)
     }
 }
 
-#[derive(Encode, Decode, Copy, Clone, Debug)]
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct ConnectPayload {
     pub loco_id: u8,
+    pub device_id: u64,
+    pub protocol_version: u8,
+    pub firmware_version: FirmwareVersion,
 }
 
-#[derive(Encode, Decode, Copy, Clone, Debug)]
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct ErrorPayload {
+    pub code: u8,
+}
+
+pub const ERROR_PAYLOAD_SIZE: usize = 1;
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct DisconnectPayload {
+    pub reason: u8,
+}
+
+pub const DISCONNECT_PAYLOAD_SIZE: usize = 1;
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct RegisterPayload {
+    pub protocol_version: u8,
+    pub firmware_version: FirmwareVersion,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct ControlLocoPayload {
     pub direction: u8,
     pub speed: u8,
 }
 
-#[derive(Encode, Decode, Copy, Clone, Debug)]
-pub struct SensorsStatusArray {
-    pub len: u8,
-}
-
-#[derive(Encode, Decode, Copy, Clone, Debug)]
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub struct SensorStatus {
     pub sensor_id: u8,
+    pub sensor_type: u8,
     pub loco_id: u8,
+    pub event: u8,
+    pub timestamp_us: u64,
 }
 
-#[derive(Encode, Decode, Copy, Clone, Debug)]
+// Carried by the detections of the sensors which can't tell the locos apart
+pub const ANONYMOUS_LOCO_ID: u8 = 0;
+
+/**
+ * Detections carried by the SensorsStatus payload, encoded as a count
+ * followed by fixed-size records rather than through bincode one at a time:
+ *
+ * | count: u8 | sensor_id: u8 | loco_id: u8 | timestamp_us: u64 (LE) | ...
+ *
+ * The extension area follows the last record. The type of the sensors and
+ * the events aren't part of the records, but of the
+ * SENSORS_STATUS_EXT_SENSOR_TYPES and SENSORS_STATUS_EXT_EVENTS extensions,
+ * each one only written when the batch holds anything else than RFID
+ * detections and arrivals respectively.
+ */
+pub const SENSOR_STATUS_RECORD_SIZE: usize = 10;
+// Most records a batch can count, so that they always fit into a payload
+// along with the types of their sensors and their events
+pub const SENSORS_STATUS_BATCH_MAX_LEN: usize =
+    (PAYLOAD_MAX_LEN - 1 - 2 * EXTENSION_FIELD_HEADER_SIZE) / (SENSOR_STATUS_RECORD_SIZE + 2);
+const _: () = assert!(SENSORS_STATUS_BATCH_MAX_LEN <= u8::MAX as usize);
+
+impl SensorStatus {
+    fn to_record(self) -> [u8; SENSOR_STATUS_RECORD_SIZE] {
+        let mut record = [0u8; SENSOR_STATUS_RECORD_SIZE];
+        record[0] = self.sensor_id;
+        record[1] = self.loco_id;
+        record[2..].copy_from_slice(&self.timestamp_us.to_le_bytes());
+        record
+    }
+
+    fn from_record(record: &[u8; SENSOR_STATUS_RECORD_SIZE], sensor_type: u8, event: u8) -> Self {
+        let [sensor_id, loco_id, timestamp_us @ ..] = *record;
+        SensorStatus {
+            sensor_id,
+            sensor_type,
+            loco_id,
+            event,
+            timestamp_us: u64::from_le_bytes(timestamp_us),
+        }
+    }
+}
+
+// Encodes records in place at the beginning of a payload, the count, the
+// types of the sensors and the events being written once the batch is
+// complete
+pub struct SensorsStatusBatch<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    sensor_types: [u8; SENSORS_STATUS_BATCH_MAX_LEN],
+    events: [u8; SENSORS_STATUS_BATCH_MAX_LEN],
+}
+
+impl<'a> SensorsStatusBatch<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        SensorsStatusBatch {
+            buf,
+            len: 0,
+            sensor_types: [0; SENSORS_STATUS_BATCH_MAX_LEN],
+            events: [0; SENSORS_STATUS_BATCH_MAX_LEN],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, status: SensorStatus) -> Result<()> {
+        if self.len >= SENSORS_STATUS_BATCH_MAX_LEN {
+            return Err(Error::BatchFull);
+        }
+
+        let offset = 1 + self.len * SENSOR_STATUS_RECORD_SIZE;
+        let record = self
+            .buf
+            .get_mut(offset..offset + SENSOR_STATUS_RECORD_SIZE)
+            .ok_or(Error::BatchBufferTooSmall)?;
+        record.copy_from_slice(&status.to_record());
+        self.sensor_types[self.len] = status.sensor_type;
+        self.events[self.len] = status.event;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    // Writes the count, followed by the types of the sensors unless they are
+    // all RFID readers and by the events unless they are all arrivals,
+    // returning the size of the whole batch
+    pub fn finish(self) -> Result<usize> {
+        let count = self.buf.first_mut().ok_or(Error::BatchBufferTooSmall)?;
+        // Safe to cast since len never goes beyond SENSORS_STATUS_BATCH_MAX_LEN
+        *count = self.len as u8;
+
+        let mut size = 1 + self.len * SENSOR_STATUS_RECORD_SIZE;
+        for (tag, values, default) in [
+            (
+                SENSORS_STATUS_EXT_SENSOR_TYPES,
+                &self.sensor_types[..self.len],
+                u8::from(SensorType::Rfid),
+            ),
+            (
+                SENSORS_STATUS_EXT_EVENTS,
+                &self.events[..self.len],
+                u8::from(SensorEvent::Arrived),
+            ),
+        ] {
+            if values.iter().any(|v| *v != default) {
+                size += encode_extension_field(&mut self.buf[size..], tag, values)
+                    .map_err(|_| Error::BatchBufferTooSmall)?;
+            }
+        }
+
+        Ok(size)
+    }
+}
+
+// Decodes the records of a SensorsStatus payload, along with the extension
+// area following them
+pub fn decode_sensors_status_batch(
+    payload: &[u8],
+) -> Result<(impl Iterator<Item = SensorStatus> + '_, Extensions<'_>)> {
+    let (count, rest) = payload.split_first().ok_or(Error::TruncatedBatch)?;
+    let count = usize::from(*count);
+    if count > SENSORS_STATUS_BATCH_MAX_LEN {
+        return Err(Error::BatchTooLarge(count));
+    }
+    let size = count * SENSOR_STATUS_RECORD_SIZE;
+    if rest.len() < size {
+        return Err(Error::TruncatedBatch);
+    }
+
+    let (records, extensions) = rest.split_at(size);
+    let (records, _) = records.as_chunks::<SENSOR_STATUS_RECORD_SIZE>();
+    let extensions = Extensions::new(extensions);
+    // Sensors whose type isn't listed are RFID readers, and events which
+    // aren't listed are arrivals
+    let sensor_types = extensions
+        .get(SENSORS_STATUS_EXT_SENSOR_TYPES)?
+        .unwrap_or_default();
+    let events = extensions
+        .get(SENSORS_STATUS_EXT_EVENTS)?
+        .unwrap_or_default();
+
+    Ok((
+        records.iter().enumerate().map(|(i, record)| {
+            let sensor_type = sensor_types
+                .get(i)
+                .copied()
+                .unwrap_or(SensorType::Rfid.into());
+            let event = events
+                .get(i)
+                .copied()
+                .unwrap_or(SensorEvent::Arrived.into());
+            SensorStatus::from_record(record, sensor_type, event)
+        }),
+        extensions,
+    ))
+}
+
+/**
+ * Extension of the SensorsStatus payload listing the tags the sensors board
+ * couldn't match with a loco, so that the loco_controller can tell what they
+ * are. Every entry is the sensor_id followed by the 4 bytes of the UID.
+ */
+pub const SENSORS_STATUS_EXT_UNKNOWN_TAGS: u8 = 1;
+pub const UNKNOWN_TAG_SIZE: usize = 5;
+
+/**
+ * Extension of the SensorsStatus payload holding the type of the sensor
+ * behind each record, one byte per record in the same order. Records beyond
+ * the end of the field, or all of them when the field is missing, come from
+ * RFID readers.
+ */
+pub const SENSORS_STATUS_EXT_SENSOR_TYPES: u8 = 2;
+
+/**
+ * Extension of the SensorsStatus payload holding the event behind each
+ * record, one byte per record in the same order. Records beyond the end of
+ * the field, or all of them when the field is missing, are arrivals. A
+ * receiver which doesn't know about the field takes departures for
+ * arrivals, which is harmless since they are timestamped from when the loco
+ * was last seen over the sensor.
+ */
+pub const SENSORS_STATUS_EXT_EVENTS: u8 = 3;
+
+/**
+ * Extension fields of the LocoTelemetry payload, each of them only sent by a
+ * loco able to measure it, so that a loco_controller skips whatever it
+ * doesn't know about. Values are little endian: the battery in millivolts,
+ * the temperature of the Pico in degrees Celsius, the strength of the WiFi
+ * signal in dBm, the raw ADC reading of the motor supply taken from the track
+ * and whether the track power is lost (0 or 1).
+ */
+pub const LOCO_TELEMETRY_EXT_BATTERY_MV: u8 = 1;
+pub const LOCO_TELEMETRY_EXT_TEMPERATURE: u8 = 2;
+pub const LOCO_TELEMETRY_EXT_RSSI: u8 = 3;
+pub const LOCO_TELEMETRY_EXT_SUPPLY: u8 = 4;
+pub const LOCO_TELEMETRY_EXT_POWER_LOST: u8 = 5;
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct TimeSyncPayload {
+    pub time_us: u64,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct HoldOnDisconnectPayload {
+    pub hold_secs: u8,
+}
+
+/**
+ * Acknowledges a ControlLoco command, carrying the state actually applied by
+ * the loco, which differs from the command if it had to be ignored.
+ */
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct ControlLocoResponse {
+    pub direction: u8,
+    pub speed: u8,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct ControlLocoFunctionsPayload {
+    pub functions: u8,
+}
+
+/**
+ * Acknowledges a ControlLocoFunctions command, carrying the functions
+ * actually switched on by the loco.
+ */
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct ControlLocoFunctionsResponse {
+    pub functions: u8,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct LocoStatusResponse {
     pub direction: u8,
     pub speed: u8,
+    pub motor_status: u8,
 }
 
-#[derive(Encode, Decode, Copy, Clone, Debug)]
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct DriveActuatorPayload {
     pub actuator_id: u8,
     pub actuator_type: u8,
+    // The angle of a servo, the state of any other actuator
     pub actuator_state: u8,
 }
 
-#[derive(Encode, Decode, Copy, Clone, Debug)]
+/**
+ * Header of a DriveActuatorsBatch payload, followed by len entries of
+ * DriveActuatorPayload. The receiver applies either all of them, or none of
+ * them if any entry is invalid. Entries are applied in the order they come,
+ * which is the order switch rails must be thrown in.
+ */
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct DriveActuatorsBatchArray {
+    pub len: u8,
+}
+
+// Size of a DriveActuatorPayload with the legacy bincode config
+pub const DRIVE_ACTUATOR_SIZE: usize = 3;
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct CommandAckPayload {
+    pub command_id: u64,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct ActuatorsTelemetryPayload {
+    pub current: u16,
+    pub overcurrent: u8,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct SelectWireFormatPayload {
+    pub format: u8,
+}
+
+/**
+ * What a loco did on its own after losing the controller while moving, which
+ * is crawling in the same direction until its hall sensor saw the magnet of
+ * the next checkpoint, unless it gave up before.
+ */
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct SafeCrawlReportPayload {
+    pub direction: u8,
+    pub reached_checkpoint: u8,
+    pub crawl_ms: u32,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct InputsStatusArray {
+    pub len: u8,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct InputStatus {
+    pub input_id: u8,
+    pub state: u8,
+}
+
+// Size of an InputStatus with the legacy bincode config
+pub const INPUT_STATUS_SIZE: usize = 2;
+
+// Checks the len entries announced by the header of an array fit in what
+// follows it, so that a malformed len is rejected before any entry is decoded
+pub fn check_array_len(len: u8, entry_size: usize, entries: &[u8]) -> Result<()> {
+    if entries.len() < usize::from(len) * entry_size {
+        return Err(Error::TruncatedBatch);
+    }
+
+    Ok(())
+}
+
+/**
+ * Precedes every framed message. With the legacy bincode config, it's always
+ * encoded into 6 bytes:
+ *
+ * | magic: u8 | operation: u8 | payload_len: u16 (LE) | sequence: u16 (LE) |
+ *
+ * Receivers bound the payloads they accept to their own buffers, whatever
+ * payload_len allows.
+ */
+#[derive(Encode, Decode, Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct Header {
     pub magic: u8,
     pub operation: u8,
-    pub payload_len: u8,
+    pub payload_len: u16,
+    pub sequence: u16,
+}
+
+pub const HEADER_SIZE: usize = 6;
+
+// Largest payload any peer sends, bounded by the buffers of the Pi Pico
+pub const PAYLOAD_MAX_LEN: usize = 1024;
+
+impl Header {
+    // Size of the whole frame this header starts, CRC included
+    pub fn frame_len(&self) -> usize {
+        HEADER_SIZE + usize::from(self.payload_len) + FRAME_CRC_SIZE
+    }
+}
+
+/**
+ * Every frame carries a sequence number in its Header, which each end of a
+ * connection counts on its own from 0, wrapping around. Responses aren't
+ * counted, since they don't come with a Header.
+ */
+#[derive(Default, Debug)]
+pub struct SequenceCounter {
+    next: u16,
+}
+
+impl SequenceCounter {
+    pub fn next_sequence(&mut self) -> u16 {
+        let sequence = self.next;
+        self.next = sequence.wrapping_add(1);
+        sequence
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SequenceCheck {
+    InOrder,
+    // Frames got lost before this one, such as the ones failing their CRC
+    Gap(u16),
+    Duplicate,
+    Reordered,
+}
+
+/**
+ * Tracks the sequence numbers received through a connection. A frame is
+ * accepted when it's newer than the last accepted one, that is less than half
+ * of the sequence space ahead of it (RFC 1982), and should be discarded
+ * otherwise.
+ */
+#[derive(Default, Debug)]
+pub struct SequenceTracker {
+    expected: u16,
+}
+
+impl SequenceTracker {
+    pub fn check(&mut self, sequence: u16) -> SequenceCheck {
+        let ahead = sequence.wrapping_sub(self.expected);
+        if ahead >= 0x8000 {
+            return if sequence == self.expected.wrapping_sub(1) {
+                SequenceCheck::Duplicate
+            } else {
+                SequenceCheck::Reordered
+            };
+        }
+
+        self.expected = sequence.wrapping_add(1);
+        match ahead {
+            0 => SequenceCheck::InOrder,
+            lost => SequenceCheck::Gap(lost),
+        }
+    }
+}
+
+/**
+ * Optional extension area following the fixed part of a payload, within the
+ * payload_len announced by the Header. It's made of TLV fields, each being a
+ * tag, the length of the value and the value itself:
+ *
+ * | tag: u8 | len: u8 | value: [u8; len] | tag: u8 | len: u8 | ...
+ *
+ * New fields are added as new tags rather than by growing the fixed structs,
+ * which older receivers would fail to decode. Receivers skip the tags they
+ * don't know, hence devices running different firmware versions keep
+ * understanding each other. Responses aren't preceded by a Header, which is
+ * why they can't be extended.
+ */
+pub const EXTENSION_FIELD_HEADER_SIZE: usize = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ExtensionField<'a> {
+    pub tag: u8,
+    pub value: &'a [u8],
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Extensions<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Extensions<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Extensions { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Value of the first field with the given tag, skipping every other one
+    pub fn get(&self, tag: u8) -> Result<Option<&'a [u8]>> {
+        for field in *self {
+            let field = field?;
+            if field.tag == tag {
+                return Ok(Some(field.value));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'a> Iterator for Extensions<'a> {
+    type Item = Result<ExtensionField<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+
+        let field = match self.bytes {
+            [tag, len, rest @ ..] if rest.len() >= usize::from(*len) => ExtensionField {
+                tag: *tag,
+                value: &rest[..usize::from(*len)],
+            },
+            _ => {
+                // Nothing after a truncated field can be trusted
+                self.bytes = &[];
+                return Some(Err(Error::TruncatedExtension));
+            }
+        };
+        self.bytes = &self.bytes[EXTENSION_FIELD_HEADER_SIZE + field.value.len()..];
+
+        Some(Ok(field))
+    }
+}
+
+// Writes a field of the extension area at the beginning of buf, returning
+// the number of bytes written
+pub fn encode_extension_field(buf: &mut [u8], tag: u8, value: &[u8]) -> Result<usize> {
+    let len = u8::try_from(value.len()).map_err(|_| Error::ExtensionTooLarge(value.len()))?;
+    let size = EXTENSION_FIELD_HEADER_SIZE + value.len();
+    let field = buf.get_mut(..size).ok_or(Error::ExtensionBufferTooSmall)?;
+
+    field[0] = tag;
+    field[1] = len;
+    field[EXTENSION_FIELD_HEADER_SIZE..].copy_from_slice(value);
+
+    Ok(size)
+}
+
+/**
+ * Extension of the ControlLoco, ControlLocoFunctions, DriveActuator and
+ * DriveActuatorsBatch payloads carrying the ID of the command, as a little
+ * endian u64. IDs only ever increase, even across controller restarts, so
+ * that a device can tell a command sent again after a reconnect from a new
+ * one, and skip it if it already applied it. Loco commands are acknowledged
+ * by their response, actuator commands by a CommandAck.
+ */
+pub const COMMAND_EXT_ID: u8 = 1;
+pub const COMMAND_ID_SIZE: usize = 8;
+
+// ID of the command carried by the extension area of a payload, if any
+pub fn decode_command_id(extensions: Extensions) -> Result<Option<u64>> {
+    let Some(value) = extensions.get(COMMAND_EXT_ID)? else {
+        return Ok(None);
+    };
+    let bytes: [u8; COMMAND_ID_SIZE] = value
+        .try_into()
+        .map_err(|_| Error::InvalidCommandId(value.len()))?;
+
+    Ok(Some(u64::from_le_bytes(bytes)))
+}
+
+/**
+ * Extension of the ControlLoco payload carrying the time over which the loco
+ * must move from its current duty cycle to the commanded one, as a little
+ * endian u16 of milliseconds. Without it, the loco applies the command right
+ * away. Reversing the direction ramps down to a stop before ramping up the
+ * other way, within the same time.
+ */
+pub const RAMP_EXT_ID: u8 = 2;
+pub const RAMP_SIZE: usize = 2;
+
+// Ramp duration carried by the extension area of a payload, if any
+pub fn decode_ramp_ms(extensions: Extensions) -> Result<Option<u16>> {
+    let Some(value) = extensions.get(RAMP_EXT_ID)? else {
+        return Ok(None);
+    };
+    let bytes: [u8; RAMP_SIZE] = value
+        .try_into()
+        .map_err(|_| Error::InvalidRamp(value.len()))?;
+
+    Ok(Some(u16::from_le_bytes(bytes)))
+}
+
+/**
+ * Extensions of the Register payload listing the hardware a board actually
+ * has, so that the controller never drives something which isn't there. The
+ * sensors board lists its sensor IDs, one byte each, and the actuators board
+ * its actuators, each as its ID followed by its type. A board listing
+ * nothing is assumed to have everything.
+ */
+pub const PRESENT_SENSORS_EXT_ID: u8 = 3;
+pub const PRESENT_ACTUATORS_EXT_ID: u8 = 4;
+pub const PRESENT_ACTUATOR_SIZE: usize = 2;
+
+/**
+ * Extension of the Connect payload listing the wire formats the loco can
+ * decode its payloads with, one byte each. The controller answers with a
+ * SelectWireFormat telling which one both sides use from then on. A loco
+ * listing nothing keeps using bincode.
+ */
+pub const WIRE_FORMATS_EXT_ID: u8 = 5;
+
+/**
+ * Extension of the Connect and Register payloads carrying the token shared
+ * by the controller and the boards. When the controller expects one, a loco
+ * giving another one is answered with an Unauthorized Error, and a sensors or
+ * actuators board is disconnected.
+ */
+pub const AUTH_TOKEN_EXT_ID: u8 = 6;
+pub const AUTH_TOKEN_MAX_LEN: usize = 64;
+
+/**
+ * Extension of the commands carrying the ID of the HTTP request or the Oracle
+ * cycle they come from, as a little endian u64, so that what a device logs
+ * can be traced back through the controller logs. The controller only sends
+ * it when told to.
+ */
+pub const CORRELATION_EXT_ID: u8 = 7;
+pub const CORRELATION_ID_SIZE: usize = 8;
+
+// Correlation ID carried by the extension area of a payload, if any
+pub fn decode_correlation_id(extensions: Extensions) -> Result<Option<u64>> {
+    let Some(value) = extensions.get(CORRELATION_EXT_ID)? else {
+        return Ok(None);
+    };
+    let bytes: [u8; CORRELATION_ID_SIZE] = value
+        .try_into()
+        .map_err(|_| Error::InvalidCorrelationId(value.len()))?;
+
+    Ok(Some(u64::from_le_bytes(bytes)))
+}
+
+/**
+ * Encoding of the fixed part of the payloads exchanged with a loco, once
+ * negotiated. The Header, the CRC and the extension area are laid out the same
+ * whatever the format, and so are the Connect, Error and SelectWireFormat
+ * payloads, which are always bincode. Postcard is only available along with
+ * the postcard feature, and turns the larger integers into varints.
+ */
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    Postcard,
+}
+
+impl WireFormat {
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            WireFormat::Bincode => true,
+            WireFormat::Postcard => cfg!(feature = "postcard"),
+        }
+    }
+}
+
+impl TryFrom<u8> for WireFormat {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Ok(match value {
+            1 => WireFormat::Bincode,
+            2 => WireFormat::Postcard,
+            _ => return Err(Error::UnknownWireFormat(value)),
+        })
+    }
+}
+
+impl From<WireFormat> for u8 {
+    fn from(item: WireFormat) -> Self {
+        match item {
+            WireFormat::Bincode => 1,
+            WireFormat::Postcard => 2,
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let format = match *self {
+            WireFormat::Bincode => "bincode",
+            WireFormat::Postcard => "postcard",
+        };
+        write!(f, "{}", format)
+    }
+}
+
+// Decodes the fixed part of a payload, along with the extension area
+// following it
+pub fn decode_payload<D: Decode<()>, C: Config>(
+    payload: &[u8],
+    config: C,
+) -> core::result::Result<(D, Extensions<'_>), DecodeError> {
+    let (decoded, len) = decode_from_slice(payload, config)?;
+    Ok((decoded, Extensions::new(&payload[len..])))
+}
+
+// Same as decode_payload(), with the fixed part in the given wire format
+pub fn decode_payload_as<D: Decode<()> + DeserializeOwned, C: Config>(
+    payload: &[u8],
+    config: C,
+    format: WireFormat,
+) -> Result<(D, Extensions<'_>)> {
+    match format {
+        WireFormat::Bincode => decode_payload(payload, config).map_err(|_| Error::InvalidPayload),
+        #[cfg(feature = "postcard")]
+        WireFormat::Postcard => postcard::take_from_bytes(payload)
+            .map(|(decoded, rest)| (decoded, Extensions::new(rest)))
+            .map_err(|_| Error::InvalidPayload),
+        #[cfg(not(feature = "postcard"))]
+        WireFormat::Postcard => Err(Error::UnsupportedWireFormat(format)),
+    }
+}
+
+// Encodes the fixed part of a payload at the beginning of buf, in the given
+// wire format, returning the number of bytes written
+pub fn encode_payload<P: Encode + Serialize, C: Config>(
+    buf: &mut [u8],
+    payload: P,
+    config: C,
+    format: WireFormat,
+) -> Result<usize> {
+    match format {
+        WireFormat::Bincode => {
+            encode_into_slice(payload, buf, config).map_err(|_| Error::FrameBufferTooSmall)
+        }
+        #[cfg(feature = "postcard")]
+        WireFormat::Postcard => postcard::to_slice(&payload, buf)
+            .map(|encoded| encoded.len())
+            .map_err(|_| Error::FrameBufferTooSmall),
+        #[cfg(not(feature = "postcard"))]
+        WireFormat::Postcard => Err(Error::UnsupportedWireFormat(format)),
+    }
+}
+
+/**
+ * Every message preceded by a Header is followed by the CRC of the Header and
+ * the payload, so that frames corrupted on the way can be detected and
+ * discarded rather than decoded. The CRC isn't accounted for in payload_len:
+ *
+ * | header | payload: [u8; payload_len] | crc: u16 (little endian) |
+ *
+ * Responses aren't covered, since they aren't framed.
+ */
+pub const FRAME_CRC_SIZE: usize = 2;
+
+// CRC-16/CCITT-FALSE, computed bitwise since frames are a few bytes long
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in bytes {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Appends the CRC to the frame made of the first frame_len bytes of buf,
+// returning the size of the whole frame
+pub fn encode_frame(buf: &mut [u8], frame_len: usize) -> Result<usize> {
+    let size = frame_len + FRAME_CRC_SIZE;
+    let frame = buf.get_mut(..size).ok_or(Error::FrameBufferTooSmall)?;
+
+    let crc = crc16(&frame[..frame_len]);
+    frame[frame_len..].copy_from_slice(&crc.to_le_bytes());
+
+    Ok(size)
+}
+
+// Checks the CRC ending a whole frame, returning the frame without it
+pub fn verify_frame(frame: &[u8]) -> Result<&[u8]> {
+    if frame.len() < FRAME_CRC_SIZE {
+        return Err(Error::TruncatedFrame);
+    }
+
+    let (content, crc) = frame.split_at(frame.len() - FRAME_CRC_SIZE);
+    let received = u16::from_le_bytes([crc[0], crc[1]]);
+    let computed = crc16(content);
+    if received != computed {
+        return Err(Error::FrameChecksumMismatch(received, computed));
+    }
+
+    Ok(content)
+}
+
+/**
+ * A whole frame received and checked by a Codec, whose payload still has to
+ * be decoded into the type its operation tells.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Frame<'a> {
+    pub header: Header,
+    pub payload: &'a [u8],
+}
+
+impl Frame<'_> {
+    pub fn operation(&self) -> Result<Operation> {
+        Operation::try_from(self.header.operation)
+    }
+}
+
+/**
+ * Framing shared by the controller and every Pico, which only differ in how
+ * they move bytes around. Hence the Codec never does any IO itself, it works
+ * on the buffers filled or sent by the caller:
+ *  - a frame is received in two steps, the Header first, which tells how
+ *    many bytes make the whole frame, then the whole frame which gets checked
+ *  - a frame is sent once its payload got encoded right after HEADER_SIZE
+ *    bytes left for the Header, with FRAME_CRC_SIZE bytes left after it
+ *
+ * Payloads larger than payload_max_len are refused, since the receiver can't
+ * hold them and can't trust the stream to be in sync anymore. Sequence
+ * numbers are left to the callers, which own the SequenceCounter and the
+ * SequenceTracker of each connection.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Codec<C: Config> {
+    config: C,
+    payload_max_len: usize,
+}
+
+impl<C: Config> Codec<C> {
+    pub const fn new(config: C, payload_max_len: usize) -> Self {
+        Codec {
+            config,
+            payload_max_len,
+        }
+    }
+
+    pub fn decode_header(&self, bytes: &[u8]) -> Result<Header> {
+        let (header, _): (Header, usize) =
+            decode_from_slice(bytes, self.config).map_err(|_| Error::TruncatedFrame)?;
+
+        if header.magic != BACKEND_PROTOCOL_MAGIC_NUMBER {
+            return Err(Error::InvalidMagicNumber(header.magic));
+        }
+        if usize::from(header.payload_len) > self.payload_max_len {
+            return Err(Error::PayloadTooLarge(usize::from(header.payload_len)));
+        }
+
+        Ok(header)
+    }
+
+    // A frame failing its CRC is reported as FrameChecksumMismatch, which
+    // callers can skip as long as the stream remains in sync
+    pub fn decode_frame<'a>(&self, frame: &'a [u8]) -> Result<Frame<'a>> {
+        let header = self.decode_header(frame)?;
+        if frame.len() != header.frame_len() {
+            return Err(Error::TruncatedFrame);
+        }
+
+        let content = verify_frame(frame)?;
+
+        Ok(Frame {
+            header,
+            payload: &content[HEADER_SIZE..],
+        })
+    }
+
+    pub fn encode_header(
+        &self,
+        buf: &mut [u8],
+        operation: Operation,
+        payload_len: usize,
+        sequence: u16,
+    ) -> Result<usize> {
+        let header = Header {
+            magic: BACKEND_PROTOCOL_MAGIC_NUMBER,
+            operation: operation.into(),
+            payload_len: u16::try_from(payload_len)
+                .map_err(|_| Error::PayloadTooLarge(payload_len))?,
+            sequence,
+        };
+
+        // The only way encoding a Header can fail
+        encode_into_slice(header, buf, self.config).map_err(|_| Error::FrameBufferTooSmall)
+    }
+
+    // Completes the frame whose payload of payload_len bytes was encoded
+    // right after HEADER_SIZE bytes of buf, returning the size of the frame
+    pub fn encode_message(
+        &self,
+        buf: &mut [u8],
+        operation: Operation,
+        payload_len: usize,
+        sequence: u16,
+    ) -> Result<usize> {
+        self.encode_header(buf, operation, payload_len, sequence)?;
+        encode_frame(buf, HEADER_SIZE + payload_len)
+    }
+
+    // Frames a message made of a payload without extensions, which is all
+    // that new operations need
+    pub fn encode<P: Encode>(
+        &self,
+        buf: &mut [u8],
+        operation: Operation,
+        payload: P,
+        sequence: u16,
+    ) -> Result<usize> {
+        let payload_buf = buf
+            .get_mut(HEADER_SIZE..)
+            .ok_or(Error::FrameBufferTooSmall)?;
+        let payload_len = encode_into_slice(payload, payload_buf, self.config)
+            .map_err(|_| Error::FrameBufferTooSmall)?;
+
+        self.encode_message(buf, operation, payload_len, sequence)
+    }
 }
@@ -0,0 +1,358 @@
+use heapless::{LinearMap, Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{Direction, SensorId};
+
+// Checkpoints of the layout, each one read by its own RFID reader
+pub const MAX_CHECKPOINTS: usize = 8;
+// Checkpoints reachable right after another one, in a given direction
+pub const MAX_BRANCHES: usize = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SegmentPriority {
+    Priority0,
+    Priority1,
+    Priority2,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackId {
+    Track1,
+    Station1,
+    Station2,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckpointId {
+    Checkpoint1,
+    Checkpoint2,
+    Checkpoint3,
+    Checkpoint4,
+    Checkpoint5,
+    Checkpoint6,
+    Station1,
+    Station2,
+}
+
+impl CheckpointId {
+    pub fn is_station(&self) -> bool {
+        matches!(self, CheckpointId::Station1 | CheckpointId::Station2)
+    }
+}
+
+impl From<SensorId> for CheckpointId {
+    fn from(sensor_id: SensorId) -> Self {
+        match sensor_id {
+            SensorId::RfidReader1 => CheckpointId::Checkpoint1,
+            SensorId::RfidReader2 => CheckpointId::Checkpoint2,
+            SensorId::RfidReader3 => CheckpointId::Checkpoint3,
+            SensorId::RfidReader4 => CheckpointId::Checkpoint4,
+            SensorId::RfidReader5 => CheckpointId::Checkpoint5,
+            SensorId::RfidReader6 => CheckpointId::Checkpoint6,
+            SensorId::RfidReader7 => CheckpointId::Station1,
+            SensorId::RfidReader8 => CheckpointId::Station2,
+        }
+    }
+}
+
+impl From<CheckpointId> for SensorId {
+    fn from(checkpoint_id: CheckpointId) -> Self {
+        match checkpoint_id {
+            CheckpointId::Checkpoint1 => SensorId::RfidReader1,
+            CheckpointId::Checkpoint2 => SensorId::RfidReader2,
+            CheckpointId::Checkpoint3 => SensorId::RfidReader3,
+            CheckpointId::Checkpoint4 => SensorId::RfidReader4,
+            CheckpointId::Checkpoint5 => SensorId::RfidReader5,
+            CheckpointId::Checkpoint6 => SensorId::RfidReader6,
+            CheckpointId::Station1 => SensorId::RfidReader7,
+            CheckpointId::Station2 => SensorId::RfidReader8,
+        }
+    }
+}
+
+struct Checkpoint {
+    forward: Vec<CheckpointId, MAX_BRANCHES>,
+    backward: Vec<CheckpointId, MAX_BRANCHES>,
+    track_id: TrackId,
+    priority: SegmentPriority,
+}
+
+impl Checkpoint {
+    fn new(
+        forward: &[CheckpointId],
+        backward: &[CheckpointId],
+        track_id: TrackId,
+        priority: SegmentPriority,
+    ) -> Self {
+        Checkpoint {
+            // Safe to unwrap since no checkpoint has more than MAX_BRANCHES
+            // checkpoints next to it
+            forward: Vec::from_slice(forward).unwrap(),
+            backward: Vec::from_slice(backward).unwrap(),
+            track_id,
+            priority,
+        }
+    }
+
+    fn checkpoint_ids(&self, direction: Direction) -> &[CheckpointId] {
+        match direction {
+            Direction::Forward => &self.forward,
+            Direction::Backward => &self.backward,
+        }
+    }
+}
+
+/**
+ * How the checkpoints of the layout follow each other, along with the graph
+ * searches finding the way from one to another. Nothing is allocated, so that
+ * the Picos can reason about the next checkpoint on their own, for instance
+ * while the `loco_controller` can't be reached. The `RailNetwork` of the
+ * `loco_controller` is built on top of it.
+ */
+pub struct RouteGraph {
+    checkpoints: LinearMap<CheckpointId, Checkpoint, MAX_CHECKPOINTS>,
+    longest_path: usize,
+}
+
+impl Default for RouteGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RouteGraph {
+    pub fn new() -> Self {
+        use CheckpointId::*;
+
+        let mut checkpoints = LinearMap::new();
+        for (checkpoint_id, checkpoint) in [
+            (
+                Checkpoint1,
+                Checkpoint::new(
+                    &[Checkpoint2],
+                    &[Checkpoint6],
+                    TrackId::Track1,
+                    SegmentPriority::Priority0,
+                ),
+            ),
+            (
+                Checkpoint2,
+                Checkpoint::new(
+                    &[Checkpoint3],
+                    &[Checkpoint1, Station1],
+                    TrackId::Track1,
+                    SegmentPriority::Priority0,
+                ),
+            ),
+            (
+                Checkpoint3,
+                Checkpoint::new(
+                    &[Checkpoint4, Station2],
+                    &[Checkpoint2],
+                    TrackId::Track1,
+                    SegmentPriority::Priority0,
+                ),
+            ),
+            (
+                Checkpoint4,
+                Checkpoint::new(
+                    &[Checkpoint5],
+                    &[Checkpoint3],
+                    TrackId::Track1,
+                    SegmentPriority::Priority0,
+                ),
+            ),
+            (
+                Checkpoint5,
+                Checkpoint::new(
+                    &[Checkpoint6],
+                    &[Checkpoint4, Station2],
+                    TrackId::Track1,
+                    SegmentPriority::Priority0,
+                ),
+            ),
+            (
+                Checkpoint6,
+                Checkpoint::new(
+                    &[Checkpoint1, Station1],
+                    &[Checkpoint5],
+                    TrackId::Track1,
+                    SegmentPriority::Priority0,
+                ),
+            ),
+            (
+                Station1,
+                Checkpoint::new(
+                    &[Checkpoint2],
+                    &[Checkpoint6],
+                    TrackId::Station1,
+                    SegmentPriority::Priority1,
+                ),
+            ),
+            (
+                Station2,
+                Checkpoint::new(
+                    &[Checkpoint5],
+                    &[Checkpoint3],
+                    TrackId::Station2,
+                    SegmentPriority::Priority1,
+                ),
+            ),
+        ] {
+            // Safe to ignore since there are exactly MAX_CHECKPOINTS of them
+            let _ = checkpoints.insert(checkpoint_id, checkpoint);
+        }
+
+        RouteGraph {
+            checkpoints,
+            longest_path: 6,
+        }
+    }
+
+    fn checkpoint(&self, checkpoint_id: &CheckpointId) -> &Checkpoint {
+        // Safe to unwrap since checkpoints has been filled with every CheckpointId
+        self.checkpoints.get(checkpoint_id).unwrap()
+    }
+
+    pub fn checkpoint_ids(&self) -> impl Iterator<Item = CheckpointId> + '_ {
+        self.checkpoints.keys().copied()
+    }
+
+    pub fn next_checkpoint_ids(
+        &self,
+        cp_id: CheckpointId,
+        direction: Direction,
+    ) -> &[CheckpointId] {
+        self.checkpoint(&cp_id).checkpoint_ids(direction)
+    }
+
+    // Checkpoints of the passing loops, where a loco can stand aside
+    pub fn station_ids(&self) -> Vec<CheckpointId, MAX_CHECKPOINTS> {
+        self.checkpoints
+            .keys()
+            .filter(|cp_id| cp_id.is_station())
+            .copied()
+            .collect()
+    }
+
+    pub fn track_id(&self, cp_id: CheckpointId) -> TrackId {
+        self.checkpoint(&cp_id).track_id
+    }
+
+    // Next checkpoints ordered by priority, the main line first
+    fn prioritized_checkpoint_ids(
+        &self,
+        cp_id: CheckpointId,
+        direction: Direction,
+    ) -> Vec<CheckpointId, MAX_BRANCHES> {
+        let mut next_cp_ids: Vec<(SegmentPriority, usize, CheckpointId), MAX_BRANCHES> = self
+            .next_checkpoint_ids(cp_id, direction)
+            .iter()
+            .enumerate()
+            .map(|(i, cid)| (self.checkpoint(cid).priority, i, *cid))
+            .collect();
+        // Sorting on the index as well keeps equal priorities in order,
+        // without needing a stable sort
+        next_cp_ids.sort_unstable();
+        next_cp_ids.iter().map(|(_, _, cid)| *cid).collect()
+    }
+
+    pub fn next_checkpoint_id_for_track_id_target(
+        &self,
+        iteration: usize,
+        cp_id: CheckpointId,
+        direction: Direction,
+        target_track_id: TrackId,
+    ) -> Option<CheckpointId> {
+        let next_cp_ids = self.next_checkpoint_ids(cp_id, direction);
+
+        for next_cp_id in next_cp_ids.iter() {
+            if self.checkpoint(next_cp_id).track_id == target_track_id {
+                return Some(*next_cp_id);
+            }
+        }
+
+        for next_cp_id in self.prioritized_checkpoint_ids(cp_id, direction) {
+            if iteration >= self.longest_path {
+                return None;
+            }
+
+            if self
+                .next_checkpoint_id_for_track_id_target(
+                    iteration + 1,
+                    next_cp_id,
+                    direction,
+                    target_track_id,
+                )
+                .is_some()
+            {
+                return Some(next_cp_id);
+            }
+        }
+
+        None
+    }
+
+    // Number of segments to go through from a checkpoint to reach another one
+    // in the given direction, if it can be reached at all.
+    pub fn distance(
+        &self,
+        cp_id: CheckpointId,
+        direction: Direction,
+        target_cp_id: CheckpointId,
+    ) -> Option<usize> {
+        let mut cp_id = cp_id;
+        let mut distance = 0;
+
+        while cp_id != target_cp_id {
+            if distance > self.longest_path {
+                return None;
+            }
+            cp_id = self.next_checkpoint_id_for_checkpoint_id_target(
+                0,
+                cp_id,
+                direction,
+                target_cp_id,
+            )?;
+            distance += 1;
+        }
+
+        Some(distance)
+    }
+
+    pub fn next_checkpoint_id_for_checkpoint_id_target(
+        &self,
+        iteration: usize,
+        cp_id: CheckpointId,
+        direction: Direction,
+        target_cp_id: CheckpointId,
+    ) -> Option<CheckpointId> {
+        let next_cp_ids = self.next_checkpoint_ids(cp_id, direction);
+
+        if next_cp_ids.contains(&target_cp_id) {
+            return Some(target_cp_id);
+        }
+
+        for next_cp_id in self.prioritized_checkpoint_ids(cp_id, direction) {
+            if iteration >= self.longest_path {
+                return None;
+            }
+
+            if self
+                .next_checkpoint_id_for_checkpoint_id_target(
+                    iteration + 1,
+                    next_cp_id,
+                    direction,
+                    target_cp_id,
+                )
+                .is_some()
+            {
+                return Some(next_cp_id);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,340 @@
+//! Feeds random and malformed bytes through every decode path, which must
+//! reject them with an error rather than panic or loop over garbage.
+
+use bincode::{Decode, config::legacy, decode_from_slice, encode_into_slice};
+use loco_protocol::{
+    ActuatorId, ActuatorType, ActuatorsTelemetryPayload, BACKEND_PROTOCOL_MAGIC_NUMBER, Codec,
+    CommandAckPayload, ConnectPayload, ControlLocoFunctionsPayload, ControlLocoFunctionsResponse,
+    ControlLocoPayload, ControlLocoResponse, DRIVE_ACTUATOR_SIZE, Direction, DisconnectPayload,
+    DisconnectReason, DriveActuatorPayload, Error, ErrorCode, ErrorPayload, Extensions,
+    FRAME_CRC_SIZE, HEADER_SIZE, Header, HoldOnDisconnectPayload, INPUT_STATUS_SIZE, InputId,
+    InputState, InputStatus, LocoFunctions, LocoId, LocoStatusResponse, MotorStatus, Operation,
+    PAYLOAD_MAX_LEN, RegisterPayload, SENSOR_STATUS_RECORD_SIZE, SENSORS_STATUS_BATCH_MAX_LEN,
+    SERVO_ANGLE_MAX, SafeCrawlReportPayload, SelectWireFormatPayload, SensorEvent, SensorId,
+    SensorType, ServoAngle, SignalState, Speed, SwitchRailsState, TimeSyncPayload, TrackPowerState,
+    WireFormat, check_array_len, decode_command_id, decode_correlation_id, decode_payload,
+    decode_payload_as, decode_ramp_ms, decode_sensors_status_batch, encode_frame, verify_frame,
+};
+use serde::de::DeserializeOwned;
+
+const ROUNDS: usize = 20_000;
+
+// Xorshift, so that a failing input can be reproduced from the seed alone
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = self.next() as usize % (max_len + 1);
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+fn codec() -> Codec<impl bincode::config::Config> {
+    Codec::new(legacy(), PAYLOAD_MAX_LEN)
+}
+
+// Frames a payload with a valid Header and CRC, so that the payload decoders
+// get to see it
+fn frame(operation: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; HEADER_SIZE + payload.len() + FRAME_CRC_SIZE];
+    let header = Header {
+        magic: BACKEND_PROTOCOL_MAGIC_NUMBER,
+        operation,
+        payload_len: payload.len() as u16,
+        sequence: 0,
+    };
+    encode_into_slice(header, &mut buf, legacy()).unwrap();
+    buf[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+    encode_frame(&mut buf, HEADER_SIZE + payload.len()).unwrap();
+    buf
+}
+
+fn decode_extensions(extensions: Extensions) {
+    // Every field takes at least its header, hence a bound on the iterations
+    let max_fields = extensions.count();
+    assert!(max_fields <= PAYLOAD_MAX_LEN);
+    let _ = decode_command_id(extensions);
+    let _ = decode_correlation_id(extensions);
+    let _ = decode_ramp_ms(extensions);
+}
+
+fn decode_any<D: Decode<()> + DeserializeOwned>(payload: &[u8]) {
+    if let Ok((_, extensions)) = decode_payload::<D, _>(payload, legacy()) {
+        decode_extensions(extensions);
+    }
+    #[cfg(feature = "postcard")]
+    if let Ok((_, extensions)) = decode_payload_as::<D, _>(payload, legacy(), WireFormat::Postcard)
+    {
+        decode_extensions(extensions);
+    }
+}
+
+fn decode_every_payload(payload: &[u8]) {
+    decode_any::<ConnectPayload>(payload);
+    decode_any::<ErrorPayload>(payload);
+    decode_any::<DisconnectPayload>(payload);
+    decode_any::<RegisterPayload>(payload);
+    decode_any::<ControlLocoPayload>(payload);
+    decode_any::<TimeSyncPayload>(payload);
+    decode_any::<HoldOnDisconnectPayload>(payload);
+    decode_any::<ControlLocoResponse>(payload);
+    decode_any::<ControlLocoFunctionsPayload>(payload);
+    decode_any::<ControlLocoFunctionsResponse>(payload);
+    decode_any::<LocoStatusResponse>(payload);
+    decode_any::<DriveActuatorPayload>(payload);
+    decode_any::<CommandAckPayload>(payload);
+    decode_any::<ActuatorsTelemetryPayload>(payload);
+    decode_any::<SelectWireFormatPayload>(payload);
+    decode_any::<SafeCrawlReportPayload>(payload);
+    decode_any::<InputStatus>(payload);
+
+    if let Ok((sensors_status, extensions)) = decode_sensors_status_batch(payload) {
+        assert!(sensors_status.count() <= SENSORS_STATUS_BATCH_MAX_LEN);
+        decode_extensions(extensions);
+    }
+    decode_array::<InputStatus>(payload, INPUT_STATUS_SIZE);
+    decode_array::<DriveActuatorPayload>(payload, DRIVE_ACTUATOR_SIZE);
+}
+
+// Decodes the entries following the len of an array, as the receivers do
+// once check_array_len() accepted it
+fn decode_array<D: Decode<()>>(payload: &[u8], entry_size: usize) {
+    let Ok((len, mut offset)) = decode_from_slice::<u8, _>(payload, legacy()) else {
+        return;
+    };
+    if check_array_len(len, entry_size, &payload[offset..]).is_err() {
+        return;
+    }
+
+    for _ in 0..len {
+        let (_, size): (D, _) = decode_from_slice(&payload[offset..], legacy()).unwrap();
+        assert_eq!(size, entry_size);
+        offset += size;
+    }
+    decode_extensions(Extensions::new(&payload[offset..]));
+}
+
+#[test]
+fn random_bytes_as_ids() {
+    for value in 0..=u8::MAX {
+        let _ = Operation::try_from(value);
+        let _ = LocoId::try_from(value);
+        let _ = SensorId::try_from(value);
+        let _ = SensorEvent::try_from(value);
+        let _ = SensorType::try_from(value);
+        let _ = ActuatorId::try_from(value);
+        let _ = ActuatorType::try_from(value);
+        let _ = SwitchRailsState::try_from(value);
+        let _ = TrackPowerState::try_from(value);
+        let _ = SignalState::try_from(value);
+        let _ = ServoAngle::try_from(value);
+        let _ = ErrorCode::try_from(value);
+        let _ = DisconnectReason::try_from(value);
+        let _ = InputId::try_from(value);
+        let _ = InputState::try_from(value);
+        let _ = Direction::try_from(value);
+        let _ = Speed::try_from(value);
+        let _ = MotorStatus::try_from(value);
+        let _ = LocoFunctions::try_from(value);
+        let _ = WireFormat::try_from(value);
+    }
+
+    let mut rng = Rng(0x5eed_0001);
+    for _ in 0..ROUNDS {
+        let _ = LocoId::try_from(rng.bytes(8).as_slice());
+    }
+}
+
+#[test]
+fn random_bytes_as_frames() {
+    let codec = codec();
+    let mut rng = Rng(0x5eed_0002);
+    for _ in 0..ROUNDS {
+        let bytes = rng.bytes(64);
+        if let Ok(header) = codec.decode_header(&bytes) {
+            assert!(usize::from(header.payload_len) <= PAYLOAD_MAX_LEN);
+        }
+        if let Ok(frame) = codec.decode_frame(&bytes) {
+            let _ = frame.operation();
+            decode_every_payload(frame.payload);
+        }
+        let _ = verify_frame(&bytes);
+    }
+}
+
+#[test]
+fn random_payloads_in_valid_frames() {
+    let codec = codec();
+    let mut rng = Rng(0x5eed_0003);
+    for _ in 0..ROUNDS {
+        let operation = rng.next() as u8;
+        let payload = rng.bytes(PAYLOAD_MAX_LEN / 8);
+        let frame = frame(operation, &payload);
+
+        let decoded = codec.decode_frame(&frame).unwrap();
+        assert_eq!(decoded.payload, payload.as_slice());
+        let _ = decoded.operation();
+        decode_every_payload(decoded.payload);
+    }
+}
+
+#[test]
+fn random_bytes_as_extensions() {
+    let mut rng = Rng(0x5eed_0004);
+    for _ in 0..ROUNDS {
+        let bytes = rng.bytes(32);
+        let mut fields = 0;
+        for field in Extensions::new(&bytes) {
+            fields += 1;
+            // Nothing follows a truncated field
+            if field.is_err() {
+                break;
+            }
+        }
+        assert!(fields <= bytes.len());
+        decode_extensions(Extensions::new(&bytes));
+    }
+}
+
+#[test]
+fn oversized_payload_rejected() {
+    let codec = codec();
+    let header = Header {
+        magic: BACKEND_PROTOCOL_MAGIC_NUMBER,
+        operation: Operation::SensorsStatus.into(),
+        payload_len: PAYLOAD_MAX_LEN as u16 + 1,
+        sequence: 0,
+    };
+    let mut bytes = [0; HEADER_SIZE];
+    encode_into_slice(header, &mut bytes, legacy()).unwrap();
+
+    assert!(matches!(
+        codec.decode_header(&bytes),
+        Err(Error::PayloadTooLarge(len)) if len == PAYLOAD_MAX_LEN + 1
+    ));
+}
+
+#[test]
+fn truncated_frame_rejected() {
+    let codec = codec();
+    let frame = frame(Operation::SensorsStatus.into(), &[0; 12]);
+    for len in 0..frame.len() {
+        assert!(codec.decode_frame(&frame[..len]).is_err());
+    }
+}
+
+#[test]
+fn impossible_sensors_status_count_rejected() {
+    // Announces as many records as a batch can count while carrying a
+    // single one
+    let mut payload = vec![SENSORS_STATUS_BATCH_MAX_LEN as u8];
+    payload.extend_from_slice(&[0; SENSOR_STATUS_RECORD_SIZE]);
+
+    assert!(matches!(
+        decode_sensors_status_batch(&payload),
+        Err(Error::TruncatedBatch)
+    ));
+    assert!(matches!(
+        decode_sensors_status_batch(&[]),
+        Err(Error::TruncatedBatch)
+    ));
+}
+
+#[test]
+fn impossible_array_len_rejected() {
+    assert!(matches!(
+        check_array_len(2, INPUT_STATUS_SIZE, &[0; INPUT_STATUS_SIZE]),
+        Err(Error::TruncatedBatch)
+    ));
+    assert!(matches!(
+        check_array_len(u8::MAX, DRIVE_ACTUATOR_SIZE, &[0; 16]),
+        Err(Error::TruncatedBatch)
+    ));
+    assert!(check_array_len(1, DRIVE_ACTUATOR_SIZE, &[0; DRIVE_ACTUATOR_SIZE]).is_ok());
+    assert!(check_array_len(0, DRIVE_ACTUATOR_SIZE, &[]).is_ok());
+}
+
+#[test]
+fn servo_angle_out_of_range_rejected() {
+    assert!(ServoAngle::try_from(SERVO_ANGLE_MAX).is_ok());
+    assert!(matches!(
+        ServoAngle::try_from(SERVO_ANGLE_MAX + 1),
+        Err(Error::InvalidServoAngle(181))
+    ));
+}
+
+#[test]
+fn array_entry_sizes_match_encoding() {
+    let mut buf = [0; 16];
+    let entry = DriveActuatorPayload {
+        actuator_id: 0,
+        actuator_type: 0,
+        actuator_state: 0,
+    };
+    assert_eq!(
+        encode_into_slice(entry, &mut buf, legacy()).unwrap(),
+        DRIVE_ACTUATOR_SIZE
+    );
+    let entry = InputStatus {
+        input_id: 0,
+        state: 0,
+    };
+    assert_eq!(
+        encode_into_slice(entry, &mut buf, legacy()).unwrap(),
+        INPUT_STATUS_SIZE
+    );
+}
+
+#[cfg(not(feature = "postcard"))]
+#[test]
+fn postcard_unsupported_without_feature() {
+    assert!(!WireFormat::Postcard.is_supported());
+    assert!(matches!(
+        decode_payload_as::<ControlLocoPayload, _>(&[0, 0], legacy(), WireFormat::Postcard),
+        Err(Error::UnsupportedWireFormat(WireFormat::Postcard))
+    ));
+}
+
+#[cfg(feature = "postcard")]
+#[test]
+fn postcard_payload_followed_by_extensions() {
+    use loco_protocol::{COMMAND_EXT_ID, encode_extension_field, encode_payload};
+
+    let mut buf = [0; 32];
+    let time_sync = TimeSyncPayload { time_us: 100 };
+    // The time fits into a single byte as a varint
+    let mut len = encode_payload(&mut buf, time_sync, legacy(), WireFormat::Postcard).unwrap();
+    assert_eq!(len, 1);
+    len += encode_extension_field(&mut buf[len..], COMMAND_EXT_ID, &7u64.to_le_bytes()).unwrap();
+
+    let (decoded, extensions): (TimeSyncPayload, _) =
+        decode_payload_as(&buf[..len], legacy(), WireFormat::Postcard).unwrap();
+    assert_eq!(decoded.time_us, 100);
+    assert_eq!(decode_command_id(extensions).unwrap(), Some(7));
+}
+
+// Responses aren't framed, hence read the same way whatever the wire format,
+// which only holds as long as they're made of bytes
+#[cfg(feature = "postcard")]
+#[test]
+fn responses_encoded_alike_by_every_wire_format() {
+    use loco_protocol::encode_payload;
+
+    let mut bincode = [0; 8];
+    let mut postcard = [0; 8];
+    let resp = LocoStatusResponse {
+        direction: 1,
+        speed: 200,
+        motor_status: 2,
+    };
+    let bincode_len = encode_payload(&mut bincode, resp, legacy(), WireFormat::Bincode).unwrap();
+    let postcard_len = encode_payload(&mut postcard, resp, legacy(), WireFormat::Postcard).unwrap();
+    assert_eq!(bincode[..bincode_len], postcard[..postcard_len]);
+}
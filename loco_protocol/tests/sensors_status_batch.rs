@@ -0,0 +1,84 @@
+//! Boundaries of the SensorsStatus batches, which the sensors board fills up
+//! to SENSORS_STATUS_BATCH_MAX_LEN records and the controller must accept.
+
+use bincode::config::legacy;
+use loco_protocol::{
+    Codec, Error, FRAME_CRC_SIZE, HEADER_SIZE, Operation, PAYLOAD_MAX_LEN,
+    SENSOR_STATUS_RECORD_SIZE, SENSORS_STATUS_BATCH_MAX_LEN, SensorEvent, SensorStatus, SensorType,
+    SensorsStatusBatch, decode_sensors_status_batch,
+};
+
+// What the Pico boards allocate for every request they send
+const REQUEST_MAX_SIZE: usize = HEADER_SIZE + PAYLOAD_MAX_LEN + FRAME_CRC_SIZE;
+
+// Mixes the types of the sensors and the events, so that both extensions
+// get written and the batch takes as much room as it can
+fn sensor_status(i: usize) -> SensorStatus {
+    SensorStatus {
+        sensor_id: i as u8,
+        sensor_type: if i.is_multiple_of(2) {
+            SensorType::Hall.into()
+        } else {
+            SensorType::Rfid.into()
+        },
+        loco_id: (i % 3) as u8,
+        event: if i.is_multiple_of(3) {
+            SensorEvent::Departed.into()
+        } else {
+            SensorEvent::Arrived.into()
+        },
+        timestamp_us: u64::MAX - i as u64,
+    }
+}
+
+#[test]
+fn full_batch_round_trip() {
+    let codec = Codec::new(legacy(), PAYLOAD_MAX_LEN);
+    let mut buf = [0u8; REQUEST_MAX_SIZE];
+    let mut batch = SensorsStatusBatch::new(&mut buf[HEADER_SIZE..HEADER_SIZE + PAYLOAD_MAX_LEN]);
+    for i in 0..SENSORS_STATUS_BATCH_MAX_LEN {
+        batch.push(sensor_status(i)).unwrap();
+    }
+    assert_eq!(batch.len(), SENSORS_STATUS_BATCH_MAX_LEN);
+    let payload_len = batch.finish().unwrap();
+    let len = codec
+        .encode_message(&mut buf, Operation::SensorsStatus, payload_len, 0)
+        .unwrap();
+    assert!(len <= REQUEST_MAX_SIZE);
+
+    let frame = codec.decode_frame(&buf[..len]).unwrap();
+    let (sensors_status, _) = decode_sensors_status_batch(frame.payload).unwrap();
+    let decoded: Vec<_> = sensors_status.collect();
+    let expected: Vec<_> = (0..SENSORS_STATUS_BATCH_MAX_LEN)
+        .map(sensor_status)
+        .collect();
+    assert_eq!(decoded, expected);
+}
+
+#[test]
+fn batch_beyond_max_len_rejected() {
+    let mut buf = [0u8; 4 * PAYLOAD_MAX_LEN];
+    let mut batch = SensorsStatusBatch::new(&mut buf);
+    for i in 0..SENSORS_STATUS_BATCH_MAX_LEN {
+        batch.push(sensor_status(i)).unwrap();
+    }
+
+    assert!(matches!(
+        batch.push(sensor_status(SENSORS_STATUS_BATCH_MAX_LEN)),
+        Err(Error::BatchFull)
+    ));
+    assert_eq!(batch.len(), SENSORS_STATUS_BATCH_MAX_LEN);
+}
+
+#[test]
+fn announced_count_beyond_max_len_rejected() {
+    // Carries every record it announces, only too many of them
+    let count = SENSORS_STATUS_BATCH_MAX_LEN + 1;
+    let mut payload = vec![count as u8];
+    payload.resize(1 + count * SENSOR_STATUS_RECORD_SIZE, 0);
+
+    assert!(matches!(
+        decode_sensors_status_batch(&payload),
+        Err(Error::BatchTooLarge(len)) if len == count
+    ));
+}
@@ -0,0 +1,228 @@
+#![no_std]
+#![no_main]
+#![allow(async_fn_in_trait)]
+
+#[cfg(feature = "actuators")]
+use actuators_pico::{
+    Actuators, DigitalInput, OVERCURRENT_ADC_THRESHOLD, SignalLight, SwitchRails, TrackPower,
+    current_monitor_task, input_monitor_task,
+};
+#[cfg(feature = "actuators")]
+use common_pico::SERVER_TCP_PORT_ACTUATORS;
+#[cfg(feature = "sensors")]
+use common_pico::SERVER_TCP_PORT_SENSORS;
+use common_pico::{
+    ServerAddresses, connect_loco_controller, initialize_logger, initialize_program,
+    initialize_wifi,
+};
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_net::Stack;
+#[cfg(feature = "actuators")]
+use embassy_rp::adc::{Adc, Channel as AdcChannel, Config as AdcConfig};
+#[cfg(feature = "actuators")]
+use embassy_rp::gpio::{Input, Pull};
+use embassy_rp::gpio::{Level, Output};
+#[cfg(feature = "sensors")]
+use embassy_rp::spi::{self, Spi};
+use embassy_time::Timer;
+#[cfg(any(feature = "actuators", feature = "sensors"))]
+use heapless::Vec;
+#[cfg(feature = "sensors")]
+use loco_protocol::SensorId;
+#[cfg(feature = "actuators")]
+use loco_protocol::{ActuatorId, InputId};
+#[cfg(feature = "sensors")]
+use sensors_pico::reader::ReaderBus;
+#[cfg(feature = "sensors")]
+use sensors_pico::{Sensors, tag_reader_task};
+use {defmt_rtt as _, panic_probe as _};
+
+/**
+ * This program hosts several roles on a single Pi Pico 2 W, which is useful
+ * for small layouts. Every role is selected through its own feature, and
+ * connects to the loco_controller through its own socket, exactly as if it
+ * was running on a dedicated board.
+ *
+ * Pins are assigned so that roles never overlap:
+ *  - actuators: PIN_2 to PIN_14 and PIN_26 (same as actuators_pico), plus
+ *    a single signal light on PIN_20 to PIN_22
+ *  - sensors: SPI0 on PIN_16/PIN_18/PIN_19, chip selects on PIN_0, PIN_1,
+ *    PIN_15 and PIN_17
+ */
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_rp::init(Default::default());
+    initialize_logger(&spawner, p.USB);
+    initialize_program("MultiPico").await;
+    let (mut control, stack, servers) = initialize_wifi(
+        &spawner, p.FLASH, p.PIN_23, p.PIN_25, p.PIO0, p.PIN_24, p.PIN_29, p.DMA_CH0,
+    )
+    .await;
+
+    #[cfg(feature = "actuators")]
+    {
+        let actuators = Actuators::new(
+            Vec::from_array([
+                SwitchRails {
+                    gpio: Output::new(p.PIN_2, Level::Low),
+                    id: ActuatorId::SwitchRails1,
+                },
+                SwitchRails {
+                    gpio: Output::new(p.PIN_3, Level::Low),
+                    id: ActuatorId::SwitchRails2,
+                },
+                SwitchRails {
+                    gpio: Output::new(p.PIN_4, Level::Low),
+                    id: ActuatorId::SwitchRails3,
+                },
+                SwitchRails {
+                    gpio: Output::new(p.PIN_5, Level::Low),
+                    id: ActuatorId::SwitchRails4,
+                },
+                SwitchRails {
+                    gpio: Output::new(p.PIN_6, Level::Low),
+                    id: ActuatorId::SwitchRails5,
+                },
+                SwitchRails {
+                    gpio: Output::new(p.PIN_7, Level::Low),
+                    id: ActuatorId::SwitchRails6,
+                },
+                SwitchRails {
+                    gpio: Output::new(p.PIN_8, Level::Low),
+                    id: ActuatorId::SwitchRails7,
+                },
+                SwitchRails {
+                    gpio: Output::new(p.PIN_9, Level::Low),
+                    id: ActuatorId::SwitchRails8,
+                },
+            ]),
+            TrackPower {
+                gpio: Output::new(p.PIN_10, Level::High),
+            },
+            Vec::from_array([SignalLight {
+                red: Output::new(p.PIN_20, Level::High),
+                yellow: Output::new(p.PIN_21, Level::Low),
+                green: Output::new(p.PIN_22, Level::Low),
+                id: ActuatorId::Signal1,
+            }]),
+            Vec::new(),
+        );
+
+        unwrap!(spawner.spawn(input_monitor_task([
+            DigitalInput::new(Input::new(p.PIN_11, Pull::Up), InputId::Input1, Level::Low),
+            DigitalInput::new(Input::new(p.PIN_12, Pull::Up), InputId::Input2, Level::Low),
+            DigitalInput::new(Input::new(p.PIN_13, Pull::Up), InputId::Input3, Level::Low),
+            DigitalInput::new(Input::new(p.PIN_14, Pull::Up), InputId::Input4, Level::Low),
+        ])));
+
+        if OVERCURRENT_ADC_THRESHOLD.is_some() {
+            unwrap!(spawner.spawn(current_monitor_task(
+                Adc::new_blocking(p.ADC, AdcConfig::default()),
+                AdcChannel::new_pin(p.PIN_26, Pull::None),
+            )));
+        }
+
+        unwrap!(spawner.spawn(actuators_role_task(stack, servers.clone(), actuators)));
+    }
+
+    #[cfg(feature = "sensors")]
+    {
+        let readers = Vec::from_array([
+            (
+                ReaderBus::Mfrc522Spi(Output::new(p.PIN_0, Level::High)),
+                SensorId::RfidReader1,
+            ),
+            (
+                ReaderBus::Mfrc522Spi(Output::new(p.PIN_1, Level::High)),
+                SensorId::RfidReader2,
+            ),
+            (
+                ReaderBus::Mfrc522Spi(Output::new(p.PIN_15, Level::High)),
+                SensorId::RfidReader3,
+            ),
+            (
+                ReaderBus::Mfrc522Spi(Output::new(p.PIN_17, Level::High)),
+                SensorId::RfidReader4,
+            ),
+        ]);
+        let sensors = Sensors::new(readers.iter().map(|(_, sensor_id)| *sensor_id).collect());
+
+        unwrap!(spawner.spawn(tag_reader_task(
+            Spi::new_blocking(p.SPI0, p.PIN_18, p.PIN_19, p.PIN_16, spi::Config::default()),
+            readers,
+        )));
+
+        unwrap!(spawner.spawn(sensors_role_task(stack, servers, sensors)));
+    }
+
+    // Every role is now running from its own task
+    control.gpio_set(0, true).await;
+}
+
+#[cfg(feature = "actuators")]
+#[embassy_executor::task]
+async fn actuators_role_task(
+    stack: Stack<'static>,
+    servers: ServerAddresses,
+    mut actuators: Actuators,
+) {
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+
+    loop {
+        let mut socket = match connect_loco_controller(
+            stack,
+            &mut rx_buffer,
+            &mut tx_buffer,
+            &servers,
+            SERVER_TCP_PORT_ACTUATORS,
+        )
+        .await
+        {
+            Ok(s) => s,
+            Err(e) => {
+                log::warn!("[actuators] connection error: {:?}", e);
+                Timer::after_secs(1).await;
+                continue;
+            }
+        };
+
+        // Handle incoming messages from the server, while reporting telemetry
+        if let Err(e) = actuators.handle_connection(&mut socket).await {
+            log::error!("[actuators] {:?}", e);
+        }
+    }
+}
+
+#[cfg(feature = "sensors")]
+#[embassy_executor::task]
+async fn sensors_role_task(stack: Stack<'static>, servers: ServerAddresses, sensors: Sensors) {
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+
+    loop {
+        let mut socket = match connect_loco_controller(
+            stack,
+            &mut rx_buffer,
+            &mut tx_buffer,
+            &servers,
+            SERVER_TCP_PORT_SENSORS,
+        )
+        .await
+        {
+            Ok(s) => s,
+            Err(e) => {
+                log::warn!("[sensors] connection error: {:?}", e);
+                Timer::after_secs(1).await;
+                continue;
+            }
+        };
+
+        // Periodically check sensors status and send updated status to
+        // loco_controller
+        if let Err(e) = sensors.handle_sensors_updates(&mut socket).await {
+            log::error!("[sensors] {:?}", e);
+        }
+    }
+}
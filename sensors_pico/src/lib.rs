@@ -0,0 +1,561 @@
+#![no_std]
+
+mod pn532;
+pub mod reader;
+
+use core::cell::RefCell;
+use core::num::TryFromIntError;
+
+use bincode::config::{Configuration, Fixint, LittleEndian, NoLimit};
+use bincode::encode_into_slice;
+use bincode::error::EncodeError;
+use common_pico::{
+    AUTH_TOKEN, CODEC, HEARTBEAT_PERIOD_MS, REQUEST_MAX_SIZE, firmware_version, send_heartbeat,
+};
+use embassy_net::tcp::TcpSocket;
+use embassy_rp::gpio::Input;
+use embassy_rp::peripherals::SPI0;
+use embassy_rp::spi::{Blocking, Spi};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Write as _;
+use heapless::{Deque, Vec};
+use loco_protocol::{
+    ANONYMOUS_LOCO_ID, AUTH_TOKEN_EXT_ID, BACKEND_PROTOCOL_VERSION, Error as LocoProtocolError,
+    HEADER_SIZE, LocoId, Operation, PRESENT_SENSORS_EXT_ID, RegisterPayload,
+    SENSORS_STATUS_BATCH_MAX_LEN, SENSORS_STATUS_EXT_UNKNOWN_TAGS, SensorEvent, SensorId,
+    SensorStatus, SensorType, SensorsStatusBatch, SequenceCounter, TimeSyncPayload,
+    UNKNOWN_TAG_SIZE, encode_extension_field,
+};
+use reader::{Reader, ReaderBus, TagReader};
+
+struct RfidReader<'a> {
+    reader: Reader<'a>,
+    sensor_id: SensorId,
+    // Loco over the reader, along with when it was last seen
+    present: Option<(LocoId, Instant)>,
+}
+
+// A loco is only reported as departed once the reader hasn't seen it for
+// this long, since a read fails now and then while the tag is still there
+const DEPARTURE_DELAY_MS: u64 = 300;
+
+impl RfidReader<'_> {
+    // Reports the loco which was over the reader as departed when it hasn't
+    // been seen for a while, or as soon as another loco shows up. The
+    // departure is timestamped from when the loco was last seen.
+    fn update_presence(&mut self, detected: Option<LocoId>) {
+        let now = Instant::now();
+        if let Some((loco_id, last_seen)) = self.present
+            && match detected {
+                Some(detected) => detected != loco_id,
+                None => now - last_seen > Duration::from_millis(DEPARTURE_DELAY_MS),
+            }
+        {
+            log::debug!("[{}] {} departed", self.sensor_id, loco_id);
+            SENSORS_DATA.lock(|d| {
+                d.borrow_mut().record(
+                    Some(loco_id),
+                    self.sensor_id,
+                    SensorType::Rfid,
+                    SensorEvent::Departed,
+                    last_seen,
+                )
+            });
+            self.present = None;
+        }
+
+        if let Some(loco_id) = detected {
+            self.present = Some((loco_id, now));
+        }
+    }
+}
+
+/**
+ * Configuration of the detections buffering.
+ *
+ * Detections are queued until they have been sent to the loco_controller, so
+ * that nothing is lost while the connection is down. If the queue gets full,
+ * the oldest detections are dropped first, as the latest ones are the most
+ * relevant to locate the locos.
+ *
+ * The number of detections sent per message is bounded so that the payload
+ * always fits into PAYLOAD_MAX_SIZE.
+ */
+const SENSORS_EVENTS_CAPACITY: usize = 64;
+const SENSORS_EVENTS_PER_MESSAGE: usize = 16;
+const _: () = assert!(SENSORS_EVENTS_PER_MESSAGE <= SENSORS_STATUS_BATCH_MAX_LEN);
+
+// Tags which aren't known to the firmware are reported to the
+// loco_controller on a best effort basis, a few per message.
+const UNKNOWN_TAGS_PER_MESSAGE: usize = 8;
+
+struct SensorData {
+    seq: u32,
+    // None when the sensor can't tell which loco it saw
+    loco_id: Option<LocoId>,
+    sensor_id: SensorId,
+    sensor_type: SensorType,
+    event: SensorEvent,
+    timestamp: Instant,
+}
+
+struct SensorsData {
+    events: Deque<SensorData, SENSORS_EVENTS_CAPACITY>,
+    next_seq: u32,
+    unknown_tags: Vec<(SensorId, [u8; 4]), UNKNOWN_TAGS_PER_MESSAGE>,
+}
+
+impl SensorsData {
+    fn record(
+        &mut self,
+        loco_id: Option<LocoId>,
+        sensor_id: SensorId,
+        sensor_type: SensorType,
+        event: SensorEvent,
+        timestamp: Instant,
+    ) {
+        // A loco standing on a reader is detected over and over. Refresh the
+        // latest pending detection of this reader rather than queuing a new
+        // one, as long as it's about the same loco and event.
+        if let Some(d) = self
+            .events
+            .iter_mut()
+            .rev()
+            .find(|d| d.sensor_id == sensor_id)
+            && d.loco_id == loco_id
+            && d.event == event
+        {
+            d.timestamp = timestamp;
+            return;
+        }
+
+        if self.events.is_full()
+            && let Some(d) = self.events.pop_front()
+        {
+            log::warn!(
+                "Detections queue is full, dropping {:?} at {}",
+                d.loco_id,
+                d.sensor_id
+            );
+        }
+
+        // Can't fail since room has been made above
+        let _ = self.events.push_back(SensorData {
+            seq: self.next_seq,
+            loco_id,
+            sensor_id,
+            sensor_type,
+            event,
+            timestamp,
+        });
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+
+    fn record_unknown_tag(&mut self, sensor_id: SensorId, uid: [u8; 4]) {
+        // Same as for the locos, a tag standing on a reader is only reported
+        // once until it's been sent
+        if self.unknown_tags.contains(&(sensor_id, uid)) {
+            return;
+        }
+
+        if self.unknown_tags.push((sensor_id, uid)).is_err() {
+            log::warn!("Unknown tags list is full, dropping {:02x?}", uid);
+        }
+    }
+
+    // Removes every detection up to the given sequence number, once they've
+    // been sent to the loco_controller. Sequence numbers within the queue are
+    // always consecutive, and if the sent detections have already been dropped
+    // meanwhile, nothing is left to remove.
+    fn acknowledge(&mut self, last_seq: u32) {
+        let Some(front) = self.events.front() else {
+            return;
+        };
+
+        let count = last_seq.wrapping_sub(front.seq).wrapping_add(1) as usize;
+        if count <= self.events.len() {
+            for _ in 0..count {
+                self.events.pop_front();
+            }
+        }
+    }
+}
+
+static SENSORS_DATA: Mutex<CriticalSectionRawMutex, RefCell<SensorsData>> =
+    Mutex::new(RefCell::new(SensorsData {
+        events: Deque::new(),
+        next_seq: 0,
+        unknown_tags: Vec::new(),
+    }));
+
+#[embassy_executor::task]
+pub async fn tag_reader_task(
+    spi: Spi<'static, SPI0, Blocking>,
+    sensors_data: Vec<(ReaderBus, SensorId), 8>,
+) {
+    let spi_rc = RefCell::new(spi);
+    let mut readers: Vec<RfidReader, 8> = Vec::new();
+
+    for (bus, sensor_id) in sensors_data {
+        if let Err(reader) = readers.push(RfidReader {
+            reader: Reader::new(bus, &spi_rc),
+            sensor_id,
+            present: None,
+        }) {
+            log::error!("Readers vector is full, can't add {:?}", reader.sensor_id);
+        };
+    }
+
+    loop {
+        for reader in readers.iter_mut() {
+            let mut detected = None;
+            if let Some(uid) = reader.reader.read_uid() {
+                match LocoId::try_from(&uid[..]) {
+                    Ok(loco_id) => {
+                        log::debug!("[{}] Detected {}", reader.sensor_id, loco_id);
+                        detected = Some(loco_id);
+                    }
+                    Err(e) => {
+                        log::error!("[{}] Invalid UID: {:?}", reader.sensor_id, e);
+                        SENSORS_DATA
+                            .lock(|d| d.borrow_mut().record_unknown_tag(reader.sensor_id, uid));
+                    }
+                }
+            }
+
+            // Any departure goes first, since it happened before
+            reader.update_presence(detected);
+            if let Some(loco_id) = detected {
+                SENSORS_DATA.lock(|d| {
+                    d.borrow_mut().record(
+                        Some(loco_id),
+                        reader.sensor_id,
+                        SensorType::Rfid,
+                        SensorEvent::Arrived,
+                        Instant::now(),
+                    )
+                });
+            }
+        }
+
+        Timer::after_millis(1).await;
+    }
+}
+
+/**
+ * Hall sensor, reed switch or IR beam receiver, pulled low while a loco is
+ * over it. Such a sensor can't tell which loco it saw, the loco_controller
+ * works it out from where the locos were last seen.
+ */
+pub struct AnonymousSensor {
+    input: Input<'static>,
+    sensor_id: SensorId,
+    sensor_type: SensorType,
+    // When something was last seen over the sensor, until it's departed
+    last_seen: Option<Instant>,
+}
+
+impl AnonymousSensor {
+    pub fn new(input: Input<'static>, sensor_id: SensorId, sensor_type: SensorType) -> Self {
+        AnonymousSensor {
+            input,
+            sensor_id,
+            sensor_type,
+            last_seen: None,
+        }
+    }
+
+    pub fn sensor_id(&self) -> SensorId {
+        self.sensor_id
+    }
+}
+
+// The arrival is reported once, when the sensor gets triggered, and the
+// departure once it stayed released for as long as for the RFID readers
+#[embassy_executor::task]
+pub async fn anonymous_sensor_task(mut sensors: Vec<AnonymousSensor, 8>) {
+    loop {
+        for sensor in sensors.iter_mut() {
+            let now = Instant::now();
+            let event = if sensor.input.is_low() {
+                let arrived = sensor.last_seen.is_none();
+                sensor.last_seen = Some(now);
+                arrived.then_some((SensorEvent::Arrived, now))
+            } else {
+                match sensor.last_seen {
+                    Some(last_seen)
+                        if now - last_seen > Duration::from_millis(DEPARTURE_DELAY_MS) =>
+                    {
+                        sensor.last_seen = None;
+                        Some((SensorEvent::Departed, last_seen))
+                    }
+                    _ => None,
+                }
+            };
+
+            if let Some((event, timestamp)) = event {
+                log::debug!("[{}] {} {}", sensor.sensor_id, sensor.sensor_type, event);
+                SENSORS_DATA.lock(|d| {
+                    d.borrow_mut().record(
+                        None,
+                        sensor.sensor_id,
+                        sensor.sensor_type,
+                        event,
+                        timestamp,
+                    )
+                });
+            }
+        }
+
+        Timer::after_millis(1).await;
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    EncodeBatch(LocoProtocolError),
+    EncodeExtension(LocoProtocolError),
+    EncodeFrame(LocoProtocolError),
+    EncodeIntoSlice(EncodeError),
+    PayloadSizeTooLarge(TryFromIntError),
+    TcpWrite(embassy_net::tcp::Error),
+}
+
+type Result<T> = core::result::Result<T, Error>;
+
+pub struct Sensors {
+    bincode_cfg: Configuration<LittleEndian, Fixint, NoLimit>,
+    // Sensors wired on this board, advertised to the loco_controller
+    sensor_ids: Vec<SensorId, 8>,
+}
+
+impl Sensors {
+    pub fn new(sensor_ids: Vec<SensorId, 8>) -> Self {
+        log::debug!("Sensors::new()");
+
+        Sensors {
+            bincode_cfg: bincode::config::legacy(),
+            sensor_ids,
+        }
+    }
+
+    // Pending detections are only encoded here, and remain queued until the
+    // message has been sent. The sequence number of the last encoded detection
+    // is returned so that they can be removed from the queue afterwards.
+    // Unknown tags follow as an extension, and are forgotten once encoded.
+    fn extend_payload_with_sensor_status_list(
+        &self,
+        payload: &mut [u8],
+    ) -> Result<(u8, u16, Option<u32>, bool)> {
+        log::debug!("Sensors::extend_payload_with_sensor_status_list()");
+
+        let mut batch = SensorsStatusBatch::new(payload);
+        let mut last_seq = None;
+        SENSORS_DATA
+            .lock(|d| {
+                let sensors_data = d.borrow();
+                for d in sensors_data.events.iter().take(SENSORS_EVENTS_PER_MESSAGE) {
+                    log::info!("{:?} {} at sensor {}", d.loco_id, d.event, d.sensor_id);
+                    batch.push(SensorStatus {
+                        sensor_id: d.sensor_id.into(),
+                        sensor_type: d.sensor_type.into(),
+                        loco_id: d.loco_id.map_or(ANONYMOUS_LOCO_ID, u8::from),
+                        event: d.event.into(),
+                        timestamp_us: d.timestamp.as_micros(),
+                    })?;
+                    last_seq = Some(d.seq);
+                }
+                Ok::<_, LocoProtocolError>(())
+            })
+            .map_err(Error::EncodeBatch)?;
+        // Safe to cast since the batch is bounded by SENSORS_EVENTS_PER_MESSAGE
+        let updated_sensors = batch.len() as u8;
+        let mut payload_offset = batch.finish().map_err(Error::EncodeBatch)?;
+
+        let mut unknown_tags = [0u8; UNKNOWN_TAG_SIZE * UNKNOWN_TAGS_PER_MESSAGE];
+        let mut unknown_tags_len = 0;
+        SENSORS_DATA.lock(|d| {
+            let mut sensors_data = d.borrow_mut();
+            for (sensor_id, uid) in sensors_data.unknown_tags.iter() {
+                log::info!("Unknown tag {:02x?} detected by reader {}", uid, sensor_id);
+                let entry =
+                    &mut unknown_tags[unknown_tags_len..unknown_tags_len + UNKNOWN_TAG_SIZE];
+                entry[0] = (*sensor_id).into();
+                entry[1..].copy_from_slice(uid);
+                unknown_tags_len += UNKNOWN_TAG_SIZE;
+            }
+            sensors_data.unknown_tags.clear();
+        });
+        if unknown_tags_len > 0 {
+            payload_offset += encode_extension_field(
+                &mut payload[payload_offset..],
+                SENSORS_STATUS_EXT_UNKNOWN_TAGS,
+                &unknown_tags[..unknown_tags_len],
+            )
+            .map_err(Error::EncodeExtension)?;
+        }
+
+        Ok((
+            updated_sensors,
+            u16::try_from(payload_offset).map_err(Error::PayloadSizeTooLarge)?,
+            last_seq,
+            unknown_tags_len > 0,
+        ))
+    }
+
+    async fn send_sensors_status_op(
+        &self,
+        socket: &mut TcpSocket<'_>,
+        sequence: u16,
+        message: &mut [u8],
+        payload_len: u16,
+    ) -> Result<()> {
+        log::debug!("Sensors::send_sensors_status_op()");
+
+        let frame_len = CODEC
+            .encode_message(
+                message,
+                Operation::SensorsStatus,
+                usize::from(payload_len),
+                sequence,
+            )
+            .map_err(Error::EncodeFrame)?;
+
+        socket
+            .write_all(&message[..frame_len])
+            .await
+            .map_err(Error::TcpWrite)?;
+
+        Ok(())
+    }
+
+    async fn send_register_op(&self, socket: &mut TcpSocket<'_>, sequence: u16) -> Result<()> {
+        log::debug!("Sensors::send_register_op()");
+
+        let mut message = [0u8; REQUEST_MAX_SIZE];
+        let mut payload_len = encode_into_slice(
+            RegisterPayload {
+                protocol_version: BACKEND_PROTOCOL_VERSION,
+                firmware_version: firmware_version!(),
+            },
+            &mut message[HEADER_SIZE..],
+            self.bincode_cfg,
+        )
+        .map_err(Error::EncodeIntoSlice)?;
+
+        let sensor_ids: Vec<u8, 8> = self.sensor_ids.iter().map(|id| (*id).into()).collect();
+        payload_len += encode_extension_field(
+            &mut message[HEADER_SIZE + payload_len..],
+            PRESENT_SENSORS_EXT_ID,
+            &sensor_ids,
+        )
+        .map_err(Error::EncodeExtension)?;
+        if let Some(auth_token) = AUTH_TOKEN {
+            payload_len += encode_extension_field(
+                &mut message[HEADER_SIZE + payload_len..],
+                AUTH_TOKEN_EXT_ID,
+                auth_token.as_bytes(),
+            )
+            .map_err(Error::EncodeExtension)?;
+        }
+
+        let frame_len = CODEC
+            .encode_message(&mut message, Operation::Register, payload_len, sequence)
+            .map_err(Error::EncodeFrame)?;
+
+        socket
+            .write_all(&message[..frame_len])
+            .await
+            .map_err(Error::TcpWrite)?;
+
+        Ok(())
+    }
+
+    async fn send_time_sync_op(&self, socket: &mut TcpSocket<'_>, sequence: u16) -> Result<()> {
+        log::debug!("Sensors::send_time_sync_op()");
+
+        let mut message = [0u8; REQUEST_MAX_SIZE];
+        let frame_len = CODEC
+            .encode(
+                &mut message,
+                Operation::TimeSync,
+                TimeSyncPayload {
+                    time_us: Instant::now().as_micros(),
+                },
+                sequence,
+            )
+            .map_err(Error::EncodeFrame)?;
+
+        socket
+            .write_all(&message[..frame_len])
+            .await
+            .map_err(Error::TcpWrite)?;
+
+        Ok(())
+    }
+
+    pub async fn handle_sensors_updates(&self, socket: &mut TcpSocket<'_>) -> Result<()> {
+        log::debug!("Sensors::handle_sensors_updates()");
+
+        // Sequence numbers of the frames sent through this connection
+        let mut tx_sequence = SequenceCounter::default();
+
+        // Register to the controller so it can check our versions
+        self.send_register_op(socket, tx_sequence.next_sequence())
+            .await?;
+
+        // Detections buffered while disconnected are about to be sent, make
+        // sure the controller can convert their timestamps right away.
+        self.send_time_sync_op(socket, tx_sequence.next_sequence())
+            .await?;
+
+        let mut message = [0u8; REQUEST_MAX_SIZE];
+        let payload_offset = HEADER_SIZE;
+        let mut now = Instant::now();
+
+        loop {
+            // Check sensors which need to be updated and fill payload
+            let (updated_sensors, payload_len, last_seq, unknown_tags) =
+                self.extend_payload_with_sensor_status_list(&mut message[payload_offset..])?;
+
+            // Communicate with the loco_controller every second, even if no
+            // sensor was updated. A heartbeat maintains the connection alive
+            // at a very minimal cost.
+            let keepalive = now.elapsed().as_millis() > HEARTBEAT_PERIOD_MS;
+            if updated_sensors > 0 || unknown_tags || keepalive {
+                // Let the controller estimate our clock offset, so that it
+                // can convert detection timestamps into its own clock.
+                if keepalive {
+                    self.send_time_sync_op(socket, tx_sequence.next_sequence())
+                        .await?;
+                }
+
+                if updated_sensors > 0 || unknown_tags {
+                    // Send update to the loco_controller server
+                    self.send_sensors_status_op(
+                        socket,
+                        tx_sequence.next_sequence(),
+                        &mut message,
+                        payload_len,
+                    )
+                    .await?;
+
+                    // Detections can be forgotten now that they've been sent
+                    if let Some(last_seq) = last_seq {
+                        SENSORS_DATA.lock(|d| d.borrow_mut().acknowledge(last_seq));
+                    }
+                } else {
+                    send_heartbeat(socket, tx_sequence.next_sequence())
+                        .await
+                        .map_err(Error::TcpWrite)?;
+                }
+
+                // Update timer
+                now = Instant::now();
+            }
+
+            Timer::after_millis(100).await;
+        }
+    }
+}
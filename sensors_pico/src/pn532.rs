@@ -0,0 +1,160 @@
+// Minimal driver for the PN532 over I2C, only listing the ISO 14443A tag in
+// front of it to get its UID. See the PN532 user manual for the frames.
+
+use embassy_time::{Duration, block_for};
+use embedded_hal::i2c::I2c;
+
+// The address can't be changed, hence a single PN532 per I2C bus
+const I2C_ADDRESS: u8 = 0x24;
+
+const PREAMBLE: [u8; 3] = [0x00, 0x00, 0xff];
+const ACK_FRAME: [u8; 6] = [0x00, 0x00, 0xff, 0x00, 0xff, 0x00];
+const HOST_TO_PN532: u8 = 0xd4;
+const PN532_TO_HOST: u8 = 0xd5;
+// First byte of every read, telling whether the PN532 has something to say
+const STATUS_READY: u8 = 0x01;
+
+const CMD_SAM_CONFIGURATION: u8 = 0x14;
+const CMD_RF_CONFIGURATION: u8 = 0x32;
+const CMD_IN_LIST_PASSIVE_TARGET: u8 = 0x4a;
+
+// Normal mode, without any timeout nor IRQ
+const SAM_CONFIGURATION: [u8; 3] = [0x01, 0x00, 0x00];
+// Default retries for ATR and PSL, but a single passive activation attempt so
+// that listing returns right away when there's no tag
+const RF_CONFIGURATION_MAX_RETRIES: [u8; 4] = [0x05, 0xff, 0x01, 0x00];
+// A single target, at 106 kbps type A
+const IN_LIST_PASSIVE_TARGET: [u8; 2] = [0x01, 0x00];
+
+// The PN532 is polled every millisecond until it's ready to answer
+const READY_POLL_MAX: u32 = 50;
+
+pub const FRAME_MAX_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Bus(E),
+    CommandTooLarge,
+    InvalidAck,
+    InvalidFrame,
+    NotReady,
+}
+
+type Result<T, E> = core::result::Result<T, Error<E>>;
+
+pub struct Pn532<I> {
+    i2c: I,
+}
+
+impl<I: I2c> Pn532<I> {
+    pub fn new(i2c: I) -> Self {
+        Pn532 { i2c }
+    }
+
+    pub fn init(&mut self) -> Result<(), I::Error> {
+        let mut response = [0u8; FRAME_MAX_SIZE];
+        self.command(CMD_SAM_CONFIGURATION, &SAM_CONFIGURATION, &mut response)?;
+        self.command(
+            CMD_RF_CONFIGURATION,
+            &RF_CONFIGURATION_MAX_RETRIES,
+            &mut response,
+        )?;
+
+        Ok(())
+    }
+
+    // UID of the tag in front of the reader, if any
+    pub fn list_target<'r>(
+        &mut self,
+        response: &'r mut [u8; FRAME_MAX_SIZE],
+    ) -> Result<Option<&'r [u8]>, I::Error> {
+        let data = self.command(
+            CMD_IN_LIST_PASSIVE_TARGET,
+            &IN_LIST_PASSIVE_TARGET,
+            response,
+        )?;
+
+        // Number of targets, then the target number, SENS_RES, SEL_RES and
+        // the length of the UID which follows
+        match data {
+            [0, ..] => Ok(None),
+            [1, _, _, _, _, uid_len, uid @ ..] if uid.len() >= usize::from(*uid_len) => {
+                Ok(Some(&uid[..usize::from(*uid_len)]))
+            }
+            _ => Err(Error::InvalidFrame),
+        }
+    }
+
+    fn wait_ready(&mut self) -> Result<(), I::Error> {
+        for _ in 0..READY_POLL_MAX {
+            let mut status = [0u8];
+            self.i2c
+                .read(I2C_ADDRESS, &mut status)
+                .map_err(Error::Bus)?;
+            if status[0] & STATUS_READY != 0 {
+                return Ok(());
+            }
+            block_for(Duration::from_millis(1));
+        }
+
+        Err(Error::NotReady)
+    }
+
+    // Sends the command and waits for its acknowledgment, then for the
+    // response, returning its data
+    fn command<'r>(
+        &mut self,
+        cmd: u8,
+        params: &[u8],
+        response: &'r mut [u8; FRAME_MAX_SIZE],
+    ) -> Result<&'r [u8], I::Error> {
+        // Preamble, then the length of TFI, command and parameters along with
+        // its checksum, the data itself, its checksum and the postamble
+        let len = params.len() + 2;
+        let frame_len = PREAMBLE.len() + len + 4;
+        if frame_len > FRAME_MAX_SIZE {
+            return Err(Error::CommandTooLarge);
+        }
+        let mut frame = [0u8; FRAME_MAX_SIZE];
+        frame[..3].copy_from_slice(&PREAMBLE);
+        frame[3] = len as u8;
+        frame[4] = (len as u8).wrapping_neg();
+        frame[5] = HOST_TO_PN532;
+        frame[6] = cmd;
+        frame[7..5 + len].copy_from_slice(params);
+        frame[5 + len] = checksum(&frame[5..5 + len]).wrapping_neg();
+        self.i2c
+            .write(I2C_ADDRESS, &frame[..frame_len])
+            .map_err(Error::Bus)?;
+
+        // Every read starts with the status byte
+        let mut ack = [0u8; 1 + ACK_FRAME.len()];
+        self.wait_ready()?;
+        self.i2c.read(I2C_ADDRESS, &mut ack).map_err(Error::Bus)?;
+        if ack[1..] != ACK_FRAME {
+            return Err(Error::InvalidAck);
+        }
+
+        self.wait_ready()?;
+        self.i2c.read(I2C_ADDRESS, response).map_err(Error::Bus)?;
+        let response: &'r [u8; FRAME_MAX_SIZE] = response;
+        let frame = &response[1..];
+        let len = usize::from(frame[3]);
+        if frame[..3] != PREAMBLE
+            || frame[3].wrapping_add(frame[4]) != 0
+            || len < 2
+            || 5 + len >= frame.len()
+            || frame[5] != PN532_TO_HOST
+            || frame[6] != cmd + 1
+            || checksum(&frame[5..=5 + len]) != 0
+        {
+            return Err(Error::InvalidFrame);
+        }
+
+        Ok(&frame[7..5 + len])
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
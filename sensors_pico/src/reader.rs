@@ -0,0 +1,123 @@
+use core::cell::RefCell;
+
+use embassy_rp::gpio::Output;
+use embassy_rp::i2c::{Blocking as I2cBlocking, I2c};
+use embassy_rp::peripherals::{I2C0, I2C1, SPI0};
+use embassy_rp::spi::{Blocking, Spi};
+use embassy_time::Delay;
+use embedded_hal::i2c::I2c as I2cBus;
+use embedded_hal_bus::spi::RefCellDevice;
+use mfrc522::comm::blocking::spi::{DummyDelay, SpiInterface};
+use mfrc522::{Initialized, Mfrc522, RxGain, Uid};
+
+use crate::pn532::{FRAME_MAX_SIZE, Pn532};
+
+/**
+ * RFID reader telling which tag is in front of it, whatever its chip and the
+ * bus it's wired on.
+ */
+pub trait TagReader {
+    // Only single size UIDs are reported, as they're the only ones which
+    // can belong to a loco. Failed reads are reported as no tag, since they
+    // happen now and then while a tag is there.
+    fn read_uid(&mut self) -> Option<[u8; 4]>;
+}
+
+type Mfrc522Spi<'a> = Mfrc522<
+    SpiInterface<
+        RefCellDevice<'a, Spi<'static, SPI0, Blocking>, Output<'static>, Delay>,
+        DummyDelay,
+    >,
+    Initialized,
+>;
+
+impl TagReader for Mfrc522Spi<'_> {
+    fn read_uid(&mut self) -> Option<[u8; 4]> {
+        let atqa = self.wupa().ok()?;
+        let uid = match self.select(&atqa) {
+            Ok(Uid::Single(ref uid)) => <[u8; 4]>::try_from(uid.as_bytes()).ok(),
+            Ok(_) => {
+                log::debug!("MFRC522 got other UID size");
+                None
+            }
+            Err(e) => {
+                log::debug!("MFRC522 error getting card UID: {:?}", e);
+                None
+            }
+        };
+        let _ = self.hlta();
+
+        uid
+    }
+}
+
+impl<I: I2cBus> TagReader for Pn532<I> {
+    fn read_uid(&mut self) -> Option<[u8; 4]> {
+        let mut response = [0u8; FRAME_MAX_SIZE];
+        match self.list_target(&mut response) {
+            Ok(Some(uid)) => {
+                let uid = <[u8; 4]>::try_from(uid).ok();
+                if uid.is_none() {
+                    log::debug!("PN532 got other UID size");
+                }
+                uid
+            }
+            Ok(None) => None,
+            Err(e) => {
+                log::debug!("PN532 error getting card UID: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+/**
+ * Chip of an RFID reader and how it's wired. MFRC522 readers share the SPI
+ * bus, each one being selected through its own chip select pin, whereas the
+ * address of a PN532 is fixed so each one takes a whole I2C bus.
+ */
+pub enum ReaderBus {
+    Mfrc522Spi(Output<'static>),
+    Pn532I2c0(I2c<'static, I2C0, I2cBlocking>),
+    Pn532I2c1(I2c<'static, I2C1, I2cBlocking>),
+}
+
+pub(crate) enum Reader<'a> {
+    Mfrc522Spi(Mfrc522Spi<'a>),
+    Pn532I2c0(Pn532<I2c<'static, I2C0, I2cBlocking>>),
+    Pn532I2c1(Pn532<I2c<'static, I2C1, I2cBlocking>>),
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bus: ReaderBus, spi: &'a RefCell<Spi<'static, SPI0, Blocking>>) -> Self {
+        match bus {
+            ReaderBus::Mfrc522Spi(cs_pin) => {
+                let mut mfrc522 =
+                    Mfrc522::new(SpiInterface::new(RefCellDevice::new(spi, cs_pin, Delay)))
+                        .init()
+                        .expect("could not create reader");
+                mfrc522.set_receive_timeout(1).unwrap();
+                mfrc522.set_antenna_gain(RxGain::DB48).unwrap();
+                Reader::Mfrc522Spi(mfrc522)
+            }
+            ReaderBus::Pn532I2c0(i2c) => Reader::Pn532I2c0(init_pn532(i2c)),
+            ReaderBus::Pn532I2c1(i2c) => Reader::Pn532I2c1(init_pn532(i2c)),
+        }
+    }
+}
+
+impl TagReader for Reader<'_> {
+    fn read_uid(&mut self) -> Option<[u8; 4]> {
+        match self {
+            Reader::Mfrc522Spi(reader) => TagReader::read_uid(reader),
+            Reader::Pn532I2c0(reader) => TagReader::read_uid(reader),
+            Reader::Pn532I2c1(reader) => TagReader::read_uid(reader),
+        }
+    }
+}
+
+fn init_pn532<I: I2cBus>(i2c: I) -> Pn532<I> {
+    let mut pn532 = Pn532::new(i2c);
+    pn532.init().expect("could not create reader");
+    pn532
+}